@@ -1,7 +1,7 @@
 //! Authorized repo list + anchor commit validation for pull operations.
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct AuthorizedReposFile {
@@ -10,9 +10,12 @@ pub struct AuthorizedReposFile {
     pub relay_server_id: Option<String>,
     #[serde(default)]
     pub repos: HashMap<String, RepoAnchor>,
+    /// File this list was loaded from (used when adding entries, e.g. for forks).
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RepoAnchor {
     /// Git object id; must be an ancestor of `branch` after every successful pull.
     pub anchor_commit: String,
@@ -26,10 +29,55 @@ fn default_branch() -> String {
 
 pub fn load_from_path(path: &Path) -> anyhow::Result<AuthorizedReposFile> {
     let s = std::fs::read_to_string(path)?;
-    let cfg: AuthorizedReposFile = serde_yaml::from_str(&s)?;
+    let mut cfg: AuthorizedReposFile = serde_yaml::from_str(&s)?;
+    cfg.path = Some(path.to_path_buf());
     Ok(cfg)
 }
 
+/// Add (or replace) `repo_name` in the allowlist file at `path`, keeping other keys intact.
+/// The running server keeps its loaded copy; the entry applies from the next start.
+pub fn add_repo_entry(path: &Path, repo_name: &str, anchor: &RepoAnchor) -> anyhow::Result<()> {
+    let s = std::fs::read_to_string(path)?;
+    let mut doc: serde_yaml::Value = serde_yaml::from_str(&s)?;
+    let root = doc
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow::anyhow!("authorized-repos file is not a mapping"))?;
+    let repos = root
+        .entry("repos".into())
+        .or_insert_with(|| serde_yaml::Value::Mapping(Default::default()));
+    if repos.is_null() {
+        *repos = serde_yaml::Value::Mapping(Default::default());
+    }
+    repos
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow::anyhow!("authorized-repos `repos` is not a mapping"))?
+        .insert(repo_name.into(), serde_yaml::to_value(anchor)?);
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_yaml::to_string(&doc)?)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Add a new fork to the allowlist at `path`, anchored at the tip of its default branch (or its
+/// first branch when it has no `main`).
+pub fn authorize_fork(path: &Path, fork: &crate::git::provision::ForkedRepo) -> anyhow::Result<RepoAnchor> {
+    let branch = if fork.branches.iter().any(|b| b == crate::types::DEFAULT_BRANCH) {
+        crate::types::DEFAULT_BRANCH.to_string()
+    } else {
+        fork.branches
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("fork has no branch to anchor"))?
+    };
+    let oid = git2::Repository::open_bare(&fork.path)?.refname_to_id(&format!("refs/heads/{}", branch))?;
+    let anchor = RepoAnchor {
+        anchor_commit: oid.to_string(),
+        branch,
+    };
+    add_repo_entry(path, &fork.name, &anchor)?;
+    Ok(anchor)
+}
+
 /// When allowlist is active: repo must be listed (server id checked at startup).
 pub fn ensure_pull_allowed(cfg: &AuthorizedReposFile, _relay_server_id: Option<&str>, repo_name: &str) -> Result<(), String> {
     if cfg.repos.is_empty() {
@@ -121,6 +169,7 @@ mod tests {
         let mut cfg = AuthorizedReposFile {
             relay_server_id: None,
             repos: HashMap::new(),
+            path: None,
         };
         cfg.repos.insert(
             "r".into(),
@@ -146,4 +195,29 @@ mod tests {
         cfg.repos.get_mut("r").unwrap().anchor_commit = c1.to_string();
         assert!(validate_anchor(&repo, "r", &cfg).is_err());
     }
+
+    #[test]
+    fn add_repo_entry_preserves_existing_entries() {
+        let td = tempdir().unwrap();
+        let path = td.path().join("authorized.yaml");
+        std::fs::write(
+            &path,
+            "relay_server_id: node-1\nrepos:\n  upstream:\n    anchor_commit: abc\n",
+        )
+        .unwrap();
+        add_repo_entry(
+            &path,
+            "fork",
+            &RepoAnchor {
+                anchor_commit: "def".into(),
+                branch: "main".into(),
+            },
+        )
+        .unwrap();
+        let cfg = load_from_path(&path).unwrap();
+        assert_eq!(cfg.relay_server_id.as_deref(), Some("node-1"));
+        assert_eq!(cfg.repos["upstream"].anchor_commit, "abc");
+        assert_eq!(cfg.repos["fork"].anchor_commit, "def");
+        assert_eq!(cfg.path.as_deref(), Some(path.as_path()));
+    }
 }
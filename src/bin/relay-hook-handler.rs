@@ -1,6 +1,10 @@
 use std::io::{self, Read};
 use std::path::PathBuf;
 use relay_server::git::{execute_repo_hook, HookContext};
+use relay_server::git::branches::push_rule;
+use relay_server::git::index_worker::request_indexing;
+use relay_server::git::schema::SchemaViolations;
+use relay_server::git::signing::{pushed_commits, verify_push, AllowedKeys};
 use tracing::{info, error, debug, Level};
 use tracing_subscriber::FmtSubscriber;
 
@@ -59,8 +63,15 @@ fn main() -> anyhow::Result<()> {
         };
 
         // Extract changed files using git CLI (quarantine-aware)
-        if let Ok(fs) = extract_changed_files(&ctx.repo_path, &old_commit, &new_commit) {
-            ctx.files = fs;
+        match extract_changed_files(&ctx.repo_path, &old_commit, &new_commit) {
+            Ok(fs) => ctx.files = fs,
+            // Without the changed files schemas would validate nothing, so pre-receive fails closed
+            Err(e) if hook_name == "pre-receive" => {
+                error!("Cannot list changed files of {}: {}", refname, e);
+                eprintln!("[relay-hook-handler] Cannot list changed files of {}: {}", refname, e);
+                std::process::exit(1);
+            }
+            Err(e) => error!("Cannot list changed files of {}: {}", refname, e),
         }
 
         // Enforce branch rules for pre-receive BEFORE execute_repo_hook
@@ -75,11 +86,29 @@ fn main() -> anyhow::Result<()> {
             }
         }
 
-        if !execute_repo_hook(&ctx, hook_name)? {
+        let accepted = match execute_repo_hook(&ctx, hook_name) {
+            Ok(accepted) => accepted,
+            Err(e) if e.is::<SchemaViolations>() => {
+                error!("{}", e);
+                eprintln!("[relay-hook-handler] {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => return Err(e),
+        };
+        if !accepted {
             error!("Hook {} failed for {}", hook_name, ctx.refname);
             std::process::exit(1);
         }
 
+        // Report the move to the server, which publishes it and indexes the pushed branch
+        if hook_name == "post-receive" && refname.starts_with("refs/heads/") {
+            let tip = |oid: &str| Some(oid.to_string()).filter(|o| !o.chars().all(|c| c == '0'));
+            let (old, new) = (tip(&old_commit), tip(&new_commit));
+            if let Err(e) = request_indexing(&ctx.repo_path, &ctx.branch, old.as_deref(), new.as_deref()) {
+                error!("Failed to request indexing for {}: {}", ctx.branch, e);
+            }
+        }
+
         // Special handling for post-receive (Auto-Push)
         if hook_name == "post-receive" {
             if let Err(e) = handle_auto_push(&ctx) {
@@ -93,44 +122,29 @@ fn main() -> anyhow::Result<()> {
 
 fn enforce_branch_rules(ctx: &HookContext) -> anyhow::Result<()> {
     let repo = git2::Repository::open_bare(&ctx.repo_path)?;
-    // Read from the new commit being pushed, as the branch ref hasn't moved yet
-    let git_config = match relay_server::git::read_git_config(&repo, &ctx.new_commit) {
-        Some(c) => c,
-        None => return Ok(()),
-    };
-
-    let rules_config = match git_config.branch_rules {
-        Some(r) => r,
+    // Rules come from the same trusted revision as for HTTP writes, so a push cannot weaken its
+    // own protection
+    let (base, rule) = match push_rule(&repo, &ctx.branch, &ctx.new_commit)? {
+        Some(found) => found,
         None => return Ok(()),
     };
 
-    // Find applicable rule
-    let mut active_rule = rules_config.default.clone();
-    if let Some(branches) = rules_config.branches {
-        for b in branches {
-            if b.name == ctx.branch {
-                active_rule = Some(b.rule);
-                break;
-            }
+    // Deletions introduce no commits to verify, but protected branches refuse them as in the API
+    if ctx.new_commit.chars().all(|c| c == '0') {
+        if rule.is_protected() {
+            anyhow::bail!("branch '{}' is protected and cannot be deleted", ctx.branch);
         }
+        return Ok(());
     }
 
-    let rule = match active_rule {
-        Some(r) => r,
-        None => return Ok(()),
-    };
-
-    // Check requireSigned
-    if rule.require_signed.unwrap_or(false) && !rule.allow_unsigned.unwrap_or(false) {
-        let verify_out = std::process::Command::new("git")
-            .arg("-C").arg(&ctx.repo_path)
-            .arg("verify-commit")
-            .arg(&ctx.new_commit)
-            .output()?;
-
-        if !verify_out.status.success() {
-            return Err(anyhow::anyhow!("Commit {} must be signed and verified", ctx.new_commit));
+    // Check requireSigned on every pushed commit, against the keys declared in the trusted base
+    if rule.requires_signature() {
+        let keys = AllowedKeys::load(&ctx.repo_path, &base, &rule)?;
+        let verified = verify_push(&ctx.repo_path, &ctx.old_commit, &ctx.new_commit, &keys)?;
+        for (commit, signer) in &verified {
+            debug!("Commit {} signature verified (signer: {})", commit, signer.as_deref().unwrap_or("keyring"));
         }
+        info!("Verified signatures of {} commit(s) pushed to {}", verified.len(), ctx.refname);
     }
 
     Ok(())
@@ -207,25 +221,23 @@ fn handle_auto_push(ctx: &HookContext) -> anyhow::Result<()> {
 
 fn extract_changed_files(repo_path: &std::path::Path, old_rev: &str, new_rev: &str) -> anyhow::Result<std::collections::HashMap<String, String>> {
     let mut files = std::collections::HashMap::new();
-    
-    // Get list of changed files
-    let output = std::process::Command::new("git")
-        .arg("-C").arg(repo_path)
-        .arg("diff-tree")
-        .arg("-r")
-        .arg("--no-commit-id")
-        .arg("--name-only")
-        .arg(old_rev)
-        .arg(new_rev)
-        .output()?;
-        
-    if !output.status.success() {
+    if new_rev.chars().all(|c| c == '0') {
         return Ok(files);
     }
-    
-    let paths = String::from_utf8_lossy(&output.stdout);
-    for path in paths.lines().filter(|l| !l.is_empty()) {
-        // Extract content for each path
+
+    // Get list of changed files; a new branch has no old tree, so take the paths touched by the
+    // commits no existing ref reaches (root commits included)
+    let mut paths = std::collections::BTreeSet::new();
+    if old_rev.chars().all(|c| c == '0') {
+        for commit in pushed_commits(repo_path, old_rev, new_rev)? {
+            paths.extend(changed_paths(repo_path, &["-m", "--root", &commit])?);
+        }
+    } else {
+        paths.extend(changed_paths(repo_path, &[old_rev, new_rev])?);
+    }
+
+    for path in &paths {
+        // Extract content for each path (deleted paths have none)
         let content_out = std::process::Command::new("git")
             .arg("-C").arg(repo_path)
             .arg("show")
@@ -239,3 +251,16 @@ fn extract_changed_files(repo_path: &std::path::Path, old_rev: &str, new_rev: &s
     
     Ok(files)
 }
+
+/// Paths `git diff-tree -r` reports for `args`.
+fn changed_paths(repo_path: &std::path::Path, args: &[&str]) -> anyhow::Result<Vec<String>> {
+    let output = std::process::Command::new("git")
+        .arg("-C").arg(repo_path)
+        .args(["diff-tree", "-r", "--no-commit-id", "--name-only"])
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("git diff-tree {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().filter(|l| !l.is_empty()).map(str::to_string).collect())
+}
@@ -19,6 +19,106 @@ pub enum Commands {
     Serve(ServeArgs),
     /// Query a repository branch
     Query(QueryArgs),
+    /// Create, clone, archive or delete bare repositories under the repo root
+    Repo(RepoArgs),
+    /// Low-level access to a branch index database (used by hook sandboxes)
+    Db(DbArgs),
+    /// Inspect and repair branch indexes under `.relay_data`
+    Index(IndexArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    #[command(subcommand)]
+    pub command: IndexCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IndexCommand {
+    /// Indexed head, document counts, size and last run per branch (all repos by default)
+    Status { repo: Option<String> },
+    /// Drop and rebuild the index of one branch (or every branch) of a repo
+    Rebuild {
+        repo: String,
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    /// Delete the index of a branch; it is rebuilt on the next query
+    Drop {
+        repo: String,
+        #[arg(long)]
+        branch: String,
+    },
+    /// Remove index directories of deleted branches and stale snapshots (all repos by default)
+    Gc { repo: Option<String> },
+}
+
+#[derive(Args, Debug)]
+pub struct DbArgs {
+    #[command(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Print the documents of a collection as a JSON array
+    Find {
+        /// Path to the branch `index.db`
+        #[arg(long)]
+        db: PathBuf,
+        #[arg(long, default_value = "index")]
+        collection: String,
+        /// Mongo-style JSON filter
+        #[arg(long)]
+        filter: Option<String>,
+        /// Hook journal whose pending writes are applied (and rolled back) before reading
+        #[arg(long)]
+        journal: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct RepoArgs {
+    #[command(subcommand)]
+    pub command: RepoCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RepoCommand {
+    /// Create `{name}.git` (empty, or cloned from --url / --template) and install hooks
+    Create {
+        /// Repository name (single DNS label)
+        name: String,
+        /// Clone from a remote URL
+        #[arg(long, conflicts_with = "template")]
+        url: Option<String>,
+        /// Clone from an existing local repo under the repo root
+        #[arg(long)]
+        template: Option<String>,
+    },
+    /// Delete a repository (or move it under `.archive/` with --archive)
+    Delete {
+        name: String,
+        #[arg(long)]
+        archive: bool,
+    },
+    /// Fork `{source}.git` into `{name}.git`, recording the upstream
+    Fork {
+        source: String,
+        name: String,
+        /// Branch to copy (repeatable; default: all branches)
+        #[arg(long = "branch", value_name = "BRANCH")]
+        branches: Vec<String>,
+        /// Share objects via alternates instead of hardlinking them
+        #[arg(long)]
+        shared: bool,
+        /// Add the fork to the authorized-repos file (RELAY_AUTHORIZED_REPOS_PATH), anchored at its
+        /// default branch tip
+        #[arg(long)]
+        authorize: bool,
+    },
+    /// (Re)install relay-hook-handler hook symlinks for an existing repo
+    InstallHooks { name: String },
 }
 
 #[derive(Args, Debug)]
@@ -31,9 +131,24 @@ pub struct QueryArgs {
     /// Search query string
     #[arg(short, long)]
     pub query: Option<String>,
+    /// Mongo-style JSON filter, e.g. '{"meta.year":{"$gte":2000}}'
+    #[arg(short, long, conflicts_with = "query")]
+    pub filter: Option<String>,
     /// Collection name (default: index)
     #[arg(short, long, default_value = "index")]
     pub collection: String,
+    /// Full-text search over the collection's declared search fields (BM25-ranked)
+    #[arg(short, long)]
+    pub search: Option<String>,
+    /// Query the index as of a commit, tag or branch instead of the branch head
+    #[arg(long, value_name = "REV")]
+    pub as_of: Option<String>,
+    /// Output format: json, ndjson, csv or table
+    #[arg(long, default_value = "json")]
+    pub format: crate::git::export::ExportFormat,
+    /// Columns for csv/table output (dotted paths, comma-separated; default: all top-level fields)
+    #[arg(long, value_delimiter = ',')]
+    pub columns: Vec<String>,
 }
 
 #[derive(Args, Debug)]
@@ -15,6 +15,8 @@ pub struct Config {
     pub tls_cert: Option<String>,
     pub tls_key: Option<String>,
     pub acme_dir: String,
+    /// Concurrent background indexing runs (`RELAY_INDEX_WORKERS`).
+    pub index_workers: usize,
 }
 
 impl Config {
@@ -113,6 +115,18 @@ impl Config {
             .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
             .map(Arc::new);
 
+        let admin_token = std::env::var("RELAY_ADMIN_TOKEN")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+
+        let index_workers = std::env::var("RELAY_INDEX_WORKERS")
+            .ok()
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(crate::git::index_worker::DEFAULT_WORKERS);
+        let index_worker = crate::git::index_worker::IndexWorker::new(repo_path.clone());
+
         Ok(Config {
             state: AppState {
                 repo_path,
@@ -121,12 +135,15 @@ impl Config {
                 relay_server_id,
                 authorized_repos,
                 features_manifest,
+                admin_token,
+                index_worker,
             },
             http_addr,
             https_port,
             tls_cert,
             tls_key,
             acme_dir,
+            index_workers,
         })
     }
 
@@ -135,6 +152,10 @@ impl Config {
         let _ = std::fs::create_dir_all(repo_path);
         // Repo cloning and updates are handled by the docker-entrypoint.sh
         // or external processes to keep the server lightweight.
+        let migrated = crate::git::indexdb::migrate_all(repo_path);
+        if migrated > 0 {
+            tracing::info!(migrated, "Migrated legacy JSON branch indexes to SQLite");
+        }
     }
 }
 
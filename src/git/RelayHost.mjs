@@ -2,7 +2,7 @@ import vm from 'node:vm';
 import fs from 'node:fs';
 import path from 'node:path';
 import { createHash } from 'node:crypto';
-import { execSync } from 'node:child_process';
+import { execSync, execFileSync } from 'node:child_process';
 
 /**
  * RelayHost.mjs
@@ -42,27 +42,41 @@ async function main() {
 
     const gitDir = process.env.GIT_DIR || repo_path;
     const relayDataDir = path.join(gitDir, '.relay_data');
-    const branchHash = Buffer.from(branch || 'main').toString('hex').slice(0, 12);
-    const branchDir = path.join(relayDataDir, 'branches', branchHash);
+    // Resolved by Rust: branches and `snapshot:<commit>` pseudo-branches live in different trees.
+    const branchDir = process.env.RELAY_BRANCH_DIR;
     const repoBlobsDir = path.join(relayDataDir, 'blobs');
 
     // Ensure basic directories exist
-    [relayDataDir, path.join(relayDataDir, 'branches'), branchDir, repoBlobsDir].forEach(d => {
+    [relayDataDir, branchDir, repoBlobsDir].forEach(d => {
         if (!fs.existsSync(d)) fs.mkdirSync(d, { recursive: true });
     });
 
-    const dbPath = path.join(branchDir, 'index.db.json');
-    const loadDb = () => {
-        if (!fs.existsSync(dbPath)) return { collections: {} };
-        try { return JSON.parse(fs.readFileSync(dbPath, 'utf8')); }
-        catch { return { collections: {} }; }
+    // The branch index (index.db, SQLite) is owned by the Rust server. Writes are journaled here
+    // and committed in one transaction after the hook exits successfully. Reads apply the journal
+    // first (and roll it back), so a hook sees its own pending writes.
+    const journalPath = process.env.RELAY_DB_JOURNAL;
+    const journal = (op) => {
+        if (journalPath) fs.appendFileSync(journalPath, JSON.stringify(op) + '\n');
+    };
+    const findDocs = (name, query) => {
+        const dbPath = process.env.RELAY_DB_PATH;
+        const pending = journalPath && fs.existsSync(journalPath);
+        if (!dbPath || (!pending && !fs.existsSync(dbPath))) return [];
+        const args = ['db', 'find', '--db', dbPath, '--collection', name];
+        if (query && Object.keys(query).length) args.push('--filter', JSON.stringify(query));
+        if (pending) args.push('--journal', journalPath);
+        try {
+            const out = execFileSync(process.env.RELAY_DB_CLI || 'relay-server', args, {
+                encoding: 'utf8',
+                maxBuffer: 256 * 1024 * 1024,
+                stdio: ['ignore', 'pipe', 'inherit']
+            });
+            return JSON.parse(out);
+        } catch (e) {
+            console.error('[RelayHost] db find failed:', e.message);
+            return [];
+        }
     };
-    const saveDb = (db) => fs.writeFileSync(dbPath, JSON.stringify(db, null, 2));
-
-    // Initialize metadata
-    const db = loadDb();
-    if (!db.metadata) db.metadata = { indexed_head: null };
-    saveDb(db);
 
     // 2. Define the Relay global
     const Relay = {
@@ -84,49 +98,27 @@ async function main() {
                 };
                 return {
                     insert: (doc) => {
-                        const db = loadDb();
-                        if (!db.collections[name]) db.collections[name] = [];
                         const newDoc = { ...doc, _id: Date.now() + Math.random() };
-                        db.collections[name].push(newDoc);
-                        saveDb(db);
+                        journal({ op: 'insert', collection: name, doc: newDoc });
                         triggerWatcher(newDoc);
                     },
+                    // Applied when the hook completes; `query` accepts the same filters as QUERY.
+                    // Returns the number of documents the write will touch.
                     update: (query, update) => {
-                        const db = loadDb();
-                        const items = db.collections[name] || [];
-                        let count = 0;
-                        items.forEach(item => {
-                            let match = true;
-                            for (let k in query) if (item[k] !== query[k]) { match = false; break; }
-                            if (match) {
-                                Object.assign(item, update);
-                                triggerWatcher(item);
-                                count++;
-                            }
-                        });
-                        if (count > 0) saveDb(db);
-                        return count;
+                        const matched = findDocs(name, query);
+                        journal({ op: 'update', collection: name, query: query || {}, update });
+                        matched.forEach(doc => triggerWatcher({ ...doc, ...update }));
+                        return matched.length;
                     },
                     remove: (query) => {
-                        const db = loadDb();
-                        const items = db.collections[name] || [];
-                        const initialLen = items.length;
-                        db.collections[name] = items.filter(item => {
-                            for (let k in query) if (item[k] !== query[k]) return true;
-                            return false;
-                        });
-                        if (db.collections[name].length !== initialLen) saveDb(db);
-                        return initialLen - db.collections[name].length;
+                        const matched = findDocs(name, query);
+                        journal({ op: 'remove', collection: name, query: query || {} });
+                        return matched.length;
                     },
-                    find: (query) => {
-                        const db = loadDb();
-                        const items = db.collections[name] || [];
-                        if (!query) return items;
-                        return items.filter(item => {
-                            for (let k in query) if (item[k] !== query[k]) return false;
-                            return true;
-                        });
-                    }
+                    createIndex: (field) => {
+                        journal({ op: 'createIndex', collection: name, field });
+                    },
+                    find: (query) => findDocs(name, query)
                 };
             }
         },
@@ -329,14 +321,6 @@ async function main() {
 
         await script.runInContext(contextObj);
 
-        // Update indexed_head after successful hook execution (if it was an indexing hook)
-        // Note: For pre-receive, we don't necessarily want to update indexed_head yet, 
-        // as the query might still see stale data until the push is finalized.
-        // But for JIT indexing (where new_commit is the actual HEAD), we MUST update it.
-        const finalDb = loadDb();
-        finalDb.metadata.indexed_head = new_commit;
-        saveDb(finalDb);
-
     } catch (e) {
         console.error(`[RelayHost] Compilation/Setup Error in ${scriptPath}:`, e.message);
         if (e.stack) console.error(e.stack);
@@ -3,13 +3,18 @@ import fs from 'node:fs';
 import path from 'node:path';
 async function main() {
     const context = JSON.parse(fs.readFileSync(0).toString());
-    const branchHash = Buffer.from(context.branch || 'main').toString('hex').slice(0, 12);
-    const dbPath = path.join(context.repo_path, '.relay_data', 'branches', branchHash, 'index.db.json');
-    if (!fs.existsSync(path.dirname(dbPath))) fs.mkdirSync(path.dirname(dbPath), { recursive: true });
-    fs.writeFileSync(dbPath, JSON.stringify({
-        metadata: { indexed_head: context.new_commit },
-        collections: { index: [ { title: "Test Item", _id: 1 } ] }
-    }));
+    const branchDir = process.env.RELAY_BRANCH_DIR;
+    if (branchDir) {
+        fs.mkdirSync(branchDir, { recursive: true });
+        fs.writeFileSync(path.join(branchDir, 'hook-ran'), '');
+    }
+    const journal = process.env.RELAY_DB_JOURNAL;
+    if (journal) {
+        fs.mkdirSync(path.dirname(journal), { recursive: true });
+        fs.appendFileSync(journal, JSON.stringify({
+            op: 'insert', collection: 'index', doc: { title: "Test Item", _id: 1, _old: context.old_commit, _commit: context.new_commit }
+        }) + '\n');
+    }
     process.exit(0);
 }
 main();
@@ -0,0 +1,273 @@
+//! Aggregations over the matches of a QUERY: grouped metrics, distinct values and facet counts.
+//!
+//! Metrics use Mongo-style accumulators (`$count`, `$sum`, `$avg`, `$min`, `$max`) over dotted
+//! field paths. Array values are unwound for metrics, `distinct` and facets; a document counts once
+//! per distinct facet value. `groupBy` keys use values as stored.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::git::filter::{path_values, FilterError};
+use crate::git::query::cmp_values;
+
+/// Field holding the group key of each aggregate row.
+pub const GROUP_ID_FIELD: &str = "_id";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum GroupBy {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// `aggregate` part of a QUERY body.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregateSpec {
+    /// Dotted fields to group by; omitted: a single group over every match.
+    #[serde(default)]
+    pub group_by: Option<GroupBy>,
+    /// Output field to accumulator, e.g. `{ "n": { "$count": {} }, "avgRating": { "$avg": "rating" } }`.
+    /// Defaults to `{ "count": { "$count": {} } }`.
+    #[serde(default)]
+    pub metrics: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+struct Metric {
+    name: String,
+    op: Op,
+    /// `None` for `$count` and `{ "$sum": 1 }`.
+    field: Option<String>,
+}
+
+fn parse_metrics(metrics: &Map<String, Value>) -> Result<Vec<Metric>, FilterError> {
+    if metrics.is_empty() {
+        return Ok(vec![Metric { name: "count".to_string(), op: Op::Count, field: None }]);
+    }
+    metrics
+        .iter()
+        .map(|(name, spec)| {
+            let (op_name, arg) = match spec.as_object().map(|o| o.iter().collect::<Vec<_>>()) {
+                Some(entries) if entries.len() == 1 => entries[0],
+                _ => {
+                    return Err(FilterError::BadOperand {
+                        op: name.clone(),
+                        expected: "a single accumulator such as { \"$sum\": \"field\" }",
+                    })
+                }
+            };
+            let op = match op_name.as_str() {
+                "$count" => Op::Count,
+                "$sum" => Op::Sum,
+                "$avg" => Op::Avg,
+                "$min" => Op::Min,
+                "$max" => Op::Max,
+                other => return Err(FilterError::UnknownOperator(other.to_string())),
+            };
+            let field = match (op, arg) {
+                (Op::Count, _) => None,
+                (_, Value::String(f)) if !f.is_empty() => Some(f.trim_start_matches('$').to_string()),
+                // `{ "$sum": 1 }` counts documents, as in Mongo.
+                (Op::Sum, Value::Number(n)) if n.as_i64() == Some(1) => None,
+                _ => {
+                    return Err(FilterError::BadOperand {
+                        op: op_name.clone(),
+                        expected: "a field path",
+                    })
+                }
+            };
+            let op = if op == Op::Sum && field.is_none() { Op::Count } else { op };
+            Ok(Metric { name: name.clone(), op, field })
+        })
+        .collect()
+}
+
+/// Values at `field`, with arrays unwound into their elements.
+fn unwound<'a>(doc: &'a Value, field: &str) -> Vec<&'a Value> {
+    path_values(doc, field)
+        .into_iter()
+        .flat_map(|v| match v {
+            Value::Array(items) => items.iter().collect::<Vec<_>>(),
+            v => vec![v],
+        })
+        .collect()
+}
+
+enum Acc {
+    Count(u64),
+    Sum { total: f64, ints: i64, all_int: bool },
+    Avg { total: f64, n: u64 },
+    Min(Option<Value>),
+    Max(Option<Value>),
+}
+
+impl Acc {
+    fn new(op: Op) -> Acc {
+        match op {
+            Op::Count => Acc::Count(0),
+            Op::Sum => Acc::Sum { total: 0.0, ints: 0, all_int: true },
+            Op::Avg => Acc::Avg { total: 0.0, n: 0 },
+            Op::Min => Acc::Min(None),
+            Op::Max => Acc::Max(None),
+        }
+    }
+
+    fn add(&mut self, doc: &Value, field: Option<&str>) {
+        if let Acc::Count(n) = self {
+            *n += 1;
+            return;
+        }
+        let Some(field) = field else { return };
+        for v in unwound(doc, field).into_iter().filter(|v| !v.is_null()) {
+            match self {
+                Acc::Sum { total, ints, all_int } => {
+                    if let Some(x) = v.as_f64() {
+                        *total += x;
+                        match v.as_i64().and_then(|i| ints.checked_add(i)) {
+                            Some(sum) => *ints = sum,
+                            None => *all_int = false,
+                        }
+                    }
+                }
+                Acc::Avg { total, n } => {
+                    if let Some(x) = v.as_f64() {
+                        *total += x;
+                        *n += 1;
+                    }
+                }
+                Acc::Min(best) => {
+                    if best.as_ref().is_none_or(|b| cmp_values(Some(v), Some(b)) == Ordering::Less) {
+                        *best = Some(v.clone());
+                    }
+                }
+                Acc::Max(best) => {
+                    if best.as_ref().is_none_or(|b| cmp_values(Some(v), Some(b)) == Ordering::Greater) {
+                        *best = Some(v.clone());
+                    }
+                }
+                Acc::Count(_) => {}
+            }
+        }
+    }
+
+    fn finish(self) -> Value {
+        match self {
+            Acc::Count(n) => json!(n),
+            Acc::Sum { ints, all_int: true, .. } => json!(ints),
+            Acc::Sum { total, .. } => json!(total),
+            Acc::Avg { n: 0, .. } => Value::Null,
+            Acc::Avg { total, n } => json!(total / n as f64),
+            Acc::Min(v) | Acc::Max(v) => v.unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// Group `items` and compute the metrics of each group. Rows are `{ _id, <metric>... }`, where
+/// `_id` maps each `groupBy` field to its value (null when missing), ordered by `_id`.
+pub fn aggregate(items: &[Value], spec: &AggregateSpec) -> Result<Vec<Value>, FilterError> {
+    let metrics = parse_metrics(&spec.metrics)?;
+    let group_by: Vec<String> = match &spec.group_by {
+        None => Vec::new(),
+        Some(GroupBy::One(f)) => vec![f.clone()],
+        Some(GroupBy::Many(fs)) => fs.clone(),
+    };
+
+    let mut groups: Vec<(Vec<Value>, Vec<Acc>)> = Vec::new();
+    let mut slots: HashMap<String, usize> = HashMap::new();
+    for doc in items {
+        let key: Vec<Value> = group_by
+            .iter()
+            .map(|f| path_values(doc, f).into_iter().next().cloned().unwrap_or(Value::Null))
+            .collect();
+        let slot = *slots.entry(Value::Array(key.clone()).to_string()).or_insert_with(|| {
+            groups.push((key, metrics.iter().map(|m| Acc::new(m.op)).collect()));
+            groups.len() - 1
+        });
+        for (acc, m) in groups[slot].1.iter_mut().zip(&metrics) {
+            acc.add(doc, m.field.as_deref());
+        }
+    }
+    if groups.is_empty() && group_by.is_empty() {
+        groups.push((Vec::new(), metrics.iter().map(|m| Acc::new(m.op)).collect()));
+    }
+
+    groups.sort_by(|(a, _), (b, _)| {
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| cmp_values(Some(x), Some(y)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    });
+    Ok(groups
+        .into_iter()
+        .map(|(key, accs)| {
+            let mut row = Map::new();
+            let id = if group_by.is_empty() {
+                Value::Null
+            } else {
+                Value::Object(group_by.iter().cloned().zip(key).collect())
+            };
+            row.insert(GROUP_ID_FIELD.to_string(), id);
+            for (acc, m) in accs.into_iter().zip(&metrics) {
+                row.insert(m.name.clone(), acc.finish());
+            }
+            Value::Object(row)
+        })
+        .collect())
+}
+
+/// Distinct values of `field` over `items` (arrays unwound, nulls skipped), in sort order.
+pub fn distinct(items: &[Value], field: &str) -> Vec<Value> {
+    let mut seen = HashMap::new();
+    for doc in items {
+        for v in unwound(doc, field).into_iter().filter(|v| !v.is_null()) {
+            seen.entry(v.to_string()).or_insert_with(|| v.clone());
+        }
+    }
+    let mut values: Vec<Value> = seen.into_values().collect();
+    values.sort_by(|a, b| cmp_values(Some(a), Some(b)));
+    values
+}
+
+/// Per field, `[{ value, count }]` counting matching documents per value, most frequent first;
+/// at most `limit` values per field when set.
+pub fn facets(items: &[Value], fields: &[String], limit: Option<usize>) -> Map<String, Value> {
+    fields
+        .iter()
+        .map(|field| {
+            let mut counts: HashMap<String, (Value, u64)> = HashMap::new();
+            for doc in items {
+                let mut values = unwound(doc, field);
+                values.retain(|v| !v.is_null());
+                let mut keys: Vec<String> = Vec::new();
+                for v in values {
+                    let key = v.to_string();
+                    if keys.contains(&key) {
+                        continue;
+                    }
+                    counts.entry(key.clone()).or_insert_with(|| (v.clone(), 0)).1 += 1;
+                    keys.push(key);
+                }
+            }
+            let mut buckets: Vec<(Value, u64)> = counts.into_values().collect();
+            buckets.sort_by(|(va, ca), (vb, cb)| cb.cmp(ca).then_with(|| cmp_values(Some(va), Some(vb))));
+            let buckets: Vec<Value> = buckets
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|(value, count)| json!({ "value": value, "count": count }))
+                .collect();
+            (field.clone(), Value::Array(buckets))
+        })
+        .collect()
+}
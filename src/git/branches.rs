@@ -0,0 +1,298 @@
+//! Explicit branch management (create, delete, rename, list) subject to `git.branchRules`.
+
+use git2::{BranchType, Oid, Reference, Repository};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::git::changes;
+use crate::git::commit::CommitError;
+use crate::git::read_git_config;
+use crate::git::signing::{pushed_commits, verify_commits, AllowedKeys, SigningError};
+use crate::types::{BranchRule, DEFAULT_BRANCH};
+
+#[derive(Debug, Error)]
+pub enum BranchError {
+    #[error("branch '{0}' not found")]
+    NotFound(String),
+    #[error("branch '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("invalid branch name '{0}'")]
+    InvalidName(String),
+    #[error("revision '{0}' not found")]
+    RevisionNotFound(String),
+    #[error("{0}")]
+    Rejected(String),
+    /// The request is malformed (e.g. a merge reverted without a mainline).
+    #[error("{0}")]
+    BadRequest(String),
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Branch tip metadata returned by the branch listing.
+#[derive(Debug, Clone, Serialize)]
+pub struct BranchInfo {
+    pub name: String,
+    pub commit: String,
+    pub summary: String,
+    pub author: String,
+    pub email: String,
+    /// Commit time in seconds since the Unix epoch.
+    pub timestamp: i64,
+    pub protected: bool,
+}
+
+/// Resolve the branch rule for `branch` from the [trusted revision](trusted_rev), so a branch
+/// cannot relax its own rules (e.g. drop its protection) through its `.relay.yaml`.
+pub fn branch_rule(repo: &Repository, branch: &str) -> Option<BranchRule> {
+    branch_rule_at(repo, &trusted_rev(repo)?, branch)
+}
+
+/// Rule for `branch` as declared in `.relay.yaml` at `rev` (see [`BranchRulesConfig::rule_for`]).
+///
+/// [`BranchRulesConfig::rule_for`]: crate::types::BranchRulesConfig::rule_for
+pub fn branch_rule_at(repo: &Repository, rev: &str, branch: &str) -> Option<BranchRule> {
+    read_git_config(repo, rev)
+        .and_then(|g| g.branch_rules)
+        .and_then(|r| r.rule_for(branch))
+}
+
+/// Commit whose branch rules and allowed key files govern writes to every branch, through the
+/// HTTP API and pushes alike: the tip of the default branch (HEAD's branch, else `main`). `None`
+/// when neither resolves. In a pre-receive hook this is still the tip from before the push.
+pub fn trusted_rev(repo: &Repository) -> Option<String> {
+    let head = repo
+        .find_reference("HEAD")
+        .ok()
+        .and_then(|h| h.symbolic_target().map(str::to_string));
+    head.into_iter()
+        .chain([format!("refs/heads/{}", DEFAULT_BRANCH)])
+        .find_map(|refname| repo.refname_to_id(&refname).ok())
+        .map(|oid| oid.to_string())
+}
+
+/// Rule for a push of `new` to `branch`, with the revision it and the allowed keys are read from:
+/// [`trusted_rev`], as for HTTP writes. Only the first push into a repo without any refs may
+/// bootstrap from its own commit; with refs but no trusted revision the push is refused.
+pub fn push_rule(repo: &Repository, branch: &str, new: &str) -> Result<Option<(String, BranchRule)>, BranchError> {
+    let base = match trusted_rev(repo) {
+        Some(base) => base,
+        None if repo.references()?.next().is_none() => {
+            warn!(branch, "empty repository; reading branch rules and allowedKeys from the pushed commit");
+            new.to_string()
+        }
+        None => {
+            return Err(BranchError::Rejected(format!(
+                "no trusted branch rules for {}: neither HEAD nor {} resolves",
+                branch, DEFAULT_BRANCH
+            )))
+        }
+    };
+    Ok(branch_rule_at(repo, &base, branch).map(|rule| (base, rule)))
+}
+
+/// List all branches with tip commit, author and timestamp, sorted by name.
+pub fn list_branch_details(repo: &Repository) -> Result<Vec<BranchInfo>, BranchError> {
+    let mut out = Vec::new();
+    for item in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = item?;
+        let name = match branch.name()? {
+            Some(n) => n.to_string(),
+            None => continue,
+        };
+        let commit = match branch.get().peel_to_commit() {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let author = commit.author();
+        let protected = branch_rule(repo, &name)
+            .map(|r| r.is_protected())
+            .unwrap_or(false);
+        out.push(BranchInfo {
+            name,
+            commit: commit.id().to_string(),
+            summary: commit.summary().unwrap_or("").to_string(),
+            author: author.name().unwrap_or("").to_string(),
+            email: author.email().unwrap_or("").to_string(),
+            timestamp: commit.time().seconds(),
+            protected,
+        });
+    }
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(out)
+}
+
+/// Create `name` pointing at `from` (any revision; defaults to the default branch).
+pub fn create_branch(
+    repo: &Repository,
+    name: &str,
+    from: Option<&str>,
+) -> Result<BranchInfo, BranchError> {
+    ensure_valid_name(name)?;
+    let refname = format!("refs/heads/{}", name);
+    if repo.find_reference(&refname).is_ok() {
+        return Err(BranchError::AlreadyExists(name.to_string()));
+    }
+    let from = from.filter(|s| !s.is_empty()).unwrap_or(DEFAULT_BRANCH);
+    let target = resolve_commit(repo, from)?;
+
+    if let Some(rule) = branch_rule(repo, name) {
+        ensure_signed_commits(repo, &rule, name, None, target, None)?;
+    }
+
+    repo.reference(&refname, target, false, &format!("branch: Created from {}", from))?;
+    changes::publish_branch(repo.path(), name, None, Some(target.to_string()));
+    find_branch_info(repo, name)
+}
+
+/// Delete `name`. Protected branches and the repository's default branch are refused.
+pub fn delete_branch(repo: &Repository, name: &str) -> Result<(), BranchError> {
+    let mut branch = repo
+        .find_branch(name, BranchType::Local)
+        .map_err(|_| BranchError::NotFound(name.to_string()))?;
+    if branch_rule(repo, name).map(|r| r.is_protected()).unwrap_or(false) {
+        return Err(BranchError::Rejected(format!(
+            "branch '{}' is protected and cannot be deleted",
+            name
+        )));
+    }
+    if is_head_branch(repo, name) {
+        return Err(BranchError::Rejected(format!(
+            "branch '{}' is the repository default branch and cannot be deleted",
+            name
+        )));
+    }
+    let tip = branch.get().target().map(|oid| oid.to_string());
+    branch.delete()?;
+    changes::publish_branch(repo.path(), name, tip, None);
+    Ok(())
+}
+
+/// Rename `from` to `to`. The source must not be protected and the target must satisfy its rule.
+pub fn rename_branch(repo: &Repository, from: &str, to: &str) -> Result<BranchInfo, BranchError> {
+    ensure_valid_name(to)?;
+    let mut branch = repo
+        .find_branch(from, BranchType::Local)
+        .map_err(|_| BranchError::NotFound(from.to_string()))?;
+    if repo.find_reference(&format!("refs/heads/{}", to)).is_ok() {
+        return Err(BranchError::AlreadyExists(to.to_string()));
+    }
+    if branch_rule(repo, from).map(|r| r.is_protected()).unwrap_or(false) {
+        return Err(BranchError::Rejected(format!(
+            "branch '{}' is protected and cannot be renamed",
+            from
+        )));
+    }
+    if is_head_branch(repo, from) {
+        return Err(BranchError::Rejected(format!(
+            "branch '{}' is the repository default branch and cannot be renamed",
+            from
+        )));
+    }
+    let tip = branch.get().peel_to_commit()?.id();
+    if let Some(rule) = branch_rule(repo, to) {
+        let moving = format!("refs/heads/{}", from);
+        ensure_signed_commits(repo, &rule, to, None, tip, Some(&moving))?;
+    }
+    branch.rename(to, false)?;
+    changes::publish_branch(repo.path(), from, Some(tip.to_string()), None);
+    changes::publish_branch(repo.path(), to, None, Some(tip.to_string()));
+    find_branch_info(repo, to)
+}
+
+fn find_branch_info(repo: &Repository, name: &str) -> Result<BranchInfo, BranchError> {
+    list_branch_details(repo)?
+        .into_iter()
+        .find(|b| b.name == name)
+        .ok_or_else(|| BranchError::NotFound(name.to_string()))
+}
+
+fn ensure_valid_name(name: &str) -> Result<(), BranchError> {
+    if name.is_empty() || !Reference::is_valid_name(&format!("refs/heads/{}", name)) {
+        return Err(BranchError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn resolve_commit(repo: &Repository, rev: &str) -> Result<Oid, BranchError> {
+    let obj = repo
+        .revparse_single(rev)
+        .map_err(|_| BranchError::RevisionNotFound(rev.to_string()))?;
+    let commit = obj
+        .peel_to_commit()
+        .map_err(|_| BranchError::RevisionNotFound(rev.to_string()))?;
+    Ok(commit.id())
+}
+
+fn is_head_branch(repo: &Repository, name: &str) -> bool {
+    repo.find_reference("HEAD")
+        .ok()
+        .and_then(|h| h.symbolic_target().map(|s| s == format!("refs/heads/{}", name)))
+        .unwrap_or(false)
+}
+
+/// Branches whose rule requires signing may only gain commits signed by an allowed key: every
+/// commit of `old..new` when the branch moves, or for a new tip (`old` is `None`) the tip and every
+/// commit no other ref reaches. `moving` names a ref that is being replaced (e.g. by a rename).
+pub(crate) fn ensure_signed_commits(
+    repo: &Repository,
+    rule: &BranchRule,
+    branch: &str,
+    old: Option<Oid>,
+    new: Oid,
+    moving: Option<&str>,
+) -> Result<(), BranchError> {
+    if !rule.requires_signature() {
+        return Ok(());
+    }
+    let reject = |e: SigningError| {
+        BranchError::Rejected(format!("branch '{}' requires signed commits; {}", branch, e))
+    };
+    let trusted = trusted_rev(repo).ok_or_else(|| {
+        BranchError::Rejected(format!("branch '{}' requires signed commits; no default branch holds its keys", branch))
+    })?;
+    let keys = AllowedKeys::load(repo.path(), &trusted, rule).map_err(reject)?;
+    let commits = match old {
+        Some(old) => pushed_commits(repo.path(), &old.to_string(), &new.to_string()).map_err(reject)?,
+        None => unreached_commits(repo, new, moving)?,
+    };
+    verify_commits(repo.path(), commits, &keys).map_err(reject)?;
+    Ok(())
+}
+
+/// `tip` and the commits it reaches that no ref other than `moving` does, oldest first.
+fn unreached_commits(repo: &Repository, tip: Oid, moving: Option<&str>) -> Result<Vec<String>, BranchError> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    walk.push(tip)?;
+    for reference in repo.references()? {
+        let reference = reference?;
+        if moving.is_some() && reference.name() == moving {
+            continue;
+        }
+        if let Ok(commit) = reference.peel_to_commit() {
+            walk.hide(commit.id())?;
+        }
+    }
+    let mut commits = walk.map(|oid| oid.map(|o| o.to_string())).collect::<Result<Vec<_>, _>>()?;
+    if commits.last() != Some(&tip.to_string()) {
+        commits.push(tip.to_string());
+    }
+    Ok(commits)
+}
+
+/// Commits created by the server are unsigned, so branches that require signing refuse them.
+pub(crate) fn ensure_server_commit_allowed(repo: &Repository, branch: &str) -> Result<(), BranchError> {
+    if branch_rule(repo, branch).map(|r| r.requires_signature()).unwrap_or(false) {
+        return Err(BranchError::Rejected(format!(
+            "branch '{}' requires signed commits; server-side commits cannot be signed",
+            branch
+        )));
+    }
+    Ok(())
+}
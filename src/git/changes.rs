@@ -0,0 +1,223 @@
+//! Live change feed behind `GET /api/events`.
+//!
+//! Two kinds of events are broadcast process-wide: a branch head moved (published by each writer
+//! right after it moves the ref, with the exact old and new tips; pushes through `git daemon`
+//! arrive via the trigger files of [`crate::git::index_worker`]) and documents of an index
+//! collection changed (published with the writes each indexing step
+//! commits, by the native indexer or a hook's journal). Document events are only built while
+//! someone listens to that repo.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use git2::Repository;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::sync::broadcast;
+
+use crate::git::indexdb::{DbOp, SNAPSHOT_PREFIX};
+
+/// Events buffered per subscriber; a subscriber falling further behind is told it lagged.
+pub const FEED_CAPACITY: usize = 1024;
+
+static FEED: OnceLock<broadcast::Sender<ChangeEvent>> = OnceLock::new();
+static LISTENERS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+
+fn feed() -> &'static broadcast::Sender<ChangeEvent> {
+    FEED.get_or_init(|| broadcast::channel(FEED_CAPACITY).0)
+}
+
+fn listeners() -> &'static Mutex<HashMap<String, usize>> {
+    LISTENERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ChangeEvent {
+    /// Published by the write that moved the branch: `old` is null when the branch is new,
+    /// `head` is null when it was deleted.
+    #[serde(rename_all = "camelCase")]
+    Branch {
+        repo: String,
+        branch: String,
+        old: Option<String>,
+        head: Option<String>,
+    },
+    /// Writes applied to `collection` when the index reached `head`, in order.
+    #[serde(rename_all = "camelCase")]
+    Documents {
+        repo: String,
+        branch: String,
+        collection: String,
+        head: String,
+        changes: Vec<DocChange>,
+    },
+}
+
+impl ChangeEvent {
+    /// SSE event name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ChangeEvent::Branch { .. } => "branch",
+            ChangeEvent::Documents { .. } => "documents",
+        }
+    }
+
+    pub fn repo(&self) -> &str {
+        match self {
+            ChangeEvent::Branch { repo, .. } | ChangeEvent::Documents { repo, .. } => repo,
+        }
+    }
+
+    pub fn branch(&self) -> &str {
+        match self {
+            ChangeEvent::Branch { branch, .. } | ChangeEvent::Documents { branch, .. } => branch,
+        }
+    }
+
+    pub fn collection(&self) -> Option<&str> {
+        match self {
+            ChangeEvent::Branch { .. } => None,
+            ChangeEvent::Documents { collection, .. } => Some(collection),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocOp {
+    Insert,
+    Update,
+    Remove,
+}
+
+/// One write to a collection, as applied to the index: `insert` carries `doc`, `update` the
+/// `query` and the `update` fields merged into each match, `remove` the `query`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocChange {
+    pub op: DocOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub doc: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub update: Option<Map<String, Value>>,
+}
+
+impl DocChange {
+    /// The change an op makes to documents; `None` for `createIndex`.
+    fn from_op(op: &DbOp) -> Option<(&str, DocChange)> {
+        let change = |op, doc, query, update| DocChange { op, doc, query, update };
+        Some(match op {
+            DbOp::Insert { collection, doc } => (collection, change(DocOp::Insert, Some(doc.clone()), None, None)),
+            DbOp::Update { collection, query, update } => (
+                collection,
+                change(DocOp::Update, None, Some(query.clone()), Some(update.clone())),
+            ),
+            DbOp::Remove { collection, query } => (collection, change(DocOp::Remove, None, Some(query.clone()), None)),
+            DbOp::CreateIndex { .. } => return None,
+        })
+    }
+}
+
+/// Keeps one listener of a repo counted while alive, so writers to repos nobody watches skip
+/// building document events.
+pub struct Listener {
+    repo: String,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let mut counts = listeners().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(n) = counts.get_mut(&self.repo) {
+            *n -= 1;
+            if *n == 0 {
+                counts.remove(&self.repo);
+            }
+        }
+    }
+}
+
+/// Receive every event; `repo` is the repo the caller will keep, counted until the returned
+/// [`Listener`] drops.
+pub fn subscribe(repo: &str) -> (Listener, broadcast::Receiver<ChangeEvent>) {
+    *listeners()
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(repo.to_string())
+        .or_default() += 1;
+    (Listener { repo: repo.to_string() }, feed().subscribe())
+}
+
+pub fn publish(event: ChangeEvent) {
+    // No receivers is not an error: nobody is listening.
+    let _ = feed().send(event);
+}
+
+pub fn has_subscribers(repo: &str) -> bool {
+    listeners().lock().unwrap_or_else(|e| e.into_inner()).contains_key(repo)
+}
+
+/// Publish that `branch` of the repo at `repo_path` moved from `old` to `head` (`None` when the
+/// branch did not exist, or no longer does).
+pub fn publish_branch(repo_path: &Path, branch: &str, old: Option<String>, head: Option<String>) {
+    if old == head {
+        return;
+    }
+    publish(ChangeEvent::Branch {
+        repo: repo_name(repo_path),
+        branch: branch.to_string(),
+        old,
+        head,
+    });
+}
+
+/// Tip of every branch of `repo`, for writers that may move any branch (fetches).
+pub fn branch_heads(repo: &Repository) -> BTreeMap<String, String> {
+    crate::git::list_branches(repo)
+        .into_iter()
+        .filter_map(|branch| {
+            let head = repo.refname_to_id(&format!("refs/heads/{}", branch)).ok()?;
+            Some((branch, head.to_string()))
+        })
+        .collect()
+}
+
+/// Publish a branch event for every branch whose tip differs between `before` and `after`.
+pub fn publish_moves(repo_path: &Path, before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) {
+    let branches: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for branch in branches {
+        publish_branch(repo_path, branch, before.get(branch).cloned(), after.get(branch).cloned());
+    }
+}
+
+/// Repo name of the bare repo at `repo_path` (`movies.git` → `movies`).
+pub fn repo_name(repo_path: &Path) -> String {
+    let name = repo_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    name.strip_suffix(".git").map(str::to_string).unwrap_or(name)
+}
+
+/// Publish the writes just committed to the index of `branch` at `head`, one event per collection
+/// in op order, when anyone listens to the repo. Snapshots of past commits never change.
+pub fn publish_ops(repo_path: &Path, branch: &str, head: &str, ops: &[DbOp]) {
+    let repo = repo_name(repo_path);
+    if branch.starts_with(SNAPSHOT_PREFIX) || !has_subscribers(&repo) {
+        return;
+    }
+    let mut by_collection: BTreeMap<&str, Vec<DocChange>> = BTreeMap::new();
+    for (collection, change) in ops.iter().filter_map(DocChange::from_op) {
+        by_collection.entry(collection).or_default().push(change);
+    }
+    for (collection, changes) in by_collection {
+        publish(ChangeEvent::Documents {
+            repo: repo.clone(),
+            branch: branch.to_string(),
+            collection: collection.to_string(),
+            head: head.to_string(),
+            changes,
+        });
+    }
+}
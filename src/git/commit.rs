@@ -0,0 +1,109 @@
+//! Shared pipeline for server-created commits: pre-commit hook, ref update, post-receive hook.
+use std::collections::HashMap;
+
+use base64::Engine;
+use git2::{Delta, DiffOptions, ErrorCode, ObjectType, Oid, Repository, Tree};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::git::changes;
+use crate::git::schema::SchemaViolations;
+use crate::git::{execute_repo_hook, HookContext};
+
+const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+#[derive(Debug, Error)]
+pub enum CommitError {
+    #[error("pre-commit hook rejected the change")]
+    Rejected,
+    #[error("pre-commit hook error: {0}")]
+    Hook(anyhow::Error),
+    #[error(transparent)]
+    Invalid(SchemaViolations),
+    #[error("branch '{0}' moved while the commit was prepared")]
+    Stale(String),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+}
+
+/// Paths added or modified between two trees, mapped to their base64 content (hook context format).
+pub fn changed_files(
+    repo: &Repository,
+    old_tree: Option<&Tree>,
+    new_tree: &Tree,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut opts = DiffOptions::new();
+    let diff = repo.diff_tree_to_tree(old_tree, Some(new_tree), Some(&mut opts))?;
+    let mut files = HashMap::new();
+    for delta in diff.deltas() {
+        if delta.status() == Delta::Deleted {
+            continue;
+        }
+        let new_file = delta.new_file();
+        let path = match new_file.path().and_then(|p| p.to_str()) {
+            Some(p) => p.to_string(),
+            None => continue,
+        };
+        if let Ok(obj) = repo.find_object(new_file.id(), Some(ObjectType::Blob)) {
+            if let Some(blob) = obj.as_blob() {
+                files.insert(
+                    path,
+                    base64::engine::general_purpose::STANDARD.encode(blob.content()),
+                );
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Run the `pre-commit` hook for `new_commit`, move `refs/heads/{branch}` from `old_commit`
+/// (compare-and-swap, so a concurrent writer is detected) and fire `post-receive`.
+pub fn apply_commit(
+    repo: &Repository,
+    branch: &str,
+    old_commit: Option<Oid>,
+    new_commit: Oid,
+    files: HashMap<String, String>,
+    msg: &str,
+) -> Result<(), CommitError> {
+    let refname = format!("refs/heads/{}", branch);
+    let ctx = HookContext {
+        repo_path: repo.path().to_path_buf(),
+        old_commit: old_commit
+            .map(|o| o.to_string())
+            .unwrap_or_else(|| ZERO_OID.to_string()),
+        new_commit: new_commit.to_string(),
+        refname: refname.clone(),
+        branch: branch.to_string(),
+        is_verified: true,
+        files,
+    };
+
+    match execute_repo_hook(&ctx, "pre-commit") {
+        Ok(false) => return Err(CommitError::Rejected),
+        Err(e) => {
+            return Err(match e.downcast::<SchemaViolations>() {
+                Ok(violations) => CommitError::Invalid(violations),
+                Err(e) => CommitError::Hook(e),
+            })
+        }
+        Ok(true) => {}
+    }
+
+    let updated = match old_commit {
+        Some(old) => repo.reference_matching(&refname, new_commit, true, old, msg),
+        None => repo.reference(&refname, new_commit, false, msg),
+    };
+    match updated {
+        Ok(_) => {}
+        Err(e) if matches!(e.code(), ErrorCode::Modified | ErrorCode::Exists) => {
+            return Err(CommitError::Stale(branch.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    }
+    debug!(%new_commit, %branch, "ref updated");
+    changes::publish_branch(repo.path(), branch, old_commit.map(|o| o.to_string()), Some(new_commit.to_string()));
+
+    let _ = execute_repo_hook(&ctx, "post-receive");
+    Ok(())
+}
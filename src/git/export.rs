@@ -0,0 +1,230 @@
+//! Row-oriented encodings of query results: NDJSON, CSV and a plain-text table (CLI only).
+//!
+//! Each row is encoded on its own so HTTP responses can stream them. CSV cells follow RFC 4180;
+//! strings are written as-is, missing values and null as empty cells, and objects and arrays as
+//! compact JSON. A CSV cell that a spreadsheet would evaluate as a formula (starting with `=`,
+//! `+`, `-` or `@`, other than a plain number) is prefixed with `'`.
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::git::filter::lookup;
+
+/// Rows per chunk of a streamed response.
+pub const ROWS_PER_CHUNK: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Ndjson,
+    Csv,
+    Table,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            "csv" => Ok(ExportFormat::Csv),
+            "table" => Ok(ExportFormat::Table),
+            other => Err(format!("unknown format '{}' (json|ndjson|csv|table)", other)),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// Streaming format requested by an `Accept` header, if any.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept.split(',').find_map(|part| {
+            match part.split(';').next().unwrap_or("").trim() {
+                "application/x-ndjson" | "application/jsonl" => Some(ExportFormat::Ndjson),
+                "text/csv" => Some(ExportFormat::Csv),
+                _ => None,
+            }
+        })
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Table => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// Columns for CSV/table output: `selected` dotted paths, or every top-level field in order of
+/// first appearance.
+pub fn columns(items: &[Value], selected: &[String]) -> Vec<String> {
+    if !selected.is_empty() {
+        return selected.to_vec();
+    }
+    let mut out: Vec<String> = Vec::new();
+    for item in items {
+        add_columns(&mut out, item);
+    }
+    out
+}
+
+/// Append the top-level fields of `item` missing from `columns`, for collecting columns one row
+/// at a time.
+pub fn add_columns(columns: &mut Vec<String>, item: &Value) {
+    if let Value::Object(map) = item {
+        for key in map.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+}
+
+/// Text of the value at `column` of `doc`; empty when missing or null.
+pub fn cell(doc: &Value, column: &str) -> String {
+    match lookup(doc, column) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.starts_with(['=', '+', '-', '@']) && text.parse::<f64>().is_err() {
+        return csv_field(&format!("'{}", text));
+    }
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// One CSV record (with CRLF) from the given cells.
+pub fn csv_record<I, S>(cells: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut line = cells
+        .into_iter()
+        .map(|c| csv_field(c.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+pub fn csv_row(doc: &Value, columns: &[String]) -> String {
+    csv_record(columns.iter().map(|c| cell(doc, c)))
+}
+
+pub fn ndjson_line(doc: &Value) -> String {
+    let mut line = doc.to_string();
+    line.push('\n');
+    line
+}
+
+/// Encodes rows pushed one at a time into chunks of [`ROWS_PER_CHUNK`] rows, starting with the
+/// CSV header row of `columns`, which must already hold every column to export (see
+/// [`columns`] and [`add_columns`]). `Json` and `Table` yield NDJSON.
+pub struct ChunkEncoder {
+    format: ExportFormat,
+    columns: Vec<String>,
+    header_sent: bool,
+    pending: Vec<Value>,
+}
+
+impl ChunkEncoder {
+    pub fn new(format: ExportFormat, columns: &[String]) -> Self {
+        ChunkEncoder {
+            format,
+            columns: columns.to_vec(),
+            header_sent: format != ExportFormat::Csv,
+            pending: Vec::with_capacity(ROWS_PER_CHUNK),
+        }
+    }
+
+    /// Queue `row`; returns the encoded chunk once [`ROWS_PER_CHUNK`] rows are queued.
+    pub fn push(&mut self, row: Value) -> Option<String> {
+        self.pending.push(row);
+        (self.pending.len() >= ROWS_PER_CHUNK).then(|| self.flush())
+    }
+
+    /// The remaining rows, or the bare CSV header when no row was pushed.
+    pub fn finish(mut self) -> Option<String> {
+        (!self.pending.is_empty() || !self.header_sent).then(|| self.flush())
+    }
+
+    fn flush(&mut self) -> String {
+        let rows = std::mem::take(&mut self.pending);
+        let mut chunk = String::new();
+        if !self.header_sent {
+            chunk.push_str(&csv_record(self.columns.iter()));
+            self.header_sent = true;
+        }
+        for doc in &rows {
+            match self.format {
+                ExportFormat::Csv => chunk.push_str(&csv_row(doc, &self.columns)),
+                _ => chunk.push_str(&ndjson_line(doc)),
+            }
+        }
+        chunk
+    }
+}
+
+/// Encoded `rows` in chunks of [`ROWS_PER_CHUNK`], preceded by the CSV header row of `columns`.
+pub fn chunks(format: ExportFormat, rows: Vec<Value>, columns: Vec<String>) -> impl Iterator<Item = String> + Send {
+    let mut encoder = Some(ChunkEncoder::new(format, &columns));
+    let mut rows = rows.into_iter();
+    std::iter::from_fn(move || {
+        for row in rows.by_ref() {
+            if let Some(chunk) = encoder.as_mut()?.push(row) {
+                return Some(chunk);
+            }
+        }
+        encoder.take()?.finish()
+    })
+}
+
+/// Aligned plain-text table for terminals.
+pub fn table(items: &[Value], columns: &[String]) -> String {
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|doc| {
+            columns
+                .iter()
+                .map(|c| cell(doc, c).replace(['\n', '\r', '\t'], " "))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain(std::iter::once(c.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let render = |cells: &[String]| -> String {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{:<width$}", cell, width = *w))
+            .collect::<Vec<_>>()
+            .join("  ");
+        format!("{}\n", line.trim_end())
+    };
+    let mut out = render(columns);
+    out.push_str(&render(&widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>()));
+    for row in &rows {
+        out.push_str(&render(row));
+    }
+    out
+}
@@ -0,0 +1,274 @@
+//! Mongo-style document filters for index queries.
+//!
+//! A filter object is an implicit `$and` of field conditions. Field keys are dotted paths
+//! (`meta.author.name`, `tags.0`); arrays along the path are traversed element-wise, and a
+//! condition on an array field matches when the array itself or any element matches.
+use std::cmp::Ordering;
+
+use regex::{Regex, RegexBuilder};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("unknown operator '{0}'")]
+    UnknownOperator(String),
+    #[error("operator '{op}' expects {expected}")]
+    BadOperand { op: String, expected: &'static str },
+    #[error("invalid $regex: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("filter must be an object")]
+    NotAnObject,
+}
+
+/// A parsed filter, ready to be evaluated against many documents.
+#[derive(Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Nor(Vec<Filter>),
+    Not(Box<Filter>),
+    Field { path: Vec<String>, cond: Cond },
+}
+
+#[derive(Debug)]
+pub enum Cond {
+    /// All must hold.
+    All(Vec<Cond>),
+    Eq(Value),
+    Ne(Value),
+    Cmp(Ordering, bool, Value),
+    In(Vec<Value>),
+    Nin(Vec<Value>),
+    Exists(bool),
+    Regex(Regex),
+    Not(Box<Cond>),
+    ElemMatch(ElemMatch),
+    Size(usize),
+}
+
+#[derive(Debug)]
+pub enum ElemMatch {
+    /// `{ $elemMatch: { field: ... } }` on arrays of documents.
+    Doc(Box<Filter>),
+    /// `{ $elemMatch: { $gt: 1, $lt: 5 } }` on arrays of scalars.
+    Value(Box<Cond>),
+}
+
+impl Filter {
+    pub fn parse(v: &Value) -> Result<Filter, FilterError> {
+        let obj = v.as_object().ok_or(FilterError::NotAnObject)?;
+        let mut parts = Vec::with_capacity(obj.len());
+        for (key, val) in obj {
+            parts.push(match key.as_str() {
+                "$and" => Filter::And(parse_list("$and", val)?),
+                "$or" => Filter::Or(parse_list("$or", val)?),
+                "$nor" => Filter::Nor(parse_list("$nor", val)?),
+                "$not" => Filter::Not(Box::new(Filter::parse(val)?)),
+                op if op.starts_with('$') => return Err(FilterError::UnknownOperator(op.to_string())),
+                path => Filter::Field {
+                    path: path.split('.').map(|s| s.to_string()).collect(),
+                    cond: parse_cond(val)?,
+                },
+            });
+        }
+        Ok(if parts.len() == 1 {
+            parts.pop().unwrap_or(Filter::And(Vec::new()))
+        } else {
+            Filter::And(parts)
+        })
+    }
+
+    pub fn matches(&self, doc: &Value) -> bool {
+        match self {
+            Filter::And(fs) => fs.iter().all(|f| f.matches(doc)),
+            Filter::Or(fs) => fs.iter().any(|f| f.matches(doc)),
+            Filter::Nor(fs) => !fs.iter().any(|f| f.matches(doc)),
+            Filter::Not(f) => !f.matches(doc),
+            Filter::Field { path, cond } => {
+                let mut found = Vec::new();
+                resolve(doc, path, &mut found);
+                cond.eval(&found)
+            }
+        }
+    }
+}
+
+fn parse_list(op: &str, v: &Value) -> Result<Vec<Filter>, FilterError> {
+    v.as_array()
+        .filter(|a| !a.is_empty())
+        .ok_or_else(|| FilterError::BadOperand {
+            op: op.to_string(),
+            expected: "a non-empty array of filters",
+        })?
+        .iter()
+        .map(Filter::parse)
+        .collect()
+}
+
+fn is_operator_object(v: &Value) -> Option<&Map<String, Value>> {
+    v.as_object()
+        .filter(|o| !o.is_empty() && o.keys().all(|k| k.starts_with('$')))
+}
+
+fn parse_cond(v: &Value) -> Result<Cond, FilterError> {
+    let Some(ops) = is_operator_object(v) else {
+        return Ok(Cond::Eq(v.clone()));
+    };
+    let mut conds = Vec::with_capacity(ops.len());
+    for (op, arg) in ops {
+        let bad = |expected| FilterError::BadOperand {
+            op: op.clone(),
+            expected,
+        };
+        conds.push(match op.as_str() {
+            "$eq" => Cond::Eq(arg.clone()),
+            "$ne" => Cond::Ne(arg.clone()),
+            "$gt" => Cond::Cmp(Ordering::Greater, false, arg.clone()),
+            "$gte" => Cond::Cmp(Ordering::Greater, true, arg.clone()),
+            "$lt" => Cond::Cmp(Ordering::Less, false, arg.clone()),
+            "$lte" => Cond::Cmp(Ordering::Less, true, arg.clone()),
+            "$in" => Cond::In(arg.as_array().ok_or_else(|| bad("an array"))?.clone()),
+            "$nin" => Cond::Nin(arg.as_array().ok_or_else(|| bad("an array"))?.clone()),
+            "$exists" => Cond::Exists(arg.as_bool().ok_or_else(|| bad("a boolean"))?),
+            "$regex" => {
+                let pattern = arg.as_str().ok_or_else(|| bad("a string"))?;
+                let flags = ops.get("$options").and_then(|o| o.as_str()).unwrap_or("");
+                Cond::Regex(build_regex(pattern, flags)?)
+            }
+            "$options" if ops.contains_key("$regex") => continue,
+            "$not" => match arg {
+                Value::String(pattern) => Cond::Not(Box::new(Cond::Regex(build_regex(pattern, "")?))),
+                _ if is_operator_object(arg).is_some() => Cond::Not(Box::new(parse_cond(arg)?)),
+                _ => return Err(bad("an operator object or regex string")),
+            },
+            "$elemMatch" => {
+                let inner = arg.as_object().ok_or_else(|| bad("an object"))?;
+                if inner.keys().all(|k| k.starts_with('$'))
+                    && !inner.keys().any(|k| matches!(k.as_str(), "$and" | "$or" | "$nor"))
+                {
+                    Cond::ElemMatch(ElemMatch::Value(Box::new(parse_cond(arg)?)))
+                } else {
+                    Cond::ElemMatch(ElemMatch::Doc(Box::new(Filter::parse(arg)?)))
+                }
+            }
+            "$size" => Cond::Size(arg.as_u64().ok_or_else(|| bad("a non-negative integer"))? as usize),
+            other => return Err(FilterError::UnknownOperator(other.to_string())),
+        });
+    }
+    Ok(if conds.len() == 1 {
+        conds.pop().unwrap_or(Cond::All(Vec::new()))
+    } else {
+        Cond::All(conds)
+    })
+}
+
+fn build_regex(pattern: &str, flags: &str) -> Result<Regex, FilterError> {
+    Ok(RegexBuilder::new(pattern)
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .dot_matches_new_line(flags.contains('s'))
+        .ignore_whitespace(flags.contains('x'))
+        .build()?)
+}
+
+/// Collect every value reachable at `path`, descending into arrays element-wise.
+fn resolve<'a>(v: &'a Value, path: &[String], out: &mut Vec<&'a Value>) {
+    let Some((head, rest)) = path.split_first() else {
+        out.push(v);
+        return;
+    };
+    match v {
+        Value::Object(o) => {
+            if let Some(next) = o.get(head) {
+                resolve(next, rest, out);
+            }
+        }
+        Value::Array(items) => {
+            if let Ok(i) = head.parse::<usize>() {
+                if let Some(next) = items.get(i) {
+                    resolve(next, rest, out);
+                }
+            } else {
+                for item in items {
+                    resolve(item, path, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Every value reachable at dotted `path` (arrays along the path are traversed).
+pub fn path_values<'a>(doc: &'a Value, path: &str) -> Vec<&'a Value> {
+    let segs: Vec<String> = path.split('.').map(|s| s.to_string()).collect();
+    let mut found = Vec::new();
+    resolve(doc, &segs, &mut found);
+    found
+}
+
+/// First value at dotted `path` (used for sorting).
+pub fn lookup<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    path_values(doc, path).into_iter().next()
+}
+
+/// A resolved value plus, for arrays, each of its elements.
+fn candidates<'a, 'b>(found: &'b [&'a Value]) -> impl Iterator<Item = &'a Value> + 'b {
+    found.iter().flat_map(|v| {
+        let elems: &[Value] = match v {
+            Value::Array(a) => a,
+            _ => &[],
+        };
+        std::iter::once(*v).chain(elems.iter())
+    })
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+impl Cond {
+    fn eq_any(found: &[&Value], target: &Value) -> bool {
+        // `{ field: null }` also matches documents without the field.
+        (target.is_null() && found.is_empty()) || candidates(found).any(|c| values_equal(c, target))
+    }
+
+    fn eval(&self, found: &[&Value]) -> bool {
+        match self {
+            Cond::All(cs) => cs.iter().all(|c| c.eval(found)),
+            Cond::Eq(t) => Cond::eq_any(found, t),
+            Cond::Ne(t) => !Cond::eq_any(found, t),
+            Cond::Cmp(ord, inclusive, t) => candidates(found).any(|c| match compare(c, t) {
+                Some(Ordering::Equal) => *inclusive,
+                Some(o) => o == *ord,
+                None => false,
+            }),
+            Cond::In(ts) => ts.iter().any(|t| Cond::eq_any(found, t)),
+            Cond::Nin(ts) => !ts.iter().any(|t| Cond::eq_any(found, t)),
+            Cond::Exists(want) => found.is_empty() != *want,
+            Cond::Regex(re) => candidates(found).any(|c| c.as_str().is_some_and(|s| re.is_match(s))),
+            Cond::Not(c) => !c.eval(found),
+            Cond::ElemMatch(m) => found.iter().any(|v| {
+                v.as_array().is_some_and(|items| {
+                    items.iter().any(|item| match m {
+                        ElemMatch::Doc(f) => f.matches(item),
+                        ElemMatch::Value(c) => c.eval(&[item]),
+                    })
+                })
+            }),
+            Cond::Size(n) => found.iter().any(|v| v.as_array().is_some_and(|a| a.len() == *n)),
+        }
+    }
+}
@@ -0,0 +1,614 @@
+//! GraphQL over the collections of one branch index (`/api/graphql`).
+//!
+//! The schema is derived per indexed commit. Each collection becomes an object type whose fields
+//! are the properties of `server.db.collections.<name>.schema` plus the top-level fields of a
+//! sample of its indexed documents. `string`/`integer`/`number`/`boolean` values (and arrays of
+//! them) map to `String`/`Int`/`Float`/`Boolean`; anything else is the `JSON` scalar.
+//!
+//! A field holding ids of another collection's documents gains a lookup field resolving them. The
+//! target is declared with `"x-ref": "<collection>"` on the schema property, or inferred from the
+//! name: `directorId` → collection `director` or `directors`, `castIds` → `cast` (a list). The id of
+//! a document is its `_id`, else `id`, else `_path`.
+//!
+//! Queries per collection: `<name>(filter, search, sort, page, pageSize)` returns a page like
+//! QUERY, and `<name>ById(id)` one document. Resolvers read through [`execute_query`].
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use async_graphql::dynamic::{
+    Enum, Field, FieldFuture, FieldValue, InputObject, InputValue, Object, ResolverContext, Scalar,
+    Schema, TypeRef,
+};
+use async_graphql::Value as GqlValue;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::git::filter::Filter;
+use crate::git::indexdb::snapshot_branch;
+use crate::git::query::{execute_query, open_index, paginate, PageOptions, QueryPage, SortKey};
+use crate::git::schema::{collection_schemas, tree_loader};
+
+/// Documents per collection sampled to infer its fields.
+pub const INFER_SAMPLE: usize = 500;
+/// Schema property keyword naming the collection a field's ids point into.
+pub const REF_KEYWORD: &str = "x-ref";
+/// Built schemas kept in memory, keyed by repo, branch and indexed commit.
+const MAX_CACHED_SCHEMAS: usize = 32;
+/// Deepest selection a query may nest (reference lookups make unbounded nesting possible).
+pub const MAX_QUERY_DEPTH: usize = 12;
+/// Most fields a query may select, counted over all nesting levels.
+pub const MAX_QUERY_COMPLEXITY: usize = 1000;
+
+const JSON_SCALAR: &str = "JSON";
+const SORT_INPUT: &str = "SortInput";
+const SORT_DIRECTION: &str = "SortDirection";
+/// Whole document as JSON, on every collection type.
+const RAW_FIELD: &str = "_raw";
+/// Names of the queryable collections, on `Query`.
+const COLLECTIONS_FIELD: &str = "_collections";
+/// Document fields tried, in order, as the id of a collection's documents.
+const ID_FIELDS: [&str; 3] = ["_id", "id", "_path"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Only null or empty arrays seen so far.
+    Unknown,
+    String,
+    Int,
+    Float,
+    Boolean,
+    Json,
+}
+
+impl Kind {
+    fn merge(self, other: Kind) -> Kind {
+        match (self, other) {
+            (Kind::Unknown, k) | (k, Kind::Unknown) => k,
+            (a, b) if a == b => a,
+            (Kind::Int, Kind::Float) | (Kind::Float, Kind::Int) => Kind::Float,
+            _ => Kind::Json,
+        }
+    }
+
+    fn of_value(v: &Value) -> Kind {
+        match v {
+            Value::Null => Kind::Unknown,
+            Value::Bool(_) => Kind::Boolean,
+            Value::Number(n) if n.as_i64().is_some_and(|i| i32::try_from(i).is_ok()) => Kind::Int,
+            Value::Number(_) => Kind::Float,
+            Value::String(_) => Kind::String,
+            _ => Kind::Json,
+        }
+    }
+
+    /// Kind of a JSON Schema (`type` may be a list, `null` aside).
+    fn of_schema(schema: &Value) -> Kind {
+        let named = |t: &str| match t {
+            "string" => Kind::String,
+            "integer" => Kind::Int,
+            "number" => Kind::Float,
+            "boolean" => Kind::Boolean,
+            "null" => Kind::Unknown,
+            _ => Kind::Json,
+        };
+        match schema.get("type") {
+            Some(Value::String(t)) => named(t),
+            Some(Value::Array(types)) => types
+                .iter()
+                .filter_map(Value::as_str)
+                .map(named)
+                .fold(Kind::Unknown, Kind::merge),
+            _ => Kind::Json,
+        }
+    }
+
+    fn type_name(self) -> &'static str {
+        match self {
+            Kind::String => TypeRef::STRING,
+            Kind::Int => TypeRef::INT,
+            Kind::Float => TypeRef::FLOAT,
+            Kind::Boolean => TypeRef::BOOLEAN,
+            Kind::Unknown | Kind::Json => JSON_SCALAR,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FieldType {
+    kind: Kind,
+    list: bool,
+}
+
+impl FieldType {
+    const UNKNOWN: FieldType = FieldType { kind: Kind::Unknown, list: false };
+
+    fn of_value(v: &Value) -> FieldType {
+        match v {
+            Value::Array(items) => FieldType {
+                kind: items.iter().map(Kind::of_value).fold(Kind::Unknown, Kind::merge),
+                list: true,
+            },
+            v => FieldType { kind: Kind::of_value(v), list: false },
+        }
+    }
+
+    fn of_schema(schema: &Value) -> FieldType {
+        let is_array = match schema.get("type") {
+            Some(Value::String(t)) => t == "array",
+            Some(Value::Array(types)) => types.iter().any(|t| t == "array"),
+            _ => false,
+        };
+        if is_array {
+            let kind = schema.get("items").map(Kind::of_schema).unwrap_or(Kind::Json);
+            return FieldType { kind, list: true };
+        }
+        FieldType { kind: Kind::of_schema(schema), list: false }
+    }
+
+    fn merge(self, other: FieldType) -> FieldType {
+        if self == FieldType::UNKNOWN {
+            return other;
+        }
+        if other == FieldType::UNKNOWN {
+            return self;
+        }
+        if self.list != other.list {
+            return FieldType { kind: Kind::Json, list: false };
+        }
+        FieldType { kind: self.kind.merge(other.kind), list: self.list }
+    }
+
+    fn type_ref(self) -> TypeRef {
+        if self.list {
+            TypeRef::named_list(self.kind.type_name())
+        } else {
+            TypeRef::named(self.kind.type_name())
+        }
+    }
+
+    /// GraphQL value of `v`; `None` (null) when it does not fit the type.
+    fn coerce(self, v: &Value) -> Option<GqlValue> {
+        if !self.list {
+            return coerce_scalar(self.kind, v);
+        }
+        match v {
+            Value::Array(items) => Some(GqlValue::List(
+                items
+                    .iter()
+                    .map(|item| coerce_scalar(self.kind, item).unwrap_or(GqlValue::Null))
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+fn coerce_scalar(kind: Kind, v: &Value) -> Option<GqlValue> {
+    match (kind, v) {
+        (_, Value::Null) => None,
+        (Kind::String, Value::String(s)) => Some(GqlValue::String(s.clone())),
+        (Kind::String, Value::Number(_) | Value::Bool(_)) => Some(GqlValue::String(v.to_string())),
+        (Kind::Int, Value::Number(n)) if n.as_i64().is_some_and(|i| i32::try_from(i).is_ok()) => {
+            Some(GqlValue::Number(n.clone()))
+        }
+        (Kind::Float, Value::Number(n)) => Some(GqlValue::Number(n.clone())),
+        (Kind::Boolean, Value::Bool(b)) => Some(GqlValue::Boolean(*b)),
+        (Kind::Unknown | Kind::Json, v) => GqlValue::from_json(v.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Text of an id value (strings and numbers only), so `1` and `"1"` match.
+fn id_text(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// `castIds` / `cast_ids` → `cast`, `directorId` / `director_id` → `director`.
+fn id_base(key: &str) -> Option<&str> {
+    ["Ids", "_ids", "Id", "_id"]
+        .iter()
+        .find_map(|suffix| key.strip_suffix(suffix))
+        .filter(|base| !base.is_empty())
+}
+
+/// Valid GraphQL name for a document key or collection.
+fn gql_name(raw: &str) -> String {
+    let mut name: String = raw
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || name.starts_with("__") {
+        name.insert(0, 'f');
+    }
+    name
+}
+
+/// `movie-people` → `MoviePeople`.
+fn pascal_name(raw: &str) -> String {
+    let name: String = raw
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars).collect::<String>()
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("T{}", name)
+    } else {
+        name
+    }
+}
+
+struct FieldShape {
+    /// Document key.
+    key: String,
+    name: String,
+    ty: FieldType,
+    /// Collection the values are ids of.
+    reference: Option<String>,
+}
+
+struct CollectionShape {
+    collection: String,
+    type_name: String,
+    query_name: String,
+    id_field: String,
+    fields: Vec<FieldShape>,
+}
+
+impl CollectionShape {
+    fn page_type(&self) -> String {
+        format!("{}Page", self.type_name)
+    }
+}
+
+/// Fields of a collection: declared schema properties first, then keys seen in `docs`.
+fn collection_fields(declared: Option<&Value>, docs: &[Value]) -> BTreeMap<String, (FieldType, Option<String>)> {
+    let mut fields = BTreeMap::new();
+    if let Some(props) = declared.and_then(|s| s.get("properties")).and_then(Value::as_object) {
+        for (key, prop) in props {
+            let reference = prop.get(REF_KEYWORD).and_then(Value::as_str).map(str::to_string);
+            fields.insert(key.clone(), (FieldType::of_schema(prop), reference));
+        }
+    }
+    let declared_keys: HashSet<String> = fields.keys().cloned().collect();
+    for doc in docs {
+        let Some(obj) = doc.as_object() else { continue };
+        for (key, value) in obj.iter().filter(|(k, _)| !declared_keys.contains(*k)) {
+            let entry = fields.entry(key.clone()).or_insert((FieldType::UNKNOWN, None));
+            entry.0 = entry.0.merge(FieldType::of_value(value));
+        }
+    }
+    fields
+}
+
+fn derive_shapes(
+    collections: &BTreeSet<String>,
+    declared: &BTreeMap<String, Value>,
+    samples: &HashMap<String, Vec<Value>>,
+) -> Vec<CollectionShape> {
+    const RESERVED: [&str; 9] = [
+        "Query", JSON_SCALAR, SORT_INPUT, SORT_DIRECTION, "String", "Int", "Float", "Boolean", "ID",
+    ];
+    let mut used_types: HashSet<String> = RESERVED.iter().map(|s| s.to_string()).collect();
+    let mut used_queries: HashSet<String> = HashSet::from([COLLECTIONS_FIELD.to_string()]);
+    let mut shapes = Vec::new();
+    for collection in collections {
+        let query_name = gql_name(collection);
+        if !used_queries.insert(query_name.clone()) {
+            warn!(%collection, "GraphQL name taken by another collection; skipped");
+            continue;
+        }
+        used_queries.insert(format!("{}ById", query_name));
+        let mut type_name = pascal_name(collection);
+        while used_types.contains(&type_name) || used_types.contains(&format!("{}Page", type_name)) {
+            type_name.push_str("Doc");
+        }
+        used_types.insert(format!("{}Page", type_name));
+        used_types.insert(type_name.clone());
+
+        let docs = samples.get(collection).map(Vec::as_slice).unwrap_or_default();
+        let fields = collection_fields(declared.get(collection), docs);
+        let id_field = ID_FIELDS
+            .iter()
+            .find(|f| fields.contains_key(**f))
+            .unwrap_or(&ID_FIELDS[0])
+            .to_string();
+        let mut names = HashSet::from([RAW_FIELD.to_string()]);
+        let fields = fields
+            .into_iter()
+            .filter_map(|(key, (ty, reference))| {
+                let name = gql_name(&key);
+                names.insert(name.clone()).then_some(FieldShape { key, name, ty, reference })
+            })
+            .collect();
+        shapes.push(CollectionShape { collection: collection.clone(), type_name, query_name, id_field, fields });
+    }
+    shapes
+}
+
+/// Target collection of an id field: declared, or by name (`directorId` → `director(s)`).
+fn reference_target(field: &FieldShape, collections: &HashSet<&str>) -> Option<String> {
+    if let Some(target) = &field.reference {
+        return collections.contains(target.as_str()).then(|| target.clone());
+    }
+    if !matches!(field.ty.kind, Kind::String | Kind::Int | Kind::Unknown) {
+        return None;
+    }
+    let base = id_base(&field.key)?;
+    [base.to_string(), format!("{}s", base)]
+        .into_iter()
+        .find(|c| collections.contains(c.as_str()))
+}
+
+fn parent_doc<'a>(ctx: &ResolverContext<'a>) -> Option<&'a Value> {
+    ctx.parent_value.downcast_ref::<Value>()
+}
+
+fn json_arg(ctx: &ResolverContext<'_>, name: &str) -> async_graphql::Result<Option<Value>> {
+    match ctx.args.get(name).filter(|v| !v.is_null()) {
+        Some(v) => Ok(Some(v.as_value().clone().into_json()?)),
+        None => Ok(None),
+    }
+}
+
+fn object_type(shape: &CollectionShape, targets: &HashMap<&str, (&str, &str)>) -> Object {
+    let mut object = Object::new(&shape.type_name).field(Field::new(
+        RAW_FIELD,
+        TypeRef::named_nn(JSON_SCALAR),
+        |ctx| FieldFuture::from_value(parent_doc(&ctx).and_then(|d| GqlValue::from_json(d.clone()).ok())),
+    ));
+    let collections: HashSet<&str> = targets.keys().copied().collect();
+    let mut names: HashSet<String> = shape.fields.iter().map(|f| f.name.clone()).collect();
+    for field in &shape.fields {
+        let (key, ty) = (field.key.clone(), field.ty);
+        object = object.field(Field::new(&field.name, ty.type_ref(), move |ctx| {
+            FieldFuture::from_value(parent_doc(&ctx).and_then(|d| d.get(&key)).and_then(|v| ty.coerce(v)))
+        }));
+
+        let Some(target) = reference_target(field, &collections) else { continue };
+        let (target_type, id_field) = targets[target.as_str()];
+        let mut name = gql_name(id_base(&field.key).unwrap_or(&field.key));
+        if names.contains(&name) {
+            name.push_str("Ref");
+        }
+        if !names.insert(name.clone()) {
+            continue;
+        }
+        let (key, id_field, many) = (field.key.clone(), id_field.to_string(), ty.list);
+        let type_ref = if many { TypeRef::named_nn_list(target_type) } else { TypeRef::named(target_type) };
+        object = object.field(Field::new(name, type_ref, move |ctx| {
+            let (key, target, id_field) = (key.clone(), target.clone(), id_field.clone());
+            FieldFuture::new(async move {
+                let ids: Vec<String> = match parent_doc(&ctx).and_then(|d| d.get(&key)) {
+                    Some(Value::Array(items)) => items.iter().filter_map(id_text).collect(),
+                    Some(v) => id_text(v).into_iter().collect(),
+                    None => Vec::new(),
+                };
+                let docs = ctx.data::<Scope>()?.by_id(&target, &id_field, &ids).await?;
+                let mut docs = docs.into_iter().map(FieldValue::owned_any);
+                Ok(if many { Some(FieldValue::list(docs)) } else { docs.next() })
+            })
+        }));
+    }
+    object
+}
+
+fn page_type(shape: &CollectionShape) -> Object {
+    let count = |name: &str, get: fn(&QueryPage) -> usize| {
+        Field::new(name, TypeRef::named_nn(TypeRef::INT), move |ctx| {
+            FieldFuture::from_value(ctx.parent_value.downcast_ref::<QueryPage>().map(|p| GqlValue::from(get(p) as i64)))
+        })
+    };
+    Object::new(shape.page_type())
+        .field(count("total", |p| p.total))
+        .field(count("page", |p| p.page))
+        .field(count("pageSize", |p| p.page_size))
+        .field(Field::new("items", TypeRef::named_nn_list_nn(&shape.type_name), |ctx| {
+            FieldFuture::new(async move {
+                let page = ctx.parent_value.try_downcast_ref::<QueryPage>()?;
+                Ok(Some(FieldValue::list(page.items.iter().map(|d| FieldValue::borrowed_any(d)))))
+            })
+        }))
+}
+
+fn query_fields(shape: &CollectionShape) -> [Field; 2] {
+    let collection = shape.collection.clone();
+    let list = Field::new(&shape.query_name, TypeRef::named_nn(shape.page_type()), move |ctx| {
+        let collection = collection.clone();
+        FieldFuture::new(async move {
+            let filter = json_arg(&ctx, "filter")?;
+            let search = ctx.args.get("search").map(|v| v.string().map(str::to_string)).transpose()?;
+            let opts = PageOptions {
+                page: ctx.args.get("page").map(|v| v.u64()).transpose()?.unwrap_or(0) as usize,
+                page_size: ctx.args.get("pageSize").map(|v| v.u64()).transpose()?.map(|n| n as usize),
+                sort: json_arg(&ctx, "sort")?.map(serde_json::from_value::<Vec<SortKey>>).transpose()?.unwrap_or_default(),
+                projection: None,
+            };
+            let items = ctx.data::<Scope>()?.query(&collection, filter, search).await?;
+            Ok(Some(FieldValue::owned_any(paginate(items, &opts)?)))
+        })
+    })
+    .description(format!("Documents of collection `{}`, filtered, sorted and paged like QUERY", shape.collection))
+    .argument(InputValue::new("filter", TypeRef::named(JSON_SCALAR)))
+    .argument(InputValue::new("search", TypeRef::named(TypeRef::STRING)))
+    .argument(InputValue::new("sort", TypeRef::named_nn_list(SORT_INPUT)))
+    .argument(InputValue::new("page", TypeRef::named(TypeRef::INT)))
+    .argument(InputValue::new("pageSize", TypeRef::named(TypeRef::INT)));
+
+    let (collection, id_field) = (shape.collection.clone(), shape.id_field.clone());
+    let by_id = Field::new(format!("{}ById", shape.query_name), TypeRef::named(&shape.type_name), move |ctx| {
+        let (collection, id_field) = (collection.clone(), id_field.clone());
+        FieldFuture::new(async move {
+            let id = json_arg(&ctx, "id")?.as_ref().and_then(id_text).unwrap_or_default();
+            let docs = ctx.data::<Scope>()?.by_id(&collection, &id_field, &[id]).await?;
+            Ok(docs.into_iter().next().map(FieldValue::owned_any))
+        })
+    })
+    .description(format!("Document of `{}` whose `{}` is `id`", shape.collection, shape.id_field))
+    .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::ID)));
+    [list, by_id]
+}
+
+fn build(shapes: &[CollectionShape]) -> Result<Schema, async_graphql::dynamic::SchemaError> {
+    let names: Vec<GqlValue> = shapes.iter().map(|s| GqlValue::from(s.collection.clone())).collect();
+    let mut query = Object::new("Query").field(Field::new(
+        COLLECTIONS_FIELD,
+        TypeRef::named_nn_list_nn(TypeRef::STRING),
+        move |_| FieldFuture::from_value(Some(GqlValue::List(names.clone()))),
+    ));
+    let targets: HashMap<&str, (&str, &str)> = shapes
+        .iter()
+        .map(|s| (s.collection.as_str(), (s.type_name.as_str(), s.id_field.as_str())))
+        .collect();
+    let mut builder = Schema::build("Query", None, None)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .register(Scalar::new(JSON_SCALAR).description("Any JSON value"))
+        .register(Enum::new(SORT_DIRECTION).item("ASC").item("DESC"))
+        .register(
+            InputObject::new(SORT_INPUT)
+                .field(InputValue::new("field", TypeRef::named_nn(TypeRef::STRING)))
+                .field(InputValue::new("dir", TypeRef::named(SORT_DIRECTION))),
+        );
+    for shape in shapes {
+        for field in query_fields(shape) {
+            query = query.field(field);
+        }
+        builder = builder.register(object_type(shape, &targets)).register(page_type(shape));
+    }
+    builder.register(query).finish()
+}
+
+/// Per-request data: where resolvers read from, and documents by id already looked up (`None`
+/// for ids without a document).
+pub struct Scope {
+    repo_root: PathBuf,
+    repo_name: String,
+    branch: String,
+    as_of: Option<String>,
+    by_id: tokio::sync::Mutex<HashMap<String, HashMap<String, Option<Value>>>>,
+}
+
+impl Scope {
+    pub fn new(repo_root: PathBuf, repo_name: String, branch: String, as_of: Option<String>) -> Self {
+        Scope { repo_root, repo_name, branch, as_of, by_id: Default::default() }
+    }
+
+    async fn query(&self, collection: &str, filter: Option<Value>, search: Option<String>) -> anyhow::Result<Vec<Value>> {
+        let (root, repo, branch) = (self.repo_root.clone(), self.repo_name.clone(), self.branch.clone());
+        let (collection, as_of) = (collection.to_string(), self.as_of.clone());
+        let results = tokio::task::spawn_blocking(move || {
+            execute_query(&root, &repo, &branch, filter, &collection, search.as_deref(), as_of.as_deref())
+        })
+        .await??;
+        Ok(match results {
+            Value::Array(items) => items,
+            other => vec![other],
+        })
+    }
+
+    /// Documents of `collection` whose `id_field` is one of `ids`, in the order of `ids`. Ids not
+    /// looked up yet in this request are read with one `$in` query.
+    async fn by_id(&self, collection: &str, id_field: &str, ids: &[String]) -> anyhow::Result<Vec<Value>> {
+        let mut loaded = self.by_id.lock().await;
+        let known = loaded.entry(collection.to_string()).or_default();
+        let missing: BTreeSet<String> = ids.iter().filter(|id| !known.contains_key(*id)).cloned().collect();
+        if !missing.is_empty() {
+            let (root, repo, branch) = (self.repo_root.clone(), self.repo_name.clone(), self.branch.clone());
+            let (collection, id_field, as_of) = (collection.to_string(), id_field.to_string(), self.as_of.clone());
+            let wanted = missing.clone();
+            let docs = tokio::task::spawn_blocking(move || {
+                find_ids(&root, &repo, &branch, as_of.as_deref(), &collection, &id_field, &wanted)
+            })
+            .await??;
+            for (id, doc) in docs {
+                known.entry(id).or_insert(Some(doc));
+            }
+            for id in missing {
+                known.entry(id).or_insert(None);
+            }
+        }
+        Ok(ids.iter().filter_map(|id| known.get(id).cloned().flatten()).collect())
+    }
+}
+
+/// `(id, document)` of the documents of `collection` whose `id_field` is one of `ids`. An id
+/// matches string and numeric values alike (`"7"` finds `7`).
+fn find_ids(
+    repo_root: &Path,
+    repo_name: &str,
+    branch: &str,
+    as_of: Option<&str>,
+    collection: &str,
+    id_field: &str,
+    ids: &BTreeSet<String>,
+) -> anyhow::Result<Vec<(String, Value)>> {
+    let (Some(index), _) = open_index(repo_root, repo_name, branch, as_of)? else {
+        return Ok(Vec::new());
+    };
+    let values: Vec<Value> = ids
+        .iter()
+        .flat_map(|id| {
+            let number = id.parse::<serde_json::Number>().ok().map(Value::Number);
+            std::iter::once(Value::String(id.clone())).chain(number)
+        })
+        .collect();
+    let filter = Filter::parse(&serde_json::json!({ id_field: { "$in": values } }))?;
+    Ok(index
+        .find_limited(collection, Some(&filter), ids.len())?
+        .into_iter()
+        .filter_map(|doc| Some((doc.get(id_field).and_then(id_text)?, doc)))
+        .collect())
+}
+
+type SchemaKey = (PathBuf, String, String);
+
+fn schema_cache() -> &'static Mutex<HashMap<SchemaKey, Arc<Schema>>> {
+    static CACHE: OnceLock<Mutex<HashMap<SchemaKey, Arc<Schema>>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// GraphQL schema of `branch` (or of the `as_of` commit), built from its index and `.relay.yaml`
+/// and cached per indexed commit. Blocking: brings the index up to date first.
+pub fn schema_for(repo_root: &Path, repo_name: &str, branch: &str, as_of: Option<&str>) -> anyhow::Result<Arc<Schema>> {
+    let (index, head) = open_index(repo_root, repo_name, branch, as_of)?;
+    let repo_path = repo_root.join(format!("{}.git", repo_name));
+    let key = (repo_path.clone(), as_of.map(snapshot_branch).unwrap_or_else(|| branch.to_string()), head.clone());
+    if let Some(schema) = schema_cache().lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        return Ok(schema.clone());
+    }
+
+    let repo = git2::Repository::open_bare(&repo_path)?;
+    let db = crate::git::read_relay_config(&repo, &head)
+        .and_then(|c| c.server)
+        .and_then(|s| s.db)
+        .unwrap_or_default();
+    let declared = collection_schemas(&db, tree_loader(&repo, &head))?;
+    let mut collections: BTreeSet<String> = db.collections.keys().cloned().collect();
+    let mut samples = HashMap::new();
+    if let Some(index) = &index {
+        collections.extend(index.collections()?);
+    }
+    collections.retain(|c| !c.starts_with('_'));
+    if let Some(index) = &index {
+        for collection in &collections {
+            samples.insert(collection.clone(), index.find_limited(collection, None, INFER_SAMPLE)?);
+        }
+    }
+
+    let shapes = derive_shapes(&collections, &declared, &samples);
+    let schema = Arc::new(build(&shapes).map_err(|e| anyhow::anyhow!("cannot build GraphQL schema: {}", e))?);
+    let mut cache = schema_cache().lock().unwrap_or_else(|e| e.into_inner());
+    if cache.len() >= MAX_CACHED_SCHEMAS {
+        cache.clear();
+    }
+    cache.insert(key, schema.clone());
+    Ok(schema)
+}
@@ -0,0 +1,144 @@
+//! Undo operations over HTTP: revert a commit, restore a path to an older revision.
+use git2::build::TreeUpdateBuilder;
+use git2::{BranchType, Commit, FileMode, Repository, Signature};
+use serde::Serialize;
+use tracing::info;
+
+use crate::git::branches::{ensure_server_commit_allowed, BranchError};
+use crate::git::commit::{apply_commit, changed_files};
+use crate::git::merge::{collect_conflicts, MergeConflict};
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum HistoryOutcome {
+    /// A new commit was created on the branch.
+    Committed { commit: String },
+    /// The branch already had the requested content; no commit was created.
+    Unchanged { commit: String },
+    /// The revert could not be applied cleanly; refs were not touched.
+    Conflicts { conflicts: Vec<MergeConflict> },
+}
+
+fn branch_tip<'r>(repo: &'r Repository, branch: &str) -> Result<Commit<'r>, BranchError> {
+    let b = repo
+        .find_branch(branch, BranchType::Local)
+        .map_err(|_| BranchError::NotFound(branch.to_string()))?;
+    Ok(b.get().peel_to_commit()?)
+}
+
+fn find_commit<'r>(repo: &'r Repository, rev: &str) -> Result<Commit<'r>, BranchError> {
+    repo.revparse_single(rev)
+        .and_then(|o| o.peel_to_commit())
+        .map_err(|_| BranchError::RevisionNotFound(rev.to_string()))
+}
+
+fn commit_tree(
+    repo: &Repository,
+    branch: &str,
+    parent: &Commit,
+    tree_oid: git2::Oid,
+    msg: &str,
+) -> Result<HistoryOutcome, BranchError> {
+    if tree_oid == parent.tree_id() {
+        return Ok(HistoryOutcome::Unchanged {
+            commit: parent.id().to_string(),
+        });
+    }
+    ensure_server_commit_allowed(repo, branch)?;
+    let tree = repo.find_tree(tree_oid)?;
+    let sig = Signature::now("relay", "relay@local")?;
+    let commit_oid = repo.commit(None, &sig, &sig, msg, &tree, &[parent])?;
+    let files = changed_files(repo, Some(&parent.tree()?), &tree).map_err(BranchError::Other)?;
+    apply_commit(repo, branch, Some(parent.id()), commit_oid, files, msg)?;
+    Ok(HistoryOutcome::Committed {
+        commit: commit_oid.to_string(),
+    })
+}
+
+/// Revert `rev` on top of `branch`. Merge commits need `mainline` (1-based parent number).
+pub fn revert_commit(
+    repo: &Repository,
+    branch: &str,
+    rev: &str,
+    mainline: Option<u32>,
+    message: Option<&str>,
+) -> Result<HistoryOutcome, BranchError> {
+    let ours = branch_tip(repo, branch)?;
+    let target = find_commit(repo, rev)?;
+    if target.parent_count() > 1 && mainline.is_none() {
+        return Err(BranchError::BadRequest(format!(
+            "commit {} is a merge; specify mainline (parent number)",
+            target.id()
+        )));
+    }
+
+    let mut index = repo.revert_commit(&target, &ours, mainline.unwrap_or(0), None)?;
+    if index.has_conflicts() {
+        return Ok(HistoryOutcome::Conflicts {
+            conflicts: collect_conflicts(&index)?,
+        });
+    }
+    let tree_oid = index.write_tree_to(repo)?;
+    let msg = message.map(|m| m.to_string()).unwrap_or_else(|| {
+        format!(
+            "Revert \"{}\"\n\nThis reverts commit {}.",
+            target.summary().unwrap_or(""),
+            target.id()
+        )
+    });
+    let outcome = commit_tree(repo, branch, &ours, tree_oid, &msg)?;
+    info!(%branch, reverted = %target.id(), "revert processed");
+    Ok(outcome)
+}
+
+/// Restore `path` (file or directory) on `branch` to its contents at `rev`.
+/// When the path does not exist at `rev` it is removed from the branch.
+pub fn restore_path(
+    repo: &Repository,
+    branch: &str,
+    path: &str,
+    rev: &str,
+    message: Option<&str>,
+) -> Result<HistoryOutcome, BranchError> {
+    let rel = path.trim_matches('/');
+    if rel.is_empty() {
+        return Err(BranchError::BadRequest("path must not be empty".to_string()));
+    }
+    let ours = branch_tip(repo, branch)?;
+    let source = find_commit(repo, rev)?;
+    let current = ours.tree()?;
+    let old_entry = source.tree()?.get_path(std::path::Path::new(rel)).ok();
+    if old_entry.is_none() && current.get_path(std::path::Path::new(rel)).is_err() {
+        return Err(BranchError::NotFound(format!("{} (at {} and {})", rel, rev, branch)));
+    }
+
+    // Drop the current path first so a restored directory does not keep newer files.
+    let mut tree_oid = current.id();
+    if current.get_path(std::path::Path::new(rel)).is_ok() {
+        tree_oid = TreeUpdateBuilder::new()
+            .remove(rel)
+            .create_updated(repo, &current)?;
+    }
+    if let Some(entry) = &old_entry {
+        let base = repo.find_tree(tree_oid)?;
+        tree_oid = TreeUpdateBuilder::new()
+            .upsert(rel, entry.id(), file_mode(entry.filemode()))
+            .create_updated(repo, &base)?;
+    }
+    let msg = message
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| format!("Restore {} from {}", rel, source.id()));
+    let outcome = commit_tree(repo, branch, &ours, tree_oid, &msg)?;
+    info!(%branch, path = %rel, from = %source.id(), "restore processed");
+    Ok(outcome)
+}
+
+fn file_mode(raw: i32) -> FileMode {
+    match raw {
+        0o040000 => FileMode::Tree,
+        0o100755 => FileMode::BlobExecutable,
+        0o120000 => FileMode::Link,
+        0o160000 => FileMode::Commit,
+        _ => FileMode::Blob,
+    }
+}
@@ -1,6 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::git::{branches, changes};
+use crate::git::indexdb;
+use crate::git::schema::{self, SchemaSet, SchemaViolations};
 use std::process::Command;
-use tracing::{error, debug};
+use tracing::{error, debug, warn};
 
 pub struct HookContext {
     pub repo_path: PathBuf,
@@ -13,21 +16,51 @@ pub struct HookContext {
     pub files: std::collections::HashMap<String, String>,
 }
 
+/// `.relay.yaml` at `rev`, read with the git CLI (quarantine-aware); `None` when absent.
+fn read_config(repo_path: &Path, rev: &str) -> anyhow::Result<Option<crate::types::RelayConfig>> {
+    let output = Command::new("git")
+        .arg("-C").arg(repo_path)
+        .arg("show")
+        .arg(format!("{}:.relay.yaml", rev))
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(serde_yaml::from_str(&String::from_utf8_lossy(&output.stdout))?))
+}
+
 pub fn execute_repo_hook(
     ctx: &HookContext,
     hook_name: &str,
 ) -> anyhow::Result<bool> {
-    // Read .relay.yaml from the NEW_COMMIT using git CLI (quarantine-aware)
-    let config_output = Command::new("git")
-        .arg("-C").arg(&ctx.repo_path)
-        .arg("show")
-        .arg(format!("{}:.relay.yaml", ctx.new_commit))
-        .output()?;
+    let config = read_config(&ctx.repo_path, &ctx.new_commit)?;
 
-    let config: crate::types::RelayConfig = if config_output.status.success() {
-        let content = String::from_utf8_lossy(&config_output.stdout);
-        serde_yaml::from_str(&content)?
-    } else {
+    // Declared JSON Schemas are enforced natively before any script runs. Pushes are checked
+    // against the schemas of the trusted base, like branch rules, so a push cannot relax its own.
+    if matches!(hook_name, "pre-commit" | "pre-receive") {
+        let base = match hook_name {
+            "pre-receive" => branches::trusted_rev(&git2::Repository::open_bare(&ctx.repo_path)?),
+            _ => None,
+        };
+        let rev = base.as_deref().unwrap_or(&ctx.new_commit);
+        let base_config;
+        let schema_config = if rev == ctx.new_commit {
+            config.as_ref()
+        } else {
+            base_config = read_config(&ctx.repo_path, rev)?;
+            base_config.as_ref()
+        };
+        let schemas = SchemaSet::for_server(
+            schema_config.and_then(|c| c.server.as_ref()),
+            schema::git_cli_loader(&ctx.repo_path, rev),
+        )?;
+        let violations = schemas.check_files(&ctx.files);
+        if !violations.is_empty() {
+            return Err(SchemaViolations(violations).into());
+        }
+    }
+
+    let Some(config) = config else {
         debug!("No .relay.yaml found in commit {}, skipping hooks", ctx.new_commit);
         return Ok(true);
     };
@@ -117,10 +150,17 @@ pub fn execute_repo_hook(
         Err(_) => "node".to_string(),
     };
 
+    // Hooks journal their index writes here; they are applied in one transaction below.
+    let journal_path = tmp_dir_path.join("db-journal.jsonl");
+
     let mut cmd = Command::new(node_bin);
     cmd.arg("RelayHost.mjs")
         .arg(hook_path) // Path relative to current_dir which is tmp_dir
         .current_dir(&tmp_dir_path)
+        .env("RELAY_DB_JOURNAL", &journal_path)
+        .env("RELAY_DB_PATH", indexdb::db_path(&ctx.repo_path, &ctx.branch))
+        .env("RELAY_BRANCH_DIR", indexdb::branch_dir(&ctx.repo_path, &ctx.branch))
+        .env("RELAY_DB_CLI", relay_cli_path())
         .env("GIT_DIR", &ctx.repo_path)
         .env("OLD_COMMIT", &ctx.old_commit)
         .env("NEW_COMMIT", &ctx.new_commit)
@@ -149,6 +189,99 @@ pub fn execute_repo_hook(
     }
 
     let status = child.wait()?;
+    if status.success() {
+        apply_journal(ctx, hook_name, &journal_path, &config)?;
+    }
 
     Ok(status.success())
 }
+
+/// Commit a successful hook's journaled index writes. The `index` hook marks the store as
+/// synchronized with `new_commit`, replacing its contents when run without `old_commit` (full
+/// replay); `post-receive` only does so when it extended an index that was current at
+/// `old_commit`. Pre-* hooks run before the push is accepted, so their writes are dropped.
+/// Inserts violating their collection's schema are diverted to the quarantine collection.
+fn apply_journal(
+    ctx: &HookContext,
+    hook_name: &str,
+    journal: &Path,
+    config: &crate::types::RelayConfig,
+) -> anyhow::Result<()> {
+    let ops = indexdb::read_journal(journal)?;
+    let mut index = match hook_name {
+        "index" => indexdb::BranchIndex::open(&ctx.repo_path, &ctx.branch)?,
+        "post-receive" if !ops.is_empty() => indexdb::BranchIndex::open(&ctx.repo_path, &ctx.branch)?,
+        _ => {
+            if !ops.is_empty() {
+                debug!(hook = hook_name, ops = ops.len(), "discarding index writes from pre-acceptance hook");
+            }
+            return Ok(());
+        }
+    };
+    let schemas = SchemaSet::for_server(
+        config.server.as_ref(),
+        schema::git_cli_loader(&ctx.repo_path, &ctx.new_commit),
+    )?;
+    let ops: Vec<indexdb::DbOp> = ops
+        .into_iter()
+        .map(|op| match op {
+            indexdb::DbOp::Insert { collection, doc } => {
+                let file = doc.get(crate::git::indexer::PATH_FIELD).and_then(|p| p.as_str());
+                let violations = schemas.check_document(&collection, file, &doc);
+                if violations.is_empty() {
+                    indexdb::DbOp::Insert { collection, doc }
+                } else {
+                    warn!(hook = hook_name, %collection, violations = violations.len(), "document failed its schema; quarantined");
+                    indexdb::DbOp::Insert {
+                        collection: schema::QUARANTINE_COLLECTION.to_string(),
+                        doc: schema::quarantine_doc(&collection, doc, &violations),
+                    }
+                }
+            }
+            op => op,
+        })
+        .collect();
+    let ops = if hook_name == "index" && ctx.old_commit.is_empty() {
+        // Full replay: start from an empty store so documents of deleted files do not linger.
+        let mut reset: Vec<indexdb::DbOp> = index
+            .collections()?
+            .into_iter()
+            .map(|collection| indexdb::DbOp::Remove {
+                collection,
+                query: serde_json::json!({}),
+            })
+            .collect();
+        reset.extend(ops);
+        reset
+    } else {
+        ops
+    };
+    let head = match hook_name {
+        "index" => Some(ctx.new_commit.as_str()),
+        _ if index.indexed_head()?.as_deref() == Some(ctx.old_commit.as_str()) => Some(ctx.new_commit.as_str()),
+        _ => None,
+    };
+    let changed = index.apply(&ops, head)?;
+    changes::publish_ops(&ctx.repo_path, &ctx.branch, &ctx.new_commit, &ops);
+    debug!(hook = hook_name, ops = ops.len(), changed, "applied index journal");
+    Ok(())
+}
+
+/// Binary that serves `db find` for hooks: this executable when it is relay-server, else its sibling.
+fn relay_cli_path() -> PathBuf {
+    let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("relay-server"));
+    let is_server = exe
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("relay-server"));
+    if is_server {
+        exe
+    } else {
+        let sibling = exe.with_file_name("relay-server");
+        if sibling.exists() {
+            sibling
+        } else {
+            PathBuf::from("relay-server")
+        }
+    }
+}
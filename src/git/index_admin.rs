@@ -0,0 +1,276 @@
+//! Inspection and repair of the index store under `<repo>.git/.relay_data`: status per branch,
+//! rebuild, drop, and garbage collection of directories no live branch or commit maps to.
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::info;
+
+use crate::git::hooks::HookContext;
+use crate::git::indexdb::{
+    branch_dir, migrate_legacy_dirs, snapshot_branch, snapshots_dir, BranchIndex, IndexDbError, DB_FILE, SNAPSHOT_PREFIX,
+};
+use crate::git::indexing::{self, LastRun};
+
+#[derive(Debug, Error)]
+pub enum IndexAdminError {
+    #[error("repository '{0}' not found")]
+    RepoNotFound(String),
+    #[error("branch '{0}' not found")]
+    BranchNotFound(String),
+    #[error("no index for branch '{0}'")]
+    NoIndex(String),
+    #[error("invalid branch name '{0}'")]
+    InvalidBranch(String),
+    #[error(transparent)]
+    IndexDb(#[from] IndexDbError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error("indexing failed: {0}")]
+    Indexing(String),
+}
+
+/// State of one index directory, as shown by `GET /api/admin/index/{repo}` and
+/// `relay-server index status`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexInfo {
+    /// Branch name; `None` for an orphaned directory whose name cannot be decoded.
+    pub branch: Option<String>,
+    /// Directory name under `.relay_data/branches`.
+    pub dir: String,
+    /// Head of the branch; `None` when the branch no longer exists (orphaned index).
+    pub head: Option<String>,
+    pub indexed_head: Option<String>,
+    pub fresh: bool,
+    /// Documents per collection.
+    pub counts: BTreeMap<String, usize>,
+    /// Size of the database files in bytes.
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<LastRun>,
+}
+
+/// What [`gc`] removed.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    /// Branch index directories without a live branch.
+    pub branches: Vec<String>,
+    /// Snapshot directories of commits no longer in the repo.
+    pub snapshots: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+/// Bare repo `name` under `repo_root`.
+pub fn open_repo(repo_root: &Path, name: &str) -> Result<(PathBuf, Repository), IndexAdminError> {
+    let name = name.trim_end_matches(".git");
+    let path = repo_root.join(format!("{}.git", name));
+    let repo = Repository::open_bare(&path).map_err(|_| IndexAdminError::RepoNotFound(name.to_string()))?;
+    Ok((path, repo))
+}
+
+fn branches_root(repo_path: &Path) -> PathBuf {
+    repo_path.join(".relay_data").join("branches")
+}
+
+fn dir_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| if m.is_dir() { 0 } else { m.len() })
+        .sum()
+}
+
+/// Branch an index directory belongs to: recorded by its last run, else decoded from a legacy
+/// directory name that is the full (untruncated) hex of a short branch name.
+fn dir_branch(dir: &Path) -> Option<String> {
+    let recorded = BranchIndex::open_read_only(&dir.join(DB_FILE))
+        .ok()
+        .and_then(|index| LastRun::read(&index))
+        .map(|run| run.branch);
+    let name = dir_name(dir);
+    recorded.or_else(|| {
+        if name.len() > 12 {
+            return None;
+        }
+        hex::decode(&name).ok().and_then(|b| String::from_utf8(b).ok())
+    })
+}
+
+fn info_for(dir: &Path, branch: Option<String>, head: Option<String>) -> Result<IndexInfo, IndexAdminError> {
+    let mut info = IndexInfo {
+        branch,
+        dir: dir_name(dir),
+        head,
+        indexed_head: None,
+        fresh: false,
+        counts: BTreeMap::new(),
+        size: dir_size(dir),
+        last_run: None,
+    };
+    let db = dir.join(DB_FILE);
+    if db.exists() {
+        let index = BranchIndex::open_read_only(&db)?;
+        info.indexed_head = index.indexed_head()?;
+        for collection in index.collections()? {
+            let n = index.count(&collection)?;
+            info.counts.insert(collection, n);
+        }
+        info.last_run = LastRun::read(&index);
+    }
+    info.fresh = info.head.is_some() && info.head == info.indexed_head;
+    Ok(info)
+}
+
+/// Status of every branch of the repo (indexed or not), followed by orphaned index directories.
+pub fn status(repo_path: &Path, repo: &Repository) -> Result<Vec<IndexInfo>, IndexAdminError> {
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+    for branch in crate::git::list_branches(repo) {
+        let dir = branch_dir(repo_path, &branch);
+        seen.insert(dir_name(&dir));
+        let head = crate::git::get_branch_commit_info(repo, &branch).map(|(id, _, _)| id);
+        out.push(info_for(&dir, Some(branch), head)?);
+    }
+    for dir in index_dirs(repo_path) {
+        let name = dir_name(&dir);
+        if !seen.contains(&name) {
+            out.push(info_for(&dir, dir_branch(&dir), None)?);
+        }
+    }
+    Ok(out)
+}
+
+fn index_dirs(repo_path: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(branches_root(repo_path))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Delete the index of `branch`; the next QUERY or background run rebuilds it from scratch.
+pub fn drop_index(repo_path: &Path, branch: &str) -> Result<(), IndexAdminError> {
+    validate_branch(branch)?;
+    indexing::exclusive(repo_path, branch, || remove_index_dir(repo_path, branch))
+}
+
+/// Only real branch names: `snapshot:` pseudo-branches and anything `git check-ref-format`
+/// rejects (`..`, leading `-`, ...) could name a directory outside `branches/`.
+fn validate_branch(branch: &str) -> Result<(), IndexAdminError> {
+    let valid = !branch.starts_with('-') && git2::Reference::is_valid_name(&format!("refs/heads/{}", branch));
+    if branch.starts_with(SNAPSHOT_PREFIX) || !valid {
+        return Err(IndexAdminError::InvalidBranch(branch.to_string()));
+    }
+    Ok(())
+}
+
+fn remove_index_dir(repo_path: &Path, branch: &str) -> Result<(), IndexAdminError> {
+    let dir = branch_dir(repo_path, branch);
+    if !dir.exists() {
+        return Err(IndexAdminError::NoIndex(branch.to_string()));
+    }
+    std::fs::remove_dir_all(&dir)?;
+    info!(repo = %repo_path.display(), %branch, "branch index dropped");
+    Ok(())
+}
+
+/// Drop the index of `branch` and build it again from scratch at the branch head. Blocking.
+pub fn rebuild(repo_path: &Path, repo: &Repository, branch: &str) -> Result<IndexInfo, IndexAdminError> {
+    validate_branch(branch)?;
+    let head = crate::git::get_branch_commit_info(repo, branch)
+        .map(|(id, _, _)| id)
+        .ok_or_else(|| IndexAdminError::BranchNotFound(branch.to_string()))?;
+    let ctx = HookContext {
+        repo_path: repo_path.to_path_buf(),
+        old_commit: String::new(),
+        new_commit: head.clone(),
+        refname: format!("refs/heads/{}", branch),
+        branch: branch.to_string(),
+        is_verified: true,
+        files: std::collections::HashMap::new(),
+    };
+    indexing::exclusive(repo_path, branch, || {
+        match remove_index_dir(repo_path, branch) {
+            Ok(()) | Err(IndexAdminError::NoIndex(_)) => {}
+            Err(e) => return Err(e),
+        }
+        indexing::index_now(&ctx).map_err(|e| IndexAdminError::Indexing(e.to_string()))
+    })?;
+    info_for(&branch_dir(repo_path, branch), Some(branch.to_string()), Some(head))
+}
+
+/// Remove `dir` unless `keep` or it is already gone; returns the bytes freed.
+fn remove_dir_unless(dir: &Path, keep: bool) -> Result<Option<u64>, IndexAdminError> {
+    if keep || !dir.exists() {
+        return Ok(None);
+    }
+    let size = dir_size(dir);
+    std::fs::remove_dir_all(dir)?;
+    Ok(Some(size))
+}
+
+/// Remove index directories of branches that no longer exist and snapshots of commits that are
+/// gone from the repo. Each removal holds the directory's flight, so a run in progress finishes
+/// first, and re-checks that the branch or commit is still gone.
+pub fn gc(repo_path: &Path, repo: &Repository) -> Result<GcReport, IndexAdminError> {
+    migrate_legacy_dirs(repo_path, &crate::git::list_branches(repo))?;
+    let dirs = index_dirs(repo_path);
+    // Listed after the scan: a branch whose first run created a directory is live.
+    let live: HashSet<String> = crate::git::list_branches(repo)
+        .iter()
+        .map(|b| dir_name(&branch_dir(repo_path, b)))
+        .collect();
+    let mut report = GcReport::default();
+    for dir in dirs {
+        let name = dir_name(&dir);
+        if live.contains(&name) {
+            continue;
+        }
+        // Without a recorded run the directory has no branch name to lock; none of the live
+        // branches maps to it, so no run writes to it.
+        let (branch, freed) = match dir_branch(&dir) {
+            Some(branch) => {
+                let freed = indexing::exclusive(repo_path, &branch, || {
+                    remove_dir_unless(&dir, crate::git::get_branch_commit_info(repo, &branch).is_some())
+                })?;
+                (branch, freed)
+            }
+            None => (name, remove_dir_unless(&dir, false)?),
+        };
+        if let Some(size) = freed {
+            report.freed_bytes += size;
+            report.branches.push(branch);
+        }
+    }
+    for entry in std::fs::read_dir(snapshots_dir(repo_path)).into_iter().flatten().flatten() {
+        let dir = entry.path();
+        let name = dir_name(&dir);
+        let exists = || git2::Oid::from_str(&name).is_ok_and(|oid| repo.find_commit(oid).is_ok());
+        if exists() {
+            continue;
+        }
+        let freed = indexing::exclusive(repo_path, &snapshot_branch(&name), || remove_dir_unless(&dir, exists()))?;
+        if let Some(size) = freed {
+            report.freed_bytes += size;
+            report.snapshots.push(name);
+        }
+    }
+    info!(repo = %repo_path.display(), branches = report.branches.len(), snapshots = report.snapshots.len(), "index gc done");
+    Ok(report)
+}
@@ -0,0 +1,355 @@
+//! Background indexing: keeps branch indexes close to their heads so QUERY rarely has to catch
+//! up inline.
+//!
+//! Writers call [`IndexWorker::notify`] after moving a branch; each (repo, branch) is queued at
+//! most once and a notification arriving while it is being indexed schedules one rerun. Pushes
+//! received by `git daemon` reach the server through trigger files dropped by
+//! `relay-hook-handler` (see [`request_indexing`]) and polled by the worker, which publishes the
+//! branch event of each push to the [change feed](crate::git::changes). Runs go through
+//! [`ensure_indexed`], so they share single-flight with JIT catch-up in QUERY.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, info, warn};
+
+use crate::git::changes;
+use crate::git::hooks::HookContext;
+use crate::git::indexing::{ensure_indexed, repo_key};
+
+/// Directory under `<repo>.git/.relay_data` holding one file per pushed branch move: a JSON
+/// [`BranchMove`] named by the hex sha256 of its content, so any branch name fits a file name.
+pub const TRIGGER_DIR: &str = "index-requests";
+/// Concurrent indexing runs when `RELAY_INDEX_WORKERS` is unset.
+pub const DEFAULT_WORKERS: usize = 2;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+type Key = (PathBuf, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexState {
+    Queued,
+    Running,
+    Idle,
+    Failed,
+}
+
+/// Worker-side view of one branch, as reported by `GET /api/indexing`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BranchStatus {
+    pub state: IndexState,
+    /// Another run is queued behind the running one.
+    pub pending: bool,
+    /// Head indexed by the last successful run.
+    pub last_head: Option<String>,
+    pub last_error: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    /// Unix seconds of the last state change.
+    pub updated_at: u64,
+    pub runs: u64,
+}
+
+#[derive(Default)]
+struct Entry {
+    queued: bool,
+    running: bool,
+    last_head: Option<String>,
+    last_error: Option<String>,
+    last_duration_ms: Option<u64>,
+    updated_at: u64,
+    runs: u64,
+}
+
+impl Entry {
+    fn status(&self) -> BranchStatus {
+        let state = if self.running {
+            IndexState::Running
+        } else if self.queued {
+            IndexState::Queued
+        } else if self.last_error.is_some() {
+            IndexState::Failed
+        } else {
+            IndexState::Idle
+        };
+        BranchStatus {
+            state,
+            pending: self.running && self.queued,
+            last_head: self.last_head.clone(),
+            last_error: self.last_error.clone(),
+            last_duration_ms: self.last_duration_ms,
+            updated_at: self.updated_at,
+            runs: self.runs,
+        }
+    }
+}
+
+struct Inner {
+    repo_root: PathBuf,
+    entries: Mutex<HashMap<Key, Entry>>,
+    tx: mpsc::UnboundedSender<Key>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<Key>>>,
+}
+
+/// Handle to the background indexer; cheap to clone into [`crate::AppState`].
+#[derive(Clone)]
+pub struct IndexWorker {
+    inner: Arc<Inner>,
+}
+
+impl IndexWorker {
+    /// Create the queue for repos under `repo_root`. Nothing runs until [`IndexWorker::start`].
+    pub fn new(repo_root: PathBuf) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        IndexWorker {
+            inner: Arc::new(Inner {
+                repo_root,
+                entries: Mutex::new(HashMap::new()),
+                tx,
+                rx: Mutex::new(Some(rx)),
+            }),
+        }
+    }
+
+    /// Spawn the dispatcher and the trigger file poller on the current Tokio runtime, with at most
+    /// `workers` concurrent runs. Later calls are no-ops.
+    pub fn start(&self, workers: usize) {
+        let Some(mut rx) = self.inner.rx.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+            return;
+        };
+        let worker = self.clone();
+        let permits = Arc::new(Semaphore::new(workers.max(1)));
+        // Each queued branch waits for a permit in its own task, so the queue keeps draining (and
+        // deduplicating) while every worker is busy.
+        tokio::spawn(async move {
+            while let Some(key) = rx.recv().await {
+                let (worker, permits) = (worker.clone(), permits.clone());
+                tokio::spawn(async move {
+                    let Ok(_permit) = permits.acquire_owned().await else { return };
+                    worker.run(key).await;
+                });
+            }
+        });
+        let worker = self.clone();
+        tokio::spawn(async move {
+            let mut poll = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                poll.tick().await;
+                let worker = worker.clone();
+                let _ = tokio::task::spawn_blocking(move || worker.poll_triggers()).await;
+            }
+        });
+        info!(workers, "background indexing worker started");
+    }
+
+    /// Queue `branch` of the bare repo at `repo_path` unless it is already queued.
+    pub fn notify(&self, repo_path: &Path, branch: &str) {
+        let key: Key = (repo_key(repo_path), branch.to_string());
+        let mut entries = self.inner.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.entry(key.clone()).or_default();
+        if entry.queued {
+            return;
+        }
+        entry.queued = true;
+        entry.updated_at = now_secs();
+        // A running branch is re-sent when its run completes.
+        if !entry.running {
+            let _ = self.inner.tx.send(key);
+        }
+    }
+
+    /// Queue every branch of the bare repo at `repo_path`.
+    pub fn notify_repo(&self, repo_path: &Path) {
+        let Ok(repo) = Repository::open_bare(repo_path) else {
+            return;
+        };
+        for branch in crate::git::list_branches(&repo) {
+            self.notify(repo_path, &branch);
+        }
+    }
+
+    /// Queue every branch of every repo under the root (startup catch-up).
+    pub fn notify_all(&self) {
+        for name in crate::git::bare_repo_names(&self.inner.repo_root) {
+            self.notify_repo(&self.inner.repo_root.join(format!("{}.git", name)));
+        }
+    }
+
+    /// Status of every branch of `repo_path` the worker has seen, by branch name.
+    pub fn status(&self, repo_path: &Path) -> HashMap<String, BranchStatus> {
+        let repo_path = repo_key(repo_path);
+        self.inner
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|((path, _), _)| *path == repo_path)
+            .map(|((_, branch), entry)| (branch.clone(), entry.status()))
+            .collect()
+    }
+
+    async fn run(&self, key: Key) {
+        self.update(&key, |entry| {
+            entry.queued = false;
+            entry.running = true;
+        });
+        let started = Instant::now();
+        let (repo_path, branch) = key.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            match branch_head(&repo_path, &branch)? {
+                Some(head) => index_branch(&repo_path, &branch, &head).map(|()| Some(head)),
+                None => Ok(None),
+            }
+        })
+            .await
+            .map_err(|e| anyhow::anyhow!("indexing task failed: {}", e))
+            .and_then(|r| r);
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(head) => debug!(repo = ?key.0, branch = %key.1, ?head, ?elapsed, "background indexing done"),
+            Err(e) => warn!(repo = ?key.0, branch = %key.1, error = %e, "background indexing failed"),
+        }
+        let rerun = self.update(&key, |entry| {
+            entry.running = false;
+            entry.runs += 1;
+            entry.last_duration_ms = Some(elapsed.as_millis() as u64);
+            match result {
+                Ok(head) => {
+                    entry.last_head = head;
+                    entry.last_error = None;
+                }
+                Err(e) => entry.last_error = Some(e.to_string()),
+            }
+            entry.queued
+        });
+        if rerun {
+            let _ = self.inner.tx.send(key);
+        }
+    }
+
+    fn update<R>(&self, key: &Key, f: impl FnOnce(&mut Entry) -> R) -> R {
+        let mut entries = self.inner.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.entry(key.clone()).or_default();
+        let out = f(entry);
+        entry.updated_at = now_secs();
+        out
+    }
+
+    /// Consume trigger files of every repo under the root (and of the root itself when it is a
+    /// single bare repo).
+    fn poll_triggers(&self) {
+        let root = &self.inner.repo_root;
+        let mut repos: Vec<PathBuf> = crate::git::bare_repo_names(root)
+            .into_iter()
+            .map(|name| root.join(format!("{}.git", name)))
+            .collect();
+        repos.push(root.clone());
+        for repo_path in repos {
+            for moved in take_requests(&repo_path) {
+                debug!(repo = ?repo_path, branch = %moved.branch, "indexing requested by trigger file");
+                changes::publish_branch(&repo_path, &moved.branch, moved.old, moved.head);
+                self.notify(&repo_path, &moved.branch);
+            }
+        }
+    }
+}
+
+/// A branch moved by a push: tips before and after (`None` when the branch was created or
+/// deleted).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BranchMove {
+    pub branch: String,
+    pub old: Option<String>,
+    pub head: Option<String>,
+}
+
+/// Tell a running relay-server that a push moved `branch` of the bare repo at `repo_path` from
+/// `old` to `head`, so it publishes the move and indexes the branch. Used from
+/// `relay-hook-handler`, which runs in a separate process.
+pub fn request_indexing(
+    repo_path: &Path,
+    branch: &str,
+    old: Option<&str>,
+    head: Option<&str>,
+) -> std::io::Result<()> {
+    let dir = trigger_dir(repo_path);
+    std::fs::create_dir_all(&dir)?;
+    let moved = BranchMove {
+        branch: branch.to_string(),
+        old: old.map(str::to_string),
+        head: head.map(str::to_string),
+    };
+    let body = serde_json::to_vec(&moved)?;
+    let name = hex::encode(Sha256::digest(&body));
+    // Written aside and renamed so the poller never reads a half-written request.
+    let partial = dir.join(format!(".{}", name));
+    std::fs::write(&partial, body)?;
+    std::fs::rename(partial, dir.join(name))
+}
+
+fn trigger_dir(repo_path: &Path) -> PathBuf {
+    repo_path.join(".relay_data").join(TRIGGER_DIR)
+}
+
+/// Remove and return the moves reported for `repo_path`, oldest first.
+fn take_requests(repo_path: &Path) -> Vec<BranchMove> {
+    let Ok(rd) = std::fs::read_dir(trigger_dir(repo_path)) else {
+        return Vec::new();
+    };
+    let mut moves = Vec::new();
+    for e in rd.flatten() {
+        let path = e.path();
+        if path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.')) {
+            continue;
+        }
+        let written = e.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+        // Empty, unreadable or malformed files are garbage; they are removed all the same.
+        let moved = std::fs::read(&path)
+            .ok()
+            .and_then(|body| serde_json::from_slice::<BranchMove>(&body).ok());
+        if std::fs::remove_file(&path).is_ok() {
+            if let Some(moved) = moved {
+                moves.push((written, moved));
+            }
+        }
+    }
+    moves.sort_by_key(|(written, _)| *written);
+    moves.into_iter().map(|(_, moved)| moved).collect()
+}
+
+/// Current head of `branch`; `None` when the branch no longer exists.
+fn branch_head(repo_path: &Path, branch: &str) -> anyhow::Result<Option<String>> {
+    let repo = Repository::open_bare(repo_path)?;
+    let head = match repo.find_reference(&format!("refs/heads/{}", branch)) {
+        Ok(r) => r.peel_to_commit()?.id().to_string(),
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(head))
+}
+
+/// Index `branch` up to `head`.
+fn index_branch(repo_path: &Path, branch: &str, head: &str) -> anyhow::Result<()> {
+    let ctx = HookContext {
+        repo_path: repo_path.to_path_buf(),
+        old_commit: String::new(),
+        new_commit: head.to_string(),
+        refname: format!("refs/heads/{}", branch),
+        branch: branch.to_string(),
+        is_verified: true,
+        files: HashMap::new(),
+    };
+    ensure_indexed(&ctx)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
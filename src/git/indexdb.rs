@@ -0,0 +1,815 @@
+//! Per-branch index store: `.relay_data/branches/<hash>/index.db` (SQLite).
+//!
+//! Documents are stored as JSON rows per collection. Fields declared as indexes (in `.relay.yaml`
+//! `server.db.collections.<name>.indexes` or by a hook via `createIndex`) get multikey entries in
+//! `index_entries`, which narrow the candidate set for equality, `$in` and range conditions before
+//! the exact [`Filter`] evaluation. Fields declared under `search` feed an FTS5 full-text index
+//! (Porter-stemmed, BM25-ranked) kept in step with every document write. Hooks never touch the
+//! database directly: they journal writes, and [`BranchIndex::apply`] commits a journal in one transaction.
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::git::filter::{path_values, Cond, Filter, FilterError};
+
+pub const DB_FILE: &str = "index.db";
+/// Pre-SQLite store written by RelayHost.mjs; imported on first open, then renamed `*.migrated`.
+pub const LEGACY_DB_FILE: &str = "index.db.json";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS documents (
+    id INTEGER PRIMARY KEY,
+    collection TEXT NOT NULL,
+    doc TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS documents_collection ON documents(collection, id);
+CREATE TABLE IF NOT EXISTS indexes (
+    collection TEXT NOT NULL,
+    field TEXT NOT NULL,
+    PRIMARY KEY (collection, field)
+);
+CREATE TABLE IF NOT EXISTS index_entries (
+    collection TEXT NOT NULL,
+    field TEXT NOT NULL,
+    value,
+    doc_id INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS index_entries_lookup ON index_entries(collection, field, value);
+CREATE INDEX IF NOT EXISTS index_entries_doc ON index_entries(doc_id);
+CREATE TABLE IF NOT EXISTS search_fields (
+    collection TEXT NOT NULL,
+    field TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (collection, field)
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(
+    collection UNINDEXED,
+    body,
+    tokenize = 'porter unicode61 remove_diacritics 2'
+);
+";
+
+/// Rank of a search hit (BM25; higher is better).
+pub const SCORE_FIELD: &str = "_score";
+/// Matched text of a search hit with terms wrapped in `<mark>`.
+pub const SNIPPET_FIELD: &str = "_snippet";
+
+
+#[derive(Debug, Error)]
+pub enum IndexDbError {
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid document: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+    #[error("collection '{0}' has no search fields (declare server.db.collections.{0}.search)")]
+    NotSearchable(String),
+}
+
+/// One journaled write from a hook (a line of `$RELAY_DB_JOURNAL`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum DbOp {
+    Insert { collection: String, doc: Value },
+    /// Shallow-merge `update` into every document matching `query`.
+    Update { collection: String, query: Value, update: Map<String, Value> },
+    Remove { collection: String, query: Value },
+    CreateIndex { collection: String, field: String },
+}
+
+/// Prefix of the pseudo-branch that names the index of a single commit (`:` cannot occur in a
+/// git branch name).
+pub const SNAPSHOT_PREFIX: &str = "snapshot:";
+
+/// Pseudo-branch under which the index "as of" `commit` is built and cached.
+pub fn snapshot_branch(commit: &str) -> String {
+    format!("{}{}", SNAPSHOT_PREFIX, commit)
+}
+
+pub fn snapshots_dir(repo_path: &Path) -> PathBuf {
+    repo_path.join(".relay_data").join("snapshots")
+}
+
+/// `.relay_data/branches/<sha256(branch)>`; snapshots live in `.relay_data/snapshots/<commit>`.
+/// Hooks get it as `RELAY_BRANCH_DIR`.
+pub fn branch_dir(repo_path: &Path, branch: &str) -> PathBuf {
+    if let Some(commit) = branch.strip_prefix(SNAPSHOT_PREFIX) {
+        return snapshots_dir(repo_path).join(commit);
+    }
+    let branch = if branch.is_empty() { "main" } else { branch };
+    let branch_hash = hex::encode(Sha256::digest(branch.as_bytes()));
+    repo_path.join(".relay_data").join("branches").join(branch_hash)
+}
+
+/// Pre-sha256 directory name of `branch`: `hex(branch)` cut to 12 characters, so every branch
+/// sharing its first 6 bytes mapped to the same directory.
+fn legacy_dir_name(branch: &str) -> String {
+    let mut name = hex::encode(branch);
+    name.truncate(12);
+    name
+}
+
+/// Move the pre-sha256 `branches/<hex(branch)[..12]>` directory of `branch` to its new name,
+/// given every branch of the repo. When another branch shares the legacy name, the directory
+/// cannot be attributed: it is left for `gc` and the branch is re-indexed.
+fn rename_legacy_dir(repo_path: &Path, branch: &str, branches: &[String]) -> Result<bool, IndexDbError> {
+    let branch = if branch.is_empty() { "main" } else { branch };
+    let dir = branch_dir(repo_path, branch);
+    let legacy_name = legacy_dir_name(branch);
+    let legacy = repo_path.join(".relay_data").join("branches").join(&legacy_name);
+    if dir.exists() || !legacy.is_dir() {
+        return Ok(false);
+    }
+    if let Some(other) = branches
+        .iter()
+        .find(|b| b.as_str() != branch && legacy_dir_name(b) == legacy_name)
+    {
+        warn!(%branch, %other, dir = %legacy.display(), "legacy index directory is shared; not migrated");
+        return Ok(false);
+    }
+    std::fs::rename(&legacy, &dir)?;
+    Ok(true)
+}
+
+/// [`rename_legacy_dir`] for one branch, listing the repo's branches to detect shared names.
+pub(crate) fn migrate_legacy_dir(repo_path: &Path, branch: &str) -> Result<(), IndexDbError> {
+    if branch.starts_with(SNAPSHOT_PREFIX) {
+        return Ok(());
+    }
+    let branches = git2::Repository::open_bare(repo_path)
+        .map(|repo| crate::git::list_branches(&repo))
+        .unwrap_or_default();
+    rename_legacy_dir(repo_path, branch, &branches)?;
+    Ok(())
+}
+
+/// Move the legacy directories of all `branches` (every branch of the repo); returns how many
+/// were renamed.
+pub fn migrate_legacy_dirs(repo_path: &Path, branches: &[String]) -> Result<usize, IndexDbError> {
+    let mut renamed = 0;
+    for branch in branches {
+        if rename_legacy_dir(repo_path, branch, branches)? {
+            renamed += 1;
+        }
+    }
+    Ok(renamed)
+}
+
+pub fn db_path(repo_path: &Path, branch: &str) -> PathBuf {
+    branch_dir(repo_path, branch).join(DB_FILE)
+}
+
+pub struct BranchIndex {
+    conn: Connection,
+}
+
+impl BranchIndex {
+    /// Open (creating if needed) the index for `branch`, importing a legacy JSON store first.
+    pub fn open(repo_path: &Path, branch: &str) -> Result<Self, IndexDbError> {
+        migrate_legacy_dir(repo_path, branch)?;
+        let dir = branch_dir(repo_path, branch);
+        std::fs::create_dir_all(&dir)?;
+        Self::open_path(&dir.join(DB_FILE))
+    }
+
+    pub fn open_path(path: &Path) -> Result<Self, IndexDbError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        let mut index = BranchIndex { conn };
+        let legacy = path.with_file_name(LEGACY_DB_FILE);
+        if legacy.exists() {
+            index.import_legacy(&legacy)?;
+        }
+        Ok(index)
+    }
+
+    /// Empty scratch store, for previewing a hook's journal before its branch has an index.
+    pub fn open_in_memory() -> Result<Self, IndexDbError> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(BranchIndex { conn })
+    }
+
+    /// Read-only handle for hook `find` calls; fails when the store does not exist yet.
+    pub fn open_read_only(path: &Path) -> Result<Self, IndexDbError> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(std::time::Duration::from_secs(10))?;
+        Ok(BranchIndex { conn })
+    }
+
+    /// Open only if a store (SQLite or legacy JSON) already exists for `branch`.
+    pub fn open_existing(repo_path: &Path, branch: &str) -> Result<Option<Self>, IndexDbError> {
+        migrate_legacy_dir(repo_path, branch)?;
+        let dir = branch_dir(repo_path, branch);
+        if dir.join(DB_FILE).exists() || dir.join(LEGACY_DB_FILE).exists() {
+            Self::open(repo_path, branch).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Commit the index was last synchronized with.
+    pub fn indexed_head(&self) -> Result<Option<String>, IndexDbError> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = 'indexed_head'", [], |r| r.get(0))
+            .optional()?)
+    }
+
+    /// Free-form bookkeeping value stored alongside the index.
+    pub fn meta(&self, key: &str) -> Result<Option<String>, IndexDbError> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |r| r.get(0))
+            .optional()?)
+    }
+
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<(), IndexDbError> {
+        set_meta(&self.conn, key, value)
+    }
+
+    pub fn collections(&self) -> Result<Vec<String>, IndexDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT collection FROM documents ORDER BY collection")?;
+        let rows = stmt.query_map([], |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn count(&self, collection: &str) -> Result<usize, IndexDbError> {
+        let n: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE collection = ?1",
+            [collection],
+            |r| r.get(0),
+        )?;
+        Ok(n as usize)
+    }
+
+    /// Declared index fields of `collection`.
+    pub fn indexes(&self, collection: &str) -> Result<Vec<String>, IndexDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT field FROM indexes WHERE collection = ?1 ORDER BY field")?;
+        let rows = stmt.query_map([collection], |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Make sure `fields` are indexed for `collection`, backfilling entries for new ones.
+    pub fn ensure_indexes(&mut self, collection: &str, fields: &[String]) -> Result<(), IndexDbError> {
+        let existing = self.indexes(collection)?;
+        if fields.iter().all(|f| existing.contains(f)) {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        for field in fields.iter().filter(|f| !existing.contains(f)) {
+            create_index(&tx, collection, field)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Documents of `collection` matching `filter` (all documents when `None`), in insertion order.
+    pub fn find(&self, collection: &str, filter: Option<&Filter>) -> Result<Vec<Value>, IndexDbError> {
+        Ok(self
+            .find_rows(collection, filter)?
+            .into_iter()
+            .map(|(_, doc)| doc)
+            .collect())
+    }
+
+    /// [`find`](Self::find) without collecting: each match goes to `visit` as the cursor reads it,
+    /// until `visit` returns false.
+    pub fn scan(
+        &self,
+        collection: &str,
+        filter: Option<&Filter>,
+        mut visit: impl FnMut(Value) -> bool,
+    ) -> Result<(), IndexDbError> {
+        scan_rows(&self.conn, collection, filter, None, |_, doc| visit(doc))
+    }
+
+    /// The first `limit` matches of [`find`](Self::find). Without a filter the limit is part of
+    /// the SQL; with one the cursor stops at the `limit`-th document passing it.
+    pub fn find_limited(
+        &self,
+        collection: &str,
+        filter: Option<&Filter>,
+        limit: usize,
+    ) -> Result<Vec<Value>, IndexDbError> {
+        let mut out = Vec::new();
+        if limit > 0 {
+            scan_rows(&self.conn, collection, filter, Some(limit), |_, doc| {
+                out.push(doc);
+                out.len() < limit
+            })?;
+        }
+        Ok(out)
+    }
+
+    fn find_rows(&self, collection: &str, filter: Option<&Filter>) -> Result<Vec<(i64, Value)>, IndexDbError> {
+        find_rows(&self.conn, collection, filter)
+    }
+
+    /// Fields of `collection` feeding the full-text index, in declared order.
+    pub fn search_fields(&self, collection: &str) -> Result<Vec<String>, IndexDbError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT field FROM search_fields WHERE collection = ?1 ORDER BY position")?;
+        let rows = stmt.query_map([collection], |r| r.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Make `fields` the searchable fields of `collection`, rebuilding its text index on change.
+    pub fn ensure_search_fields(&mut self, collection: &str, fields: &[String]) -> Result<(), IndexDbError> {
+        let mut wanted: Vec<String> = Vec::with_capacity(fields.len());
+        for f in fields {
+            if !wanted.contains(f) {
+                wanted.push(f.clone());
+            }
+        }
+        if self.search_fields(collection)? == wanted {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM search_fields WHERE collection = ?1", [collection])?;
+        for (position, field) in wanted.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO search_fields (collection, field, position) VALUES (?1, ?2, ?3)",
+                params![collection, field, position as i64],
+            )?;
+        }
+        tx.execute("DELETE FROM search_index WHERE collection = ?1", [collection])?;
+        let docs = {
+            let mut stmt = tx.prepare("SELECT id, doc FROM documents WHERE collection = ?1")?;
+            let rows = stmt.query_map([collection], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for (id, raw) in docs {
+            let doc: Value = serde_json::from_str(&raw)?;
+            index_text(&tx, collection, id, &doc)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Full-text search over `collection`, best match first. Each hit carries [`SCORE_FIELD`] and
+    /// [`SNIPPET_FIELD`].
+    pub fn search(&self, collection: &str, text: &str) -> Result<Vec<Value>, IndexDbError> {
+        if self.search_fields(collection)?.is_empty() {
+            return Err(IndexDbError::NotSearchable(collection.to_string()));
+        }
+        let Some(expr) = fts_query(text) else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self.conn.prepare(
+            "SELECT d.doc, -bm25(search_index), snippet(search_index, 1, '<mark>', '</mark>', '…', 16)
+             FROM search_index JOIN documents d ON d.id = search_index.rowid
+             WHERE search_index MATCH ?1 AND search_index.collection = ?2
+             ORDER BY bm25(search_index)",
+        )?;
+        let rows = stmt.query_map(params![expr, collection], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, f64>(1)?, r.get::<_, String>(2)?))
+        })?;
+        let mut out = Vec::new();
+        for row in rows {
+            let (raw, score, snippet) = row?;
+            let mut doc: Value = serde_json::from_str(&raw)?;
+            if let Some(obj) = doc.as_object_mut() {
+                obj.insert(SCORE_FIELD.to_string(), score.into());
+                obj.insert(SNIPPET_FIELD.to_string(), Value::String(snippet));
+            }
+            out.push(doc);
+        }
+        Ok(out)
+    }
+
+    /// Apply a hook journal atomically; `head` becomes the new `indexed_head` in the same transaction.
+    pub fn apply(&mut self, ops: &[DbOp], head: Option<&str>) -> Result<usize, IndexDbError> {
+        let tx = self.conn.transaction()?;
+        let changed = apply_ops(&tx, ops)?;
+        if let Some(head) = head {
+            set_meta(&tx, "indexed_head", head)?;
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// `find` as it would answer once `pending` is applied; the ops are rolled back afterwards.
+    pub fn find_pending(
+        &mut self,
+        pending: &[DbOp],
+        collection: &str,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<Value>, IndexDbError> {
+        let tx = self.conn.transaction()?;
+        apply_ops(&tx, pending)?;
+        let docs = find_rows(&tx, collection, filter)?.into_iter().map(|(_, doc)| doc).collect();
+        tx.rollback()?;
+        Ok(docs)
+    }
+
+    /// Replace the store's contents with a legacy `index.db.json` and rename the file.
+    fn import_legacy(&mut self, legacy: &Path) -> Result<(), IndexDbError> {
+        let raw = std::fs::read_to_string(legacy)?;
+        let db: Value = match serde_json::from_str(&raw) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!(path = %legacy.display(), error = %e, "unreadable legacy index; discarding");
+                std::fs::rename(legacy, legacy.with_extension("json.corrupt"))?;
+                return Ok(());
+            }
+        };
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM index_entries", [])?;
+        tx.execute("DELETE FROM search_index", [])?;
+        tx.execute("DELETE FROM documents", [])?;
+        let mut imported = 0;
+        if let Some(collections) = db.get("collections").and_then(|c| c.as_object()) {
+            for (name, docs) in collections {
+                for doc in docs.as_array().into_iter().flatten() {
+                    insert_doc(&tx, name, doc)?;
+                    imported += 1;
+                }
+            }
+        }
+        match db.pointer("/metadata/indexed_head").and_then(|h| h.as_str()) {
+            Some(head) => set_meta(&tx, "indexed_head", head)?,
+            None => {
+                tx.execute("DELETE FROM meta WHERE key = 'indexed_head'", [])?;
+            }
+        }
+        tx.commit()?;
+        std::fs::rename(legacy, legacy.with_extension("json.migrated"))?;
+        info!(path = %legacy.display(), documents = imported, "migrated legacy JSON index");
+        Ok(())
+    }
+}
+
+/// Read a hook journal: one JSON op per line; a missing file means no writes.
+pub fn read_journal(path: &Path) -> Result<Vec<DbOp>, IndexDbError> {
+    let raw = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    raw.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| serde_json::from_str(l).map_err(IndexDbError::from))
+        .collect()
+}
+
+/// Delete the least recently used snapshots of `repo_path` beyond `keep`; returns how many went.
+/// Use is tracked by the modification time of each snapshot's database.
+pub fn prune_snapshots(repo_path: &Path, keep: usize) -> usize {
+    let mut snapshots: Vec<(std::time::SystemTime, PathBuf)> = std::fs::read_dir(snapshots_dir(repo_path))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| {
+            let used = std::fs::metadata(e.path().join(DB_FILE))
+                .and_then(|m| m.modified())
+                .unwrap_or(std::time::UNIX_EPOCH);
+            (used, e.path())
+        })
+        .collect();
+    if snapshots.len() <= keep {
+        return 0;
+    }
+    snapshots.sort();
+    let excess = snapshots.len() - keep;
+    snapshots
+        .into_iter()
+        .take(excess)
+        .filter(|(_, dir)| match std::fs::remove_dir_all(dir) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(path = %dir.display(), error = %e, "failed to prune index snapshot");
+                false
+            }
+        })
+        .count()
+}
+
+/// Mark the snapshot of `commit` as just used, so pruning keeps it.
+pub fn touch_snapshot(repo_path: &Path, commit: &str) {
+    let path = db_path(repo_path, &snapshot_branch(commit));
+    if let Ok(f) = std::fs::File::options().append(true).open(path) {
+        let _ = f.set_modified(std::time::SystemTime::now());
+    }
+}
+
+/// Move legacy branch directories of every bare repo under the repo root to their sha256 names,
+/// then import each legacy `index.db.json` found under a migrated name. Directories still under
+/// a legacy name (shared or orphaned) are left untouched for `gc`.
+pub fn migrate_all(repo_root: &Path) -> usize {
+    let mut migrated = 0;
+    for repo in std::fs::read_dir(repo_root).into_iter().flatten().flatten() {
+        let repo_path = repo.path();
+        if let Ok(git) = git2::Repository::open_bare(&repo_path) {
+            let branches = crate::git::list_branches(&git);
+            if let Err(e) = migrate_legacy_dirs(&repo_path, &branches) {
+                warn!(repo = %repo_path.display(), error = %e, "legacy index directory migration failed");
+            }
+        }
+        let branches = repo_path.join(".relay_data").join("branches");
+        for dir in std::fs::read_dir(&branches).into_iter().flatten().flatten() {
+            let dir = dir.path();
+            let hashed = dir.file_name().is_some_and(|n| n.len() == 64);
+            if !hashed || !dir.join(LEGACY_DB_FILE).exists() {
+                continue;
+            }
+            match BranchIndex::open_path(&dir.join(DB_FILE)) {
+                Ok(_) => migrated += 1,
+                Err(e) => warn!(path = %dir.display(), error = %e, "legacy index migration failed"),
+            }
+        }
+    }
+    migrated
+}
+
+/// Apply journal ops inside the caller's transaction; returns how many documents they touched.
+fn apply_ops(tx: &Connection, ops: &[DbOp]) -> Result<usize, IndexDbError> {
+    let mut changed = 0;
+    for op in ops {
+        changed += match op {
+            DbOp::Insert { collection, doc } => {
+                insert_doc(tx, collection, doc)?;
+                1
+            }
+            DbOp::Update { collection, query, update } => {
+                let filter = Filter::parse(query)?;
+                let rows = find_rows(tx, collection, Some(&filter))?;
+                for (id, mut doc) in rows.iter().cloned() {
+                    if let Some(obj) = doc.as_object_mut() {
+                        for (k, v) in update {
+                            obj.insert(k.clone(), v.clone());
+                        }
+                    }
+                    tx.execute(
+                        "UPDATE documents SET doc = ?2 WHERE id = ?1",
+                        params![id, serde_json::to_string(&doc)?],
+                    )?;
+                    index_doc(tx, collection, id, &doc)?;
+                }
+                rows.len()
+            }
+            DbOp::Remove { collection, query } => {
+                let filter = Filter::parse(query)?;
+                let rows = find_rows(tx, collection, Some(&filter))?;
+                for (id, _) in &rows {
+                    tx.execute("DELETE FROM index_entries WHERE doc_id = ?1", [id])?;
+                    tx.execute("DELETE FROM search_index WHERE rowid = ?1", [id])?;
+                    tx.execute("DELETE FROM documents WHERE id = ?1", [id])?;
+                }
+                rows.len()
+            }
+            DbOp::CreateIndex { collection, field } => {
+                create_index(tx, collection, field)?;
+                0
+            }
+        };
+    }
+    Ok(changed)
+}
+
+fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<(), IndexDbError> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+fn insert_doc(conn: &Connection, collection: &str, doc: &Value) -> Result<i64, IndexDbError> {
+    conn.execute(
+        "INSERT INTO documents (collection, doc) VALUES (?1, ?2)",
+        params![collection, serde_json::to_string(doc)?],
+    )?;
+    let id = conn.last_insert_rowid();
+    index_doc(conn, collection, id, doc)?;
+    Ok(id)
+}
+
+fn create_index(conn: &Connection, collection: &str, field: &str) -> Result<(), IndexDbError> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO indexes (collection, field) VALUES (?1, ?2)",
+        params![collection, field],
+    )?;
+    if added == 0 {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare("SELECT id, doc FROM documents WHERE collection = ?1")?;
+    let rows = stmt.query_map([collection], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (id, raw) = row?;
+        let doc: Value = serde_json::from_str(&raw)?;
+        insert_entries(conn, collection, field, id, &doc)?;
+    }
+    Ok(())
+}
+
+/// Rewrite all index entries of one document.
+fn index_doc(conn: &Connection, collection: &str, id: i64, doc: &Value) -> Result<(), IndexDbError> {
+    conn.execute("DELETE FROM index_entries WHERE doc_id = ?1", [id])?;
+    let mut stmt = conn.prepare_cached("SELECT field FROM indexes WHERE collection = ?1")?;
+    let fields: Vec<String> = stmt
+        .query_map([collection], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    for field in fields {
+        insert_entries(conn, collection, &field, id, doc)?;
+    }
+    index_text(conn, collection, id, doc)
+}
+
+/// Rewrite the full-text row of one document from its collection's search fields.
+fn index_text(conn: &Connection, collection: &str, id: i64, doc: &Value) -> Result<(), IndexDbError> {
+    conn.execute("DELETE FROM search_index WHERE rowid = ?1", [id])?;
+    let mut stmt = conn.prepare_cached("SELECT field FROM search_fields WHERE collection = ?1 ORDER BY position")?;
+    let fields: Vec<String> = stmt
+        .query_map([collection], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut parts = Vec::new();
+    for field in &fields {
+        for v in path_values(doc, field) {
+            match v {
+                Value::Array(items) => parts.extend(items.iter().filter_map(text_of)),
+                other => parts.extend(text_of(other)),
+            }
+        }
+    }
+    if !parts.is_empty() {
+        conn.execute(
+            "INSERT INTO search_index (rowid, collection, body) VALUES (?1, ?2, ?3)",
+            params![id, collection, parts.join("\n")],
+        )?;
+    }
+    Ok(())
+}
+
+fn text_of(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Turn free text into an FTS5 expression: every word must match (stemmed); a trailing `*` makes
+/// a word a prefix. Punctuation separates words, so FTS operators and quotes in the input are plain text.
+fn fts_query(text: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    for word in text.split_whitespace() {
+        let tokens: Vec<&str> = word.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()).collect();
+        for (i, token) in tokens.iter().enumerate() {
+            let prefix = i + 1 == tokens.len() && word.ends_with('*');
+            terms.push(format!("\"{}\"{}", token, if prefix { "*" } else { "" }));
+        }
+    }
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+fn insert_entries(conn: &Connection, collection: &str, field: &str, id: i64, doc: &Value) -> Result<(), IndexDbError> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO index_entries (collection, field, value, doc_id) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for v in path_values(doc, field) {
+        let elems: Vec<&Value> = match v {
+            Value::Array(items) => items.iter().collect(),
+            other => vec![other],
+        };
+        for e in elems {
+            if let Some(sql) = to_sql(e) {
+                stmt.execute(params![collection, field, sql, id])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Scalar JSON → SQLite value used in `index_entries` (booleans are stored as 0/1).
+fn to_sql(v: &Value) -> Option<rusqlite::types::Value> {
+    use rusqlite::types::Value as Sql;
+    match v {
+        Value::Number(n) => n
+            .as_i64()
+            .map(Sql::Integer)
+            .or_else(|| n.as_f64().map(Sql::Real)),
+        Value::String(s) => Some(Sql::Text(s.clone())),
+        Value::Bool(b) => Some(Sql::Integer(*b as i64)),
+        _ => None,
+    }
+}
+
+fn find_rows(
+    conn: &Connection,
+    collection: &str,
+    filter: Option<&Filter>,
+) -> Result<Vec<(i64, Value)>, IndexDbError> {
+    let mut out = Vec::new();
+    scan_rows(conn, collection, filter, None, |id, doc| {
+        out.push((id, doc));
+        true
+    })?;
+    Ok(out)
+}
+
+/// Feed the matching documents to `visit` in id order while the SQLite cursor advances; stops
+/// when `visit` returns false. `limit` caps the rows read when no filter is left to apply to them.
+fn scan_rows(
+    conn: &Connection,
+    collection: &str,
+    filter: Option<&Filter>,
+    limit: Option<usize>,
+    mut visit: impl FnMut(i64, Value) -> bool,
+) -> Result<(), IndexDbError> {
+    let mut sql = String::from("SELECT id, doc FROM documents WHERE collection = ?1");
+    let mut args: Vec<rusqlite::types::Value> = vec![rusqlite::types::Value::Text(collection.to_string())];
+    if let Some(filter) = filter {
+        let indexed: Vec<String> = {
+            let mut stmt = conn.prepare_cached("SELECT field FROM indexes WHERE collection = ?1")?;
+            let rows = stmt.query_map([collection], |r| r.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        push_down(filter, &indexed, &mut sql, &mut args);
+    }
+    sql.push_str(" ORDER BY id");
+    if let (None, Some(limit)) = (filter, limit) {
+        sql.push_str(" LIMIT ?");
+        args.push(rusqlite::types::Value::Integer(i64::try_from(limit).unwrap_or(i64::MAX)));
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(args.iter()), |r| {
+        Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?))
+    })?;
+    for row in rows {
+        let (id, raw) = row?;
+        let doc: Value = serde_json::from_str(&raw)?;
+        if filter.is_none_or(|f| f.matches(&doc)) && !visit(id, doc) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Narrow the scan with index lookups for top-level conditions on indexed fields.
+/// Every clause is a superset of the exact match, which is still applied afterwards.
+fn push_down(filter: &Filter, indexed: &[String], sql: &mut String, args: &mut Vec<rusqlite::types::Value>) {
+    let parts: Vec<&Filter> = match filter {
+        Filter::And(parts) => parts.iter().collect(),
+        other => vec![other],
+    };
+    for part in parts {
+        let Filter::Field { path, cond } = part else { continue };
+        let field = path.join(".");
+        if !indexed.contains(&field) {
+            continue;
+        }
+        let conds: Vec<&Cond> = match cond {
+            Cond::All(cs) => cs.iter().collect(),
+            other => vec![other],
+        };
+        for cond in conds {
+            let clause = match cond {
+                Cond::Eq(v) => to_sql(v).map(|s| ("value = ?".to_string(), vec![s])),
+                Cond::In(vs) => {
+                    let sqls: Option<Vec<_>> = vs.iter().map(to_sql).collect();
+                    sqls.filter(|s| !s.is_empty()).map(|s| {
+                        let marks = vec!["?"; s.len()].join(", ");
+                        (format!("value IN ({})", marks), s)
+                    })
+                }
+                Cond::Cmp(ord, inclusive, v @ (Value::Number(_) | Value::String(_))) => {
+                    let op = match (ord, inclusive) {
+                        (std::cmp::Ordering::Greater, false) => ">",
+                        (std::cmp::Ordering::Greater, true) => ">=",
+                        (std::cmp::Ordering::Less, false) => "<",
+                        _ => "<=",
+                    };
+                    let ty = if v.is_number() { "IN ('integer', 'real')" } else { "= 'text'" };
+                    to_sql(v).map(|s| (format!("value {} ? AND typeof(value) {}", op, ty), vec![s]))
+                }
+                _ => None,
+            };
+            if let Some((pred, vals)) = clause {
+                sql.push_str(" AND id IN (SELECT doc_id FROM index_entries WHERE collection = ?1 AND field = ? AND ");
+                sql.push_str(&pred);
+                sql.push(')');
+                args.push(rusqlite::types::Value::Text(field.clone()));
+                args.extend(vals);
+            }
+        }
+    }
+}
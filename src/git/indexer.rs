@@ -0,0 +1,249 @@
+//! Native indexer: turns structured files in the tree (`meta.yaml` by default) into documents of
+//! the branch index without a Node hook.
+//!
+//! Sources are declared in `.relay.yaml` as `server.db.sources: [{ glob, collection }]`. Each
+//! matching file (YAML, or JSON for `*.json`) becomes one document carrying [`PATH_FIELD`],
+//! [`META_DIR_FIELD`] and `_branch`. Runs follow the delta between the last indexed commit and the
+//! new head, so only changed files are read; the whole batch commits in one transaction.
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use git2::{Oid, Repository, Tree};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::git::changes;
+use crate::git::indexdb::{BranchIndex, DbOp, IndexDbError};
+use crate::git::schema::{quarantine_doc, SchemaSet, QUARANTINE_COLLECTION};
+use crate::types::IndexSource;
+
+/// Repo-relative path of the file a document was built from.
+pub const PATH_FIELD: &str = "_path";
+/// Directory of that file (`.` at the root), as written by the former JS `upsertIndex`.
+pub const META_DIR_FIELD: &str = "_meta_dir";
+/// `meta` key holding the sources the index was built with; a change forces a rebuild.
+const SOURCES_META_KEY: &str = "indexer_sources";
+/// `meta` key holding the [`SchemaSet::fingerprint`] the index was validated with.
+const SCHEMAS_META_KEY: &str = "indexer_schemas";
+
+#[derive(Debug, Error)]
+pub enum IndexerError {
+    #[error("invalid glob in server.db.sources: {0}")]
+    Glob(#[from] globset::Error),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    Db(#[from] IndexDbError),
+    #[error(transparent)]
+    Schema(#[from] crate::git::schema::SchemaError),
+}
+
+/// Sources used when `.relay.yaml` declares none: every `meta.yaml`/`meta.yml` into `index`.
+pub fn default_sources() -> Vec<IndexSource> {
+    ["**/meta.yaml", "**/meta.yml"]
+        .into_iter()
+        .map(|glob| IndexSource {
+            glob: glob.to_string(),
+            collection: "index".to_string(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IndexRun {
+    /// True when the index was rebuilt from the full tree rather than a delta.
+    pub full: bool,
+    /// Matching files read (added or modified).
+    pub indexed: usize,
+    /// Matching files removed from the index.
+    pub removed: usize,
+    /// Documents that failed their schema and went to the quarantine collection.
+    pub quarantined: usize,
+}
+
+struct Sources {
+    set: GlobSet,
+    /// Collection of each glob, by glob index.
+    collections: Vec<String>,
+}
+
+impl Sources {
+    fn compile(sources: &[IndexSource]) -> Result<Sources, IndexerError> {
+        let mut builder = GlobSetBuilder::new();
+        for s in sources {
+            builder.add(Glob::new(&s.glob)?);
+        }
+        Ok(Sources {
+            set: builder.build()?,
+            collections: sources.iter().map(|s| s.collection.clone()).collect(),
+        })
+    }
+
+    fn collections_for(&self, path: &str) -> Vec<&str> {
+        let mut out: Vec<&str> = self
+            .set
+            .matches(path)
+            .into_iter()
+            .map(|i| self.collections[i].as_str())
+            .collect();
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+/// Bring the index of `branch` up to `new_commit` from the files matching `sources`. Documents
+/// violating `schemas` are quarantined instead of indexed.
+pub fn index_commit(
+    repo: &Repository,
+    repo_path: &Path,
+    branch: &str,
+    new_commit: &str,
+    sources: &[IndexSource],
+    schemas: &SchemaSet,
+) -> Result<IndexRun, IndexerError> {
+    let compiled = Sources::compile(sources)?;
+    let new_tree = repo.revparse_single(new_commit)?.peel_to_commit()?.tree()?;
+    let mut index = BranchIndex::open(repo_path, branch)?;
+
+    let sources_json = serde_json::to_string(sources).unwrap_or_default();
+    let previous_sources = index.meta(SOURCES_META_KEY)?;
+    let same_schemas = index.meta(SCHEMAS_META_KEY)?.unwrap_or_default() == schemas.fingerprint();
+    let old_tree: Option<Tree> = match previous_sources.as_deref() {
+        Some(prev) if prev == sources_json && same_schemas => index
+            .indexed_head()?
+            .and_then(|h| Oid::from_str(&h).ok())
+            .and_then(|oid| repo.find_commit(oid).ok())
+            .and_then(|c| c.tree().ok()),
+        _ => None,
+    };
+    let full = old_tree.is_none();
+    let previous: Vec<IndexSource> = previous_sources
+        .and_then(|p| serde_json::from_str(&p).ok())
+        .unwrap_or_default();
+
+    let mut ops = Vec::new();
+    let mut touched: Vec<&str> = sources.iter().map(|s| s.collection.as_str()).collect();
+    touched.push(QUARANTINE_COLLECTION);
+    if full {
+        // Drop every file-backed document, including collections of sources no longer declared.
+        touched.extend(previous.iter().map(|s| s.collection.as_str()));
+        touched.sort();
+        touched.dedup();
+        for collection in &touched {
+            ops.push(DbOp::Remove {
+                collection: collection.to_string(),
+                query: json!({ PATH_FIELD: { "$exists": true } }),
+            });
+        }
+    }
+    for collection in &touched {
+        index.ensure_indexes(collection, &[PATH_FIELD.to_string()])?;
+    }
+
+    let mut run = IndexRun { full, ..Default::default() };
+    let diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None)?;
+    let paths: BTreeSet<String> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter_map(|p| p.to_str().map(|s| s.to_string()))
+        .collect();
+    for path in &paths {
+        let collections = compiled.collections_for(path);
+        if collections.is_empty() {
+            continue;
+        }
+        let doc = new_tree
+            .get_path(Path::new(path))
+            .ok()
+            .and_then(|entry| entry.to_object(repo).ok())
+            .and_then(|obj| obj.into_blob().ok())
+            .and_then(|blob| parse_document(path, blob.content()));
+        if !full {
+            ops.push(DbOp::Remove {
+                collection: QUARANTINE_COLLECTION.to_string(),
+                query: json!({ PATH_FIELD: path }),
+            });
+        }
+        for collection in collections {
+            if !full {
+                ops.push(DbOp::Remove {
+                    collection: collection.to_string(),
+                    query: json!({ PATH_FIELD: path }),
+                });
+            }
+            match &doc {
+                Some(raw) => {
+                    let mut violations = schemas.check_path(path, raw);
+                    violations.extend(schemas.check_document(collection, Some(path), raw));
+                    let doc = decorate(path, branch, raw.clone());
+                    if violations.is_empty() {
+                        ops.push(DbOp::Insert {
+                            collection: collection.to_string(),
+                            doc,
+                        });
+                        run.indexed += 1;
+                    } else {
+                        warn!(path, collection, violations = violations.len(), "document failed its schema; quarantined");
+                        ops.push(DbOp::Insert {
+                            collection: QUARANTINE_COLLECTION.to_string(),
+                            doc: quarantine_doc(collection, doc, &violations),
+                        });
+                        run.quarantined += 1;
+                    }
+                }
+                None if !full => run.removed += 1,
+                None => {}
+            }
+        }
+    }
+
+    index.apply(&ops, Some(new_commit))?;
+    index.set_meta(SOURCES_META_KEY, &sources_json)?;
+    index.set_meta(SCHEMAS_META_KEY, schemas.fingerprint())?;
+    changes::publish_ops(repo_path, branch, new_commit, &ops);
+    debug!(branch, new_commit, ?run, "native index run complete");
+    Ok(run)
+}
+
+/// Parse a data file: JSON for `*.json`, YAML otherwise.
+pub fn parse_file(path: &str, content: &[u8]) -> Result<Value, String> {
+    if path.ends_with(".json") {
+        serde_json::from_slice(content).map_err(|e| e.to_string())
+    } else {
+        serde_yaml::from_slice(content).map_err(|e| e.to_string())
+    }
+}
+
+/// Parse one source file into a document; `None` (with a warning) when it is not a mapping.
+fn parse_document(path: &str, content: &[u8]) -> Option<Value> {
+    match parse_file(path, content) {
+        Ok(doc @ Value::Object(_)) => Some(doc),
+        Ok(_) => {
+            warn!(path, "indexed file is not a mapping; skipping");
+            None
+        }
+        Err(e) => {
+            warn!(path, error = %e, "failed to parse indexed file; skipping");
+            None
+        }
+    }
+}
+
+/// Add the file-backed fields ([`PATH_FIELD`], [`META_DIR_FIELD`], `_branch`) to a parsed document.
+fn decorate(path: &str, branch: &str, mut doc: Value) -> Value {
+    let meta_dir = Path::new(path)
+        .parent()
+        .and_then(|p| p.to_str())
+        .filter(|p| !p.is_empty())
+        .unwrap_or(".");
+    if let Value::Object(map) = &mut doc {
+        map.insert(PATH_FIELD.to_string(), Value::String(path.to_string()));
+        map.insert(META_DIR_FIELD.to_string(), Value::String(meta_dir.to_string()));
+        map.insert("_branch".to_string(), Value::String(branch.to_string()));
+    }
+    doc
+}
@@ -1,69 +1,273 @@
-use std::path::PathBuf;
-use tracing::{info, debug};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tracing::{info, debug, warn};
 use crate::git::hooks::{execute_repo_hook, HookContext};
-use std::sync::{Mutex, OnceLock};
-use std::collections::HashSet;
+use crate::git::indexdb::BranchIndex;
+use crate::git::indexer;
+use crate::git::schema::{self, SchemaSet};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
 
-static ONGOING_INDEXING: OnceLock<Mutex<HashSet<(PathBuf, String)>>> = OnceLock::new();
+type FlightKey = (PathBuf, String);
 
-fn get_indexing_lock() -> &'static Mutex<HashSet<(PathBuf, String)>> {
-    ONGOING_INDEXING.get_or_init(|| Mutex::new(HashSet::new()))
+/// One spelling of the bare repo at `repo_path` for keying runs: the relative
+/// `data/<name>.git` of the handlers and git2's absolute `repo.path()` (with its trailing
+/// slash) name the same repo.
+pub fn repo_key(repo_path: &Path) -> PathBuf {
+    std::fs::canonicalize(repo_path).unwrap_or_else(|_| repo_path.components().collect())
 }
 
+/// One indexing run for a (repo, branch); concurrent callers wait for its outcome.
+#[derive(Default)]
+struct Flight {
+    outcome: Mutex<Option<Result<(), String>>>,
+    done: Condvar,
+}
+
+static IN_FLIGHT: OnceLock<Mutex<HashMap<FlightKey, Arc<Flight>>>> = OnceLock::new();
+
+fn in_flight() -> &'static Mutex<HashMap<FlightKey, Arc<Flight>>> {
+    IN_FLIGHT.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Publishes the leader's outcome and clears the slot, also when the indexer panics.
+struct FlightGuard {
+    key: FlightKey,
+    flight: Arc<Flight>,
+    outcome: Option<Result<(), String>>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        in_flight().lock().unwrap_or_else(|e| e.into_inner()).remove(&self.key);
+        let outcome = self
+            .outcome
+            .take()
+            .unwrap_or_else(|| Err("indexing run panicked".to_string()));
+        *self.flight.outcome.lock().unwrap_or_else(|e| e.into_inner()) = Some(outcome);
+        self.flight.done.notify_all();
+    }
+}
+
+/// Meta key of the [`LastRun`] record of an index.
+pub const LAST_RUN_META_KEY: &str = "last_run";
+
+/// Outcome of the latest indexing run of a branch, kept in the index so it survives restarts
+/// and is visible to `relay-server index status`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LastRun {
+    pub branch: String,
+    /// Head the run indexed to.
+    pub head: String,
+    /// Unix seconds when the run finished.
+    pub at: u64,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LastRun {
+    pub fn read(index: &BranchIndex) -> Option<LastRun> {
+        let raw = index.meta(LAST_RUN_META_KEY).ok()??;
+        serde_json::from_str(&raw).ok()
+    }
+
+    fn record(ctx: &HookContext, elapsed: Duration, result: &anyhow::Result<()>) {
+        let run = LastRun {
+            branch: ctx.branch.clone(),
+            head: ctx.new_commit.clone(),
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            duration_ms: elapsed.as_millis() as u64,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        let stored = BranchIndex::open(&ctx.repo_path, &ctx.branch).and_then(|mut index| {
+            index.set_meta(LAST_RUN_META_KEY, &serde_json::to_string(&run).unwrap_or_default())
+        });
+        if let Err(e) = stored {
+            warn!(branch = %ctx.branch, error = %e, "cannot record indexing run");
+        }
+    }
+}
+
+fn indexed_head(ctx: &HookContext) -> anyhow::Result<String> {
+    Ok(BranchIndex::open_existing(&ctx.repo_path, &ctx.branch)?
+        .map(|index| index.indexed_head())
+        .transpose()?
+        .flatten()
+        .unwrap_or_default())
+}
+
+/// Bring the branch index up to `ctx.new_commit`. Only one run per (repo, branch) executes at a
+/// time; callers arriving meanwhile block until it finishes and share its result, then re-check
+/// (the run may have indexed an older head). The writes of the leader's run reach the change feed.
+/// Blocking: call from `spawn_blocking` in async code.
 pub fn ensure_indexed(ctx: &HookContext) -> anyhow::Result<()> {
-    let branch_bytes = if ctx.branch.is_empty() { "main".as_bytes() } else { ctx.branch.as_bytes() };
-    let branch_hash = hex::encode(branch_bytes);
-    let branch_hash_short = if branch_hash.len() > 12 { &branch_hash[..12] } else { &branch_hash };
-    
-    let relay_data_path = ctx.repo_path.join(".relay_data");
-    let db_path = relay_data_path.join("branches").join(branch_hash_short).join("index.db.json");
-    
-    let mut indexed_head = String::new();
-    if db_path.exists() {
-        if let Ok(content) = std::fs::read_to_string(&db_path) {
-            if let Ok(db) = serde_json::from_str::<serde_json::Value>(&content) {
-                if let Some(meta) = db.get("metadata") {
-                    if let Some(head) = meta.get("indexed_head") {
-                        indexed_head = head.as_str().unwrap_or("").to_string();
-                    }
+    let key: FlightKey = (repo_key(&ctx.repo_path), ctx.branch.clone());
+    loop {
+        let current = indexed_head(ctx)?;
+        if current == ctx.new_commit {
+            debug!("Branch {} is up to date (head: {})", ctx.branch, ctx.new_commit);
+            return Ok(());
+        }
+
+        let (flight, leader) = {
+            let mut map = in_flight().lock().unwrap_or_else(|e| e.into_inner());
+            match map.get(&key) {
+                Some(f) => (f.clone(), false),
+                None => {
+                    let f = Arc::new(Flight::default());
+                    map.insert(key.clone(), f.clone());
+                    (f, true)
                 }
             }
+        };
+
+        if leader {
+            let mut guard = FlightGuard { key, flight, outcome: None };
+            info!("Branch {} is stale ({} != {}). Running JIT indexing...", ctx.branch, current, ctx.new_commit);
+            let result = index_now(ctx);
+            guard.outcome = Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+            drop(guard);
+            debug!("JIT indexing completed for branch {}", ctx.branch);
+            return result;
         }
+
+        debug!("JIT indexing already in progress for branch {} in repo {:?}; waiting", ctx.branch, ctx.repo_path);
+        if let Err(e) = wait_for(&flight) {
+            anyhow::bail!("concurrent indexing of branch {} failed: {}", ctx.branch, e);
+        }
+    }
+}
+
+/// One indexing run to `ctx.new_commit`, recorded as the branch's last run. Each step publishes
+/// the writes it commits to the change feed. The caller must hold the branch's flight.
+pub(crate) fn index_now(ctx: &HookContext) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let result = run_indexer(ctx);
+    LastRun::record(ctx, started.elapsed(), &result);
+    result
+}
+
+fn wait_for(flight: &Flight) -> Result<(), String> {
+    let mut outcome = flight.outcome.lock().unwrap_or_else(|e| e.into_inner());
+    while outcome.is_none() {
+        outcome = flight.done.wait(outcome).unwrap_or_else(|e| e.into_inner());
     }
-    
-    if indexed_head != ctx.new_commit {
-        let lock_key = (ctx.repo_path.clone(), ctx.branch.clone());
-        
-        {
-            let mut ongoing = get_indexing_lock().lock().unwrap();
-            if ongoing.contains(&lock_key) {
-                debug!("JIT indexing already in progress for branch {} in repo {:?}", ctx.branch, ctx.repo_path);
-                // Drop lock and wait a bit or just return?
-                // Ideally we wait for the other one to finish, but for simplicity we can just return 
-                // and the query caller will try to read a potentially partially written file or wait.
-                // But better to wait here.
-            } else {
-                ongoing.insert(lock_key.clone());
+    outcome.clone().unwrap_or(Ok(()))
+}
+
+/// Run `f` (dropping or rebuilding the branch index) as the branch's flight, once any indexing
+/// run in progress has finished. [`ensure_indexed`] callers arriving meanwhile wait for `f` and
+/// then re-check the index, so they never read a half-removed store.
+pub fn exclusive<T, E: std::fmt::Display>(
+    repo_path: &Path,
+    branch: &str,
+    f: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    let key: FlightKey = (repo_key(repo_path), branch.to_string());
+    let mut guard = loop {
+        let running = {
+            let mut map = in_flight().lock().unwrap_or_else(|e| e.into_inner());
+            match map.get(&key) {
+                Some(f) => f.clone(),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    map.insert(key.clone(), flight.clone());
+                    break FlightGuard { key, flight, outcome: None };
+                }
             }
+        };
+        let _ = wait_for(&running);
+    };
+    let result = f();
+    guard.outcome = Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+    result
+}
+
+/// Work needed to move a branch index from its `indexed_head` to the branch head.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexPlan {
+    UpToDate,
+    /// Rebuild from the whole tree at head: nothing indexed yet, or the old head is gone or no
+    /// longer an ancestor (force-push).
+    Full,
+    /// Replay these commits oldest first (first-parent history of head since the old head).
+    Commits(Vec<git2::Oid>),
+}
+
+pub fn plan_range(repo: &git2::Repository, indexed_head: &str, head: &str) -> anyhow::Result<IndexPlan> {
+    let head = git2::Oid::from_str(head)?;
+    let Some(old) = git2::Oid::from_str(indexed_head)
+        .ok()
+        .filter(|oid| repo.find_commit(*oid).is_ok())
+    else {
+        return Ok(IndexPlan::Full);
+    };
+    if old == head {
+        return Ok(IndexPlan::UpToDate);
+    }
+    if !repo.graph_descendant_of(head, old)? {
+        return Ok(IndexPlan::Full);
+    }
+    let mut walk = repo.revwalk()?;
+    walk.push(head)?;
+    walk.hide(old)?;
+    walk.simplify_first_parent()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    Ok(IndexPlan::Commits(walk.collect::<Result<_, _>>()?))
+}
+
+/// Index with the repo's `index` hook when `.relay.yaml` configures one, otherwise natively
+/// from `server.db.sources`. Ranges are replayed commit by commit; each step commits its own
+/// transaction and advances `indexed_head`, so an interrupted run resumes where it stopped.
+fn run_indexer(ctx: &HookContext) -> anyhow::Result<()> {
+    let repo = git2::Repository::open_bare(&ctx.repo_path)?;
+    let config = crate::git::read_relay_config(&repo, &ctx.new_commit);
+    let server = config.as_ref().and_then(|c| c.server.as_ref());
+    let steps = match plan_range(&repo, &indexed_head(ctx)?, &ctx.new_commit)? {
+        IndexPlan::UpToDate => return Ok(()),
+        IndexPlan::Full => vec![ctx.new_commit.clone()],
+        IndexPlan::Commits(commits) => commits.iter().map(|c| c.to_string()).collect(),
+    };
+    let total = steps.len();
+
+    if server
+        .and_then(|s| s.hooks.as_ref())
+        .is_some_and(|h| h.contains_key("index"))
+    {
+        for (n, commit) in steps.into_iter().enumerate() {
+            // The hook sees the delta from what is actually indexed (empty: full tree replay).
+            let old_commit = match indexed_head(ctx)? {
+                head if plan_range(&repo, &head, &commit)? == IndexPlan::Full => String::new(),
+                head => head,
+            };
+            let step = HookContext {
+                repo_path: ctx.repo_path.clone(),
+                old_commit,
+                new_commit: commit,
+                refname: ctx.refname.clone(),
+                branch: ctx.branch.clone(),
+                is_verified: ctx.is_verified,
+                files: std::collections::HashMap::new(),
+            };
+            if !execute_repo_hook(&step, "index")? {
+                anyhow::bail!("index hook failed at commit {}", step.new_commit);
+            }
+            debug!(branch = %ctx.branch, commit = %step.new_commit, "indexed {}/{}", n + 1, total);
         }
-        
-        // If we want to wait, we need a better primitive than HashSet.
-        // But for "hardening", preventing the parallel execution is the first step.
-        
-        info!("Branch {} is stale ({} != {}). Running JIT indexing...", ctx.branch, indexed_head, ctx.new_commit);
-        
-        let result = execute_repo_hook(ctx, "index");
-        
-        {
-            let mut ongoing = get_indexing_lock().lock().unwrap();
-            ongoing.remove(&lock_key);
-        }
-        
-        result?;
-        debug!("JIT indexing completed for branch {}", ctx.branch);
-    } else {
-        debug!("Branch {} is up to date (head: {})", ctx.branch, ctx.new_commit);
+        return Ok(());
+    }
+
+    let sources = server
+        .and_then(|s| s.db.as_ref())
+        .and_then(|db| db.sources.clone())
+        .unwrap_or_else(indexer::default_sources);
+    let schemas = SchemaSet::for_server(server, schema::tree_loader(&repo, &ctx.new_commit))?;
+    for (n, commit) in steps.iter().enumerate() {
+        let run = indexer::index_commit(&repo, &ctx.repo_path, &ctx.branch, commit, &sources, &schemas)?;
+        debug!(branch = %ctx.branch, %commit, full = run.full, indexed = run.indexed, removed = run.removed, quarantined = run.quarantined, "indexed {}/{}", n + 1, total);
     }
-    
     Ok(())
 }
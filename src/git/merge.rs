@@ -0,0 +1,107 @@
+//! Server-side merge of one revision into a branch (fast-forward or in-memory merge commit).
+use git2::{Index, Repository, Signature};
+use serde::Serialize;
+use tracing::info;
+
+use crate::git::branches::{branch_rule, ensure_server_commit_allowed, ensure_signed_commits, BranchError};
+use crate::git::commit::{apply_commit, changed_files};
+
+/// A path that could not be merged automatically. Blob ids are `None` when that side lacks the path.
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeConflict {
+    pub path: String,
+    pub ancestor: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "camelCase")]
+pub enum MergeOutcome {
+    /// Target already contains the source; nothing changed.
+    UpToDate { commit: String },
+    FastForward { commit: String },
+    Merged { commit: String },
+    /// Merge stopped; refs were not touched.
+    Conflicts { conflicts: Vec<MergeConflict> },
+}
+
+/// Merge `source` (any revision) into the existing branch `target`.
+pub fn merge_into_branch(
+    repo: &Repository,
+    source: &str,
+    target: &str,
+    message: Option<&str>,
+) -> Result<MergeOutcome, BranchError> {
+    let target_ref = repo
+        .find_branch(target, git2::BranchType::Local)
+        .map_err(|_| BranchError::NotFound(target.to_string()))?;
+    let ours = target_ref.get().peel_to_commit()?;
+    let theirs = repo
+        .revparse_single(source)
+        .and_then(|o| o.peel_to_commit())
+        .map_err(|_| BranchError::RevisionNotFound(source.to_string()))?;
+
+    if ours.id() == theirs.id() || repo.graph_descendant_of(ours.id(), theirs.id())? {
+        return Ok(MergeOutcome::UpToDate {
+            commit: ours.id().to_string(),
+        });
+    }
+
+    if repo.graph_descendant_of(theirs.id(), ours.id())? {
+        if let Some(rule) = branch_rule(repo, target) {
+            ensure_signed_commits(repo, &rule, target, Some(ours.id()), theirs.id(), None)?;
+        }
+        let files = changed_files(repo, Some(&ours.tree()?), &theirs.tree()?)
+            .map_err(BranchError::Other)?;
+        let msg = format!("merge {}: Fast-forward", source);
+        apply_commit(repo, target, Some(ours.id()), theirs.id(), files, &msg)?;
+        info!(%source, %target, commit = %theirs.id(), "fast-forward merge");
+        return Ok(MergeOutcome::FastForward {
+            commit: theirs.id().to_string(),
+        });
+    }
+
+    let mut index = repo.merge_commits(&ours, &theirs, None)?;
+    if index.has_conflicts() {
+        return Ok(MergeOutcome::Conflicts {
+            conflicts: collect_conflicts(&index)?,
+        });
+    }
+    ensure_server_commit_allowed(repo, target)?;
+
+    let tree_oid = index.write_tree_to(repo)?;
+    let tree = repo.find_tree(tree_oid)?;
+    let sig = Signature::now("relay", "relay@local")?;
+    let msg = message
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| format!("Merge {} into {}", source, target));
+    let commit_oid = repo.commit(None, &sig, &sig, &msg, &tree, &[&ours, &theirs])?;
+
+    let files = changed_files(repo, Some(&ours.tree()?), &tree).map_err(BranchError::Other)?;
+    apply_commit(repo, target, Some(ours.id()), commit_oid, files, &msg)?;
+    info!(%source, %target, commit = %commit_oid, "merge commit created");
+    Ok(MergeOutcome::Merged {
+        commit: commit_oid.to_string(),
+    })
+}
+
+/// Conflict entries of an in-memory merge/revert index.
+pub(crate) fn collect_conflicts(index: &Index) -> Result<Vec<MergeConflict>, git2::Error> {
+    let mut conflicts = Vec::new();
+    for c in index.conflicts()? {
+        let c = c?;
+        let path = [&c.our, &c.their, &c.ancestor]
+            .iter()
+            .find_map(|e| e.as_ref())
+            .map(|e| String::from_utf8_lossy(&e.path).to_string())
+            .unwrap_or_default();
+        conflicts.push(MergeConflict {
+            path,
+            ancestor: c.ancestor.map(|e| e.id.to_string()),
+            ours: c.our.map(|e| e.id.to_string()),
+            theirs: c.their.map(|e| e.id.to_string()),
+        });
+    }
+    Ok(conflicts)
+}
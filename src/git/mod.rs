@@ -1,8 +1,25 @@
 pub mod repo;
 pub mod resolve;
 pub mod hooks;
+pub mod indexdb;
+pub mod indexer;
+pub mod schema;
+pub mod signing;
 pub mod indexing;
+pub mod index_worker;
+pub mod index_admin;
+pub mod changes;
+pub mod filter;
 pub mod query;
+pub mod aggregate;
+pub mod export;
+pub mod graphql;
+pub mod branches;
+pub mod commit;
+pub mod merge;
+pub mod history;
+pub mod uploads;
+pub mod provision;
 
 #[cfg(test)]
 mod tests;
@@ -14,3 +31,4 @@ pub use repo::{
 pub use resolve::git_resolve_and_respond;
 pub use hooks::{execute_repo_hook, HookContext};
 pub use indexing::ensure_indexed;
+pub use branches::{BranchError, BranchInfo};
@@ -0,0 +1,383 @@
+//! Repository provisioning: create, clone (URL or local template), fork, hook install, archive/delete.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use git2::{Repository, RepositoryInitOptions};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::types::DEFAULT_BRANCH;
+
+/// Hooks that dispatch to `relay-hook-handler` (same set the Docker image installs system-wide).
+pub const HANDLER_HOOKS: &[&str] = &["pre-receive", "post-receive", "post-update"];
+
+#[derive(Debug, Error)]
+pub enum ProvisionError {
+    #[error("invalid repository name '{0}' (use a single DNS label: a-z, 0-9, '-')")]
+    InvalidName(String),
+    #[error("repository '{0}' already exists")]
+    AlreadyExists(String),
+    #[error("repository '{0}' not found")]
+    NotFound(String),
+    #[error("repository '{0}' shares its objects with forks {1:?}; run `git repack -a -d` in them and remove their objects/info/alternates first")]
+    HasDependentForks(String, Vec<String>),
+    #[error("invalid clone source '{0}'")]
+    InvalidSource(String),
+    #[error("clone failed: {0}")]
+    Clone(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+}
+
+/// Where a new repository's history comes from.
+#[derive(Debug, Clone)]
+pub enum RepoSource {
+    Empty,
+    /// Remote URL; kept as `origin` so `/git-pull` keeps working.
+    Url(String),
+    /// Name of an existing local repository under the repo root.
+    Template(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProvisionedRepo {
+    pub name: String,
+    pub path: PathBuf,
+    pub branches: Vec<String>,
+    /// Hooks symlinked to relay-hook-handler (empty when the handler binary was not found).
+    pub hooks: Vec<String>,
+}
+
+/// Repo names double as the first DNS label of `{repo}.{node_fqdn}`.
+pub fn validate_repo_name(name: &str) -> Result<(), ProvisionError> {
+    let ok = !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if ok {
+        Ok(())
+    } else {
+        Err(ProvisionError::InvalidName(name.to_string()))
+    }
+}
+
+fn repo_dir(root: &Path, name: &str) -> PathBuf {
+    root.join(format!("{}.git", name))
+}
+
+/// Create `{name}.git` under `root` from `source` and install handler hooks.
+pub fn create_repo(
+    root: &Path,
+    name: &str,
+    source: &RepoSource,
+) -> Result<ProvisionedRepo, ProvisionError> {
+    validate_repo_name(name)?;
+    let dir = repo_dir(root, name);
+    if dir.exists() {
+        return Err(ProvisionError::AlreadyExists(name.to_string()));
+    }
+    std::fs::create_dir_all(root)?;
+
+    match source {
+        RepoSource::Empty => {
+            let mut opts = RepositoryInitOptions::new();
+            opts.bare(true).initial_head(DEFAULT_BRANCH);
+            Repository::init_opts(&dir, &opts)?;
+        }
+        RepoSource::Url(url) => {
+            validate_clone_source(url)?;
+            git_clone_bare(url, &dir)?
+        }
+        RepoSource::Template(template) => {
+            validate_repo_name(template)?;
+            let template_dir = repo_dir(root, template);
+            if !template_dir.exists() {
+                return Err(ProvisionError::NotFound(template.to_string()));
+            }
+            git_clone_bare(&template_dir.to_string_lossy(), &dir)?;
+            // A template copy is a new project, not a mirror of the template.
+            let repo = Repository::open_bare(&dir)?;
+            let _ = repo.remote_delete("origin");
+        }
+    }
+
+    let hooks = install_hooks(&dir)?;
+    let repo = Repository::open_bare(&dir)?;
+    let branches = crate::git::list_branches(&repo);
+    info!(%name, path = %dir.display(), ?branches, "repository provisioned");
+    Ok(ProvisionedRepo {
+        name: name.to_string(),
+        path: dir,
+        branches,
+        hooks,
+    })
+}
+
+/// Refuse sources `git clone` would parse as an option or run as a command (`ext::`).
+fn validate_clone_source(source: &str) -> Result<(), ProvisionError> {
+    let source = source.trim();
+    if source.is_empty() || source.starts_with('-') || source.to_ascii_lowercase().starts_with("ext::") {
+        return Err(ProvisionError::InvalidSource(source.to_string()));
+    }
+    Ok(())
+}
+
+fn git_clone_bare(source: &str, dir: &Path) -> Result<(), ProvisionError> {
+    let out = Command::new("git")
+        .args(["-c", "protocol.ext.allow=never", "clone", "--bare", "--"])
+        .arg(source)
+        .arg(dir)
+        .output()?;
+    if !out.status.success() {
+        let _ = std::fs::remove_dir_all(dir);
+        return Err(ProvisionError::Clone(
+            String::from_utf8_lossy(&out.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Locate `relay-hook-handler`: `RELAY_HOOK_HANDLER`, next to the running binary, or on `PATH`.
+pub fn hook_handler_path() -> Option<PathBuf> {
+    if let Ok(p) = std::env::var("RELAY_HOOK_HANDLER") {
+        let p = PathBuf::from(p.trim());
+        if p.exists() {
+            return Some(p);
+        }
+    }
+    if let Some(p) = std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("relay-hook-handler"))
+        .filter(|p| p.exists())
+    {
+        return Some(p);
+    }
+    Command::new("which")
+        .arg("relay-hook-handler")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| PathBuf::from(String::from_utf8_lossy(&o.stdout).trim()))
+        .filter(|p| p.exists())
+}
+
+/// Symlink `hooks/{pre-receive,post-receive,post-update}` to relay-hook-handler.
+pub fn install_hooks(repo_dir: &Path) -> Result<Vec<String>, ProvisionError> {
+    let handler = match hook_handler_path() {
+        Some(h) => h,
+        None => {
+            warn!(repo = %repo_dir.display(), "relay-hook-handler not found; hooks not installed");
+            return Ok(Vec::new());
+        }
+    };
+    let hooks_dir = repo_dir.join("hooks");
+    std::fs::create_dir_all(&hooks_dir)?;
+    let mut installed = Vec::new();
+    for hook in HANDLER_HOOKS {
+        let link = hooks_dir.join(hook);
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)?;
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&handler, &link)?;
+        #[cfg(not(unix))]
+        std::fs::copy(&handler, &link)?;
+        installed.push(hook.to_string());
+    }
+    Ok(installed)
+}
+
+/// Repos under `root` whose `objects/info/alternates` points into `{name}.git` (shared forks).
+/// Their objects live in `name`, so moving or removing it would corrupt them.
+pub fn dependent_forks(root: &Path, name: &str) -> Vec<String> {
+    let Ok(objects) = std::fs::canonicalize(repo_dir(root, name).join("objects")) else {
+        return Vec::new();
+    };
+    crate::git::bare_repo_names(&root.to_path_buf())
+        .into_iter()
+        .filter(|fork| fork != name)
+        .filter(|fork| {
+            let alternates = repo_dir(root, fork).join("objects").join("info").join("alternates");
+            std::fs::read_to_string(alternates).is_ok_and(|list| {
+                list.lines()
+                    .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+                    .any(|l| std::fs::canonicalize(l.trim()).is_ok_and(|p| p == objects))
+            })
+        })
+        .collect()
+}
+
+fn ensure_no_dependent_forks(root: &Path, name: &str) -> Result<(), ProvisionError> {
+    let forks = dependent_forks(root, name);
+    if forks.is_empty() {
+        Ok(())
+    } else {
+        Err(ProvisionError::HasDependentForks(name.to_string(), forks))
+    }
+}
+
+/// Move `{name}.git` to `{root}/.archive/{name}-{unix_ts}.git`; returns the archive path.
+/// Refused while shared forks borrow its objects.
+pub fn archive_repo(root: &Path, name: &str) -> Result<PathBuf, ProvisionError> {
+    validate_repo_name(name)?;
+    let dir = repo_dir(root, name);
+    if !dir.exists() {
+        return Err(ProvisionError::NotFound(name.to_string()));
+    }
+    ensure_no_dependent_forks(root, name)?;
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let archive_root = root.join(".archive");
+    std::fs::create_dir_all(&archive_root)?;
+    let dest = archive_root.join(format!("{}-{}.git", name, ts));
+    std::fs::rename(&dir, &dest)?;
+    info!(%name, dest = %dest.display(), "repository archived");
+    Ok(dest)
+}
+
+/// Permanently remove `{name}.git`. Refused while shared forks borrow its objects.
+pub fn delete_repo(root: &Path, name: &str) -> Result<(), ProvisionError> {
+    validate_repo_name(name)?;
+    let dir = repo_dir(root, name);
+    if !dir.exists() {
+        return Err(ProvisionError::NotFound(name.to_string()));
+    }
+    ensure_no_dependent_forks(root, name)?;
+    std::fs::remove_dir_all(&dir)?;
+    info!(%name, "repository deleted");
+    Ok(())
+}
+
+/// How a fork obtains the source repository's objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectSharing {
+    /// Hardlink object files (copy across filesystems); the fork survives deletion of the source.
+    Hardlink,
+    /// Reference the source via `objects/info/alternates`; cheapest, but depends on the source staying in place
+    /// (deleting or archiving the source is refused while such forks exist).
+    Alternates,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForkedRepo {
+    pub name: String,
+    pub upstream: String,
+    pub path: PathBuf,
+    pub branches: Vec<String>,
+    pub hooks: Vec<String>,
+}
+
+/// Fork `{source}.git` into `{name}.git`, copying `branches` (all branches when empty).
+/// The upstream is recorded as remote `upstream` and `relay.upstream` in the fork's config.
+pub fn fork_repo(
+    root: &Path,
+    source: &str,
+    name: &str,
+    branches: &[String],
+    sharing: ObjectSharing,
+) -> Result<ForkedRepo, ProvisionError> {
+    validate_repo_name(source)?;
+    validate_repo_name(name)?;
+    let src_dir = repo_dir(root, source);
+    if !src_dir.exists() {
+        return Err(ProvisionError::NotFound(source.to_string()));
+    }
+    let dir = repo_dir(root, name);
+    if dir.exists() {
+        return Err(ProvisionError::AlreadyExists(name.to_string()));
+    }
+    let src = Repository::open_bare(&src_dir)?;
+    let available = crate::git::list_branches(&src);
+    let selected: Vec<String> = if branches.is_empty() {
+        available
+    } else {
+        if let Some(missing) = branches.iter().find(|b| !available.contains(b)) {
+            return Err(ProvisionError::NotFound(format!("{}:{}", source, missing)));
+        }
+        branches.to_vec()
+    };
+
+    let result = populate_fork(&src, &src_dir, &dir, source, &selected, sharing);
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    result?;
+
+    let hooks = install_hooks(&dir)?;
+    info!(%source, %name, branches = ?selected, ?sharing, "repository forked");
+    Ok(ForkedRepo {
+        name: name.to_string(),
+        upstream: source.to_string(),
+        path: dir,
+        branches: selected,
+        hooks,
+    })
+}
+
+fn populate_fork(
+    src: &Repository,
+    src_dir: &Path,
+    dir: &Path,
+    source: &str,
+    branches: &[String],
+    sharing: ObjectSharing,
+) -> Result<(), ProvisionError> {
+    let head = if branches.iter().any(|b| b == DEFAULT_BRANCH) {
+        DEFAULT_BRANCH
+    } else {
+        branches.first().map(|s| s.as_str()).unwrap_or(DEFAULT_BRANCH)
+    };
+    let mut opts = RepositoryInitOptions::new();
+    opts.bare(true).initial_head(head);
+    Repository::init_opts(dir, &opts)?;
+
+    let src_objects = std::fs::canonicalize(src_dir.join("objects"))?;
+    match sharing {
+        ObjectSharing::Alternates => {
+            let info = dir.join("objects").join("info");
+            std::fs::create_dir_all(&info)?;
+            std::fs::write(info.join("alternates"), format!("{}\n", src_objects.display()))?;
+        }
+        ObjectSharing::Hardlink => link_tree(&src_objects, &dir.join("objects"))?,
+    }
+
+    // Reopen so the object database sees the linked/alternate objects.
+    let fork = Repository::open_bare(dir)?;
+    for branch in branches {
+        let oid = src.refname_to_id(&format!("refs/heads/{}", branch))?;
+        fork.reference(
+            &format!("refs/heads/{}", branch),
+            oid,
+            false,
+            &format!("fork: from {}", source),
+        )?;
+    }
+    fork.remote("upstream", &src_dir.canonicalize()?.to_string_lossy())?;
+    fork.config()?.set_str("relay.upstream", source)?;
+    Ok(())
+}
+
+/// Mirror `from` into `to`, hardlinking files (copying when links are not possible).
+/// `objects/info/*` is always copied so the fork can edit it independently.
+fn link_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_tree(&entry.path(), &target)?;
+        } else if from.ends_with("info") || std::fs::hard_link(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
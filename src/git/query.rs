@@ -1,91 +1,551 @@
+use std::cmp::Ordering;
 use std::path::Path;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::debug;
+use tracing::{debug, warn};
+use crate::git::filter::{lookup, Filter, FilterError};
+use crate::git::branches::BranchError;
+use crate::git::indexdb::{prune_snapshots, snapshot_branch, touch_snapshot, BranchIndex, IndexDbError, SCORE_FIELD};
 use crate::git::indexing::ensure_indexed;
 use crate::git::hooks::HookContext;
 use crate::git;
+use crate::types::ALL_BRANCHES;
 
+/// Field added to each result of an `X-Relay-Branch: all` query.
+pub const BRANCH_FIELD: &str = "_branch";
+
+enum Matcher {
+    All,
+    /// Case-insensitive substring over top-level string fields
+    Text(String),
+    Filter(Filter),
+}
+
+impl Matcher {
+    fn parse(query: Option<Value>) -> Result<Matcher, FilterError> {
+        Ok(match query {
+            Some(Value::String(s)) if !s.is_empty() => Matcher::Text(s.to_lowercase()),
+            Some(v @ Value::Object(_)) => Matcher::Filter(Filter::parse(&v)?),
+            _ => Matcher::All,
+        })
+    }
+
+    fn matches(&self, item: &Value) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Text(q_lower) => item.as_object().is_some_and(|obj| {
+                obj.values()
+                    .filter_map(|v| v.as_str())
+                    .any(|s| s.to_lowercase().contains(q_lower))
+            }),
+            Matcher::Filter(f) => f.matches(item),
+        }
+    }
+}
+
+/// Index snapshots of past commits kept per repo (least recently used are pruned).
+pub const MAX_SNAPSHOTS: usize = 16;
+
+/// Resolve `rev` (commit, tag or branch) of `repo_name` to a commit id for an "as of" query.
+pub fn resolve_commit(repo_root: &Path, repo_name: &str, rev: &str) -> anyhow::Result<String> {
+    let repo = git2::Repository::open_bare(repo_root.join(format!("{}.git", repo_name)))?;
+    let commit = repo
+        .revparse_single(rev)
+        .and_then(|o| o.peel_to_commit())
+        .map_err(|_| BranchError::RevisionNotFound(rev.to_string()))?;
+    Ok(commit.id().to_string())
+}
+
+/// Query `collection` on `branch`, or on every branch when `branch` is [`ALL_BRANCHES`]
+/// (each result then carries its branch in [`BRANCH_FIELD`]). With `search`, only full-text hits
+/// are returned, best [`SCORE_FIELD`] first. With `as_of` (a commit id, see [`resolve_commit`])
+/// `branch` is ignored and the index of that commit is queried; it is built on first use and
+/// cached as a snapshot.
 pub fn execute_query(
     repo_root: &Path,
     repo_name: &str,
     branch: &str,
     query: Option<Value>,
     collection: &str,
+    search: Option<&str>,
+    as_of: Option<&str>,
 ) -> anyhow::Result<Value> {
     let repo_full_path = repo_root.join(format!("{}.git", repo_name));
-    
-    // Get current HEAD for JIT indexing
     let repo = git2::Repository::open_bare(&repo_full_path)?;
-    let head = git::get_branch_commit_info(&repo, branch)
+    let matcher = Matcher::parse(query)?;
+    let search = search.map(str::trim).filter(|s| !s.is_empty());
+
+    if let Some(commit) = as_of {
+        let items = query_snapshot(&repo, &repo_full_path, commit, &matcher, collection, search)?;
+        return Ok(Value::Array(items));
+    }
+    if branch != ALL_BRANCHES {
+        let items = query_branch(&repo, &repo_full_path, branch, &matcher, collection, search)?;
+        return Ok(Value::Array(items));
+    }
+
+    let mut all = Vec::new();
+    for b in git::list_branches(&repo) {
+        match query_branch(&repo, &repo_full_path, &b, &matcher, collection, search) {
+            Ok(items) => all.extend(items.into_iter().map(|mut item| {
+                if let Some(obj) = item.as_object_mut() {
+                    obj.insert(BRANCH_FIELD.to_string(), Value::String(b.clone()));
+                }
+                item
+            })),
+            // One broken proposal branch should not hide every other branch's results.
+            Err(e) => warn!(branch = %b, error = %e, "skipping branch in cross-branch query"),
+        }
+    }
+    if search.is_some() {
+        sort_items(&mut all, &[SortKey { field: SCORE_FIELD.to_string(), dir: SortDir::Desc }]);
+    }
+    Ok(Value::Array(all))
+}
+
+fn query_branch(
+    repo: &git2::Repository,
+    repo_full_path: &Path,
+    branch: &str,
+    matcher: &Matcher,
+    collection: &str,
+    search: Option<&str>,
+) -> anyhow::Result<Vec<Value>> {
+    // Get current HEAD for JIT indexing
+    let head = git::get_branch_commit_info(repo, branch)
         .ok_or_else(|| anyhow::anyhow!("Branch {} not found", branch))?.0;
+    let ctx = index_context(repo_full_path, branch, format!("refs/heads/{}", branch), head);
+    query_index(repo, &ctx, matcher, collection, search)
+}
+
+/// Query the index of one commit, building it under its snapshot pseudo-branch if needed.
+fn query_snapshot(
+    repo: &git2::Repository,
+    repo_full_path: &Path,
+    commit: &str,
+    matcher: &Matcher,
+    collection: &str,
+    search: Option<&str>,
+) -> anyhow::Result<Vec<Value>> {
+    let ctx = open_snapshot(repo_full_path, commit)?;
+    query_index(repo, &ctx, matcher, collection, search)
+}
 
-    // Prepare context for indexing
-    let ctx = HookContext {
-        repo_path: repo_full_path.clone(),
+/// Build the index snapshot of `commit` unless it is cached, mark it recently used and prune the
+/// least recently used ones once a new snapshot was built. Returns the context to read it with.
+/// The commit is immutable, so a built snapshot never needs re-indexing.
+fn open_snapshot(repo_full_path: &Path, commit: &str) -> anyhow::Result<HookContext> {
+    let key = snapshot_branch(commit);
+    let built = BranchIndex::open_existing(repo_full_path, &key)?.is_some();
+    let ctx = index_context(repo_full_path, &key, commit.to_string(), commit.to_string());
+    ensure_indexed(&ctx)?;
+    touch_snapshot(repo_full_path, commit);
+    if !built {
+        let pruned = prune_snapshots(repo_full_path, MAX_SNAPSHOTS);
+        debug!(%commit, pruned, "built index snapshot");
+    }
+    Ok(ctx)
+}
+
+/// Bring the index of `branch` (or of the `as_of` commit) up to date and open it, with the commit
+/// it reflects. The index is `None` when indexing produced no database (nothing to index).
+pub fn open_index(
+    repo_root: &Path,
+    repo_name: &str,
+    branch: &str,
+    as_of: Option<&str>,
+) -> anyhow::Result<(Option<BranchIndex>, String)> {
+    let repo_full_path = repo_root.join(format!("{}.git", repo_name));
+    let repo = git2::Repository::open_bare(&repo_full_path)?;
+    let ctx = match as_of {
+        Some(commit) => open_snapshot(&repo_full_path, commit)?,
+        None => {
+            let head = git::get_branch_commit_info(&repo, branch)
+                .ok_or_else(|| BranchError::NotFound(branch.to_string()))?
+                .0;
+            let ctx = index_context(&repo_full_path, branch, format!("refs/heads/{}", branch), head);
+            ensure_indexed(&ctx)?;
+            ctx
+        }
+    };
+    Ok((BranchIndex::open_existing(&repo_full_path, &ctx.branch)?, ctx.new_commit))
+}
+
+/// Context for indexing `branch` (or a snapshot pseudo-branch) up to `head`.
+fn index_context(repo_full_path: &Path, branch: &str, refname: String, head: String) -> HookContext {
+    HookContext {
+        repo_path: repo_full_path.to_path_buf(),
         old_commit: String::new(),
         new_commit: head,
-        refname: format!("refs/heads/{}", branch),
+        refname,
         branch: branch.to_string(),
         is_verified: true,
         files: std::collections::HashMap::new(),
+    }
+}
+
+fn query_index(
+    repo: &git2::Repository,
+    ctx: &HookContext,
+    matcher: &Matcher,
+    collection: &str,
+    search: Option<&str>,
+) -> anyhow::Result<Vec<Value>> {
+    let Some(index) = prepared_index(repo, ctx, collection)? else {
+        return Ok(Vec::new());
     };
 
+    if let Some(text) = search {
+        return Ok(index
+            .search(collection, text)?
+            .into_iter()
+            .filter(|item| matcher.matches(item))
+            .collect());
+    }
+    match matcher {
+        Matcher::Filter(f) => Ok(index.find(collection, Some(f))?),
+        _ => Ok(index
+            .find(collection, None)?
+            .into_iter()
+            .filter(|item| matcher.matches(item))
+            .collect()),
+    }
+}
+
+/// Bring the index of `ctx` up to date and open it with the secondary indexes and search fields
+/// the repo config declares for `collection`; `None` when nothing is indexed.
+fn prepared_index(
+    repo: &git2::Repository,
+    ctx: &HookContext,
+    collection: &str,
+) -> anyhow::Result<Option<BranchIndex>> {
     // Run JIT indexing if stale
-    ensure_indexed(&ctx)?;
+    ensure_indexed(ctx)?;
+
+    let Some(mut index) = BranchIndex::open_existing(&ctx.repo_path, &ctx.branch)? else {
+        return Ok(None);
+    };
+    if let Some(cfg) = git::read_relay_config(repo, &ctx.new_commit)
+        .and_then(|c| c.server)
+        .and_then(|s| s.db)
+        .and_then(|mut db| db.collections.remove(collection))
+    {
+        index.ensure_indexes(collection, &cfg.indexes)?;
+        index.ensure_search_fields(collection, &cfg.search)?;
+    }
+    Ok(Some(index))
+}
+
+/// A streamed export read straight off the index cursor. Without sorting, ranking or merging
+/// branches, rows can be sent as they are read; [`Scan::prepare`] does the fallible work
+/// (indexing, parsing) before the first row.
+pub struct Scan {
+    index: Option<BranchIndex>,
+    collection: String,
+    matcher: Matcher,
+    projection: Option<CompiledProjection>,
+    skip: usize,
+    take: Option<usize>,
+}
 
-    // Now perform the query against the database
-    let branch_bytes = if branch.is_empty() { "main".as_bytes() } else { branch.as_bytes() };
-    let branch_hash = hex::encode(branch_bytes);
-    let branch_hash_short = if branch_hash.len() > 12 { &branch_hash[..12] } else { &branch_hash };
-    let db_path = repo_full_path.join(".relay_data").join("branches").join(branch_hash_short).join("index.db.json");
-
-    if !db_path.exists() {
-        return Ok(serde_json::json!([]));
-    }
-
-    let db_content = std::fs::read_to_string(&db_path)?;
-    let db: Value = serde_json::from_str(&db_content)?;
-
-    let mut results = db.get("collections")
-        .and_then(|c| c.get(collection))
-        .cloned()
-        .unwrap_or(serde_json::json!([]));
-
-    // Filtering logic
-    if let Some(query_val) = query {
-        if let Some(results_arr) = results.as_array_mut() {
-            if let Some(q_str) = query_val.as_str() {
-                if !q_str.is_empty() {
-                    let q_lower = q_str.to_lowercase();
-                    results_arr.retain(|item| {
-                        if let Some(obj) = item.as_object() {
-                            for value in obj.values() {
-                                if let Some(s) = value.as_str() {
-                                    if s.to_lowercase().contains(&q_lower) {
-                                        return true;
-                                    }
-                                }
-                            }
-                        }
-                        false
-                    });
+impl Scan {
+    /// Whether a query can be answered by a scan: one branch, no search and no sort.
+    pub fn applies(branch: &str, search: Option<&str>, opts: &PageOptions) -> bool {
+        branch != ALL_BRANCHES && search.is_none_or(|s| s.trim().is_empty()) && opts.sort.is_empty()
+    }
+
+    pub fn prepare(
+        repo_root: &Path,
+        repo_name: &str,
+        branch: &str,
+        query: Option<Value>,
+        collection: &str,
+        as_of: Option<&str>,
+        opts: &PageOptions,
+    ) -> anyhow::Result<Scan> {
+        let matcher = Matcher::parse(query)?;
+        let projection = opts.projection.as_ref().map(parse_projection).transpose()?;
+        let repo_full_path = repo_root.join(format!("{}.git", repo_name));
+        let repo = git2::Repository::open_bare(&repo_full_path)?;
+        let index = match as_of {
+            Some(commit) => prepared_index(&repo, &open_snapshot(&repo_full_path, commit)?, collection)?,
+            None => {
+                let head = git::get_branch_commit_info(&repo, branch)
+                    .ok_or_else(|| anyhow::anyhow!("Branch {} not found", branch))?
+                    .0;
+                let ctx = index_context(&repo_full_path, branch, format!("refs/heads/{}", branch), head);
+                prepared_index(&repo, &ctx, collection)?
+            }
+        };
+        let page_size = opts.page_size.map(|n| n.max(1));
+        Ok(Scan {
+            index,
+            collection: collection.to_string(),
+            matcher,
+            projection,
+            skip: page_size.map_or(0, |n| opts.page.saturating_mul(n)),
+            take: page_size,
+        })
+    }
+
+    /// Feed the matches, paged and projected, to `emit` in index order until it returns false.
+    pub fn run(&self, mut emit: impl FnMut(Value) -> bool) -> Result<(), IndexDbError> {
+        let Some(index) = &self.index else {
+            return Ok(());
+        };
+        let filter = match &self.matcher {
+            Matcher::Filter(f) => Some(f),
+            _ => None,
+        };
+        let mut skip = self.skip;
+        let mut left = self.take.unwrap_or(usize::MAX);
+        index.scan(&self.collection, filter, |doc| {
+            if filter.is_none() && !self.matcher.matches(&doc) {
+                return true;
+            }
+            if skip > 0 {
+                skip -= 1;
+                return true;
+            }
+            left -= 1;
+            let doc = match &self.projection {
+                Some(p) => p.apply(doc),
+                None => doc,
+            };
+            emit(doc) && left > 0
+        })
+    }
+}
+
+pub const DEFAULT_PAGE_SIZE: usize = 25;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortDir {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl<'de> Deserialize<'de> for SortDir {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match Value::deserialize(d)? {
+            Value::String(s) if s.eq_ignore_ascii_case("asc") => Ok(SortDir::Asc),
+            Value::String(s) if s.eq_ignore_ascii_case("desc") => Ok(SortDir::Desc),
+            Value::Number(n) if n.as_i64() == Some(1) => Ok(SortDir::Asc),
+            Value::Number(n) if n.as_i64() == Some(-1) => Ok(SortDir::Desc),
+            other => Err(serde::de::Error::custom(format!(
+                "sort dir must be asc|desc|1|-1, got {}",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SortKey {
+    /// Dotted path into the document
+    pub field: String,
+    #[serde(default)]
+    pub dir: SortDir,
+}
+
+/// `["title", "meta.year"]` (include) or `{ "body": 0 }` / `{ "title": 1 }` (exclude / include).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Projection {
+    Fields(Vec<String>),
+    Spec(serde_json::Map<String, Value>),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageOptions {
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    #[serde(default)]
+    pub sort: Vec<SortKey>,
+    #[serde(default)]
+    pub projection: Option<Projection>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryPage {
+    /// Matches before paging
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub items: Vec<Value>,
+    /// Documents matched by the filter, when `items` are aggregate groups.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<usize>,
+    /// Facet counts over every match (not only this page).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<serde_json::Map<String, Value>>,
+    /// Commit the results reflect, for queries "as of" a past revision.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_of: Option<String>,
+}
+
+/// Sort, page and project already-filtered `items`.
+pub fn paginate(mut items: Vec<Value>, opts: &PageOptions) -> Result<QueryPage, FilterError> {
+    let projection = opts.projection.as_ref().map(parse_projection).transpose()?;
+    sort_items(&mut items, &opts.sort);
+    let total = items.len();
+    let page_size = opts.page_size.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let items = items
+        .into_iter()
+        .skip(opts.page.saturating_mul(page_size))
+        .take(page_size)
+        .map(|doc| match &projection {
+            Some(p) => p.apply(doc),
+            None => doc,
+        })
+        .collect();
+    Ok(QueryPage {
+        total,
+        page: opts.page,
+        page_size,
+        items,
+        matched: None,
+        facets: None,
+        as_of: None,
+    })
+}
+
+/// Sort and project already-filtered `items` for export. Unlike [`paginate`], every match is kept
+/// unless `pageSize` is given, and the page size is not capped.
+pub fn export_items(mut items: Vec<Value>, opts: &PageOptions) -> Result<Vec<Value>, FilterError> {
+    let projection = opts.projection.as_ref().map(parse_projection).transpose()?;
+    sort_items(&mut items, &opts.sort);
+    if let Some(page_size) = opts.page_size.map(|n| n.max(1)) {
+        items = items.into_iter().skip(opts.page.saturating_mul(page_size)).take(page_size).collect();
+    }
+    Ok(match projection {
+        Some(p) => items.into_iter().map(|doc| p.apply(doc)).collect(),
+        None => items,
+    })
+}
+
+/// Stable multi-key sort; missing values sort first, then null, numbers, strings, objects, arrays, booleans.
+pub fn sort_items(items: &mut [Value], keys: &[SortKey]) {
+    if keys.is_empty() {
+        return;
+    }
+    items.sort_by(|a, b| {
+        for key in keys {
+            let ord = cmp_values(lookup(a, &key.field), lookup(b, &key.field));
+            let ord = if key.dir == SortDir::Desc { ord.reverse() } else { ord };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+fn type_rank(v: Option<&Value>) -> u8 {
+    match v {
+        None => 0,
+        Some(Value::Null) => 1,
+        Some(Value::Number(_)) => 2,
+        Some(Value::String(_)) => 3,
+        Some(Value::Object(_)) => 4,
+        Some(Value::Array(_)) => 5,
+        Some(Value::Bool(_)) => 6,
+    }
+}
+
+/// Order used by `sort`; also ranks aggregate keys and min/max values.
+pub(crate) fn cmp_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(Value::Number(x)), Some(Value::Number(y))) => x
+            .as_f64()
+            .partial_cmp(&y.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(Value::String(x)), Some(Value::String(y))) => x.cmp(y),
+        (Some(Value::Bool(x)), Some(Value::Bool(y))) => x.cmp(y),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+enum CompiledProjection {
+    Include(Vec<Vec<String>>),
+    Exclude(Vec<Vec<String>>),
+}
+
+fn parse_projection(p: &Projection) -> Result<CompiledProjection, FilterError> {
+    let split = |f: &str| f.split('.').map(|s| s.to_string()).collect::<Vec<_>>();
+    match p {
+        Projection::Fields(fields) => Ok(CompiledProjection::Include(fields.iter().map(|f| split(f)).collect())),
+        Projection::Spec(spec) => {
+            let truthy = |v: &Value| v.as_bool().unwrap_or_else(|| v.as_i64().unwrap_or(0) != 0);
+            let (inc, exc): (Vec<_>, Vec<_>) = spec.iter().partition(|(_, v)| truthy(v));
+            match (inc.is_empty(), exc.is_empty()) {
+                (false, true) => Ok(CompiledProjection::Include(inc.iter().map(|(k, _)| split(k)).collect())),
+                (true, _) => Ok(CompiledProjection::Exclude(exc.iter().map(|(k, _)| split(k)).collect())),
+                (false, false) => Err(FilterError::BadOperand {
+                    op: "projection".to_string(),
+                    expected: "only inclusions or only exclusions",
+                }),
+            }
+        }
+    }
+}
+
+impl CompiledProjection {
+    fn apply(&self, doc: Value) -> Value {
+        match self {
+            CompiledProjection::Include(paths) => {
+                let mut out = Value::Object(Default::default());
+                for path in paths {
+                    copy_path(&doc, &mut out, path);
                 }
-            } else if let Some(q_obj) = query_val.as_object() {
-                results_arr.retain(|item| {
-                    if let Some(item_obj) = item.as_object() {
-                        for (k, v) in q_obj {
-                            if item_obj.get(k) != Some(v) {
-                                return false;
-                            }
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                });
+                out
+            }
+            CompiledProjection::Exclude(paths) => {
+                let mut doc = doc;
+                for path in paths {
+                    remove_path(&mut doc, path);
+                }
+                doc
             }
         }
     }
+}
+
+fn copy_path(src: &Value, dst: &mut Value, path: &[String]) {
+    let (Some((head, rest)), Some(src_obj)) = (path.split_first(), src.as_object()) else {
+        return;
+    };
+    let Some(val) = src_obj.get(head) else { return };
+    let Some(dst_obj) = dst.as_object_mut() else { return };
+    if rest.is_empty() || !val.is_object() {
+        // Arrays and scalars along the path are kept whole.
+        dst_obj.insert(head.clone(), val.clone());
+        return;
+    }
+    let child = dst_obj
+        .entry(head.clone())
+        .or_insert_with(|| Value::Object(Default::default()));
+    copy_path(val, child, rest);
+}
 
-    Ok(results)
+fn remove_path(doc: &mut Value, path: &[String]) {
+    let Some((head, rest)) = path.split_first() else { return };
+    match doc {
+        Value::Object(o) if rest.is_empty() => {
+            o.remove(head);
+        }
+        Value::Object(o) => {
+            if let Some(child) = o.get_mut(head) {
+                remove_path(child, rest);
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| remove_path(item, path)),
+        _ => {}
+    }
 }
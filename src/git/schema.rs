@@ -0,0 +1,334 @@
+//! JSON Schema validation of repo data declared in `.relay.yaml`.
+//!
+//! `server.db.collections.<name>.schema` constrains the documents of a collection (files routed to
+//! it by `server.db.sources`, and documents inserted by an `index` hook); `server.db.schemas`
+//! constrains files by path glob. Schemas are inline or the repo path of a JSON/YAML file. Changed
+//! files are checked during `pre-commit`/`pre-receive`, where violations reject the change; while
+//! indexing, invalid documents go to [`QUARANTINE_COLLECTION`] instead of their collection.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use base64::Engine;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use jsonschema::JSONSchema;
+use serde::Serialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::git::indexer::{self, PATH_FIELD};
+use crate::types::{DbConfig, IndexSource, SchemaRef, ServerConfig};
+
+/// Collection holding documents that failed their schema during indexing.
+pub const QUARANTINE_COLLECTION: &str = "_quarantine";
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("invalid glob in server.db: {0}")]
+    Glob(#[from] globset::Error),
+    #[error("schema file '{0}' not found")]
+    Missing(String),
+    #[error("cannot parse schema '{0}': {1}")]
+    Parse(String, String),
+    #[error("invalid JSON Schema for {0}: {1}")]
+    Compile(String, String),
+}
+
+/// One failed schema constraint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    /// File the document was read from, when known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Schema that failed: a `server.db.schemas` glob or `collection <name>`.
+    pub schema: String,
+    /// JSON Pointer to the offending value (empty for the document itself).
+    pub instance_path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        let at = if self.instance_path.is_empty() { "/" } else { &self.instance_path };
+        write!(f, "{}: {} ({})", at, self.message, self.schema)
+    }
+}
+
+/// Rejection raised by `pre-commit`/`pre-receive` when changed files violate their schemas.
+#[derive(Debug, Error)]
+#[error("schema validation failed: {}", .0.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("; "))]
+pub struct SchemaViolations(pub Vec<Violation>);
+
+struct Compiled {
+    label: String,
+    schema: JSONSchema,
+}
+
+impl Compiled {
+    fn check(&self, file: Option<&str>, doc: &Value, out: &mut Vec<Violation>) {
+        if let Err(errors) = self.schema.validate(doc) {
+            out.extend(errors.map(|e| Violation {
+                file: file.map(str::to_string),
+                schema: self.label.clone(),
+                instance_path: e.instance_path.to_string(),
+                message: e.to_string(),
+            }));
+        }
+    }
+}
+
+/// Compiled schemas of one `.relay.yaml`.
+#[derive(Default)]
+pub struct SchemaSet {
+    paths: Option<GlobSet>,
+    path_schemas: Vec<Compiled>,
+    /// Sources routing files into collections that have a schema.
+    sources: Option<GlobSet>,
+    source_collections: Vec<String>,
+    collections: HashMap<String, Compiled>,
+    fingerprint: String,
+}
+
+impl SchemaSet {
+    /// Compile the schemas of `db`; `load` reads schema files referenced by path from the commit
+    /// being checked. `sources` map files to collections (see [`indexer::default_sources`]).
+    pub fn compile(
+        db: &DbConfig,
+        sources: &[IndexSource],
+        load: impl Fn(&str) -> Option<Vec<u8>>,
+    ) -> Result<Self, SchemaError> {
+        let mut resolved = BTreeMap::new();
+        let mut set = SchemaSet::default();
+
+        let mut paths = GlobSetBuilder::new();
+        for rule in &db.schemas {
+            paths.add(Glob::new(&rule.glob)?);
+            let schema = resolve(&rule.schema, &load)?;
+            set.path_schemas.push(compile_one(&rule.glob, &schema)?);
+            resolved.insert(format!("glob {}", rule.glob), schema);
+        }
+        if !db.schemas.is_empty() {
+            set.paths = Some(paths.build()?);
+        }
+
+        for (name, collection) in &db.collections {
+            let Some(schema_ref) = &collection.schema else { continue };
+            let label = format!("collection {}", name);
+            let schema = resolve(schema_ref, &load)?;
+            set.collections.insert(name.clone(), compile_one(&label, &schema)?);
+            resolved.insert(label, schema);
+        }
+
+        let mut routed = GlobSetBuilder::new();
+        for source in sources.iter().filter(|s| set.collections.contains_key(&s.collection)) {
+            routed.add(Glob::new(&source.glob)?);
+            set.source_collections.push(source.collection.clone());
+        }
+        if !set.source_collections.is_empty() {
+            set.sources = Some(routed.build()?);
+        }
+
+        set.fingerprint = serde_json::to_string(&resolved).unwrap_or_default();
+        Ok(set)
+    }
+
+    /// Schemas of `server.db` (none when absent); files are routed by its sources or the defaults.
+    pub fn for_server(
+        server: Option<&ServerConfig>,
+        load: impl Fn(&str) -> Option<Vec<u8>>,
+    ) -> Result<Self, SchemaError> {
+        match server.and_then(|s| s.db.as_ref()) {
+            Some(db) => {
+                let sources = db.sources.clone().unwrap_or_else(indexer::default_sources);
+                SchemaSet::compile(db, &sources, load)
+            }
+            None => Ok(SchemaSet::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path_schemas.is_empty() && self.collections.is_empty()
+    }
+
+    /// Stable serialization of the resolved schemas; indexes built under another one are rebuilt.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Violations of the `server.db.schemas` entries whose glob matches `path`.
+    pub fn check_path(&self, path: &str, doc: &Value) -> Vec<Violation> {
+        let mut out = Vec::new();
+        if let Some(paths) = &self.paths {
+            for i in paths.matches(path) {
+                self.path_schemas[i].check(Some(path), doc, &mut out);
+            }
+        }
+        out
+    }
+
+    /// Violations of the schema of `collection`, if it declares one.
+    pub fn check_document(&self, collection: &str, file: Option<&str>, doc: &Value) -> Vec<Violation> {
+        let mut out = Vec::new();
+        if let Some(schema) = self.collections.get(collection) {
+            schema.check(file, doc, &mut out);
+        }
+        out
+    }
+
+    /// Whether a glob schema or a collection with a schema covers `path`.
+    pub fn covers(&self, path: &str) -> bool {
+        self.paths.as_ref().is_some_and(|p| p.is_match(path))
+            || self.sources.as_ref().is_some_and(|s| s.is_match(path))
+    }
+
+    /// Check one file against its glob schemas and the schemas of the collections it is indexed
+    /// into. Files no schema applies to are not parsed.
+    pub fn check_file(&self, path: &str, content: &[u8]) -> Vec<Violation> {
+        let mut collections: Vec<&str> = match &self.sources {
+            Some(sources) => sources
+                .matches(path)
+                .into_iter()
+                .map(|i| self.source_collections[i].as_str())
+                .collect(),
+            None => Vec::new(),
+        };
+        collections.sort_unstable();
+        collections.dedup();
+        let by_path = self.paths.as_ref().is_some_and(|p| p.is_match(path));
+        if collections.is_empty() && !by_path {
+            return Vec::new();
+        }
+        let doc = match indexer::parse_file(path, content) {
+            Ok(doc) => doc,
+            Err(e) => {
+                return vec![Violation {
+                    file: Some(path.to_string()),
+                    schema: "parse".to_string(),
+                    instance_path: String::new(),
+                    message: e,
+                }]
+            }
+        };
+        let mut out = self.check_path(path, &doc);
+        for collection in collections {
+            out.extend(self.check_document(collection, Some(path), &doc));
+        }
+        out
+    }
+
+    /// Check changed files given in hook context format (path to base64 content), by path.
+    pub fn check_files(&self, files: &HashMap<String, String>) -> Vec<Violation> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut paths: Vec<&String> = files.keys().collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .flat_map(|path| {
+                let content = base64::engine::general_purpose::STANDARD
+                    .decode(&files[path])
+                    .unwrap_or_default();
+                self.check_file(path, &content)
+            })
+            .collect()
+    }
+}
+
+/// Resolved JSON Schema of every collection of `db` that declares one.
+pub fn collection_schemas(
+    db: &DbConfig,
+    load: impl Fn(&str) -> Option<Vec<u8>>,
+) -> Result<BTreeMap<String, Value>, SchemaError> {
+    db.collections
+        .iter()
+        .filter_map(|(name, c)| c.schema.as_ref().map(|s| (name, s)))
+        .map(|(name, schema)| Ok((name.clone(), resolve(schema, &load)?)))
+        .collect()
+}
+
+/// Schema file loader reading `rev` of an opened repository.
+pub fn tree_loader<'r>(repo: &'r git2::Repository, rev: &str) -> impl Fn(&str) -> Option<Vec<u8>> + 'r {
+    let tree = repo.revparse_single(rev).and_then(|o| o.peel_to_tree()).ok();
+    move |path| {
+        let entry = tree.as_ref()?.get_path(std::path::Path::new(path)).ok()?;
+        let blob = entry.to_object(repo).ok()?.into_blob().ok()?;
+        Some(blob.content().to_vec())
+    }
+}
+
+/// Schema file loader using `git show`, which also sees objects still in a push quarantine.
+pub fn git_cli_loader(repo_path: &Path, rev: &str) -> impl Fn(&str) -> Option<Vec<u8>> {
+    let repo_path = repo_path.to_path_buf();
+    let rev = rev.to_string();
+    move |path| {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(&repo_path)
+            .arg("show")
+            .arg(format!("{}:{}", rev, path))
+            .output()
+            .ok()?;
+        out.status.success().then_some(out.stdout)
+    }
+}
+
+/// Quarantine entry for a document of `collection` that failed validation.
+pub fn quarantine_doc(collection: &str, doc: Value, violations: &[Violation]) -> Value {
+    let mut entry = json!({
+        "_collection": collection,
+        "_errors": violations,
+    });
+    for field in [PATH_FIELD, "_branch"] {
+        if let Some(v) = doc.get(field) {
+            entry[field] = v.clone();
+        }
+    }
+    entry["document"] = doc;
+    entry
+}
+
+fn resolve(schema: &SchemaRef, load: &impl Fn(&str) -> Option<Vec<u8>>) -> Result<Value, SchemaError> {
+    match schema {
+        SchemaRef::Inline(v) => Ok(v.clone()),
+        SchemaRef::Path(path) => {
+            let content = load(path).ok_or_else(|| SchemaError::Missing(path.clone()))?;
+            indexer::parse_file(path, &content).map_err(|e| SchemaError::Parse(path.clone(), e))
+        }
+    }
+}
+
+/// First `$ref` of `schema` pointing outside the schema itself. Schemas come from repo content,
+/// so resolving such a reference would let any pusher make the server fetch URLs or read files.
+fn external_ref(schema: &Value) -> Option<&str> {
+    match schema {
+        Value::Object(map) => map.iter().find_map(|(key, value)| match (key.as_str(), value) {
+            ("$ref" | "$recursiveRef" | "$dynamicRef", Value::String(r)) if !r.starts_with('#') => Some(r.as_str()),
+            // Instance values, not subschemas.
+            ("enum" | "const" | "default" | "examples", _) => None,
+            _ => external_ref(value),
+        }),
+        Value::Array(items) => items.iter().find_map(external_ref),
+        _ => None,
+    }
+}
+
+fn compile_one(label: &str, schema: &Value) -> Result<Compiled, SchemaError> {
+    if let Some(reference) = external_ref(schema) {
+        return Err(SchemaError::Compile(
+            label.to_string(),
+            format!("external $ref '{}' is not allowed; only local '#...' references resolve", reference),
+        ));
+    }
+    let compiled = JSONSchema::compile(schema)
+        .map_err(|e| SchemaError::Compile(label.to_string(), e.to_string()))?;
+    Ok(Compiled {
+        label: label.to_string(),
+        schema: compiled,
+    })
+}
@@ -0,0 +1,333 @@
+//! Verification of commit signatures against the keys a branch rule allows.
+//!
+//! `allowedKeys` are repo-path globs of key files, read from a commit the server already trusts
+//! (never from the commit being verified): OpenSSH public keys, one per line (`authorized_keys` /
+//! `allowed_signers` lines work too), and ASCII-armored OpenPGP public keys.
+//! `allowedKeyFingerprints` lists SSH fingerprints (`SHA256:…`) and full OpenPGP fingerprints (40 or
+//! 64 hex digits, spaces ignored). Short and long key ids can collide, so they are ignored with a
+//! warning.
+//!
+//! SSH signatures are verified natively. OpenPGP signatures are checked by `gpg`, first against a
+//! throwaway keyring holding only the allowed key files, then (for `allowedKeyFingerprints`) against
+//! the server keyring, accepting only the listed fingerprints. A rule that lists neither falls back to
+//! `git verify-commit`, i.e. whatever the server's keyring and `gpg.ssh.allowedSignersFile` accept.
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use globset::{Glob, GlobSetBuilder};
+use ssh_key::{HashAlg, PublicKey, SshSig};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::types::BranchRule;
+
+/// Namespace git signs commits under with `gpg.format=ssh`.
+pub const SSH_NAMESPACE: &str = "git";
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error("commit {0} is not signed")]
+    Unsigned(String),
+    #[error("commit {commit} has an invalid signature: {reason}")]
+    BadSignature { commit: String, reason: String },
+    #[error("commit {commit} is signed by {signer}, which is not an allowed key")]
+    NotAllowed { commit: String, signer: String },
+    #[error("commit {0} must be signed and verified")]
+    Unverified(String),
+    #[error("allowed key file '{path}': {reason}")]
+    KeyFile { path: String, reason: String },
+    #[error("invalid allowedKeys glob: {0}")]
+    Glob(#[from] globset::Error),
+    #[error("git {0} failed")]
+    Git(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Keys a branch rule accepts commit signatures from.
+#[derive(Debug, Default)]
+pub struct AllowedKeys {
+    /// Whether the rule lists keys or fingerprints at all.
+    declared: bool,
+    ssh: Vec<PublicKey>,
+    /// ASCII-armored OpenPGP public key blocks.
+    pgp: Vec<String>,
+    fingerprints: Vec<String>,
+}
+
+impl AllowedKeys {
+    /// Keys of `rule`, with `allowedKeys` files read from `rev` (a commit already on the server).
+    pub fn load(repo_path: &Path, rev: &str, rule: &BranchRule) -> Result<Self, SigningError> {
+        let globs = rule.allowed_keys.as_deref().unwrap_or_default();
+        let fingerprints = rule.allowed_key_fingerprints.as_deref().unwrap_or_default();
+        let mut keys = AllowedKeys {
+            declared: !globs.is_empty() || !fingerprints.is_empty(),
+            fingerprints: fingerprints
+                .iter()
+                .map(|f| normalize_fingerprint(f))
+                .filter(|f| {
+                    let full = is_full_fingerprint(f);
+                    if !full {
+                        warn!(entry = %f, "ignoring allowedKeyFingerprints entry: not a full fingerprint");
+                    }
+                    full
+                })
+                .collect(),
+            ..AllowedKeys::default()
+        };
+        if globs.is_empty() {
+            return Ok(keys);
+        }
+        let mut set = GlobSetBuilder::new();
+        for glob in globs {
+            set.add(Glob::new(glob)?);
+        }
+        let set = set.build()?;
+        let listing = git(repo_path, &["ls-tree", "-r", "--name-only", "-z", rev], None)?;
+        for path in listing.split(|b| *b == 0).filter_map(|p| std::str::from_utf8(p).ok()) {
+            if !path.is_empty() && set.is_match(path) {
+                let content = git(repo_path, &["show", &format!("{}:{}", rev, path)], None)?;
+                keys.add_key_file(path, &String::from_utf8_lossy(&content))?;
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Add the keys of one key file.
+    pub fn add_key_file(&mut self, path: &str, content: &str) -> Result<(), SigningError> {
+        self.declared = true;
+        if content.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            self.pgp.push(content.to_string());
+            return Ok(());
+        }
+        for line in content.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            // Skip `allowed_signers` principals / `authorized_keys` options before the key type.
+            let start = line
+                .split_whitespace()
+                .position(|t| t.starts_with("ssh-") || t.starts_with("ecdsa-") || t.starts_with("sk-"))
+                .unwrap_or(0);
+            let key = line.split_whitespace().skip(start).collect::<Vec<_>>().join(" ");
+            let key = PublicKey::from_openssh(&key).map_err(|e| SigningError::KeyFile {
+                path: path.to_string(),
+                reason: e.to_string(),
+            })?;
+            self.ssh.push(key);
+        }
+        Ok(())
+    }
+
+    fn allows_fingerprint(&self, fingerprint: &str) -> bool {
+        self.fingerprints.contains(&normalize_fingerprint(fingerprint))
+    }
+}
+
+/// An SSH `SHA256:` fingerprint or a v4 (40 hex digits) / v5 (64 hex digits) OpenPGP fingerprint.
+fn is_full_fingerprint(f: &str) -> bool {
+    f.starts_with("SHA256:") || ((f.len() == 40 || f.len() == 64) && f.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn normalize_fingerprint(f: &str) -> String {
+    let f = f.trim();
+    if f.starts_with("SHA256:") {
+        f.to_string()
+    } else {
+        f.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_uppercase()
+    }
+}
+
+fn git(repo_path: &Path, args: &[&str], stdin: Option<&[u8]>) -> Result<Vec<u8>, SigningError> {
+    let out = run(Command::new("git").arg("-C").arg(repo_path).args(args), stdin)?;
+    if !out.status.success() {
+        return Err(SigningError::Git(args.join(" ")));
+    }
+    Ok(out.stdout)
+}
+
+fn run(cmd: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<std::process::Output> {
+    let mut child = cmd
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(data)?;
+    }
+    child.wait_with_output()
+}
+
+/// Signature of a raw commit object and the payload it signs (the commit without the signature
+/// header).
+pub fn split_signature(raw: &[u8]) -> Option<(String, Vec<u8>)> {
+    let mut payload = Vec::with_capacity(raw.len());
+    let mut signature: Option<Vec<u8>> = None;
+    let (mut in_headers, mut in_signature) = (true, false);
+    for line in raw.split_inclusive(|b| *b == b'\n') {
+        if in_headers {
+            if in_signature && line.starts_with(b" ") {
+                signature.as_mut()?.extend_from_slice(&line[1..]);
+                continue;
+            }
+            in_signature = false;
+            if line == b"\n" {
+                in_headers = false;
+            } else if let Some(rest) = line.strip_prefix(b"gpgsig ").or_else(|| line.strip_prefix(b"gpgsig-sha256 ")) {
+                signature = Some(rest.to_vec());
+                in_signature = true;
+                continue;
+            }
+        }
+        payload.extend_from_slice(line);
+    }
+    Some((String::from_utf8(signature?).ok()?, payload))
+}
+
+/// Verify the signature of `commit` against `keys`, reading the commit through the git CLI (so
+/// objects still in a push quarantine are found). Returns the signer's fingerprint, or `None` when
+/// the rule lists no keys and `git verify-commit` vouched for the commit.
+pub fn verify_commit(repo_path: &Path, commit: &str, keys: &AllowedKeys) -> Result<Option<String>, SigningError> {
+    if !keys.declared {
+        let out = run(Command::new("git").arg("-C").arg(repo_path).args(["verify-commit", commit]), None)?;
+        return if out.status.success() { Ok(None) } else { Err(SigningError::Unverified(commit.to_string())) };
+    }
+    let raw = git(repo_path, &["cat-file", "commit", commit], None)?;
+    let (signature, payload) = split_signature(&raw).ok_or_else(|| SigningError::Unsigned(commit.to_string()))?;
+    if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+        verify_ssh(commit, &signature, &payload, keys).map(Some)
+    } else if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+        verify_pgp(commit, &signature, &payload, keys).map(Some)
+    } else {
+        Err(SigningError::BadSignature { commit: commit.to_string(), reason: "unknown signature format".into() })
+    }
+}
+
+/// Commits a push from `old` to `new` introduces, oldest first. For a new ref (`old` all zeros)
+/// these are the commits of `new` that no existing ref reaches.
+pub fn pushed_commits(repo_path: &Path, old: &str, new: &str) -> Result<Vec<String>, SigningError> {
+    let exclude = format!("^{}", old);
+    let mut args = vec!["rev-list", "--reverse", new];
+    if old.chars().all(|c| c == '0') {
+        args.extend(["--not", "--all"]);
+    } else {
+        args.push(&exclude);
+    }
+    let out = git(repo_path, &args, None)?;
+    Ok(String::from_utf8_lossy(&out).lines().map(str::to_string).collect())
+}
+
+/// Verify every commit of a push from `old` to `new`, stopping at the first (oldest) one that
+/// fails. Returns each verified commit with its signer (see [`verify_commit`]).
+pub fn verify_push(
+    repo_path: &Path,
+    old: &str,
+    new: &str,
+    keys: &AllowedKeys,
+) -> Result<Vec<(String, Option<String>)>, SigningError> {
+    verify_commits(repo_path, pushed_commits(repo_path, old, new)?, keys)
+}
+
+/// Verify `commits` in order, stopping at the first one that fails.
+pub fn verify_commits(
+    repo_path: &Path,
+    commits: impl IntoIterator<Item = String>,
+    keys: &AllowedKeys,
+) -> Result<Vec<(String, Option<String>)>, SigningError> {
+    commits
+        .into_iter()
+        .map(|commit| verify_commit(repo_path, &commit, keys).map(|signer| (commit, signer)))
+        .collect()
+}
+
+fn verify_ssh(commit: &str, signature: &str, payload: &[u8], keys: &AllowedKeys) -> Result<String, SigningError> {
+    let bad = |reason: String| SigningError::BadSignature { commit: commit.to_string(), reason };
+    let sig = SshSig::from_pem(signature.trim_end()).map_err(|e| bad(e.to_string()))?;
+    if sig.namespace() != SSH_NAMESPACE {
+        return Err(bad(format!("namespace '{}' is not '{}'", sig.namespace(), SSH_NAMESPACE)));
+    }
+    let signer = PublicKey::from(sig.public_key().clone());
+    let fingerprint = signer.fingerprint(HashAlg::Sha256).to_string();
+    let allowed = keys.ssh.iter().any(|k| k.key_data() == signer.key_data()) || keys.allows_fingerprint(&fingerprint);
+    if !allowed {
+        return Err(SigningError::NotAllowed { commit: commit.to_string(), signer: fingerprint });
+    }
+    signer.verify(SSH_NAMESPACE, payload, &sig).map_err(|e| bad(e.to_string()))?;
+    Ok(fingerprint)
+}
+
+/// Outcome of `gpg --verify`, from its status output.
+enum GpgStatus {
+    /// Signing key fingerprint and its primary key fingerprint.
+    Valid(String, String),
+    /// Bad signature, or one made by a revoked or expired key: the gpg status keyword.
+    Bad(String),
+    /// No public key for this key id.
+    NoKey(String),
+    Unknown,
+}
+
+fn gpg_verify(home: Option<&Path>, signature: &str, payload: &[u8]) -> Result<GpgStatus, SigningError> {
+    let mut sig_file = tempfile::NamedTempFile::new()?;
+    sig_file.write_all(signature.as_bytes())?;
+    let mut cmd = Command::new("gpg");
+    if let Some(home) = home {
+        cmd.arg("--homedir").arg(home);
+    }
+    cmd.args(["--batch", "--no-tty", "--status-fd", "1", "--verify"]).arg(sig_file.path()).arg("-");
+    let out = run(&mut cmd, Some(payload))?;
+    let status = String::from_utf8_lossy(&out.stdout);
+    // gpg also reports VALIDSIG for signatures of revoked or expired keys, so every line counts.
+    let (mut good, mut valid, mut bad, mut no_key) = (false, None, None, None);
+    for line in status.lines().filter_map(|l| l.strip_prefix("[GNUPG:] ")) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["GOODSIG", ..] => good = true,
+            ["VALIDSIG", fpr, rest @ ..] => {
+                let primary = rest.get(8).unwrap_or(fpr);
+                valid = Some(GpgStatus::Valid(fpr.to_string(), primary.to_string()));
+            }
+            [keyword @ ("BADSIG" | "REVKEYSIG" | "EXPKEYSIG" | "EXPSIG"), ..] => bad = Some(keyword.to_string()),
+            ["ERRSIG", keyid, ..] | ["NO_PUBKEY", keyid, ..] => no_key = Some(keyid.to_string()),
+            _ => {}
+        }
+    }
+    Ok(match (bad, valid, no_key) {
+        (Some(keyword), _, _) => GpgStatus::Bad(keyword),
+        (None, Some(valid), _) if good => valid,
+        (None, _, Some(keyid)) => GpgStatus::NoKey(keyid),
+        _ => GpgStatus::Unknown,
+    })
+}
+
+fn verify_pgp(commit: &str, signature: &str, payload: &[u8], keys: &AllowedKeys) -> Result<String, SigningError> {
+    let mut status = GpgStatus::Unknown;
+    if !keys.pgp.is_empty() {
+        let home = tempfile::tempdir()?;
+        // An existing (empty) common.conf keeps gpg from enabling keyboxd in the throwaway home.
+        std::fs::write(home.path().join("common.conf"), "")?;
+        let armored = keys.pgp.join("\n");
+        run(
+            Command::new("gpg").arg("--homedir").arg(home.path()).args(["--batch", "--no-tty", "--import"]),
+            Some(armored.as_bytes()),
+        )?;
+        status = gpg_verify(Some(home.path()), signature, payload)?;
+        if let GpgStatus::Valid(_, primary) = &status {
+            return Ok(primary.clone());
+        }
+    }
+    if !keys.fingerprints.is_empty() {
+        status = match gpg_verify(None, signature, payload)? {
+            GpgStatus::Valid(fpr, primary) if keys.allows_fingerprint(&fpr) || keys.allows_fingerprint(&primary) => {
+                return Ok(primary)
+            }
+            GpgStatus::Valid(_, primary) => GpgStatus::NoKey(primary),
+            other => other,
+        };
+    }
+    Err(match status {
+        GpgStatus::Bad(keyword) => {
+            SigningError::BadSignature { commit: commit.to_string(), reason: format!("gpg reported {}", keyword) }
+        }
+        GpgStatus::NoKey(signer) => SigningError::NotAllowed { commit: commit.to_string(), signer },
+        GpgStatus::Valid(..) | GpgStatus::Unknown => SigningError::Unverified(commit.to_string()),
+    })
+}
@@ -72,4 +72,1548 @@ git:
         let config = read_relay_config(&repo, "main");
         assert!(config.is_none());
     }
+
+    /// Commit nested `files` on top of `parent`; `None` content deletes the path.
+    fn commit_paths(
+        repo: &Repository,
+        refname: &str,
+        parent: Option<git2::Oid>,
+        files: &[(&str, Option<&str>)],
+    ) -> git2::Oid {
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let parent = parent.map(|p| repo.find_commit(p).unwrap());
+        let mut index = git2::Index::new().unwrap();
+        if let Some(p) = &parent {
+            index.read_tree(&p.tree().unwrap()).unwrap();
+        }
+        for (path, content) in files {
+            match content {
+                Some(content) => {
+                    let entry = git2::IndexEntry {
+                        ctime: git2::IndexTime::new(0, 0),
+                        mtime: git2::IndexTime::new(0, 0),
+                        dev: 0,
+                        ino: 0,
+                        mode: 0o100644,
+                        uid: 0,
+                        gid: 0,
+                        file_size: content.len() as u32,
+                        id: repo.blob(content.as_bytes()).unwrap(),
+                        flags: 0,
+                        flags_extended: 0,
+                        path: path.as_bytes().to_vec(),
+                    };
+                    index.add(&entry).unwrap();
+                }
+                None => index.remove_path(std::path::Path::new(path)).unwrap(),
+            }
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo).unwrap()).unwrap();
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let oid = repo.commit(None, &sig, &sig, "commit", &tree, &parents).unwrap();
+        repo.reference(refname, oid, true, "test").unwrap();
+        oid
+    }
+
+    fn commit_config(repo: &Repository, refname: &str, config_yaml: &str) -> git2::Oid {
+        commit_paths(repo, refname, None, &[(".relay.yaml", Some(config_yaml))])
+    }
+
+    #[test]
+    fn test_branch_create_rename_delete() {
+        use crate::git::branches::{create_branch, delete_branch, list_branch_details, rename_branch};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let head = commit_config(&repo, "refs/heads/main", "name: test\n");
+
+        let created = create_branch(&repo, "feature/preview", Some("main")).unwrap();
+        assert_eq!(created.commit, head.to_string());
+        assert_eq!(created.author, "test");
+        assert!(create_branch(&repo, "feature/preview", None).is_err());
+        assert!(create_branch(&repo, "bad..name", None).is_err());
+        assert!(create_branch(&repo, "other", Some("missing-rev")).is_err());
+
+        let renamed = rename_branch(&repo, "feature/preview", "feature/renamed").unwrap();
+        assert_eq!(renamed.name, "feature/renamed");
+
+        let names: Vec<String> = list_branch_details(&repo).unwrap().into_iter().map(|b| b.name).collect();
+        assert_eq!(names, vec!["feature/renamed".to_string(), "main".to_string()]);
+
+        delete_branch(&repo, "feature/renamed").unwrap();
+        assert!(delete_branch(&repo, "feature/renamed").is_err());
+    }
+
+    #[test]
+    fn test_protected_branch_cannot_be_deleted() {
+        use crate::git::branches::{delete_branch, list_branch_details, BranchError};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let config_yaml = r#"
+git:
+  branchRules:
+    branches:
+      - name: staging
+        rule:
+          protected: true
+"#;
+        commit_config(&repo, "refs/heads/main", config_yaml);
+        commit_config(&repo, "refs/heads/staging", config_yaml);
+
+        let staging = list_branch_details(&repo)
+            .unwrap()
+            .into_iter()
+            .find(|b| b.name == "staging")
+            .unwrap();
+        assert!(staging.protected);
+        assert!(matches!(delete_branch(&repo, "staging"), Err(BranchError::Rejected(_))));
+    }
+
+    #[test]
+    fn test_branch_rule_patterns_and_precedence() {
+        use crate::git::branches::{branch_rule, delete_branch, push_rule, rename_branch, trusted_rev, BranchError};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let config_yaml = r#"
+git:
+  branchRules:
+    default:
+      requireSigned: true
+    branches:
+      - name: "users/**"
+        rule:
+          allowUnsigned: true
+      - name: "release/*"
+        rule:
+          protected: true
+      - name: "release/legacy"
+        rule:
+          protected: false
+      - name: "preview-*"
+        rule:
+          allowUnsigned: true
+      - name: "preview-*-pinned"
+        rule:
+          protected: true
+"#;
+        commit_config(&repo, "refs/heads/main", config_yaml);
+        let protected = |branch: &str| branch_rule(&repo, branch).unwrap().is_protected();
+        let signed = |branch: &str| branch_rule(&repo, branch).unwrap().requires_signature();
+
+        assert!(protected("release/1.0"));
+        assert!(!protected("release/legacy"), "an exact name beats a pattern");
+        assert!(!protected("release/1.0/hotfix"), "`*` stays within one segment");
+        assert!(signed("release/1.0/hotfix"));
+        assert!(!signed("users/ana/wip"));
+        assert!(!signed("preview-42"));
+        assert!(protected("preview-42-pinned"), "more literal characters win");
+        assert!(signed("feature/x"));
+
+        // Rules come from the default branch, not from the branch's own (relaxed) config.
+        commit_config(&repo, "refs/heads/release/2.0", "git:\n  branchRules: {}\n");
+        assert!(protected("release/2.0"));
+        assert!(matches!(delete_branch(&repo, "release/2.0"), Err(BranchError::Rejected(_))));
+        assert!(matches!(rename_branch(&repo, "release/2.0", "scratch"), Err(BranchError::Rejected(_))));
+
+        // A push resolves the same rule as a PUT, even after an earlier push relaxed the branch's own config.
+        let relaxed = commit_config(&repo, "refs/heads/feature/x", "git:\n  branchRules: {}\n");
+        for branch in ["feature/x", "release/2.0", "users/ana/wip"] {
+            let (base, pushed) = push_rule(&repo, branch, &relaxed.to_string()).unwrap().unwrap();
+            let put = branch_rule(&repo, branch).unwrap();
+            assert_eq!(base, trusted_rev(&repo).unwrap());
+            assert_eq!(base, repo.refname_to_id("refs/heads/main").unwrap().to_string());
+            assert_eq!(
+                (pushed.is_protected(), pushed.requires_signature()),
+                (put.is_protected(), put.requires_signature())
+            );
+        }
+        assert!(push_rule(&repo, "feature/x", &relaxed.to_string()).unwrap().unwrap().1.requires_signature());
+    }
+
+    #[test]
+    fn test_merge_fast_forward_and_merge_commit() {
+        use crate::git::merge::{merge_into_branch, MergeOutcome};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let base = commit_paths(&repo, "refs/heads/main", None, &[("a.txt", Some("a"))]);
+        let staged = commit_paths(&repo, "refs/heads/staging", Some(base), &[("b.txt", Some("b"))]);
+
+        match merge_into_branch(&repo, "staging", "main", None).unwrap() {
+            MergeOutcome::FastForward { commit } => assert_eq!(commit, staged.to_string()),
+            other => panic!("expected fast-forward, got {:?}", other),
+        }
+        assert!(matches!(
+            merge_into_branch(&repo, "staging", "main", None).unwrap(),
+            MergeOutcome::UpToDate { .. }
+        ));
+
+        commit_paths(&repo, "refs/heads/main", Some(staged), &[("c.txt", Some("c"))]);
+        commit_paths(&repo, "refs/heads/staging", Some(staged), &[("d.txt", Some("d"))]);
+        let merged = match merge_into_branch(&repo, "staging", "main", None).unwrap() {
+            MergeOutcome::Merged { commit } => commit,
+            other => panic!("expected merge commit, got {:?}", other),
+        };
+        let commit = repo.find_commit(git2::Oid::from_str(&merged).unwrap()).unwrap();
+        assert_eq!(commit.parent_count(), 2);
+        let tree = commit.tree().unwrap();
+        assert!(tree.get_name("c.txt").is_some() && tree.get_name("d.txt").is_some());
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), commit.id());
+    }
+
+    #[test]
+    fn test_merge_conflicts_leave_refs_untouched() {
+        use crate::git::merge::{merge_into_branch, MergeOutcome};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let base = commit_paths(&repo, "refs/heads/main", None, &[("a.txt", Some("a"))]);
+        let main_tip = commit_paths(&repo, "refs/heads/main", Some(base), &[("a.txt", Some("main"))]);
+        commit_paths(&repo, "refs/heads/staging", Some(base), &[("a.txt", Some("staging"))]);
+
+        match merge_into_branch(&repo, "staging", "main", None).unwrap() {
+            MergeOutcome::Conflicts { conflicts } => {
+                assert_eq!(conflicts.len(), 1);
+                assert_eq!(conflicts[0].path, "a.txt");
+                assert!(conflicts[0].ours.is_some() && conflicts[0].theirs.is_some());
+            }
+            other => panic!("expected conflicts, got {:?}", other),
+        }
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), main_tip);
+    }
+
+    #[test]
+    fn test_revert_commit_creates_inverse_commit() {
+        use crate::git::history::{revert_commit, HistoryOutcome};
+        use crate::git::BranchError;
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let base = commit_paths(&repo, "refs/heads/main", None, &[("a.txt", Some("a"))]);
+        let bad = commit_paths(&repo, "refs/heads/main", Some(base), &[("a.txt", Some("broken"))]);
+        commit_paths(&repo, "refs/heads/main", Some(bad), &[("b.txt", Some("b"))]);
+
+        match revert_commit(&repo, "main", &bad.to_string(), None, None).unwrap() {
+            HistoryOutcome::Committed { .. } => {}
+            other => panic!("expected commit, got {:?}", other),
+        }
+        let content = crate::git::read_file_from_repo(&repo.path().to_path_buf(), "main", "a.txt").unwrap();
+        assert_eq!(content, b"a");
+        let tip = repo.find_reference("refs/heads/main").unwrap().peel_to_commit().unwrap();
+        assert!(tip.tree().unwrap().get_name("b.txt").is_some());
+        assert!(tip.message().unwrap().starts_with("Revert"));
+
+        // A merge without a mainline is a malformed request, not a refused one.
+        let side = commit_paths(&repo, "refs/heads/side", Some(base), &[("c.txt", Some("c"))]);
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let side_commit = repo.find_commit(side).unwrap();
+        let merge = repo
+            .commit(Some("refs/heads/main"), &sig, &sig, "merge", &side_commit.tree().unwrap(), &[&tip, &side_commit])
+            .unwrap();
+        let err = revert_commit(&repo, "main", &merge.to_string(), None, None).unwrap_err();
+        assert!(matches!(err, BranchError::BadRequest(_)));
+        assert_eq!(crate::handlers::branches::branch_error_response(err).status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_restore_path_from_older_revision() {
+        use crate::git::history::{restore_path, HistoryOutcome};
+        use git2::build::TreeUpdateBuilder;
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let empty = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        let write = |base: &git2::Tree, path: &str, content: &str| {
+            let blob = repo.blob(content.as_bytes()).unwrap();
+            let oid = TreeUpdateBuilder::new()
+                .upsert(path, blob, git2::FileMode::Blob)
+                .create_updated(&repo, base)
+                .unwrap();
+            repo.find_tree(oid).unwrap()
+        };
+        let t1 = write(&empty, "docs/a.md", "v1");
+        let c1 = repo.commit(Some("refs/heads/main"), &sig, &sig, "v1", &t1, &[]).unwrap();
+        let t2 = write(&write(&t1, "docs/a.md", "v2"), "docs/new.md", "new");
+        let p1 = repo.find_commit(c1).unwrap();
+        repo.commit(Some("refs/heads/main"), &sig, &sig, "v2", &t2, &[&p1]).unwrap();
+
+        let root = repo.path().to_path_buf();
+        assert!(matches!(
+            restore_path(&repo, "main", "docs/a.md", &c1.to_string(), None).unwrap(),
+            HistoryOutcome::Committed { .. }
+        ));
+        assert_eq!(crate::git::read_file_from_repo(&root, "main", "docs/a.md").unwrap(), b"v1");
+        assert!(crate::git::read_file_from_repo(&root, "main", "docs/new.md").is_ok());
+
+        restore_path(&repo, "main", "docs", &c1.to_string(), None).unwrap();
+        assert!(crate::git::read_file_from_repo(&root, "main", "docs/new.md").is_err());
+        assert!(matches!(
+            restore_path(&repo, "main", "docs", &c1.to_string(), None).unwrap(),
+            HistoryOutcome::Unchanged { .. }
+        ));
+
+        let err = restore_path(&repo, "main", "/", &c1.to_string(), None).unwrap_err();
+        assert!(matches!(err, crate::git::BranchError::BadRequest(_)));
+        assert_eq!(crate::handlers::branches::branch_error_response(err).status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_resumable_upload_commits_blob() {
+        use crate::git::uploads::{append_chunk, create_session, finalize, load_session, UploadError};
+        use std::io::Write;
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        commit_paths(&repo, "refs/heads/main", None, &[("a.txt", Some("a"))]);
+        let root = repo.path().to_path_buf();
+
+        let session = create_session(&root, "main", "media/clip.bin", Some(6), None).unwrap();
+        append_chunk(&root, &session.id, 0, b"abc").unwrap();
+        assert!(matches!(
+            append_chunk(&root, &session.id, 0, b"abc"),
+            Err(UploadError::OffsetMismatch { expected: 3 })
+        ));
+        assert!(matches!(finalize(&repo, &session.id), Err(UploadError::Incomplete { .. })));
+        append_chunk(&root, &session.id, 3, b"def").unwrap();
+        assert_eq!(load_session(&root, &session.id).unwrap().offset, 6);
+
+        let (_, commit) = finalize(&repo, &session.id).unwrap();
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap().to_string(), commit);
+        assert_eq!(crate::git::read_file_from_repo(&root, "main", "media/clip.bin").unwrap(), b"abcdef");
+        assert!(crate::git::read_file_from_repo(&root, "main", "a.txt").is_ok());
+        assert!(matches!(load_session(&root, &session.id), Err(UploadError::NotFound(_))));
+
+        // Deferred length: only the bytes a PATCH has recorded may be committed.
+        let deferred = create_session(&root, "main", "media/open.bin", None, None).unwrap();
+        append_chunk(&root, &deferred.id, 0, b"abc").unwrap();
+        let data = crate::git::uploads::data_path(&root, &deferred.id).unwrap();
+        std::fs::OpenOptions::new().append(true).open(&data).unwrap().write_all(b"de").unwrap();
+        assert!(matches!(
+            finalize(&repo, &deferred.id),
+            Err(UploadError::Unsettled { offset: 3, size: 5 })
+        ));
+        append_chunk(&root, &deferred.id, 3, b"de").unwrap();
+        finalize(&repo, &deferred.id).unwrap();
+        assert_eq!(crate::git::read_file_from_repo(&root, "main", "media/open.bin").unwrap(), b"abcde");
+
+        // Files too large to inline into the hook context are still checked against schemas.
+        let head = repo.refname_to_id("refs/heads/main").unwrap();
+        let config = "server:\n  db:\n    schemas:\n      - glob: \"catalog/*.json\"\n        schema: { type: object, required: [sku] }\n";
+        let head = commit_paths(&repo, "refs/heads/main", Some(head), &[(".relay.yaml", Some(config))]);
+        let padded = format!("{{\"name\": \"x\"{}}}", " ".repeat(3 * 1024 * 1024));
+        let large = create_session(&root, "main", "catalog/x.json", Some(padded.len() as u64), None).unwrap();
+        append_chunk(&root, &large.id, 0, padded.as_bytes()).unwrap();
+        assert!(matches!(
+            finalize(&repo, &large.id),
+            Err(UploadError::Branch(crate::git::BranchError::Commit(crate::git::commit::CommitError::Invalid(_))))
+        ));
+        assert_eq!(repo.refname_to_id("refs/heads/main").unwrap(), head);
+    }
+
+    #[test]
+    fn test_provision_template_clone_and_archive() {
+        use crate::git::provision::{archive_repo, create_repo, delete_repo, ProvisionError, RepoSource};
+
+        let root = tempdir().unwrap();
+        let root = root.path();
+        assert!(matches!(
+            create_repo(root, "Bad_Name", &RepoSource::Empty),
+            Err(ProvisionError::InvalidName(_))
+        ));
+        for source in ["--upload-pack=touch pwned", "ext::sh -c touch% pwned", "EXT::sh"] {
+            assert!(matches!(
+                create_repo(root, "evil", &RepoSource::Url(source.to_string())),
+                Err(ProvisionError::InvalidSource(_))
+            ));
+        }
+        assert!(!root.join("evil.git").exists());
+
+        let empty = create_repo(root, "blank", &RepoSource::Empty).unwrap();
+        assert!(empty.branches.is_empty());
+        let blank = Repository::open_bare(root.join("blank.git")).unwrap();
+        assert_eq!(blank.find_reference("HEAD").unwrap().symbolic_target(), Some("refs/heads/main"));
+
+        commit_paths(&blank, "refs/heads/main", None, &[("index.md", Some("# template"))]);
+        let copy = create_repo(root, "project-a", &RepoSource::Template("blank".into())).unwrap();
+        assert_eq!(copy.branches, vec!["main".to_string()]);
+        let project = Repository::open_bare(root.join("project-a.git")).unwrap();
+        assert!(project.find_remote("origin").is_err());
+        assert!(matches!(
+            create_repo(root, "project-a", &RepoSource::Empty),
+            Err(ProvisionError::AlreadyExists(_))
+        ));
+
+        let archived = archive_repo(root, "project-a").unwrap();
+        assert!(archived.starts_with(root.join(".archive")));
+        assert!(!root.join("project-a.git").exists());
+        delete_repo(root, "blank").unwrap();
+        assert!(matches!(delete_repo(root, "blank"), Err(ProvisionError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_fork_copies_selected_branches_and_records_upstream() {
+        use crate::git::provision::{
+            archive_repo, delete_repo, dependent_forks, fork_repo, ObjectSharing, ProvisionError,
+        };
+
+        let root = tempdir().unwrap();
+        let root = root.path();
+        let src = Repository::init_bare(root.join("origin.git")).unwrap();
+        let c1 = commit_paths(&src, "refs/heads/main", None, &[("a.txt", Some("a"))]);
+        commit_paths(&src, "refs/heads/dev", Some(c1), &[("b.txt", Some("b"))]);
+
+        let fork = fork_repo(root, "origin", "mine", &["main".to_string()], ObjectSharing::Hardlink).unwrap();
+        assert_eq!(fork.branches, vec!["main".to_string()]);
+        let mine = Repository::open_bare(root.join("mine.git")).unwrap();
+        assert_eq!(crate::git::list_branches(&mine), vec!["main".to_string()]);
+        assert_eq!(mine.config().unwrap().get_string("relay.upstream").unwrap(), "origin");
+        assert!(mine.find_remote("upstream").is_ok());
+
+        let shared = fork_repo(root, "origin", "shared", &[], ObjectSharing::Alternates).unwrap();
+        assert_eq!(shared.branches.len(), 2);
+        assert!(root.join("shared.git/objects/info/alternates").exists());
+        assert!(matches!(
+            fork_repo(root, "origin", "bad", &["nope".to_string()], ObjectSharing::Hardlink),
+            Err(ProvisionError::NotFound(_))
+        ));
+        assert!(!root.join("bad.git").exists());
+
+        // The shared fork borrows the upstream's objects, so the upstream cannot go away.
+        assert_eq!(dependent_forks(root, "origin"), ["shared"]);
+        for res in [archive_repo(root, "origin").map(|_| ()), delete_repo(root, "origin")] {
+            assert!(matches!(res, Err(ProvisionError::HasDependentForks(_, forks)) if forks == ["shared"]));
+        }
+        let repack = std::process::Command::new("git")
+            .args(["repack", "-a", "-d", "-q"])
+            .current_dir(root.join("shared.git"))
+            .status()
+            .unwrap();
+        assert!(repack.success());
+        std::fs::remove_file(root.join("shared.git/objects/info/alternates")).unwrap();
+        assert!(dependent_forks(root, "origin").is_empty());
+
+        // Forks keep working after the upstream is gone.
+        drop(src);
+        delete_repo(root, "origin").unwrap();
+        assert_eq!(crate::git::read_file_from_repo(&root.join("mine.git"), "main", "a.txt").unwrap(), b"a");
+        assert_eq!(crate::git::read_file_from_repo(&root.join("shared.git"), "dev", "b.txt").unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_filter_operators_paths_and_arrays() {
+        use crate::git::filter::{Filter, FilterError};
+        use serde_json::json;
+
+        let docs = [
+            json!({"title": "Alien", "year": 1979, "meta": {"director": {"name": "Scott"}}, "tags": ["scifi", "horror"],
+                   "cast": [{"name": "Weaver", "role": "Ripley"}]}),
+            json!({"title": "Heat", "year": 1995, "meta": {"director": {"name": "Mann"}}, "tags": ["crime"],
+                   "cast": [{"name": "Pacino", "role": "Hanna"}, {"name": "De Niro", "role": "McCauley"}]}),
+            json!({"title": "Untitled", "tags": []}),
+        ];
+        let titles = |f: serde_json::Value| -> Vec<String> {
+            let filter = Filter::parse(&f).unwrap();
+            docs.iter()
+                .filter(|d| filter.matches(d))
+                .map(|d| d["title"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(titles(json!({"year": {"$gte": 1980, "$lt": 2000}})), ["Heat"]);
+        assert_eq!(titles(json!({"meta.director.name": "Scott"})), ["Alien"]);
+        assert_eq!(titles(json!({"tags": "crime"})), ["Heat"]);
+        assert_eq!(titles(json!({"tags": {"$in": ["horror", "western"]}})), ["Alien"]);
+        assert_eq!(titles(json!({"tags": {"$nin": ["horror"]}})), ["Heat", "Untitled"]);
+        assert_eq!(titles(json!({"year": {"$exists": false}})), ["Untitled"]);
+        assert_eq!(titles(json!({"year": null})), ["Untitled"]);
+        assert_eq!(titles(json!({"title": {"$ne": "Heat"}})), ["Alien", "Untitled"]);
+        assert_eq!(titles(json!({"title": {"$regex": "^a", "$options": "i"}})), ["Alien"]);
+        assert_eq!(titles(json!({"title": {"$not": {"$regex": "a"}}})), ["Alien", "Untitled"]);
+        assert_eq!(titles(json!({"cast.name": "De Niro"})), ["Heat"]);
+        assert_eq!(titles(json!({"cast.0.role": "Ripley"})), ["Alien"]);
+        assert_eq!(
+            titles(json!({"cast": {"$elemMatch": {"name": "Pacino", "role": "Hanna"}}})),
+            ["Heat"]
+        );
+        assert!(titles(json!({"cast": {"$elemMatch": {"name": "Pacino", "role": "McCauley"}}})).is_empty());
+        assert_eq!(titles(json!({"tags": {"$size": 0}})), ["Untitled"]);
+        assert_eq!(
+            titles(json!({"$or": [{"year": 1979}, {"tags": "crime"}], "title": {"$ne": "Alien"}})),
+            ["Heat"]
+        );
+        assert_eq!(titles(json!({"$not": {"year": {"$exists": true}}})), ["Untitled"]);
+
+        assert!(matches!(Filter::parse(&json!({"year": {"$near": 1}})), Err(FilterError::UnknownOperator(_))));
+        assert!(matches!(Filter::parse(&json!({"title": {"$regex": "("}})), Err(FilterError::Regex(_))));
+        assert!(matches!(Filter::parse(&json!({"$or": []})), Err(FilterError::BadOperand { .. })));
+    }
+
+    #[test]
+    fn test_paginate_sort_and_project() {
+        use crate::git::query::{paginate, PageOptions};
+        use serde_json::json;
+
+        let items: Vec<serde_json::Value> = (0..60)
+            .map(|i| json!({"n": i, "group": i % 3, "meta": {"title": format!("t{}", i), "body": "x"}}))
+            .collect();
+        let opts: PageOptions = serde_json::from_value(json!({
+            "page": 1,
+            "pageSize": 10,
+            "sort": [{"field": "group", "dir": "desc"}, {"field": "n", "dir": 1}],
+            "projection": ["n", "meta.title"]
+        }))
+        .unwrap();
+        let page = paginate(items.clone(), &opts).unwrap();
+        assert_eq!((page.total, page.page, page.page_size), (60, 1, 10));
+        assert_eq!(page.items.len(), 10);
+        assert_eq!(page.items[0], json!({"n": 32, "meta": {"title": "t32"}}));
+
+        let opts: PageOptions = serde_json::from_value(json!({"projection": {"meta.body": 0}})).unwrap();
+        let page = paginate(items.clone(), &opts).unwrap();
+        assert_eq!(page.page_size, 25);
+        assert_eq!(page.items[0], json!({"n": 0, "group": 0, "meta": {"title": "t0"}}));
+
+        let past_end: PageOptions = serde_json::from_value(json!({"page": 9, "pageSize": 10})).unwrap();
+        assert!(paginate(items.clone(), &past_end).unwrap().items.is_empty());
+        let mixed: PageOptions = serde_json::from_value(json!({"projection": {"n": 1, "meta": 0}})).unwrap();
+        assert!(paginate(items, &mixed).is_err());
+        assert!(serde_json::from_value::<PageOptions>(json!({"sort": [{"field": "n", "dir": "up"}]})).is_err());
+    }
+
+    #[test]
+    fn test_index_db_journal_apply_and_indexed_queries() {
+        use crate::git::filter::Filter;
+        use crate::git::indexdb::{read_journal, BranchIndex};
+        use serde_json::json;
+
+        let dir = tempdir().unwrap();
+        let journal = dir.path().join("journal.jsonl");
+        let mut lines = vec![json!({"op": "createIndex", "collection": "index", "field": "year"}).to_string()];
+        for i in 0..20 {
+            lines.push(
+                json!({"op": "insert", "collection": "index", "doc": {"_id": i, "year": 2000 + i, "tags": ["a", format!("t{}", i % 2)]}})
+                    .to_string(),
+            );
+        }
+        std::fs::write(&journal, lines.join("\n")).unwrap();
+
+        let mut index = BranchIndex::open(dir.path(), "main").unwrap();
+        let ops = read_journal(&journal).unwrap();
+        assert_eq!(index.apply(&ops, Some("abc123")).unwrap(), 20);
+        assert_eq!(index.indexed_head().unwrap().as_deref(), Some("abc123"));
+        assert_eq!(index.indexes("index").unwrap(), vec!["year".to_string()]);
+        index.ensure_indexes("index", &["tags".to_string()]).unwrap();
+
+        let find = |index: &BranchIndex, f: serde_json::Value| {
+            let f = Filter::parse(&f).unwrap();
+            index.find("index", Some(&f)).unwrap()
+        };
+        assert_eq!(find(&index, json!({"year": {"$gte": 2015, "$lt": 2018}})).len(), 3);
+        assert_eq!(find(&index, json!({"year": {"$in": [2001, 2003, 1999]}})).len(), 2);
+        assert_eq!(find(&index, json!({"tags": "t1", "year": {"$lte": 2005}})).len(), 3);
+        // Non-pushable operators still evaluate exactly.
+        assert_eq!(find(&index, json!({"year": {"$ne": 2000}})).len(), 19);
+        // A scan hands matches over one by one and stops when told to.
+        let mut seen = Vec::new();
+        let odd = Filter::parse(&json!({"tags": "t1"})).unwrap();
+        index
+            .scan("index", Some(&odd), |doc| {
+                seen.push(doc["_id"].clone());
+                seen.len() < 3
+            })
+            .unwrap();
+        assert_eq!(seen, [json!(1), json!(3), json!(5)]);
+        let first = index.find_limited("index", None, 2).unwrap();
+        assert_eq!(first.iter().map(|d| d["_id"].clone()).collect::<Vec<_>>(), [json!(0), json!(1)]);
+        let odd_first = index.find_limited("index", Some(&odd), 2).unwrap();
+        assert_eq!(odd_first.iter().map(|d| d["_id"].clone()).collect::<Vec<_>>(), [json!(1), json!(3)]);
+        assert!(index.find_limited("index", None, 0).unwrap().is_empty());
+
+        let ops = vec![
+            serde_json::from_value(json!({"op": "update", "collection": "index", "query": {"year": 2004}, "update": {"year": 1990}})).unwrap(),
+            serde_json::from_value(json!({"op": "remove", "collection": "index", "query": {"tags": "t0"}})).unwrap(),
+        ];
+        assert_eq!(index.apply(&ops, None).unwrap(), 11);
+        assert_eq!(index.count("index").unwrap(), 10);
+        assert!(find(&index, json!({"year": 2004})).is_empty());
+        assert!(find(&index, json!({"year": 1990})).is_empty()); // 2004 was in t0 and removed
+        assert_eq!(find(&index, json!({"year": {"$lt": 2002}})).len(), 1);
+        assert_eq!(index.indexed_head().unwrap().as_deref(), Some("abc123"));
+
+        // A failing op rolls back the whole journal.
+        let bad = vec![
+            serde_json::from_value(json!({"op": "insert", "collection": "index", "doc": {"_id": 99}})).unwrap(),
+            serde_json::from_value(json!({"op": "remove", "collection": "index", "query": {"$bogus": 1}})).unwrap(),
+        ];
+        assert!(index.apply(&bad, Some("def456")).is_err());
+        assert_eq!(index.count("index").unwrap(), 10);
+        assert_eq!(index.indexed_head().unwrap().as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_index_db_migrates_legacy_json() {
+        use crate::git::indexdb::{branch_dir, migrate_all, BranchIndex, LEGACY_DB_FILE};
+        use serde_json::json;
+
+        let root = tempdir().unwrap();
+        let repo = root.path().join("demo.git");
+        let dir = branch_dir(&repo, "main");
+        std::fs::create_dir_all(&dir).unwrap();
+        let legacy = json!({
+            "metadata": {"indexed_head": "feedbeef"},
+            "collections": {"index": [{"_id": 1, "title": "A"}, {"_id": 2, "title": "B"}], "other": [{"x": 1}]}
+        });
+        std::fs::write(dir.join(LEGACY_DB_FILE), legacy.to_string()).unwrap();
+
+        assert_eq!(migrate_all(root.path()), 1);
+        assert!(!dir.join(LEGACY_DB_FILE).exists());
+        assert!(dir.join("index.db.json.migrated").exists());
+        assert_eq!(migrate_all(root.path()), 0);
+
+        let index = BranchIndex::open_existing(&repo, "main").unwrap().unwrap();
+        assert_eq!(index.indexed_head().unwrap().as_deref(), Some("feedbeef"));
+        assert_eq!(index.collections().unwrap(), vec!["index".to_string(), "other".to_string()]);
+        assert_eq!(index.count("index").unwrap(), 2);
+        assert!(BranchIndex::open_existing(&repo, "dev").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_index_db_find_pending_reads_own_writes() {
+        use crate::git::filter::Filter;
+        use crate::git::indexdb::{BranchIndex, DbOp};
+        use serde_json::json;
+
+        let op = |v: serde_json::Value| -> DbOp { serde_json::from_value(v).unwrap() };
+        let dir = tempdir().unwrap();
+        let mut index = BranchIndex::open(dir.path(), "main").unwrap();
+        index
+            .apply(&[op(json!({"op": "insert", "collection": "index", "doc": {"n": 1, "tag": "a"}}))], Some("abc"))
+            .unwrap();
+
+        let pending = [
+            op(json!({"op": "insert", "collection": "index", "doc": {"n": 2, "tag": "a"}})),
+            op(json!({"op": "update", "collection": "index", "query": {"tag": "a"}, "update": {"tag": "b"}})),
+        ];
+        let filter = Filter::parse(&json!({"tag": "b"})).unwrap();
+        assert_eq!(index.find_pending(&pending, "index", Some(&filter)).unwrap().len(), 2);
+        // Rolled back: the committed store is unchanged.
+        assert_eq!(index.find("index", Some(&filter)).unwrap().len(), 0);
+        assert_eq!(index.count("index").unwrap(), 1);
+
+        let mut scratch = BranchIndex::open_in_memory().unwrap();
+        assert_eq!(scratch.find_pending(&pending, "index", None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_branch_dirs_do_not_collide_and_legacy_dirs_move() {
+        use crate::git::indexdb::{branch_dir, migrate_all, BranchIndex, DbOp, DB_FILE, LEGACY_DB_FILE};
+        use serde_json::json;
+
+        let root = tempdir().unwrap();
+        let repo = root.path().join("demo.git");
+        let git = Repository::init_bare(&repo).unwrap();
+        let sig = Signature::now("relay", "relay@local").unwrap();
+        let tree = git.find_tree(git.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        let head = git.commit(None, &sig, &sig, "init", &tree, &[]).unwrap();
+        let head = git.find_commit(head).unwrap();
+        for branch in ["dev", "release/2024", "feature/a", "feature/b"] {
+            git.branch(branch, &head, false).unwrap();
+        }
+        assert_ne!(branch_dir(&repo, "feature/a"), branch_dir(&repo, "feature/b"));
+        assert_eq!(branch_dir(&repo, ""), branch_dir(&repo, "main"));
+        let legacy_dir = |branch: &str| {
+            let dir = repo.join(".relay_data").join("branches").join(&hex::encode(branch)[..12.min(branch.len() * 2)]);
+            std::fs::create_dir_all(&dir).unwrap();
+            dir
+        };
+
+        // Short legacy name (`hex("dev")`): renamed on first open, data kept.
+        let legacy = legacy_dir("dev");
+        let op: DbOp = serde_json::from_value(json!({"op": "insert", "collection": "index", "doc": {"a": 1}})).unwrap();
+        BranchIndex::open_path(&legacy.join(DB_FILE)).unwrap().apply(&[op], Some("abc")).unwrap();
+        let index = BranchIndex::open_existing(&repo, "dev").unwrap().unwrap();
+        assert_eq!(index.count("index").unwrap(), 1);
+        assert!(!legacy.exists());
+        assert!(branch_dir(&repo, "dev").join(DB_FILE).exists());
+
+        // Truncated names: `releas` belongs to one branch and moves at startup; `featur` is shared
+        // by two branches and stays where it is, without an index.db created in it.
+        let store = json!({"metadata": {"indexed_head": "feedbeef"}, "collections": {"index": [{"_id": 1}]}});
+        let release = legacy_dir("release/2024");
+        std::fs::write(release.join(LEGACY_DB_FILE), store.to_string()).unwrap();
+        let shared = legacy_dir("feature/a");
+        assert_eq!(shared, legacy_dir("feature/b"));
+        std::fs::write(shared.join(LEGACY_DB_FILE), store.to_string()).unwrap();
+
+        assert_eq!(migrate_all(root.path()), 1);
+        assert!(!release.exists());
+        let index = BranchIndex::open_existing(&repo, "release/2024").unwrap().unwrap();
+        assert_eq!(index.indexed_head().unwrap().as_deref(), Some("feedbeef"));
+        assert!(shared.join(LEGACY_DB_FILE).exists());
+        assert!(!shared.join(DB_FILE).exists());
+        assert!(BranchIndex::open_existing(&repo, "feature/a").unwrap().is_none());
+        assert!(shared.exists());
+    }
+
+    #[test]
+    fn test_index_db_full_text_search() {
+        use crate::git::indexdb::{BranchIndex, DbOp, IndexDbError, SCORE_FIELD, SNIPPET_FIELD};
+        use serde_json::json;
+
+        let op = |v: serde_json::Value| -> DbOp { serde_json::from_value(v).unwrap() };
+        let dir = tempdir().unwrap();
+        let mut index = BranchIndex::open(dir.path(), "main").unwrap();
+        let ops: Vec<DbOp> = [
+            json!({"title": "Running with Scissors", "meta": {"tags": ["memoir", "run"]}}),
+            json!({"title": "The Runner", "meta": {"tags": ["thriller", "running"]}}),
+            json!({"title": "Gardening", "meta": {"tags": ["home"]}, "body": "not searchable: running"}),
+        ]
+        .into_iter()
+        .map(|doc| op(json!({"op": "insert", "collection": "index", "doc": doc})))
+        .collect();
+        index.apply(&ops, None).unwrap();
+
+        assert!(matches!(index.search("index", "run"), Err(IndexDbError::NotSearchable(_))));
+        index
+            .ensure_search_fields("index", &["title".to_string(), "meta.tags".to_string()])
+            .unwrap();
+        let titles = |index: &BranchIndex, q: &str| -> Vec<String> {
+            index
+                .search("index", q)
+                .unwrap()
+                .iter()
+                .map(|h| h["title"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Stemmed matching; the document matching twice ranks first. `body` is not a search field.
+        let hits = index.search("index", "runs").unwrap();
+        assert_eq!(titles(&index, "runs"), ["Running with Scissors", "The Runner"]);
+        assert!(hits[0][SCORE_FIELD].as_f64().unwrap() > hits[1][SCORE_FIELD].as_f64().unwrap());
+        assert_eq!(hits[0][SNIPPET_FIELD], "<mark>Running</mark> with Scissors\nmemoir\n<mark>run</mark>");
+        assert_eq!(titles(&index, "garden"), ["Gardening"]);
+        assert_eq!(titles(&index, "thrill*"), ["The Runner"]);
+        assert!(titles(&index, "running scissors memoir home").is_empty());
+        // FTS syntax in user input is treated as text.
+        assert_eq!(titles(&index, "\"scissors\" OR (NEAR"), Vec::<String>::new());
+        assert_eq!(titles(&index, "with-scissors!"), ["Running with Scissors"]);
+
+        // Writes keep the text index in step.
+        index
+            .apply(
+                &[
+                    op(json!({"op": "remove", "collection": "index", "query": {"title": "The Runner"}})),
+                    op(json!({"op": "update", "collection": "index", "query": {"title": "Gardening"}, "update": {"title": "Trail running"}})),
+                ],
+                None,
+            )
+            .unwrap();
+        assert_eq!(titles(&index, "run"), ["Running with Scissors", "Trail running"]);
+
+        // Changing the declared fields rebuilds the collection's text index.
+        index.ensure_search_fields("index", &["body".to_string()]).unwrap();
+        assert_eq!(titles(&index, "searchable"), ["Trail running"]);
+        assert!(titles(&index, "scissors").is_empty());
+    }
+
+    #[test]
+    fn test_native_indexer_follows_commit_deltas() {
+        use crate::git::indexdb::BranchIndex;
+        use crate::git::indexer::{default_sources, index_commit, IndexRun};
+        use crate::git::schema::SchemaSet;
+        use crate::types::IndexSource;
+        use serde_json::json;
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let nested = "title: Alien\nmeta:\n  director:\n    name: Ridley Scott\ncast:\n  - name: Sigourney Weaver\n    role: Ripley\n";
+        let c1 = commit_paths(
+            &repo,
+            "refs/heads/main",
+            None,
+            &[
+                ("movies/alien/meta.yaml", Some(nested)),
+                ("movies/heat/meta.yml", Some("title: Heat\nyear: 1995\n")),
+                ("meta.yaml", Some("- not\n- a mapping\n")),
+                ("movies/alien/poster.txt", Some("x")),
+            ],
+        );
+        let sources = default_sources();
+        let run = index_commit(&repo, repo_dir.path(), "main", &c1.to_string(), &sources, &SchemaSet::default()).unwrap();
+        assert_eq!(run, IndexRun { full: true, indexed: 2, ..Default::default() });
+
+        let index = BranchIndex::open_existing(repo_dir.path(), "main").unwrap().unwrap();
+        assert_eq!(index.indexed_head().unwrap(), Some(c1.to_string()));
+        let alien = &index.find("index", None).unwrap()[0];
+        assert_eq!(alien["meta"]["director"]["name"], "Ridley Scott");
+        assert_eq!(alien["cast"][0]["role"], "Ripley");
+        assert_eq!(alien["_path"], "movies/alien/meta.yaml");
+        assert_eq!(alien["_meta_dir"], "movies/alien");
+        assert_eq!(alien["_branch"], "main");
+
+        // Incremental: one edit, one delete, one add.
+        let c2 = commit_paths(
+            &repo,
+            "refs/heads/main",
+            Some(c1),
+            &[
+                ("movies/heat/meta.yml", Some("title: Heat\nyear: 1996\n")),
+                ("movies/alien/meta.yaml", None),
+                ("movies/ran/meta.yaml", Some("title: Ran\n")),
+            ],
+        );
+        let run = index_commit(&repo, repo_dir.path(), "main", &c2.to_string(), &sources, &SchemaSet::default()).unwrap();
+        assert_eq!(run, IndexRun { full: false, indexed: 2, removed: 1, ..Default::default() });
+        let titles = |index: &BranchIndex, c: &str| -> Vec<serde_json::Value> {
+            let mut t: Vec<_> = index.find(c, None).unwrap().iter().map(|d| d["title"].clone()).collect();
+            t.sort_by_key(|v| v.to_string());
+            t
+        };
+        assert_eq!(titles(&index, "index"), [json!("Heat"), json!("Ran")]);
+        assert_eq!(index.find("index", None).unwrap().iter().find(|d| d["title"] == "Heat").unwrap()["year"], 1996);
+
+        // Changing the declared sources rebuilds from the full tree and clears old collections.
+        let custom = vec![IndexSource {
+            glob: "movies/*/meta.{yaml,yml}".to_string(),
+            collection: "movies".to_string(),
+        }];
+        let run = index_commit(&repo, repo_dir.path(), "main", &c2.to_string(), &custom, &SchemaSet::default()).unwrap();
+        assert!(run.full);
+        assert!(index.find("index", None).unwrap().is_empty());
+        assert_eq!(titles(&index, "movies"), [json!("Heat"), json!("Ran")]);
+    }
+
+    #[test]
+    fn test_index_range_replays_commits_and_falls_back_on_force_push() {
+        use crate::git::hooks::HookContext;
+        use crate::git::indexdb::BranchIndex;
+        use crate::git::indexing::{ensure_indexed, plan_range, IndexPlan};
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let config = "server:\n  hooks:\n    index:\n      path: hooks/server/index.mjs\n";
+        let c1 = commit_paths(
+            &repo,
+            "refs/heads/main",
+            None,
+            &[(".relay.yaml", Some(config)), ("hooks/server/index.mjs", Some("process.exit(0);"))],
+        );
+        let c2 = commit_paths(&repo, "refs/heads/main", Some(c1), &[("a/meta.yaml", Some("title: A\n"))]);
+        let c3 = commit_paths(&repo, "refs/heads/main", Some(c2), &[("b/meta.yaml", Some("title: B\n"))]);
+        let rewritten = commit_paths(&repo, "refs/heads/other", Some(c1), &[("c/meta.yaml", Some("title: C\n"))]);
+
+        assert_eq!(plan_range(&repo, "", &c3.to_string()).unwrap(), IndexPlan::Full);
+        assert_eq!(plan_range(&repo, &c3.to_string(), &c3.to_string()).unwrap(), IndexPlan::UpToDate);
+        assert_eq!(plan_range(&repo, &c1.to_string(), &c3.to_string()).unwrap(), IndexPlan::Commits(vec![c2, c3]));
+        assert_eq!(plan_range(&repo, &c3.to_string(), &rewritten.to_string()).unwrap(), IndexPlan::Full);
+        assert_eq!(plan_range(&repo, &"1".repeat(40), &c3.to_string()).unwrap(), IndexPlan::Full);
+
+        let ctx = |commit: git2::Oid| HookContext {
+            repo_path: repo_dir.path().to_path_buf(),
+            old_commit: String::new(),
+            new_commit: commit.to_string(),
+            refname: "refs/heads/main".to_string(),
+            branch: "main".to_string(),
+            is_verified: true,
+            files: std::collections::HashMap::new(),
+        };
+        // The test hook records the (old, new) delta it was run for.
+        let runs = || -> Vec<(String, String)> {
+            BranchIndex::open_existing(repo_dir.path(), "main")
+                .unwrap()
+                .unwrap()
+                .find("index", None)
+                .unwrap()
+                .iter()
+                .map(|d| (d["_old"].as_str().unwrap().to_string(), d["_commit"].as_str().unwrap().to_string()))
+                .collect()
+        };
+        ensure_indexed(&ctx(c1)).unwrap();
+        ensure_indexed(&ctx(c3)).unwrap();
+        let s = |o: git2::Oid| o.to_string();
+        assert_eq!(runs(), [(String::new(), s(c1)), (s(c1), s(c2)), (s(c2), s(c3))]);
+
+        // Force-push onto a commit that does not descend from the indexed head: the store is
+        // rebuilt from a full replay.
+        ensure_indexed(&ctx(rewritten)).unwrap();
+        assert_eq!(runs(), [(String::new(), s(rewritten))]);
+    }
+
+    #[test]
+    fn test_schemas_reject_changes_and_quarantine_indexed_docs() {
+        use crate::git::hooks::{execute_repo_hook, HookContext};
+        use crate::git::indexdb::BranchIndex;
+        use crate::git::indexer::{default_sources, index_commit};
+        use crate::git::schema::{self, SchemaSet, SchemaViolations, QUARANTINE_COLLECTION};
+        use base64::Engine;
+        use std::collections::HashMap;
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path()).unwrap();
+        let config = r#"server:
+  db:
+    collections:
+      index:
+        schema: schemas/movie.yaml
+    schemas:
+      - glob: "catalog/*.json"
+        schema: { type: object, required: [sku] }
+"#;
+        let movie = "type: object\nrequired: [title]\nproperties:\n  title: { type: string }\n  year: { type: integer, minimum: 1888 }\n";
+        let c1 = commit_paths(
+            &repo,
+            "refs/heads/main",
+            None,
+            &[
+                (".relay.yaml", Some(config)),
+                ("schemas/movie.yaml", Some(movie)),
+                ("movies/heat/meta.yaml", Some("title: Heat\nyear: 1995\n")),
+                ("movies/bad/meta.yaml", Some("title: Bad\nyear: 1700\n")),
+            ],
+        );
+
+        // pre-commit: violations name the file and the JSON pointer of the offending value.
+        let b64 = |s: &str| base64::engine::general_purpose::STANDARD.encode(s);
+        let ctx = HookContext {
+            repo_path: repo_dir.path().to_path_buf(),
+            old_commit: "0".repeat(40),
+            new_commit: c1.to_string(),
+            refname: "refs/heads/main".to_string(),
+            branch: "main".to_string(),
+            is_verified: true,
+            files: HashMap::from([
+                ("movies/bad/meta.yaml".to_string(), b64("title: Bad\nyear: 1700\n")),
+                ("catalog/x.json".to_string(), b64("{\"name\": 1}")),
+                ("notes.txt".to_string(), b64("not data")),
+            ]),
+        };
+        let err = execute_repo_hook(&ctx, "pre-commit").unwrap_err();
+        let violations = &err.downcast_ref::<SchemaViolations>().unwrap().0;
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].file.as_deref(), Some("catalog/x.json"));
+        assert_eq!(violations[0].schema, "catalog/*.json");
+        assert_eq!(violations[1].file.as_deref(), Some("movies/bad/meta.yaml"));
+        assert_eq!(violations[1].instance_path, "/year");
+        assert_eq!(violations[1].schema, "collection index");
+        assert!(err.to_string().starts_with("schema validation failed: catalog/x.json: /: "));
+
+        let valid = HookContext {
+            files: HashMap::from([("movies/heat/meta.yaml".to_string(), b64("title: Heat\n"))]),
+            ..ctx
+        };
+        assert!(execute_repo_hook(&valid, "pre-commit").unwrap());
+
+        // Indexing: the invalid document is quarantined rather than indexed.
+        let server = crate::git::read_relay_config(&repo, &c1.to_string()).unwrap().server;
+        let schemas = SchemaSet::for_server(server.as_ref(), schema::tree_loader(&repo, &c1.to_string())).unwrap();
+        let run = index_commit(&repo, repo_dir.path(), "main", &c1.to_string(), &default_sources(), &schemas).unwrap();
+        assert_eq!((run.indexed, run.quarantined), (1, 1));
+        let index = BranchIndex::open_existing(repo_dir.path(), "main").unwrap().unwrap();
+        assert_eq!(index.find("index", None).unwrap()[0]["title"], "Heat");
+        let quarantined = index.find(QUARANTINE_COLLECTION, None).unwrap();
+        assert_eq!(quarantined[0]["_collection"], "index");
+        assert_eq!(quarantined[0]["_path"], "movies/bad/meta.yaml");
+        assert_eq!(quarantined[0]["_errors"][0]["instancePath"], "/year");
+        assert_eq!(quarantined[0]["document"]["year"], 1700);
+
+        // Fixing the file releases it from quarantine on the next incremental run.
+        let c2 = commit_paths(&repo, "refs/heads/main", Some(c1), &[("movies/bad/meta.yaml", Some("title: Fixed\n"))]);
+        let run = index_commit(&repo, repo_dir.path(), "main", &c2.to_string(), &default_sources(), &schemas).unwrap();
+        assert!(!run.full);
+        assert_eq!(index.count("index").unwrap(), 2);
+        assert_eq!(index.count(QUARANTINE_COLLECTION).unwrap(), 0);
+
+        // A pushed schema may not point the server at URLs or local files.
+        for reference in ["file:///etc/passwd", "http://169.254.169.254/latest/meta-data"] {
+            let config = format!(
+                "server:\n  db:\n    schemas:\n      - glob: \"*.json\"\n        schema:\n          properties:\n            a: {{ $ref: \"{}\" }}\n",
+                reference
+            );
+            let pushed = commit_paths(&repo, "refs/heads/evil", None, &[(".relay.yaml", Some(config.as_str()))]);
+            let push = HookContext {
+                repo_path: repo_dir.path().to_path_buf(),
+                old_commit: "0".repeat(40),
+                new_commit: pushed.to_string(),
+                refname: "refs/heads/evil".to_string(),
+                branch: "evil".to_string(),
+                is_verified: true,
+                files: HashMap::from([("x.json".to_string(), b64("{\"a\": 1}"))]),
+            };
+            let err = execute_repo_hook(&push, "pre-commit").unwrap_err().to_string();
+            assert!(err.contains("external $ref") && err.contains(reference), "{}", err);
+            let server = crate::git::read_relay_config(&repo, &pushed.to_string()).unwrap().server;
+            assert!(SchemaSet::for_server(server.as_ref(), schema::tree_loader(&repo, &pushed.to_string())).is_err());
+        }
+
+        // Pushes are checked against the schemas of the trusted base, not the ones they relax.
+        let relaxed = commit_paths(
+            &repo,
+            "refs/heads/relaxed",
+            Some(c2),
+            &[(".relay.yaml", Some("name: relaxed\n")), ("catalog/y.json", Some("{}"))],
+        );
+        let push = HookContext {
+            repo_path: repo_dir.path().to_path_buf(),
+            old_commit: c2.to_string(),
+            new_commit: relaxed.to_string(),
+            refname: "refs/heads/relaxed".to_string(),
+            branch: "relaxed".to_string(),
+            is_verified: true,
+            files: HashMap::from([("catalog/y.json".to_string(), b64("{}"))]),
+        };
+        let err = execute_repo_hook(&push, "pre-receive").unwrap_err();
+        assert_eq!(err.downcast_ref::<SchemaViolations>().unwrap().0[0].schema, "catalog/*.json");
+        assert!(execute_repo_hook(&push, "pre-commit").unwrap());
+    }
+
+    #[test]
+    fn test_aggregate_groups_distinct_and_facets() {
+        use crate::git::aggregate::{aggregate, distinct, facets, AggregateSpec};
+        use serde_json::json;
+
+        let items = vec![
+            json!({"title": "Alien", "genre": ["horror", "scifi"], "year": 1979, "rating": 8.5, "released": "1979-05-25"}),
+            json!({"title": "Heat", "genre": ["crime"], "year": 1995, "rating": 8.3, "released": "1995-12-15"}),
+            json!({"title": "Aliens", "genre": ["scifi", "action"], "year": 1986, "rating": 8.4, "released": "1986-07-18"}),
+            json!({"title": "Ran", "genre": "drama", "year": 1985}),
+        ];
+
+        let spec: AggregateSpec = serde_json::from_value(json!({
+            "metrics": {
+                "n": { "$count": {} },
+                "years": { "$sum": "year" },
+                "avgRating": { "$avg": "rating" },
+                "first": { "$min": "released" },
+                "last": { "$max": "released" }
+            }
+        }))
+        .unwrap();
+        let all = aggregate(&items, &spec).unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0]["_id"], json!(null));
+        assert_eq!(all[0]["n"], 4);
+        assert_eq!(all[0]["years"], 7945);
+        assert!((all[0]["avgRating"].as_f64().unwrap() - 8.4).abs() < 1e-9);
+        assert_eq!(all[0]["first"], "1979-05-25");
+        assert_eq!(all[0]["last"], "1995-12-15");
+
+        // Groups are keyed by the stored value and ordered by key; missing values group under null.
+        let spec: AggregateSpec = serde_json::from_value(json!({
+            "groupBy": "decade",
+            "metrics": { "n": { "$sum": 1 }, "best": { "$max": "rating" } }
+        }))
+        .unwrap();
+        let items_with_decade: Vec<_> = items
+            .iter()
+            .cloned()
+            .map(|mut d| {
+                let decade = d["year"].as_i64().unwrap() / 10 * 10;
+                if d["title"] != "Ran" {
+                    d["decade"] = json!(decade);
+                }
+                d
+            })
+            .collect();
+        let groups = aggregate(&items_with_decade, &spec).unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                json!({"_id": {"decade": null}, "n": 1, "best": null}),
+                json!({"_id": {"decade": 1970}, "n": 1, "best": 8.5}),
+                json!({"_id": {"decade": 1980}, "n": 1, "best": 8.4}),
+                json!({"_id": {"decade": 1990}, "n": 1, "best": 8.3}),
+            ]
+        );
+
+        let bad: AggregateSpec = serde_json::from_value(json!({"metrics": {"x": {"$median": "year"}}})).unwrap();
+        assert!(aggregate(&items, &bad).is_err());
+
+        assert_eq!(distinct(&items, "genre"), [json!("action"), json!("crime"), json!("drama"), json!("horror"), json!("scifi")]);
+
+        let f = facets(&items, &["genre".to_string()], Some(2));
+        assert_eq!(
+            f["genre"],
+            json!([{"value": "scifi", "count": 2}, {"value": "action", "count": 1}])
+        );
+    }
+
+    #[test]
+    fn test_query_as_of_past_commit_uses_cached_snapshot() {
+        use crate::git::branches::BranchError;
+        use crate::git::indexdb::{snapshot_branch, snapshots_dir, BranchIndex};
+        use crate::git::query::{execute_query, resolve_commit};
+
+        let root = tempdir().unwrap();
+        let repo_path = root.path().join("films.git");
+        let repo = Repository::init_bare(&repo_path).unwrap();
+        let c1 = commit_paths(&repo, "refs/heads/main", None, &[("alien/meta.yaml", Some("title: Alien\n"))]);
+        let obj = repo.find_object(c1, None).unwrap();
+        let sig = Signature::now("Test", "test@example.com").unwrap();
+        repo.tag("v1", &obj, &sig, "release", false).unwrap();
+        let c2 = commit_paths(
+            &repo,
+            "refs/heads/main",
+            Some(c1),
+            &[("alien/meta.yaml", None), ("heat/meta.yaml", Some("title: Heat\n"))],
+        );
+
+        let titles = |results: serde_json::Value| -> Vec<String> {
+            results.as_array().unwrap().iter().map(|d| d["title"].as_str().unwrap().to_string()).collect()
+        };
+        let head = execute_query(root.path(), "films", "main", None, "index", None, None).unwrap();
+        assert_eq!(titles(head), ["Heat"]);
+
+        let sha = resolve_commit(root.path(), "films", "v1").unwrap();
+        assert_eq!(sha, c1.to_string());
+        let past = execute_query(root.path(), "films", "main", None, "index", None, Some(&sha)).unwrap();
+        assert_eq!(titles(past), ["Alien"]);
+        assert!(snapshots_dir(&repo_path).join(&sha).is_dir());
+        let snapshot = BranchIndex::open_existing(&repo_path, &snapshot_branch(&sha)).unwrap().unwrap();
+        assert_eq!(snapshot.indexed_head().unwrap(), Some(sha.clone()));
+
+        // The branch index is untouched by snapshot queries.
+        let main = BranchIndex::open_existing(&repo_path, "main").unwrap().unwrap();
+        assert_eq!(main.indexed_head().unwrap(), Some(c2.to_string()));
+
+        let err = resolve_commit(root.path(), "films", "v9").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(BranchError::RevisionNotFound(_))));
+    }
+
+    #[test]
+    fn test_index_admin_status_rebuild_drop_and_gc() {
+        use crate::git::hooks::HookContext;
+        use crate::git::index_admin::{self, IndexAdminError};
+        use crate::git::indexdb::{snapshots_dir, BranchIndex};
+        use crate::git::indexing::ensure_indexed;
+
+        let root = tempdir().unwrap();
+        let repo_path = root.path().join("films.git");
+        let repo = Repository::init_bare(&repo_path).unwrap();
+        let c1 = commit_paths(&repo, "refs/heads/main", None, &[("a/meta.yaml", Some("title: A\n"))]);
+        commit_paths(&repo, "refs/heads/feature/long-name", Some(c1), &[("b/meta.yaml", Some("title: B\n"))]);
+        let index = |branch: &str| {
+            let head = repo.refname_to_id(&format!("refs/heads/{}", branch)).unwrap().to_string();
+            ensure_indexed(&HookContext {
+                repo_path: repo_path.clone(),
+                old_commit: String::new(),
+                new_commit: head,
+                refname: format!("refs/heads/{}", branch),
+                branch: branch.to_string(),
+                is_verified: true,
+                files: std::collections::HashMap::new(),
+            })
+            .unwrap();
+        };
+        index("main");
+        index("feature/long-name");
+
+        let status = index_admin::status(&repo_path, &repo).unwrap();
+        assert_eq!(status.len(), 2);
+        let feature = status.iter().find(|i| i.branch.as_deref() == Some("feature/long-name")).unwrap();
+        assert!(feature.fresh);
+        assert_eq!(feature.counts["index"], 2);
+        let run = feature.last_run.as_ref().unwrap();
+        assert_eq!(run.head, feature.head.clone().unwrap());
+        assert!(run.error.is_none());
+
+        // Deleting the branch orphans its index; the name is recovered from the last run.
+        repo.find_reference("refs/heads/feature/long-name").unwrap().delete().unwrap();
+        std::fs::create_dir_all(snapshots_dir(&repo_path).join("0".repeat(40))).unwrap();
+        let status = index_admin::status(&repo_path, &repo).unwrap();
+        let orphan = status.iter().find(|i| i.head.is_none()).unwrap();
+        assert_eq!(orphan.branch.as_deref(), Some("feature/long-name"));
+        let report = index_admin::gc(&repo_path, &repo).unwrap();
+        assert_eq!(report.branches, ["feature/long-name"]);
+        assert_eq!(report.snapshots, ["0".repeat(40)]);
+        assert!(report.freed_bytes > 0);
+        assert_eq!(index_admin::status(&repo_path, &repo).unwrap().len(), 1);
+
+        index_admin::drop_index(&repo_path, "main").unwrap();
+        assert!(BranchIndex::open_existing(&repo_path, "main").unwrap().is_none());
+        assert!(matches!(index_admin::drop_index(&repo_path, "main"), Err(IndexAdminError::NoIndex(_))));
+        let rebuilt = index_admin::rebuild(&repo_path, &repo, "main").unwrap();
+        assert!(rebuilt.fresh);
+        assert_eq!(rebuilt.counts["index"], 1);
+        assert!(matches!(
+            index_admin::rebuild(&repo_path, &repo, "gone"),
+            Err(IndexAdminError::BranchNotFound(_))
+        ));
+
+        // Names that are not branches never reach the filesystem.
+        let snapshot = format!("snapshot:{}", "0".repeat(40));
+        std::fs::create_dir_all(snapshots_dir(&repo_path).join("0".repeat(40))).unwrap();
+        for bad in [snapshot.as_str(), "../../x", "-x", "a..b", ""] {
+            assert!(matches!(index_admin::drop_index(&repo_path, bad), Err(IndexAdminError::InvalidBranch(_))));
+            assert!(matches!(index_admin::rebuild(&repo_path, &repo, bad), Err(IndexAdminError::InvalidBranch(_))));
+        }
+        assert!(snapshots_dir(&repo_path).join("0".repeat(40)).exists());
+
+        // Drops share the indexing single-flight: one waits for a run in progress.
+        let order = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                crate::git::indexing::exclusive(&repo_path, "main", || {
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    order.lock().unwrap().push("run");
+                    Ok::<_, String>(())
+                })
+            });
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            index_admin::drop_index(&repo_path, "main").unwrap();
+            order.lock().unwrap().push("drop");
+        });
+        assert_eq!(*order.lock().unwrap(), ["run", "drop"]);
+
+        // So does gc: the snapshot being built is removed only once its run is over.
+        let order = std::sync::Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                crate::git::indexing::exclusive(&repo_path, &snapshot, || {
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    order.lock().unwrap().push("run");
+                    Ok::<_, String>(())
+                })
+            });
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(index_admin::gc(&repo_path, &repo).unwrap().snapshots, ["0".repeat(40)]);
+            order.lock().unwrap().push("gc");
+        });
+        assert_eq!(*order.lock().unwrap(), ["run", "gc"]);
+    }
+
+    #[test]
+    fn test_export_csv_ndjson_and_table() {
+        use crate::git::export::{self, ExportFormat};
+        use serde_json::{json, Value};
+        assert_eq!(ExportFormat::from_accept("text/html, text/csv;q=0.9"), Some(ExportFormat::Csv));
+        assert_eq!("jsonl".parse::<ExportFormat>(), Ok(ExportFormat::Ndjson));
+        assert!("xml".parse::<ExportFormat>().is_err());
+
+        let rows = vec![
+            json!({ "title": "a, \"quoted\"", "meta": { "year": 2001 } }),
+            json!({ "title": "line\nbreak", "tags": ["x"], "extra": null }),
+        ];
+        let columns = export::columns(&rows, &[]);
+        assert_eq!(columns, vec!["meta", "title", "extra", "tags"]);
+
+        let csv: String = export::chunks(
+            ExportFormat::Csv,
+            rows.clone(),
+            vec!["title".into(), "meta.year".into(), "tags".into()],
+        )
+        .collect();
+        assert_eq!(
+            csv,
+            "title,meta.year,tags\r\n\"a, \"\"quoted\"\"\",2001,\r\n\"line\nbreak\",,\"[\"\"x\"\"]\"\r\n"
+        );
+
+        let ndjson: String = export::chunks(ExportFormat::Ndjson, rows.clone(), Vec::new()).collect();
+        let parsed: Vec<Value> = ndjson.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(parsed, rows);
+
+        // Row by row, the header holds the columns collected up front, including late fields.
+        let mut many: Vec<Value> = (0..export::ROWS_PER_CHUNK).map(|i| json!({ "n": i })).collect();
+        many.push(json!({ "n": 0, "late": true }));
+        let mut columns = Vec::new();
+        for row in &many {
+            export::add_columns(&mut columns, row);
+        }
+        let mut encoder = export::ChunkEncoder::new(ExportFormat::Csv, &columns);
+        let mut csv = String::new();
+        for row in many {
+            csv.extend(encoder.push(row));
+        }
+        csv.extend(encoder.finish());
+        assert!(csv.starts_with("n,late\r\n0,\r\n1,\r\n"));
+        assert!(csv.ends_with("255,\r\n0,true\r\n"));
+
+        // Formula-like text is defused; plain numbers are not.
+        let formulas = json!({ "a": "=HYPERLINK(\"x\")", "b": "+1", "c": "-2.5", "d": "@SUM(A1)", "e": "-cmd|x" });
+        let columns: Vec<String> = ["a", "b", "c", "d", "e"].map(String::from).to_vec();
+        assert_eq!(export::csv_row(&formulas, &columns), "\"'=HYPERLINK(\"\"x\"\")\",+1,-2.5,'@SUM(A1),'-cmd|x\r\n");
+        assert_eq!(export::ChunkEncoder::new(ExportFormat::Csv, &["a".into()]).finish().as_deref(), Some("a\r\n"));
+        assert_eq!(export::ChunkEncoder::new(ExportFormat::Ndjson, &[]).finish(), None);
+
+        let table = export::table(&rows, &["title".into(), "meta.year".into()]);
+        assert_eq!(table, "title        meta.year\n-----------  ---------\na, \"quoted\"  2001\nline break\n");
+    }
+
+    /// Commit `files` on top of `parent` (without moving any ref), signed by `sign(payload)`.
+    fn signed_commit(
+        repo: &Repository,
+        parent: git2::Oid,
+        files: &[(&str, &str)],
+        sign: impl Fn(&[u8]) -> String,
+    ) -> git2::Oid {
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.find_commit(parent).unwrap();
+        let mut builder = repo.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        for (path, content) in files {
+            builder.insert(path, repo.blob(content.as_bytes()).unwrap(), 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let buf = repo.commit_create_buffer(&sig, &sig, "signed", &tree, &[&parent]).unwrap();
+        let payload = std::str::from_utf8(&buf).unwrap();
+        repo.commit_signed(payload, &sign(&buf), None).unwrap()
+    }
+
+    #[test]
+    fn test_ssh_signatures_checked_against_allowed_keys() {
+        use crate::git::signing::{verify_commit, AllowedKeys, SigningError};
+        use crate::types::BranchRule;
+        use ssh_key::{private::Ed25519Keypair, HashAlg, LineEnding, PrivateKey};
+
+        let dir = tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let admin = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+        let stranger = PrivateKey::from(Ed25519Keypair::from_seed(&[9; 32]));
+        let ssh_sign = |key: &PrivateKey| {
+            let key = key.clone();
+            move |payload: &[u8]| key.sign("git", HashAlg::Sha512, payload).unwrap().to_pem(LineEnding::LF).unwrap()
+        };
+
+        let admin_pub = format!("admin@example.com {}\n", admin.public_key().to_openssh().unwrap());
+        let base = commit_paths(&repo, "refs/heads/main", None, &[(".ssh/admin.pub", Some(admin_pub.as_str()))]);
+        let base = base.to_string();
+        let rule = BranchRule {
+            require_signed: Some(true),
+            allowed_keys: Some(vec![".ssh/*.pub".into()]),
+            ..BranchRule::default()
+        };
+        let keys = AllowedKeys::load(dir.path(), &base, &rule).unwrap();
+        let parent = git2::Oid::from_str(&base).unwrap();
+
+        let good = signed_commit(&repo, parent, &[("a.txt", "a")], ssh_sign(&admin));
+        let signer = verify_commit(dir.path(), &good.to_string(), &keys).unwrap();
+        assert_eq!(signer, Some(admin.public_key().fingerprint(HashAlg::Sha256).to_string()));
+
+        // A pushed commit cannot vouch for itself by adding its own key.
+        let stranger_pub = stranger.public_key().to_openssh().unwrap();
+        let intruder = signed_commit(&repo, parent, &[("stranger.pub", stranger_pub.as_str())], ssh_sign(&stranger));
+        assert!(matches!(
+            verify_commit(dir.path(), &intruder.to_string(), &keys),
+            Err(SigningError::NotAllowed { .. })
+        ));
+
+        let unsigned = commit_paths(&repo, "refs/heads/unsigned", Some(parent), &[("b.txt", Some("b"))]);
+        assert!(matches!(
+            verify_commit(dir.path(), &unsigned.to_string(), &keys),
+            Err(SigningError::Unsigned(_))
+        ));
+
+        // A signature over a different payload is rejected.
+        let other = repo.find_commit(good).unwrap();
+        let odb = repo.odb().unwrap();
+        let raw = odb.read(good).unwrap();
+        let (signature, _) = crate::git::signing::split_signature(raw.data()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let forged = repo
+            .commit_create_buffer(&sig, &sig, "forged", &other.tree().unwrap(), &[&repo.find_commit(parent).unwrap()])
+            .unwrap();
+        let forged = repo.commit_signed(std::str::from_utf8(&forged).unwrap(), &signature, None).unwrap();
+        assert!(matches!(
+            verify_commit(dir.path(), &forged.to_string(), &keys),
+            Err(SigningError::BadSignature { .. })
+        ));
+
+        // Fingerprint-only rules need no key files.
+        let rule = BranchRule {
+            require_signed: Some(true),
+            allowed_key_fingerprints: Some(vec![stranger.public_key().fingerprint(HashAlg::Sha256).to_string()]),
+            ..BranchRule::default()
+        };
+        let keys = AllowedKeys::load(dir.path(), &base, &rule).unwrap();
+        assert!(verify_commit(dir.path(), &intruder.to_string(), &keys).is_ok());
+        assert!(matches!(
+            verify_commit(dir.path(), &good.to_string(), &keys),
+            Err(SigningError::NotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_pgp_signatures_checked_against_allowed_keys() {
+        use crate::git::signing::{verify_commit, AllowedKeys, SigningError};
+        use crate::types::BranchRule;
+        use std::process::{Command, Stdio};
+
+        let home = tempdir().unwrap();
+        let gpg = |args: &[&str]| {
+            Command::new("gpg")
+                .arg("--homedir")
+                .arg(home.path())
+                .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+                .args(args)
+                .output()
+        };
+        match gpg(&["--quick-gen-key", "Relay Test <relay@example.com>", "ed25519", "sign", "never"]) {
+            Ok(out) if out.status.success() => {}
+            _ => return, // gpg unavailable
+        }
+        let fingerprint_of = |uid: &str| {
+            let listing = String::from_utf8(gpg(&["--with-colons", "--list-keys", uid]).unwrap().stdout).unwrap();
+            listing.lines().find(|l| l.starts_with("fpr:")).unwrap().split(':').nth(9).unwrap().to_string()
+        };
+        let fingerprint = fingerprint_of("relay@example.com");
+        // A key that expired long ago, and one revoked through the certificate gpg stored for it.
+        gpg(&["--faked-system-time", "20200101T000000", "--quick-gen-key", "Expired <expired@example.com>", "ed25519", "sign", "1d"]).unwrap();
+        gpg(&["--quick-gen-key", "Revoked <revoked@example.com>", "ed25519", "sign", "never"]).unwrap();
+        let (expired, revoked) = (fingerprint_of("expired@example.com"), fingerprint_of("revoked@example.com"));
+        let export = |fpr: &str| String::from_utf8(gpg(&["--armor", "--export", fpr]).unwrap().stdout).unwrap();
+        let detach_sign = |payload: &[u8], extra: &[&str]| {
+            let mut child = Command::new("gpg")
+                .arg("--homedir")
+                .arg(home.path())
+                .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", "", "--armor", "--detach-sign"])
+                .args(extra)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            std::io::Write::write_all(child.stdin.as_mut().unwrap(), payload).unwrap();
+            String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap()
+        };
+
+        let dir = tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let root = commit_paths(&repo, "refs/heads/main", None, &[("README.md", Some("signed\n"))]);
+        let signed = signed_commit(&repo, root, &[("a.txt", "a")], |payload| detach_sign(payload, &["-u", &fingerprint]));
+        let by_expired = signed_commit(&repo, root, &[("a.txt", "e")], |payload| {
+            detach_sign(payload, &["--faked-system-time", "20200101T000100", "-u", &expired])
+        });
+        // Signed while the key was still good; the revocation is published with the key.
+        let by_revoked = signed_commit(&repo, root, &[("a.txt", "r")], |payload| detach_sign(payload, &["-u", &revoked]));
+        let revocation = std::fs::read_to_string(home.path().join("openpgp-revocs.d").join(format!("{}.rev", revoked))).unwrap();
+        let revocation_file = home.path().join("revoke.asc");
+        std::fs::write(&revocation_file, revocation.replace(":-----BEGIN", "-----BEGIN")).unwrap();
+        assert!(gpg(&["--import", revocation_file.to_str().unwrap()]).unwrap().status.success());
+        let base = commit_paths(
+            &repo,
+            "refs/heads/main",
+            Some(root),
+            &[
+                ("keys/release.asc", Some(export(&fingerprint).as_str())),
+                ("keys/expired.asc", Some(export(&expired).as_str())),
+                ("keys/revoked.asc", Some(export(&revoked).as_str())),
+            ],
+        );
+
+        let rule = BranchRule {
+            require_signed: Some(true),
+            allowed_keys: Some(vec!["keys/*.asc".into()]),
+            ..BranchRule::default()
+        };
+        let keys = AllowedKeys::load(dir.path(), &base.to_string(), &rule).unwrap();
+        assert_eq!(verify_commit(dir.path(), &signed.to_string(), &keys).unwrap(), Some(fingerprint.clone()));
+        // gpg still prints VALIDSIG for these; the key's state must reject them.
+        for (commit, keyword) in [(by_expired, "EXPKEYSIG"), (by_revoked, "REVKEYSIG")] {
+            match verify_commit(dir.path(), &commit.to_string(), &keys) {
+                Err(SigningError::BadSignature { reason, .. }) => assert!(reason.contains(keyword), "{}", reason),
+                other => panic!("{} accepted or misreported: {:?}", keyword, other),
+            }
+        }
+
+        let rule = BranchRule {
+            require_signed: Some(true),
+            allowed_key_fingerprints: Some(vec!["0123456789ABCDEF0123456789ABCDEF01234567".into()]),
+            ..BranchRule::default()
+        };
+        let keys = AllowedKeys::load(dir.path(), &base.to_string(), &rule).unwrap();
+        assert!(matches!(
+            verify_commit(dir.path(), &signed.to_string(), &keys),
+            Err(SigningError::NotAllowed { .. } | SigningError::Unverified(_))
+        ));
+    }
+
+    #[test]
+    fn test_every_pushed_commit_must_be_signed() {
+        use crate::git::signing::{pushed_commits, verify_push, AllowedKeys, SigningError};
+        use crate::types::BranchRule;
+        use ssh_key::{private::Ed25519Keypair, HashAlg, LineEnding, PrivateKey};
+
+        let dir = tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let admin = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+        let sign = |payload: &[u8]| admin.sign("git", HashAlg::Sha512, payload).unwrap().to_pem(LineEnding::LF).unwrap();
+        let admin_pub = admin.public_key().to_openssh().unwrap();
+        let base = commit_paths(&repo, "refs/heads/main", None, &[(".ssh/admin.pub", Some(admin_pub.as_str()))]);
+        let rule = BranchRule {
+            require_signed: Some(true),
+            allowed_keys: Some(vec![".ssh/*.pub".into()]),
+            ..BranchRule::default()
+        };
+        let keys = AllowedKeys::load(dir.path(), &base.to_string(), &rule).unwrap();
+
+        // A signed tip does not cover the unsigned commit beneath it.
+        let unsigned = commit_paths(&repo, "refs/heads/scratch", Some(base), &[("a.txt", Some("a"))]);
+        let tip = signed_commit(&repo, unsigned, &[("b.txt", "b")], sign);
+        let (old, new) = (base.to_string(), tip.to_string());
+        assert_eq!(pushed_commits(dir.path(), &old, &new).unwrap(), vec![unsigned.to_string(), new.clone()]);
+        match verify_push(dir.path(), &old, &new, &keys) {
+            Err(SigningError::Unsigned(commit)) => assert_eq!(commit, unsigned.to_string()),
+            other => panic!("expected the unsigned commit to be reported, got {:?}", other),
+        }
+
+        let first = signed_commit(&repo, base, &[("a.txt", "a")], sign);
+        let second = signed_commit(&repo, first, &[("b.txt", "b")], sign);
+        let verified = verify_push(dir.path(), &old, &second.to_string(), &keys).unwrap();
+        assert_eq!(verified.len(), 2);
+        assert!(verified.iter().all(|(_, signer)| signer.is_some()));
+
+        // A new branch only brings the commits no existing ref reaches: `base` is on main already.
+        let zero = "0".repeat(40);
+        repo.reference("refs/heads/scratch", base, true, "test").unwrap();
+        assert_eq!(pushed_commits(dir.path(), &zero, &second.to_string()).unwrap().len(), 2);
+        assert!(verify_push(dir.path(), &zero, &second.to_string(), &keys).is_ok());
+        assert!(verify_push(dir.path(), &zero, &tip.to_string(), &keys).is_err());
+    }
+
+    #[test]
+    fn test_branch_api_verifies_every_new_commit() {
+        use crate::git::branches::{create_branch, rename_branch, BranchError};
+        use crate::git::merge::merge_into_branch;
+        use ssh_key::{private::Ed25519Keypair, HashAlg, LineEnding, PrivateKey};
+
+        let dir = tempdir().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let admin = PrivateKey::from(Ed25519Keypair::from_seed(&[7; 32]));
+        let sign = |payload: &[u8]| admin.sign("git", HashAlg::Sha512, payload).unwrap().to_pem(LineEnding::LF).unwrap();
+        let admin_pub = admin.public_key().to_openssh().unwrap();
+        let config = r#"
+git:
+  branchRules:
+    branches:
+      - name: "release/*"
+        rule:
+          requireSigned: true
+          allowedKeys: [".ssh/*.pub"]
+"#;
+        let base = commit_paths(
+            &repo,
+            "refs/heads/main",
+            None,
+            &[(".relay.yaml", Some(config)), (".ssh/admin.pub", Some(admin_pub.as_str()))],
+        );
+        let release = signed_commit(&repo, base, &[("r.txt", "r")], sign);
+        repo.reference("refs/heads/release/1", release, true, "test").unwrap();
+
+        // A signed tip on top of an unsigned commit.
+        let unsigned = commit_paths(&repo, "refs/heads/feature", Some(release), &[("a.txt", Some("a"))]);
+        let tip = signed_commit(&repo, unsigned, &[("b.txt", "b")], sign);
+        repo.reference("refs/heads/feature", tip, true, "test").unwrap();
+
+        match merge_into_branch(&repo, "feature", "release/1", None) {
+            Err(BranchError::Rejected(msg)) => assert!(msg.contains(&unsigned.to_string()), "{}", msg),
+            other => panic!("expected rejection, got {:?}", other),
+        }
+        assert!(matches!(rename_branch(&repo, "feature", "release/2"), Err(BranchError::Rejected(_))));
+
+        repo.find_reference("refs/heads/feature").unwrap().delete().unwrap();
+        let tip = tip.to_string();
+        assert!(matches!(create_branch(&repo, "release/3", Some(&tip)), Err(BranchError::Rejected(_))));
+        let release = release.to_string();
+        assert!(create_branch(&repo, "release/4", Some(&release)).is_ok());
+    }
 }
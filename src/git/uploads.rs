@@ -0,0 +1,310 @@
+//! Resumable (tus-like) uploads spooled under `.relay_data/uploads/<id>/` and committed as one blob.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use git2::build::TreeUpdateBuilder;
+use git2::{FileMode, ObjectType, Repository, Signature};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::git::branches::{ensure_server_commit_allowed, BranchError};
+use crate::git::commit::{apply_commit, CommitError};
+use crate::git::schema::{self, SchemaSet, SchemaViolations};
+
+/// Sessions older than this are removed when a new session is created.
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Blobs up to this size are passed to hooks in the `files` context; larger ones are omitted
+/// (and checked against the declared schemas in [`finalize`] instead).
+const HOOK_INLINE_LIMIT: u64 = 2 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("upload '{0}' not found")]
+    NotFound(String),
+    #[error("offset mismatch: upload is at {expected}")]
+    OffsetMismatch { expected: u64 },
+    #[error("chunk exceeds declared upload length {0}")]
+    TooLarge(u64),
+    #[error("upload incomplete: {offset} of {length} bytes received")]
+    Incomplete { offset: u64, length: u64 },
+    /// The spool file differs from the recorded offset: a chunk is still being written.
+    #[error("upload is at {offset} but {size} bytes are spooled; finish or resend the last PATCH")]
+    Unsettled { offset: u64, size: u64 },
+    #[error("invalid path '{0}'")]
+    InvalidPath(String),
+    #[error(transparent)]
+    Branch(#[from] BranchError),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("git error: {0}")]
+    Git(#[from] git2::Error),
+}
+
+/// Persisted as `session.json` next to the spooled `data` file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub path: String,
+    pub branch: String,
+    /// Declared total size (`Upload-Length`); `None` when deferred until finalize.
+    pub length: Option<u64>,
+    pub offset: u64,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+}
+
+pub fn uploads_dir(repo_path: &Path) -> PathBuf {
+    repo_path.join(".relay_data").join("uploads")
+}
+
+fn session_dir(repo_path: &Path, id: &str) -> Result<PathBuf, UploadError> {
+    // ids are generated names; refuse anything that could escape the uploads directory
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(UploadError::NotFound(id.to_string()));
+    }
+    let dir = uploads_dir(repo_path).join(id);
+    if !dir.join("session.json").exists() {
+        return Err(UploadError::NotFound(id.to_string()));
+    }
+    Ok(dir)
+}
+
+/// Path of the spooled data file for an existing session.
+pub fn data_path(repo_path: &Path, id: &str) -> Result<PathBuf, UploadError> {
+    Ok(session_dir(repo_path, id)?.join("data"))
+}
+
+fn save_session(dir: &Path, session: &UploadSession) -> Result<(), UploadError> {
+    let tmp = dir.join("session.json.tmp");
+    fs::write(&tmp, serde_json::to_vec(session).map_err(io::Error::from)?)?;
+    fs::rename(tmp, dir.join("session.json"))?;
+    Ok(())
+}
+
+pub fn load_session(repo_path: &Path, id: &str) -> Result<UploadSession, UploadError> {
+    let dir = session_dir(repo_path, id)?;
+    let raw = fs::read(dir.join("session.json"))?;
+    serde_json::from_slice(&raw).map_err(|e| UploadError::Io(e.into()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Start a new upload session for `path` on `branch`.
+pub fn create_session(
+    repo_path: &Path,
+    branch: &str,
+    path: &str,
+    length: Option<u64>,
+    message: Option<String>,
+) -> Result<UploadSession, UploadError> {
+    let rel = path.trim_matches('/');
+    if rel.is_empty() || rel.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+        return Err(UploadError::InvalidPath(path.to_string()));
+    }
+    let root = uploads_dir(repo_path);
+    fs::create_dir_all(&root)?;
+    purge_expired(&root);
+
+    let dir = tempfile::Builder::new()
+        .prefix("up-")
+        .rand_bytes(16)
+        .tempdir_in(&root)?
+        .keep();
+    let id = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_string();
+    File::create(dir.join("data"))?;
+    let session = UploadSession {
+        id,
+        path: rel.to_string(),
+        branch: branch.to_string(),
+        length,
+        offset: 0,
+        message,
+        created: now_secs(),
+    };
+    save_session(&dir, &session)?;
+    info!(id = %session.id, path = %session.path, branch = %session.branch, "upload session created");
+    Ok(session)
+}
+
+/// Check that a chunk may be appended at `offset`; returns the session on success.
+pub fn check_append(
+    repo_path: &Path,
+    id: &str,
+    offset: u64,
+    chunk_len: Option<u64>,
+) -> Result<UploadSession, UploadError> {
+    let session = load_session(repo_path, id)?;
+    if offset != session.offset {
+        return Err(UploadError::OffsetMismatch {
+            expected: session.offset,
+        });
+    }
+    if let (Some(length), Some(n)) = (session.length, chunk_len) {
+        if offset + n > length {
+            return Err(UploadError::TooLarge(length));
+        }
+    }
+    Ok(session)
+}
+
+/// Record that the data file now holds `new_offset` bytes.
+pub fn commit_offset(repo_path: &Path, id: &str, new_offset: u64) -> Result<UploadSession, UploadError> {
+    let dir = session_dir(repo_path, id)?;
+    let mut session = load_session(repo_path, id)?;
+    if let Some(length) = session.length {
+        if new_offset > length {
+            return Err(UploadError::TooLarge(length));
+        }
+    }
+    session.offset = new_offset;
+    save_session(&dir, &session)?;
+    Ok(session)
+}
+
+/// Append `chunk` at `offset` (synchronous variant used by tests and small writers).
+pub fn append_chunk(
+    repo_path: &Path,
+    id: &str,
+    offset: u64,
+    chunk: &[u8],
+) -> Result<UploadSession, UploadError> {
+    check_append(repo_path, id, offset, Some(chunk.len() as u64))?;
+    let data = data_path(repo_path, id)?;
+    let mut f = OpenOptions::new().write(true).open(&data)?;
+    f.set_len(offset)?;
+    f.seek(io::SeekFrom::Start(offset))?;
+    f.write_all(chunk)?;
+    f.sync_data()?;
+    commit_offset(repo_path, id, offset + chunk.len() as u64)
+}
+
+/// Stream the spooled data into the object database and commit it to the session's branch.
+/// The session is removed once the commit lands. Returns the new commit id.
+pub fn finalize(repo: &Repository, id: &str) -> Result<(UploadSession, String), UploadError> {
+    let repo_path = repo.path().to_path_buf();
+    let session = load_session(&repo_path, id)?;
+    let data = data_path(&repo_path, id)?;
+    let size = fs::metadata(&data)?.len();
+    if let Some(length) = session.length {
+        if size != length || session.offset != length {
+            return Err(UploadError::Incomplete {
+                offset: session.offset,
+                length,
+            });
+        }
+    } else if size != session.offset {
+        return Err(UploadError::Unsettled {
+            offset: session.offset,
+            size,
+        });
+    }
+    ensure_server_commit_allowed(repo, &session.branch)?;
+
+    let odb = repo.odb()?;
+    let mut writer = odb.writer(size as usize, ObjectType::Blob)?;
+    io::copy(&mut File::open(&data)?, &mut writer)?;
+    let blob_oid = writer.finalize()?;
+
+    let refname = format!("refs/heads/{}", session.branch);
+    let parent = repo.find_reference(&refname).ok().and_then(|r| r.peel_to_commit().ok());
+    let base = match &parent {
+        Some(c) => c.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+    let tree_oid = TreeUpdateBuilder::new()
+        .upsert(session.path.as_str(), blob_oid, FileMode::Blob)
+        .create_updated(repo, &base)?;
+    let tree = repo.find_tree(tree_oid)?;
+    let sig = Signature::now("relay", "relay@local")?;
+    let msg = session
+        .message
+        .clone()
+        .unwrap_or_else(|| format!("PUT {}", session.path));
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    let commit_oid = repo.commit(None, &sig, &sig, &msg, &tree, &parents)?;
+
+    let mut files = HashMap::new();
+    if size <= HOOK_INLINE_LIMIT {
+        files.insert(
+            session.path.clone(),
+            base64::engine::general_purpose::STANDARD.encode(fs::read(&data)?),
+        );
+    } else {
+        // The pre-commit hook only validates inlined files; schemas apply at any size.
+        check_schemas(repo, &commit_oid.to_string(), &session.path, &data)?;
+        warn!(path = %session.path, size, "upload too large to inline into hook context");
+    }
+    apply_commit(
+        repo,
+        &session.branch,
+        parent.as_ref().map(|c| c.id()),
+        commit_oid,
+        files,
+        &msg,
+    )
+    .map_err(BranchError::from)?;
+
+    abort(&repo_path, id)?;
+    info!(id, commit = %commit_oid, path = %session.path, "upload finalized");
+    Ok((session, commit_oid.to_string()))
+}
+
+/// Validate the spooled file at `data` against the `server.db.schemas` of `commit`.
+fn check_schemas(repo: &Repository, commit: &str, path: &str, data: &Path) -> Result<(), UploadError> {
+    let config = crate::git::read_relay_config(repo, commit);
+    let schemas = SchemaSet::for_server(
+        config.as_ref().and_then(|c| c.server.as_ref()),
+        schema::tree_loader(repo, commit),
+    )
+    .map_err(|e| BranchError::from(CommitError::Hook(e.into())))?;
+    if !schemas.covers(path) {
+        return Ok(());
+    }
+    let violations = schemas.check_file(path, &fs::read(data)?);
+    if !violations.is_empty() {
+        return Err(BranchError::from(CommitError::Invalid(SchemaViolations(violations))).into());
+    }
+    Ok(())
+}
+
+/// Discard a session and its spooled data.
+pub fn abort(repo_path: &Path, id: &str) -> Result<(), UploadError> {
+    let dir = session_dir(repo_path, id)?;
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+fn purge_expired(root: &Path) {
+    let now = now_secs();
+    let entries = match fs::read_dir(root) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let expired = fs::read(dir.join("session.json"))
+            .ok()
+            .and_then(|raw| serde_json::from_slice::<UploadSession>(&raw).ok())
+            .map(|s| now.saturating_sub(s.created) > SESSION_TTL.as_secs())
+            .unwrap_or(false);
+        if expired {
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
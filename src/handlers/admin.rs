@@ -0,0 +1,404 @@
+use axum::{
+    extract::{Path as AxPath, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use crate::authorized_repos;
+use crate::git::index_admin::{self, IndexAdminError};
+use crate::git::provision::{self, ObjectSharing, ProvisionError, RepoSource};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRepoRequest {
+    pub name: String,
+    /// Clone from this remote URL (kept as `origin`).
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Clone from an existing local repo under the repo root (origin removed).
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForkRepoRequest {
+    /// Name of the new repository
+    pub name: String,
+    /// Branches to copy; all branches when omitted or empty.
+    #[serde(default)]
+    pub branches: Vec<String>,
+    /// Share objects through `objects/info/alternates` instead of hardlinking them.
+    #[serde(default)]
+    pub shared: bool,
+    /// Add the fork to the authorized-repos file, anchored at its default branch tip.
+    #[serde(default)]
+    pub authorize: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteRepoParams {
+    /// `archive` (default) moves the repo under `.archive/`; `delete` removes it.
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct IndexBranchParams {
+    #[serde(default)]
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RebuildIndexRequest {
+    /// Branch to rebuild; every branch of the repo when omitted.
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// Rebuild before responding instead of queueing the background worker.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+/// `None` when the request carries the admin bearer token; otherwise the error response.
+pub(super) fn check_admin(state: &AppState, headers: &HeaderMap) -> Option<Response> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Some(
+            (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({"error": "admin API disabled (set RELAY_ADMIN_TOKEN)"})),
+            )
+                .into_response(),
+        );
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim());
+    if given.is_some_and(|given| token_matches(given, expected)) {
+        None
+    } else {
+        Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(serde_json::json!({"error": "invalid or missing admin token"})),
+            )
+                .into_response(),
+        )
+    }
+}
+
+/// Compare sha256 digests of both tokens without early exit, so the response time reveals
+/// neither the token's length nor how many leading bytes matched.
+fn token_matches(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given.as_bytes()), Sha256::digest(expected.as_bytes()));
+    given.iter().zip(expected.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn provision_error_response(e: ProvisionError) -> Response {
+    let status = match &e {
+        ProvisionError::InvalidName(_) | ProvisionError::InvalidSource(_) => StatusCode::BAD_REQUEST,
+        ProvisionError::AlreadyExists(_) | ProvisionError::HasDependentForks(..) => StatusCode::CONFLICT,
+        ProvisionError::NotFound(_) => StatusCode::NOT_FOUND,
+        ProvisionError::Clone(_) => StatusCode::BAD_GATEWAY,
+        ProvisionError::Io(_) | ProvisionError::Git(_) => {
+            error!(?e, "repository provisioning failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(serde_json::json!({"error": e.to_string()}))).into_response()
+}
+
+fn index_admin_error_response(e: IndexAdminError) -> Response {
+    let status = match &e {
+        IndexAdminError::RepoNotFound(_)
+        | IndexAdminError::BranchNotFound(_)
+        | IndexAdminError::NoIndex(_) => StatusCode::NOT_FOUND,
+        IndexAdminError::InvalidBranch(_) => StatusCode::BAD_REQUEST,
+        _ => {
+            error!(?e, "index administration failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(serde_json::json!({"error": e.to_string()}))).into_response()
+}
+
+/// POST /api/admin/repos — create an empty repo, or clone one from `url` or `template`
+pub async fn create_repo(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateRepoRequest>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let source = match (req.url, req.template) {
+        (Some(_), Some(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "specify either url or template, not both"})),
+            )
+                .into_response()
+        }
+        (Some(url), None) => RepoSource::Url(url),
+        (None, Some(template)) => RepoSource::Template(template),
+        (None, None) => RepoSource::Empty,
+    };
+    let root = state.repo_path.clone();
+    let name = req.name;
+    // Cloning shells out to git and may take a while.
+    let res = tokio::task::spawn_blocking(move || provision::create_repo(&root, &name, &source)).await;
+    match res {
+        Ok(Ok(repo)) => (StatusCode::CREATED, Json(serde_json::json!({ "repo": repo }))).into_response(),
+        Ok(Err(e)) => provision_error_response(e),
+        Err(e) => {
+            error!(?e, "provisioning task panicked");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// DELETE /api/admin/repos/{name}?mode=archive|delete
+pub async fn delete_repo(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+    Query(params): Query<DeleteRepoParams>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let name = name.trim_end_matches(".git");
+    match params.mode.as_deref().unwrap_or("archive") {
+        "archive" => match provision::archive_repo(&state.repo_path, name) {
+            Ok(dest) => Json(serde_json::json!({ "archived": name, "path": dest })).into_response(),
+            Err(e) => provision_error_response(e),
+        },
+        "delete" => match provision::delete_repo(&state.repo_path, name) {
+            Ok(()) => Json(serde_json::json!({ "deleted": name })).into_response(),
+            Err(e) => provision_error_response(e),
+        },
+        other => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": format!("unknown mode '{}' (archive|delete)", other)})),
+        )
+            .into_response(),
+    }
+}
+
+/// POST /api/admin/repos/{name}/fork — fork a local repo under a new name
+pub async fn fork_repo(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(source): AxPath<String>,
+    Json(req): Json<ForkRepoRequest>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let authorized_path = match (req.authorize, state.authorized_repos.as_ref()) {
+        (false, _) => None,
+        (true, Some(cfg)) if cfg.path.is_some() => cfg.path.clone(),
+        (true, _) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "authorize requires RELAY_AUTHORIZED_REPOS_PATH"})),
+            )
+                .into_response()
+        }
+    };
+    let root = state.repo_path.clone();
+    let source = source.trim_end_matches(".git").to_string();
+    let sharing = if req.shared {
+        ObjectSharing::Alternates
+    } else {
+        ObjectSharing::Hardlink
+    };
+    let res = tokio::task::spawn_blocking(move || {
+        provision::fork_repo(&root, &source, &req.name, &req.branches, sharing)
+    })
+    .await;
+    let fork = match res {
+        Ok(Ok(fork)) => fork,
+        Ok(Err(e)) => return provision_error_response(e),
+        Err(e) => {
+            error!(?e, "fork task panicked");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let authorized = match authorized_path.map(|path| authorized_repos::authorize_fork(&path, &fork)) {
+        None => None,
+        Some(Ok(anchor)) => Some(anchor),
+        // The fork exists: report it with the failure so the client can retry or remove it.
+        Some(Err(e)) => {
+            error!(?e, fork = %fork.name, "failed to add fork to authorized-repos file");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "error": format!("fork '{}' was created but not added to the authorized-repos file: {}", fork.name, e),
+                    "repo": fork,
+                })),
+            )
+                .into_response();
+        }
+    };
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "repo": fork, "authorized": authorized })),
+    )
+        .into_response()
+}
+
+/// GET /api/admin/index/{repo} — index status per branch (indexed head, document counts, size,
+/// last run) plus the background worker state, and orphaned index directories
+pub async fn get_index_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let root = state.repo_path.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let (path, repo) = index_admin::open_repo(&root, &name)?;
+        index_admin::status(&path, &repo).map(|infos| (path, infos))
+    })
+    .await;
+    let (path, infos) = match res {
+        Ok(Ok(out)) => out,
+        Ok(Err(e)) => return index_admin_error_response(e),
+        Err(e) => {
+            error!(?e, "index status task panicked");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let mut worker = state.index_worker.status(&path);
+    let branches: Vec<serde_json::Value> = infos
+        .into_iter()
+        .map(|info| {
+            let status = info.branch.as_ref().and_then(|b| worker.remove(b));
+            let mut entry = serde_json::to_value(&info).unwrap_or_default();
+            entry["worker"] = serde_json::to_value(status).unwrap_or_default();
+            entry
+        })
+        .collect();
+    Json(serde_json::json!({ "branches": branches })).into_response()
+}
+
+/// POST /api/admin/index/{repo}/rebuild — drop and re-index one branch (`branch`) or all of
+/// them; queued to the background worker (202) unless `wait` is set
+pub async fn rebuild_index(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+    body: Option<Json<RebuildIndexRequest>>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let req = body.map(|Json(b)| b).unwrap_or_default();
+    let root = state.repo_path.clone();
+    let worker = state.index_worker.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let (path, repo) = index_admin::open_repo(&root, &name)?;
+        let branches = match req.branch {
+            Some(branch) => vec![branch],
+            None => crate::git::list_branches(&repo),
+        };
+        if req.wait {
+            let rebuilt = branches
+                .iter()
+                .map(|b| index_admin::rebuild(&path, &repo, b))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok((StatusCode::OK, serde_json::json!({ "rebuilt": rebuilt })));
+        }
+        for branch in &branches {
+            if crate::git::get_branch_commit_info(&repo, branch).is_none() {
+                return Err(IndexAdminError::BranchNotFound(branch.clone()));
+            }
+        }
+        for branch in &branches {
+            match index_admin::drop_index(&path, branch) {
+                Ok(()) | Err(IndexAdminError::NoIndex(_)) => worker.notify(&path, branch),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((StatusCode::ACCEPTED, serde_json::json!({ "queued": branches })))
+    })
+    .await;
+    match res {
+        Ok(Ok((status, body))) => (status, Json(body)).into_response(),
+        Ok(Err(e)) => index_admin_error_response(e),
+        Err(e) => {
+            error!(?e, "index rebuild task panicked");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// DELETE /api/admin/index/{repo}?branch= — drop a branch index
+pub async fn drop_index(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+    Query(params): Query<IndexBranchParams>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let Some(branch) = params.branch else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "branch query parameter is required"})),
+        )
+            .into_response();
+    };
+    let root = state.repo_path.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        index_admin::open_repo(&root, &name)
+            .and_then(|(path, _)| index_admin::drop_index(&path, &branch))
+            .map(|()| branch)
+    })
+    .await;
+    match res {
+        Ok(Ok(branch)) => Json(serde_json::json!({ "dropped": branch })).into_response(),
+        Ok(Err(e)) => index_admin_error_response(e),
+        Err(e) => {
+            error!(?e, "index drop task panicked");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// POST /api/admin/index/{repo}/gc — remove index directories of deleted branches and snapshots
+/// of vanished commits
+pub async fn gc_index(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+) -> Response {
+    if let Some(resp) = check_admin(&state, &headers) {
+        return resp;
+    }
+    let root = state.repo_path.clone();
+    let res = tokio::task::spawn_blocking(move || {
+        let (path, repo) = index_admin::open_repo(&root, &name)?;
+        index_admin::gc(&path, &repo)
+    })
+    .await;
+    match res {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(e)) => index_admin_error_response(e),
+        Err(e) => {
+            error!(?e, "index gc task panicked");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use axum::{
+    extract::{Path as AxPath, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use git2::Repository;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::git::commit::CommitError;
+use crate::git::merge::{self, MergeOutcome};
+use crate::git::{self, branches, BranchError};
+use crate::{helpers, AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBranchRequest {
+    pub name: String,
+    /// Any revision (branch, tag or commit id); defaults to the default branch.
+    #[serde(default)]
+    pub from: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameBranchRequest {
+    /// New branch name
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    /// Revision to merge (usually a branch name)
+    pub source: String,
+    /// Existing branch that receives the merge
+    pub target: String,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+pub(super) fn open_host_repo(state: &AppState, headers: &HeaderMap) -> Result<Repository, String> {
+    let repo_name = helpers::repo_from_host(&state.repo_path, state.node_fqdn.as_deref(), headers)
+        .ok_or_else(|| {
+            "Repository not resolved from Host (use {repo}.{RELAY_PUBLIC_HOSTNAME})".to_string()
+        })?;
+    git::open_repo(&state.repo_path, &repo_name)
+        .ok_or_else(|| format!("Repository {}.git not found", repo_name))
+}
+
+pub(super) fn repo_not_found(msg: String) -> Response {
+    (StatusCode::NOT_FOUND, Json(serde_json::json!({"error": msg}))).into_response()
+}
+
+/// Queue moved branches for background indexing (the moves themselves were published to the
+/// change feed by the write).
+pub(super) fn notify_moved(state: &AppState, repo: &Repository, branches: &[&str]) {
+    for branch in branches {
+        state.index_worker.notify(repo.path(), branch);
+    }
+}
+
+pub(crate) fn branch_error_response(e: BranchError) -> Response {
+    let status = match &e {
+        BranchError::NotFound(_) | BranchError::RevisionNotFound(_) => StatusCode::NOT_FOUND,
+        BranchError::AlreadyExists(_) => StatusCode::CONFLICT,
+        BranchError::InvalidName(_) | BranchError::BadRequest(_) => StatusCode::BAD_REQUEST,
+        BranchError::Rejected(_) => StatusCode::FORBIDDEN,
+        BranchError::Commit(CommitError::Rejected) => StatusCode::BAD_REQUEST,
+        BranchError::Commit(CommitError::Invalid(v)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": e.to_string(), "violations": v.0})),
+            )
+                .into_response()
+        }
+        BranchError::Commit(CommitError::Stale(_)) => StatusCode::CONFLICT,
+        BranchError::Commit(_) => {
+            error!(?e, "commit pipeline failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        BranchError::Git(_) | BranchError::Other(_) => {
+            error!(?e, "branch operation failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(serde_json::json!({"error": e.to_string()}))).into_response()
+}
+
+/// GET /api/branches — list branches of the Host repo with tip commit, author and timestamp
+pub async fn list_branches(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match branches::list_branch_details(&repo) {
+        Ok(list) => Json(serde_json::json!({ "branches": list })).into_response(),
+        Err(e) => branch_error_response(e),
+    }
+}
+
+/// POST /api/branches — create a branch from any revision
+pub async fn create_branch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateBranchRequest>,
+) -> Response {
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match branches::create_branch(&repo, &req.name, req.from.as_deref()) {
+        Ok(info) => {
+            info!(branch = %info.name, commit = %info.commit, "branch created");
+            notify_moved(&state, &repo, &[&info.name]);
+            (StatusCode::CREATED, Json(serde_json::json!({ "branch": info }))).into_response()
+        }
+        Err(e) => branch_error_response(e),
+    }
+}
+
+/// DELETE /api/branches/{name} — delete a branch unless its rule protects it
+pub async fn delete_branch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+) -> Response {
+    let name = helpers::url_decode(&name).decode_utf8_lossy().to_string();
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match branches::delete_branch(&repo, &name) {
+        Ok(()) => {
+            info!(branch = %name, "branch deleted");
+            notify_moved(&state, &repo, &[&name]);
+            Json(serde_json::json!({ "deleted": name })).into_response()
+        }
+        Err(e) => branch_error_response(e),
+    }
+}
+
+/// PATCH /api/branches/{name} — rename a branch (`{ "name": "<new>" }`)
+pub async fn rename_branch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(name): AxPath<String>,
+    Json(req): Json<RenameBranchRequest>,
+) -> Response {
+    let name = helpers::url_decode(&name).decode_utf8_lossy().to_string();
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match branches::rename_branch(&repo, &name, &req.name) {
+        Ok(info) => {
+            info!(from = %name, to = %info.name, "branch renamed");
+            notify_moved(&state, &repo, &[&name, &info.name]);
+            Json(serde_json::json!({ "branch": info, "renamedFrom": name })).into_response()
+        }
+        Err(e) => branch_error_response(e),
+    }
+}
+
+/// POST /api/merge — merge `source` into `target` (fast-forward when possible).
+/// Conflicts are reported as 409 with `{ result: "conflicts", conflicts: [...] }`; refs are untouched.
+pub async fn merge_branches(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<MergeRequest>,
+) -> Response {
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match merge::merge_into_branch(&repo, &req.source, &req.target, req.message.as_deref()) {
+        Ok(outcome @ MergeOutcome::Conflicts { .. }) => {
+            (StatusCode::CONFLICT, Json(outcome)).into_response()
+        }
+        Ok(outcome) => {
+            notify_moved(&state, &repo, &[&req.target]);
+            Json(outcome).into_response()
+        }
+        Err(e) => branch_error_response(e),
+    }
+}
@@ -0,0 +1,98 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use serde::Deserialize;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::git::changes::{self, ChangeEvent};
+use crate::{git, helpers, AppState};
+
+/// Filters of the change feed; query parameters because `EventSource` cannot send headers.
+#[derive(Debug, Default, Deserialize)]
+pub struct EventsQuery {
+    /// Only events of this branch.
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// Only document events of this collection (branch events are always sent).
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+impl EventsQuery {
+    fn accepts(&self, repo: &str, event: &ChangeEvent) -> bool {
+        event.repo() == repo
+            && self.branch.as_deref().is_none_or(|b| b == event.branch())
+            && match (self.collection.as_deref(), event.collection()) {
+                (Some(wanted), Some(collection)) => wanted == collection,
+                _ => true,
+            }
+    }
+}
+
+/// GET /api/events — Server-Sent Events for the Host repo: `ready` with the current
+/// `branchHeads`, then `branch` (head moved) and `documents` (index documents inserted, updated
+/// or removed). `lagged` means events were dropped and the client should re-sync.
+pub async fn get_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(filter): Query<EventsQuery>,
+) -> Response {
+    let Some(repo_name) =
+        helpers::repo_from_host(&state.repo_path, state.node_fqdn.as_deref(), &headers)
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": "Repository not resolved from Host (use {repo}.{RELAY_PUBLIC_HOSTNAME})"})),
+        )
+            .into_response();
+    };
+    let Some(repo) = git::open_repo(&state.repo_path, &repo_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Repository {}.git not found", repo_name)})),
+        )
+            .into_response();
+    };
+
+    // Subscribe before reading the heads so no move falls between the two.
+    let (listener, rx) = changes::subscribe(&repo_name);
+    let heads: serde_json::Map<String, serde_json::Value> = git::list_branches(&repo)
+        .into_iter()
+        .filter(|b| filter.branch.as_deref().is_none_or(|wanted| wanted == b))
+        .filter_map(|b| {
+            let (id, _, _) = git::get_branch_commit_info(&repo, &b)?;
+            Some((b, serde_json::Value::String(id)))
+        })
+        .collect();
+    let ready = Event::default()
+        .event("ready")
+        .json_data(serde_json::json!({ "repo": repo_name, "branchHeads": heads }))
+        .unwrap_or_default();
+
+    // The stream owns the listener, so the repo stops counting as watched when the client leaves.
+    let events = BroadcastStream::new(rx).filter_map(move |item| {
+        let _listening = &listener;
+        match item {
+            Ok(event) if filter.accepts(&repo_name, &event) => {
+                Event::default().event(event.kind()).json_data(&event).ok()
+            }
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Event::default()
+                .event("lagged")
+                .json_data(serde_json::json!({ "missed": missed }))
+                .ok(),
+        }
+    });
+    let stream = tokio_stream::once(ready).chain(events).map(Ok::<_, Infallible>);
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
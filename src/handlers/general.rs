@@ -10,6 +10,7 @@ use axum::{
 use git2::Repository;
 use serde::Serialize;
 
+use crate::git::changes;
 use crate::{authorized_repos, git, helpers, types::*};
 
 /// Serve a minimal OpenAPI YAML specification (placeholder)
@@ -275,6 +276,7 @@ pub async fn post_git_pull(
         }
     };
 
+    let heads_before = changes::branch_heads(&repo);
     let fetch_specs = ["+refs/heads/*:refs/heads/*"];
     let fetch_res = remote.fetch(&fetch_specs, None, None);
     if let Err(e) = fetch_res {
@@ -307,6 +309,7 @@ pub async fn post_git_pull(
         if let Err(msg) = authorized_repos::validate_anchor(&repo, &repo_label, cfg) {
             tracing::warn!("git-pull trust validation failed: {}", msg);
             authorized_repos::rollback_main(&repo, before_commit.as_deref());
+            changes::publish_moves(&bare_path, &heads_before, &changes::branch_heads(&repo));
             return (
                 StatusCode::FORBIDDEN,
                 Json(GitPullResponse {
@@ -323,6 +326,9 @@ pub async fn post_git_pull(
     }
 
     let updated = before_commit != after_commit;
+    // The fetch may move any branch, not only main.
+    changes::publish_moves(&bare_path, &heads_before, &changes::branch_heads(&repo));
+    state.index_worker.notify_repo(&bare_path);
     let message = if updated {
         format!(
             "Fetched {}. main: {:?} -> {:?}",
@@ -485,9 +491,12 @@ pub async fn post_github_hook(
     // For bare repos, we just fetch
     let res = match repo.find_remote("origin") {
         Ok(mut remote) => {
+            let heads_before = changes::branch_heads(&repo);
             if let Err(e) = remote.fetch(&["main"], None, None) {
                 (StatusCode::INTERNAL_SERVER_ERROR, format!("Fetch failed: {}", e)).into_response()
             } else {
+                changes::publish_moves(&full_repo_path, &heads_before, &changes::branch_heads(&repo));
+                state.index_worker.notify(&full_repo_path, "main");
                 (StatusCode::OK, "GitHub hook processed, repository fetched").into_response()
             }
         }
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::error;
+
+use crate::git::branches::BranchError;
+use crate::git::graphql::{self, Scope};
+use crate::types::ALL_BRANCHES;
+use crate::helpers::bad_request;
+use crate::{helpers, AppState};
+
+/// GET /api/graphql — `?query=` (with optional `variables` JSON and `operationName`) runs a query;
+/// without `query` the schema of the branch is returned as SDL.
+pub async fn get_graphql(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(query) = params.get("query") else {
+        return match schema(&state, &headers, &params).await {
+            Ok((schema, _)) => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], schema.sdl()).into_response(),
+            Err(response) => response,
+        };
+    };
+    let mut request = async_graphql::Request::new(query.clone());
+    if let Some(name) = params.get("operationName") {
+        request = request.operation_name(name.clone());
+    }
+    if let Some(vars) = params.get("variables") {
+        match serde_json::from_str(vars) {
+            Ok(vars) => request = request.variables(async_graphql::Variables::from_json(vars)),
+            Err(e) => return bad_request(format!("invalid variables: {}", e)),
+        }
+    }
+    execute(&state, &headers, &params, request).await
+}
+
+/// POST /api/graphql — GraphQL over the collections of the `X-Relay-Branch` index of the Host
+/// repo (`{ query, variables?, operationName? }`). `?asOf=` reads the index as of a past commit.
+pub async fn post_graphql(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    execute(&state, &headers, &params, request).await
+}
+
+async fn execute(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    request: async_graphql::Request,
+) -> Response {
+    match schema(state, headers, params).await {
+        Ok((schema, scope)) => Json(schema.execute(request.data(scope)).await).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Schema of the requested branch (indexing it first if needed) and the scope its resolvers read.
+async fn schema(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<(std::sync::Arc<async_graphql::dynamic::Schema>, Scope), Response> {
+    let Some(repo_name) = helpers::repo_from_host(&state.repo_path, state.node_fqdn.as_deref(), headers) else {
+        return Err(bad_request("Host must be {repo}.{RELAY_PUBLIC_HOSTNAME}".to_string()));
+    };
+    let branch = helpers::branch_from(headers);
+    if branch == ALL_BRANCHES {
+        return Err(bad_request("GraphQL queries one branch; set X-Relay-Branch to a branch name".to_string()));
+    }
+    let repo_root = state.repo_path.clone();
+    let as_of = params.get("asOf").cloned();
+    let task = tokio::task::spawn_blocking(move || {
+        let as_of = as_of
+            .map(|rev| crate::git::query::resolve_commit(&repo_root, &repo_name, &rev))
+            .transpose()?;
+        let schema = graphql::schema_for(&repo_root, &repo_name, &branch, as_of.as_deref())?;
+        Ok::<_, anyhow::Error>((schema, Scope::new(repo_root, repo_name, branch, as_of)))
+    })
+    .await;
+    match task {
+        Ok(Ok(built)) => Ok(built),
+        Ok(Err(e)) if matches!(e.downcast_ref(), Some(BranchError::NotFound(_) | BranchError::RevisionNotFound(_))) => {
+            Err((StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": e.to_string() }))).into_response())
+        }
+        Ok(Err(e)) => {
+            error!(?e, "GraphQL schema build failed");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e.to_string() }))).into_response())
+        }
+        Err(e) => {
+            error!(?e, "GraphQL schema task panicked");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "GraphQL schema build failed").into_response())
+        }
+    }
+}
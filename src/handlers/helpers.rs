@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use git2::Repository;
 use percent_encoding::percent_decode_str;
 
@@ -62,6 +64,11 @@ pub fn branch_from(headers: &HeaderMap) -> String {
     crate::types::DEFAULT_BRANCH.to_string()
 }
 
+/// 400 with `{ "error": msg }`.
+pub fn bad_request(msg: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": msg }))).into_response()
+}
+
 /// Minimal URL percent-decoder wrapper used by handlers.
 /// Returns a percent-decoder so callers can choose utf8 lossless decoding.
 pub fn url_decode(input: &str) -> percent_encoding::PercentDecode<'_> {
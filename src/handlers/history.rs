@@ -0,0 +1,77 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use git2::Repository;
+use serde::Deserialize;
+
+use super::branches::{branch_error_response, notify_moved, open_host_repo, repo_not_found};
+use crate::git::history::{self, HistoryOutcome};
+use crate::{helpers, AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct RevertRequest {
+    /// Commit (or any revision) to revert
+    pub commit: String,
+    /// Parent number to revert against when `commit` is a merge
+    #[serde(default)]
+    pub mainline: Option<u32>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    pub path: String,
+    /// Revision whose contents of `path` are restored
+    pub revision: String,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+fn outcome_response(state: &AppState, repo: &Repository, branch: &str, outcome: HistoryOutcome) -> Response {
+    match outcome {
+        HistoryOutcome::Conflicts { .. } => (StatusCode::CONFLICT, Json(outcome)).into_response(),
+        HistoryOutcome::Committed { .. } => {
+            notify_moved(state, repo, &[branch]);
+            Json(outcome).into_response()
+        }
+        HistoryOutcome::Unchanged { .. } => Json(outcome).into_response(),
+    }
+}
+
+/// POST /api/revert — revert a commit on the `X-Relay-Branch` branch
+pub async fn post_revert(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<RevertRequest>,
+) -> Response {
+    let branch = helpers::branch_from(&headers);
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match history::revert_commit(&repo, &branch, &req.commit, req.mainline, req.message.as_deref()) {
+        Ok(outcome) => outcome_response(&state, &repo, &branch, outcome),
+        Err(e) => branch_error_response(e),
+    }
+}
+
+/// POST /api/restore — restore a path on the `X-Relay-Branch` branch to an older revision
+pub async fn post_restore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<RestoreRequest>,
+) -> Response {
+    let branch = helpers::branch_from(&headers);
+    let repo = match open_host_repo(&state, &headers) {
+        Ok(r) => r,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match history::restore_path(&repo, &branch, &req.path, &req.revision, req.message.as_deref()) {
+        Ok(outcome) => outcome_response(&state, &repo, &branch, outcome),
+        Err(e) => branch_error_response(e),
+    }
+}
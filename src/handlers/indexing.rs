@@ -0,0 +1,64 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::git::index_worker::BranchStatus;
+use crate::git::indexdb::BranchIndex;
+use crate::{git, helpers, AppState};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BranchIndexStatus {
+    branch: String,
+    head: Option<String>,
+    indexed_head: Option<String>,
+    /// The index matches the branch head; QUERY will not index inline.
+    fresh: bool,
+    /// Background worker state; absent when the branch was never queued.
+    #[serde(flatten)]
+    worker: Option<BranchStatus>,
+}
+
+/// GET /api/indexing — index freshness and background worker state per branch of the Host repo
+pub async fn get_indexing_status(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let Some(repo_name) =
+        helpers::repo_from_host(&state.repo_path, state.node_fqdn.as_deref(), &headers)
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": "Repository not resolved from Host (use {repo}.{RELAY_PUBLIC_HOSTNAME})"})),
+        )
+            .into_response();
+    };
+    let Some(repo) = git::open_repo(&state.repo_path, &repo_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({"error": format!("Repository {}.git not found", repo_name)})),
+        )
+            .into_response();
+    };
+    let repo_path = state.repo_path.join(format!("{}.git", repo_name));
+    let mut worker = state.index_worker.status(&repo_path);
+    let branches: Vec<BranchIndexStatus> = git::list_branches(&repo)
+        .into_iter()
+        .map(|branch| {
+            let head = git::get_branch_commit_info(&repo, &branch).map(|(id, _, _)| id);
+            let indexed_head = BranchIndex::open_existing(&repo_path, &branch)
+                .ok()
+                .flatten()
+                .and_then(|index| index.indexed_head().ok().flatten());
+            BranchIndexStatus {
+                fresh: head.is_some() && head == indexed_head,
+                worker: worker.remove(&branch),
+                branch,
+                head,
+                indexed_head,
+            }
+        })
+        .collect();
+    Json(serde_json::json!({ "repo": repo_name, "branches": branches })).into_response()
+}
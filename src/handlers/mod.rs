@@ -1,15 +1,31 @@
+pub mod admin;
+pub mod branches;
+pub mod events;
 pub mod file;
 pub mod general;
+pub mod graphql;
 pub mod head;
+pub mod history;
 pub mod helpers;
+pub mod indexing;
 pub mod write;
 pub mod query;
+pub mod uploads;
 
+pub use admin::{create_repo, delete_repo, drop_index, fork_repo, gc_index, get_index_status, rebuild_index};
+pub use branches::{create_branch, delete_branch, list_branches, merge_branches, rename_branch};
+pub use events::get_events;
 pub use file::{handle_get_file, try_static};
 pub use general::{
     get_api_config, get_openapi_yaml, get_root, get_swagger_ui, options_capabilities,
     post_git_pull, post_github_hook, serve_acme_challenge,
 };
+pub use graphql::{get_graphql, post_graphql};
 pub use head::{head_file, head_root};
+pub use history::{post_restore, post_revert};
+pub use indexing::get_indexing_status;
 pub use write::{delete_file, put_file};
-pub use query::handle_query;
+pub use query::{handle_query, relay_path_fallback};
+pub use uploads::{
+    create_upload, delete_upload, finalize_upload, get_upload, head_upload, patch_upload,
+};
@@ -1,18 +1,64 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{FromRequest, Query, State},
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
 use tracing::error;
+use crate::git::filter::FilterError;
+use crate::git::indexdb::IndexDbError;
+use crate::git::aggregate::{self, AggregateSpec};
+use crate::git::branches::BranchError;
+use crate::git::export::{self, ExportFormat};
+use crate::git::query::{export_items, paginate, sort_items, PageOptions, QueryPage, Scan};
 use crate::{AppState, helpers};
+use crate::helpers::bad_request;
 
+/// Axum's `MethodFilter` does not support the custom `QUERY` verb; unhandled methods hit fallback.
+pub async fn relay_path_fallback(State(state): State<AppState>, req: Request<Body>) -> Response {
+    if req.method().as_str() != "QUERY" {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, "GET, HEAD, PUT, DELETE, OPTIONS, QUERY")],
+            "Method Not Allowed",
+        )
+            .into_response();
+    }
+    let (parts, body) = req.into_parts();
+    let path = parts.uri.path().trim_start_matches('/').to_string();
+    let params = match Query::<HashMap<String, String>>::try_from_uri(&parts.uri) {
+        Ok(params) => params,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.body_text()).into_response(),
+    };
+    let headers = parts.headers.clone();
+    // The `Bytes` extractor applies axum's default 2 MB body limit and answers 413 past it.
+    let bytes = match Bytes::from_request(Request::from_parts(parts, body), &state).await {
+        Ok(bytes) => bytes,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let json = if bytes.is_empty() {
+        None
+    } else {
+        match serde_json::from_slice::<serde_json::Value>(&bytes) {
+            Ok(value) => Some(Json(value)),
+            Err(e) => return bad_request(format!("invalid JSON body: {}", e)),
+        }
+    };
+    handle_query(State(state), headers, axum::extract::Path(path), Some(params), json)
+        .await
+        .into_response()
+}
+
+/// QUERY /{path} — filter, search or aggregate the branch index; the request and response
+/// contract is documented in the README.
 pub async fn handle_query(
     State(state): State<AppState>,
     headers: axum::http::HeaderMap,
     axum::extract::Path(path): axum::extract::Path<String>,
-    _query: Option<Query<HashMap<String, String>>>,
+    query: Option<Query<HashMap<String, String>>>,
     body: Option<Json<serde_json::Value>>,
 ) -> impl IntoResponse {
     let branch = helpers::branch_from(&headers);
@@ -32,28 +78,286 @@ pub async fn handle_query(
     };
 
     let mut collection_storage = "index".to_string();
+    let mut legacy = query
+        .as_ref()
+        .and_then(|q| q.get("legacy"))
+        .is_some_and(|v| v == "true" || v == "1");
+    let mut search = query.as_ref().and_then(|q| q.get("search")).cloned();
+    let mut as_of = query.as_ref().and_then(|q| q.get("asOf")).cloned();
+    let mut page_opts = PageOptions::default();
+    let mut shape = ResultShape {
+        format: query.as_ref().and_then(|q| q.get("format")).cloned(),
+        columns: query
+            .as_ref()
+            .and_then(|q| q.get("columns"))
+            .map(|c| c.split(',').map(str::trim).filter(|c| !c.is_empty()).map(String::from).collect())
+            .unwrap_or_default(),
+        ..ResultShape::default()
+    };
 
     // Override or refine with body if present
     if let Some(Json(b)) = body {
-        if let Some(q) = b.get("query") {
+        if let Some(q) = b.get("filter").or_else(|| b.get("query")) {
             query_val = Some(q.clone());
         }
         if let Some(c) = b.get("collection").and_then(|v| v.as_str()) {
             collection_storage = c.to_string();
         }
+        if let Some(l) = b.get("legacy").and_then(|v| v.as_bool()) {
+            legacy = l;
+        }
+        if let Some(s) = b.get("search").and_then(|v| v.as_str()) {
+            search = Some(s.to_string());
+        }
+        if let Some(rev) = b.get("asOf").and_then(|v| v.as_str()) {
+            as_of = Some(rev.to_string());
+        }
+        let body_shape: ResultShape = match serde_json::from_value(b.clone()) {
+            Ok(s) => s,
+            Err(e) => return bad_request(e.to_string()),
+        };
+        shape = ResultShape {
+            format: body_shape.format.or(shape.format),
+            columns: if body_shape.columns.is_empty() { shape.columns } else { body_shape.columns },
+            ..body_shape
+        };
+        page_opts = match serde_json::from_value(b) {
+            Ok(o) => o,
+            Err(e) => return bad_request(e.to_string()),
+        };
+    }
+
+    let format = match shape.format.as_deref().map(str::parse::<ExportFormat>).transpose() {
+        Ok(Some(ExportFormat::Table)) => return bad_request("format 'table' is only available in the CLI".into()),
+        Ok(f) => f.or_else(|| {
+            headers
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .and_then(ExportFormat::from_accept)
+        }),
+        Err(e) => return bad_request(e),
+    };
+
+    if let Some(format @ (ExportFormat::Ndjson | ExportFormat::Csv)) = format {
+        let collected = shape.aggregate.is_some() || shape.distinct.is_some();
+        if !collected && Scan::applies(&branch, search.as_deref(), &page_opts) {
+            let scan = ScanRequest {
+                repo_name,
+                branch,
+                query: query_val,
+                collection: collection_storage,
+                as_of,
+                page_opts,
+            };
+            return stream_scan(state.repo_path.clone(), scan, format, shape.columns).await;
+        }
+    }
+
+    // Indexing may run a Node hook and wait on other queries' runs; keep it off the async workers.
+    let repo_root = state.repo_path.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let as_of = as_of
+            .map(|rev| crate::git::query::resolve_commit(&repo_root, &repo_name, &rev))
+            .transpose()?;
+        crate::git::query::execute_query(
+            &repo_root,
+            &repo_name,
+            &branch,
+            query_val,
+            &collection_storage,
+            search.as_deref(),
+            as_of.as_deref(),
+        )
+        .map(|results| (results, as_of))
+    })
+    .await;
+    let (results, as_of) = match task {
+        Ok(Ok(results)) => results,
+        Ok(Err(e)) => return query_error_response(e),
+        Err(e) => {
+            error!(?e, "Query task panicked");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Query execution failed").into_response();
+        }
+    };
+    let items = match results {
+        serde_json::Value::Array(items) => items,
+        other => vec![other],
+    };
+
+    if let Some(field) = shape.distinct {
+        let values = aggregate::distinct(&items, &field);
+        let mut body = serde_json::json!({ "field": field, "matched": items.len(), "values": values });
+        if let Some(commit) = as_of {
+            body["asOf"] = serde_json::Value::String(commit);
+        }
+        return Json(body).into_response();
+    }
+    if let Some(spec) = shape.aggregate {
+        let groups = match aggregate::aggregate(&items, &spec) {
+            Ok(g) => g,
+            Err(e) => return bad_request(e.to_string()),
+        };
+        if let Some(format @ (ExportFormat::Ndjson | ExportFormat::Csv)) = format {
+            return stream_rows(format, groups, &page_opts, &shape.columns, as_of);
+        }
+        return match paginate(groups, &page_opts) {
+            Ok(page) => Json(QueryPage { matched: Some(items.len()), as_of, ..page }).into_response(),
+            Err(e) => bad_request(e.to_string()),
+        };
     }
 
-    match crate::git::query::execute_query(
-        &state.repo_path,
-        &repo_name,
-        &branch,
-        query_val,
-        &collection_storage,
-    ) {
-        Ok(results) => (StatusCode::OK, Json(serde_json::json!({ "results": results }))).into_response(),
+    if let Some(format @ (ExportFormat::Ndjson | ExportFormat::Csv)) = format {
+        return stream_rows(format, items, &page_opts, &shape.columns, as_of);
+    }
+    if legacy {
+        let mut items = items;
+        sort_items(&mut items, &page_opts.sort);
+        return (StatusCode::OK, Json(serde_json::json!({ "results": items }))).into_response();
+    }
+    let facets = (!shape.facets.is_empty())
+        .then(|| aggregate::facets(&items, &shape.facets, shape.facet_limit));
+    match paginate(items, &page_opts) {
+        Ok(page) => (StatusCode::OK, Json(QueryPage { facets, as_of, ..page })).into_response(),
+        Err(e) => bad_request(e.to_string()),
+    }
+}
+
+/// Aggregation options of a QUERY body; at most one of `distinct` / `aggregate` applies.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResultShape {
+    #[serde(default)]
+    aggregate: Option<AggregateSpec>,
+    #[serde(default)]
+    distinct: Option<String>,
+    #[serde(default)]
+    facets: Vec<String>,
+    #[serde(default)]
+    facet_limit: Option<usize>,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    columns: Vec<String>,
+}
+
+fn query_error_response(e: anyhow::Error) -> Response {
+    if matches!(e.downcast_ref(), Some(BranchError::RevisionNotFound(_))) {
+        return (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": e.to_string() }))).into_response();
+    }
+    if e.is::<FilterError>() || matches!(e.downcast_ref(), Some(IndexDbError::NotSearchable(_))) {
+        return bad_request(e.to_string());
+    }
+    error!(?e, "Query failed");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Query execution failed").into_response()
+}
+
+/// Encoded chunks buffered between the cursor and the client.
+const SCAN_CHUNKS_IN_FLIGHT: usize = 4;
+
+struct ScanRequest {
+    repo_name: String,
+    branch: String,
+    query: Option<serde_json::Value>,
+    collection: String,
+    as_of: Option<String>,
+    page_opts: PageOptions,
+}
+
+/// Streamed NDJSON / CSV body read off the index cursor on the blocking pool and handed over a
+/// bounded channel, so memory stays flat however many rows match. The row count is not known up
+/// front, so there is no `X-Total-Count`; a failure mid-stream aborts the body. CSV without
+/// `columns` reads the cursor twice: first for the header (every field of every match), then for
+/// the rows.
+async fn stream_scan(repo_root: PathBuf, req: ScanRequest, format: ExportFormat, columns: Vec<String>) -> Response {
+    let prepared = tokio::task::spawn_blocking(move || {
+        let as_of = req
+            .as_of
+            .map(|rev| crate::git::query::resolve_commit(&repo_root, &req.repo_name, &rev))
+            .transpose()?;
+        let scan = Scan::prepare(
+            &repo_root,
+            &req.repo_name,
+            &req.branch,
+            req.query,
+            &req.collection,
+            as_of.as_deref(),
+            &req.page_opts,
+        )?;
+        anyhow::Ok((scan, as_of))
+    })
+    .await;
+    let (scan, as_of) = match prepared {
+        Ok(Ok(prepared)) => prepared,
+        Ok(Err(e)) => return query_error_response(e),
         Err(e) => {
-            error!(?e, "Query failed");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Query execution failed").into_response()
+            error!(?e, "Query task panicked");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Query execution failed").into_response();
+        }
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(SCAN_CHUNKS_IN_FLIGHT);
+    tokio::task::spawn_blocking(move || {
+        let mut columns = columns;
+        let header = if format == ExportFormat::Csv && columns.is_empty() {
+            scan.run(|doc| {
+                export::add_columns(&mut columns, &doc);
+                !tx.is_closed()
+            })
+        } else {
+            Ok(())
+        };
+        let mut encoder = export::ChunkEncoder::new(format, &columns);
+        // A failed send means the client went away: stop reading.
+        let scanned = header
+            .and_then(|()| scan.run(|doc| encoder.push(doc).is_none_or(|chunk| tx.blocking_send(Ok(chunk)).is_ok())));
+        let last = match scanned {
+            Ok(()) => encoder.finish().map(Ok),
+            Err(e) => {
+                error!(?e, "streamed query failed");
+                Some(Err(std::io::Error::other(e.to_string())))
+            }
+        };
+        if let Some(last) = last {
+            let _ = tx.blocking_send(last);
         }
+    });
+    let mut response = (
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)),
+    )
+        .into_response();
+    if let Some(commit) = as_of.and_then(|c| c.parse().ok()) {
+        response.headers_mut().insert("x-as-of", commit);
+    }
+    response
+}
+
+/// Streamed NDJSON / CSV body over rows that had to be collected first (sorted, ranked, merged
+/// across branches or aggregated); `X-Total-Count` carries the row count.
+fn stream_rows(
+    format: ExportFormat,
+    rows: Vec<serde_json::Value>,
+    page_opts: &PageOptions,
+    selected: &[String],
+    as_of: Option<String>,
+) -> axum::response::Response {
+    let rows = match export_items(rows, page_opts) {
+        Ok(rows) => rows,
+        Err(e) => return bad_request(e.to_string()),
+    };
+    let columns = export::columns(&rows, selected);
+    let total = rows.len();
+    let chunks = export::chunks(format, rows, columns).map(Ok::<_, std::convert::Infallible>);
+    let mut response = (
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(tokio_stream::iter(chunks)),
+    )
+        .into_response();
+    let headers = response.headers_mut();
+    headers.insert("x-total-count", total.into());
+    if let Some(commit) = as_of.and_then(|c| c.parse().ok()) {
+        headers.insert("x-as-of", commit);
     }
+    response
 }
+
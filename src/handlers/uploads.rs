@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+
+use axum::{
+    body::Body,
+    extract::{Path as AxPath, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http_body::Body as HttpBody;
+use serde::Deserialize;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tracing::{error, warn};
+
+use super::branches::{branch_error_response, open_host_repo, repo_not_found};
+use crate::git::uploads::{self, UploadError};
+use crate::{helpers, AppState};
+
+const HEADER_UPLOAD_OFFSET: &str = "Upload-Offset";
+const HEADER_UPLOAD_LENGTH: &str = "Upload-Length";
+
+/// Sessions with a PATCH, finalize or DELETE in flight; any other request on the same session is
+/// refused instead of interleaving.
+static ACTIVE_UPLOADS: OnceLock<Mutex<HashSet<(PathBuf, String)>>> = OnceLock::new();
+
+fn active_uploads() -> &'static Mutex<HashSet<(PathBuf, String)>> {
+    ACTIVE_UPLOADS.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// The slot of one session, released on drop so a cancelled request (client gone) frees it.
+struct UploadSlot((PathBuf, String));
+
+impl UploadSlot {
+    fn acquire(key: (PathBuf, String)) -> Option<Self> {
+        let mut active = active_uploads().lock().unwrap_or_else(|e| e.into_inner());
+        active.insert(key.clone()).then(|| UploadSlot(key))
+    }
+}
+
+fn upload_busy() -> Response {
+    (
+        StatusCode::LOCKED,
+        Json(serde_json::json!({"error": "another request is in progress for this upload"})),
+    )
+        .into_response()
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        active_uploads().lock().unwrap_or_else(|e| e.into_inner()).remove(&self.0);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadRequest {
+    /// Destination path in the repository
+    pub path: String,
+    /// Total size in bytes; may also be sent as `Upload-Length`
+    #[serde(default)]
+    pub length: Option<u64>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+fn upload_status(e: &UploadError) -> StatusCode {
+    match e {
+        UploadError::NotFound(_) => StatusCode::NOT_FOUND,
+        UploadError::OffsetMismatch { .. } | UploadError::Incomplete { .. } | UploadError::Unsettled { .. } => {
+            StatusCode::CONFLICT
+        }
+        UploadError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        UploadError::InvalidPath(_) => StatusCode::BAD_REQUEST,
+        UploadError::Branch(_) | UploadError::Io(_) | UploadError::Git(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+fn upload_error_response(e: UploadError) -> Response {
+    match e {
+        UploadError::Branch(be) => branch_error_response(be),
+        UploadError::OffsetMismatch { expected } => (
+            StatusCode::CONFLICT,
+            [(HEADER_UPLOAD_OFFSET, expected.to_string())],
+            Json(serde_json::json!({"error": e.to_string()})),
+        )
+            .into_response(),
+        other => {
+            let status = upload_status(&other);
+            if status == StatusCode::INTERNAL_SERVER_ERROR {
+                error!(?other, "upload failed");
+            }
+            (status, Json(serde_json::json!({"error": other.to_string()}))).into_response()
+        }
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse().ok())
+}
+
+fn host_repo_path(state: &AppState, headers: &HeaderMap) -> Result<PathBuf, String> {
+    open_host_repo(state, headers).map(|r| r.path().to_path_buf())
+}
+
+/// POST /api/uploads — start a resumable upload to `path` on the `X-Relay-Branch` branch
+pub async fn create_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateUploadRequest>,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    let branch = helpers::branch_from(&headers);
+    let length = req.length.or_else(|| header_u64(&headers, HEADER_UPLOAD_LENGTH));
+    match uploads::create_session(&repo_path, &branch, &req.path, length, req.message) {
+        Ok(session) => (
+            StatusCode::CREATED,
+            [
+                ("Location", format!("/api/uploads/{}", session.id)),
+                (HEADER_UPLOAD_OFFSET, "0".to_string()),
+            ],
+            Json(session),
+        )
+            .into_response(),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+/// GET /api/uploads/{id} — session status as JSON
+pub async fn get_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(id): AxPath<String>,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match uploads::load_session(&repo_path, &id) {
+        Ok(session) => Json(session).into_response(),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+/// HEAD /api/uploads/{id} — current `Upload-Offset` (and `Upload-Length` when declared)
+pub async fn head_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(id): AxPath<String>,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    match uploads::load_session(&repo_path, &id) {
+        Ok(session) => {
+            let mut resp = StatusCode::OK.into_response();
+            let h = resp.headers_mut();
+            if let Ok(v) = session.offset.to_string().parse() {
+                h.insert(HEADER_UPLOAD_OFFSET, v);
+            }
+            if let Some(Ok(v)) = session.length.map(|l| l.to_string().parse()) {
+                h.insert(HEADER_UPLOAD_LENGTH, v);
+            }
+            h.insert("Cache-Control", axum::http::HeaderValue::from_static("no-store"));
+            resp
+        }
+        Err(e) => upload_status(&e).into_response(),
+    }
+}
+
+/// PATCH /api/uploads/{id} — append the request body at `Upload-Offset`.
+/// Bytes received before a dropped connection are kept so the client can resume.
+pub async fn patch_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(id): AxPath<String>,
+    body: Body,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    let offset = match header_u64(&headers, HEADER_UPLOAD_OFFSET) {
+        Some(o) => o,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "Upload-Offset header required"})),
+            )
+                .into_response()
+        }
+    };
+    // Take the slot before checking the offset, so two PATCHes at the same offset cannot both pass
+    let Some(_slot) = UploadSlot::acquire((repo_path.clone(), id.clone())) else {
+        return upload_busy();
+    };
+    let chunk_len = header_u64(&headers, "content-length");
+    let session = match uploads::check_append(&repo_path, &id, offset, chunk_len) {
+        Ok(s) => s,
+        Err(e) => return upload_error_response(e),
+    };
+    let result = stream_chunk(&repo_path, &id, offset, session.length, body).await;
+
+    match result {
+        Ok(new_offset) => (
+            StatusCode::NO_CONTENT,
+            [(HEADER_UPLOAD_OFFSET, new_offset.to_string())],
+        )
+            .into_response(),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+async fn stream_chunk(
+    repo_path: &std::path::Path,
+    id: &str,
+    offset: u64,
+    length: Option<u64>,
+    mut body: Body,
+) -> Result<u64, UploadError> {
+    let data = uploads::data_path(repo_path, id)?;
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(&data).await?;
+    file.set_len(offset).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    let mut written = offset;
+    let mut failure = None;
+    loop {
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await;
+        let frame = match frame {
+            None => break,
+            Some(Ok(f)) => f,
+            Some(Err(e)) => {
+                warn!(%id, ?e, "upload body interrupted");
+                failure = Some(UploadError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    e.to_string(),
+                )));
+                break;
+            }
+        };
+        if let Ok(chunk) = frame.into_data() {
+            if let Some(l) = length {
+                if written + chunk.len() as u64 > l {
+                    failure = Some(UploadError::TooLarge(l));
+                    break;
+                }
+            }
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+    }
+    file.set_len(written).await?;
+    file.sync_data().await?;
+    uploads::commit_offset(repo_path, id, written)?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(written),
+    }
+}
+
+/// POST /api/uploads/{id}/finalize — write the spooled file as a blob and commit it
+pub async fn finalize_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(id): AxPath<String>,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    let Some(slot) = UploadSlot::acquire((repo_path.clone(), id.clone())) else {
+        return upload_busy();
+    };
+    let bare_path = repo_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        let repo = git2::Repository::open_bare(&repo_path)?;
+        uploads::finalize(&repo, &id)
+    })
+    .await;
+    match result {
+        Ok(Ok((session, commit))) => {
+            state.index_worker.notify(&bare_path, &session.branch);
+            Json(serde_json::json!({
+                "commit": commit,
+                "branch": session.branch,
+                "path": session.path,
+            }))
+            .into_response()
+        }
+        Ok(Err(e)) => upload_error_response(e),
+        Err(e) => {
+            error!(?e, "finalize task failed");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// DELETE /api/uploads/{id} — abort and discard the spooled data
+pub async fn delete_upload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AxPath(id): AxPath<String>,
+) -> Response {
+    let repo_path = match host_repo_path(&state, &headers) {
+        Ok(p) => p,
+        Err(msg) => return repo_not_found(msg),
+    };
+    let Some(_slot) = UploadSlot::acquire((repo_path.clone(), id.clone())) else {
+        return upload_busy();
+    };
+    match uploads::abort(&repo_path, &id) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => upload_error_response(e),
+    }
+}
@@ -13,6 +13,8 @@ use git2::{ObjectType, Oid, Repository, Signature};
 use thiserror::Error;
 use tracing::{debug, error};
 
+use crate::git::branches::{ensure_server_commit_allowed, BranchError};
+use crate::git::changes;
 use crate::{git, helpers, types::AppState};
 
 /// Handle PUT writes into a repo branch and commit changes.
@@ -37,13 +39,18 @@ pub async fn put_file(
     };
     match write_file_to_repo(&state.repo_path, &repo_name, &branch, &decoded, &body) {
         Ok((commit, branch)) => {
+            state
+                .index_worker
+                .notify(&state.repo_path.join(format!("{}.git", repo_name)), &branch);
             Json(serde_json::json!({"commit": commit, "branch": branch, "path": decoded}))
                 .into_response()
         }
         Err(e) => {
             error!(?e, "write error");
             let msg = e.to_string();
-            if msg.contains("rejected by") || msg.contains("validation failed") {
+            if matches!(e.downcast_ref(), Some(BranchError::Rejected(_))) {
+                (StatusCode::FORBIDDEN, msg).into_response()
+            } else if msg.contains("rejected by") || msg.contains("validation failed") {
                 (StatusCode::BAD_REQUEST, msg).into_response()
             } else {
                 (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
@@ -67,10 +74,14 @@ pub async fn delete_file(
     };
     match delete_file_in_repo(&state.repo_path, &repo_name, &branch, &decoded) {
         Ok((commit, branch)) => {
+            state
+                .index_worker
+                .notify(&state.repo_path.join(format!("{}.git", repo_name)), &branch);
             Json(serde_json::json!({"commit": commit, "branch": branch, "path": decoded}))
                 .into_response()
         }
         Err(RepoEditError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(e @ RepoEditError::Rejected(_)) => (StatusCode::FORBIDDEN, e.to_string()).into_response(),
         Err(e) => {
             error!(?e, "delete error");
             (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
@@ -83,6 +94,8 @@ pub enum RepoEditError {
     #[error("not found")]
     NotFound,
     #[error(transparent)]
+    Rejected(BranchError),
+    #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
@@ -100,6 +113,7 @@ pub fn write_file_to_repo(
         }
     };
     let refname = format!("refs/heads/{}", branch);
+    ensure_server_commit_allowed(&repo, branch)?;
     let sig = Signature::now("relay", "relay@local")?;
 
     // Current tree (or empty)
@@ -194,6 +208,7 @@ pub fn write_file_to_repo(
 
         match git::execute_repo_hook(&ctx, "pre-commit") {
             Ok(false) => anyhow::bail!("pre-commit hook rejected the change"),
+            Err(e) if e.is::<git::schema::SchemaViolations>() => return Err(e),
             Err(e) => anyhow::bail!("pre-commit hook error: {}", e),
             Ok(true) => {} // Success
         }
@@ -208,6 +223,12 @@ pub fn write_file_to_repo(
             repo.reference(&refname, commit_oid, true, &msg)?;
         }
     }
+    changes::publish_branch(
+        repo.path(),
+        branch,
+        parent_commit.as_ref().map(|c| c.id().to_string()),
+        Some(commit_oid.to_string()),
+    );
 
     // Trigger post-receive hooks (like Auto-Push)
     {
@@ -247,6 +268,7 @@ pub fn delete_file_in_repo(
         }
         Err(_) => return Err(RepoEditError::NotFound),
     };
+    ensure_server_commit_allowed(&repo, branch).map_err(RepoEditError::Rejected)?;
 
     // Recursively remove path
     fn remove_path(
@@ -302,5 +324,11 @@ pub fn delete_file_in_repo(
         repo.commit(Some(&refname), &sig, &sig, &msg, &new_tree, &[])
             .map_err(|e| RepoEditError::Other(e.into()))?
     };
+    changes::publish_branch(
+        repo.path(),
+        branch,
+        parent_commit.as_ref().map(|c| c.id().to_string()),
+        Some(commit_oid.to_string()),
+    );
     Ok((commit_oid.to_string(), branch.to_string()))
 }
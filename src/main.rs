@@ -1,12 +1,9 @@
 use std::net::SocketAddr;
 use anyhow::Result;
 use axum::{
-    body::Body,
-    extract::{Path as AxPath, State},
-    http::{header, Request, StatusCode},
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router,
+    extract::Path as AxPath,
+    routing::{delete, get, post},
+    Router,
 };
 use clap::Parser;
 use tokio::net::TcpListener;
@@ -19,41 +16,35 @@ use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 use relay_server::{
     cli::Cli,
     config::{self, Config},
-    handlers, transpiler, AppState,
+    handlers, transpiler,
 };
 
-/// Axum's `MethodFilter` does not support the custom `QUERY` verb; unhandled methods hit fallback.
-async fn relay_path_fallback(
-    State(state): State<AppState>,
-    req: Request<Body>,
-) -> axum::response::Response {
-    if req.method().as_str() != "QUERY" {
-        return (
-            StatusCode::METHOD_NOT_ALLOWED,
-            [(header::ALLOW, "GET, HEAD, PUT, DELETE, OPTIONS, QUERY")],
-            "Method Not Allowed",
-        )
-            .into_response();
-    }
-    let (parts, body) = req.into_parts();
-    let path = parts.uri.path().trim_start_matches('/').to_string();
-    let headers = parts.headers;
-    let json = match axum::body::to_bytes(body, 2 * 1024 * 1024).await {
-        Ok(b) if b.is_empty() => None,
-        Ok(b) => serde_json::from_slice::<serde_json::Value>(&b)
-            .ok()
-            .map(Json),
-        Err(_) => None,
-    };
-    handlers::handle_query(State(state), headers, AxPath(path), None, json)
-        .await
-        .into_response()
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // Invoked from hook sandboxes: keep stdout clean and skip logging/config setup.
+    if let Some(relay_server::cli::Commands::Db(args)) = &cli.command {
+        let relay_server::cli::DbCommand::Find { db, collection, filter, journal } = &args.command;
+        let filter = filter
+            .as_deref()
+            .map(|f| serde_json::from_str::<serde_json::Value>(f).map_err(anyhow::Error::from))
+            .transpose()?
+            .map(|v| relay_server::git::filter::Filter::parse(&v))
+            .transpose()?;
+        use relay_server::git::indexdb::{read_journal, BranchIndex};
+        let pending = journal.as_deref().map(read_journal).transpose()?.unwrap_or_default();
+        let docs = if pending.is_empty() {
+            BranchIndex::open_read_only(db)?.find(collection, filter.as_ref())?
+        } else if db.exists() {
+            BranchIndex::open_path(db)?.find_pending(&pending, collection, filter.as_ref())?
+        } else {
+            BranchIndex::open_in_memory()?.find_pending(&pending, collection, filter.as_ref())?
+        };
+        println!("{}", serde_json::to_string(&docs)?);
+        return Ok(());
+    }
+
     // Set up logging: stdout + rolling file appender
     let _ = std::fs::create_dir_all("logs");
     let file_appender = rolling::daily("logs", "server.log");
@@ -78,16 +69,49 @@ async fn main() -> Result<()> {
     config.initialize_repos();
 
     if let Some(relay_server::cli::Commands::Query(args)) = cli.command {
-        let query_val = args.query.map(serde_json::Value::String);
-        match relay_server::git::query::execute_query(
-            &config.state.repo_path,
-            &args.repo,
-            &args.branch,
-            query_val,
-            &args.collection,
-        ) {
+        let query_val = match args.filter {
+            Some(f) => Some(serde_json::from_str::<serde_json::Value>(&f)?),
+            None => args.query.map(serde_json::Value::String),
+        };
+        let results = args
+            .as_of
+            .as_deref()
+            .map(|rev| relay_server::git::query::resolve_commit(&config.state.repo_path, &args.repo, rev))
+            .transpose()
+            .and_then(|as_of| {
+                relay_server::git::query::execute_query(
+                    &config.state.repo_path,
+                    &args.repo,
+                    &args.branch,
+                    query_val,
+                    &args.collection,
+                    args.search.as_deref(),
+                    as_of.as_deref(),
+                )
+            });
+        match results {
             Ok(results) => {
-                println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "results": results }))?);
+                use relay_server::git::export::{self, ExportFormat};
+                let items = match results {
+                    serde_json::Value::Array(items) => items,
+                    other => vec![other],
+                };
+                match args.format {
+                    ExportFormat::Json => {
+                        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "results": items }))?)
+                    }
+                    ExportFormat::Table => {
+                        print!("{}", export::table(&items, &export::columns(&items, &args.columns)))
+                    }
+                    format => {
+                        use std::io::Write;
+                        let columns = export::columns(&items, &args.columns);
+                        let mut out = std::io::stdout().lock();
+                        for chunk in export::chunks(format, items, columns) {
+                            out.write_all(chunk.as_bytes())?;
+                        }
+                    }
+                }
                 return Ok(());
             }
             Err(e) => {
@@ -97,8 +121,127 @@ async fn main() -> Result<()> {
         }
     }
 
+    if let Some(relay_server::cli::Commands::Repo(args)) = cli.command {
+        use relay_server::cli::RepoCommand;
+        use relay_server::git::provision::{self, RepoSource};
+        let root = &config.state.repo_path;
+        let res = match args.command {
+            RepoCommand::Create { name, url, template } => {
+                let source = match (url, template) {
+                    (Some(url), _) => RepoSource::Url(url),
+                    (None, Some(template)) => RepoSource::Template(template),
+                    (None, None) => RepoSource::Empty,
+                };
+                provision::create_repo(root, &name, &source)
+                    .map(|repo| serde_json::json!({ "repo": repo }))
+            }
+            RepoCommand::Delete { name, archive } => {
+                if archive {
+                    provision::archive_repo(root, &name)
+                        .map(|dest| serde_json::json!({ "archived": name, "path": dest }))
+                } else {
+                    provision::delete_repo(root, &name).map(|()| serde_json::json!({ "deleted": name }))
+                }
+            }
+            RepoCommand::Fork { source, name, branches, shared, authorize } => {
+                let authorized_path = config.state.authorized_repos.as_ref().and_then(|cfg| cfg.path.clone());
+                if authorize && authorized_path.is_none() {
+                    error!("--authorize requires RELAY_AUTHORIZED_REPOS_PATH");
+                    std::process::exit(1);
+                }
+                let sharing = if shared {
+                    provision::ObjectSharing::Alternates
+                } else {
+                    provision::ObjectSharing::Hardlink
+                };
+                provision::fork_repo(root, &source, &name, &branches, sharing).map(|repo| {
+                    let authorized = match authorized_path.filter(|_| authorize) {
+                        None => None,
+                        Some(path) => match relay_server::authorized_repos::authorize_fork(&path, &repo) {
+                            Ok(anchor) => Some(anchor),
+                            Err(e) => {
+                                println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "repo": repo })).unwrap_or_default());
+                                error!("Fork {} was created but not added to the authorized-repos file: {}", repo.name, e);
+                                std::process::exit(1);
+                            }
+                        },
+                    };
+                    serde_json::json!({ "repo": repo, "authorized": authorized })
+                })
+            }
+            RepoCommand::InstallHooks { name } => {
+                provision::validate_repo_name(&name)
+                    .and_then(|()| provision::install_hooks(&root.join(format!("{}.git", name))))
+                    .map(|hooks| serde_json::json!({ "repo": name, "hooks": hooks }))
+            }
+        };
+        match res {
+            Ok(out) => {
+                println!("{}", serde_json::to_string_pretty(&out)?);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Repo command failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(relay_server::cli::Commands::Index(args)) = cli.command {
+        use relay_server::cli::IndexCommand;
+        use relay_server::git::index_admin;
+        let root = &config.state.repo_path;
+        let all_repos = |repo: Option<String>| match repo {
+            Some(name) => vec![name],
+            None => relay_server::git::bare_repo_names(root),
+        };
+        let res = match args.command {
+            IndexCommand::Status { repo } => all_repos(repo)
+                .into_iter()
+                .map(|name| {
+                    let (path, repo) = index_admin::open_repo(root, &name)?;
+                    let branches = index_admin::status(&path, &repo)?;
+                    Ok(serde_json::json!({ "repo": name, "branches": branches }))
+                })
+                .collect::<Result<Vec<_>, index_admin::IndexAdminError>>()
+                .map(serde_json::Value::from),
+            IndexCommand::Rebuild { repo: name, branch } => index_admin::open_repo(root, &name)
+                .and_then(|(path, repo)| {
+                    let branches = branch.map(|b| vec![b]).unwrap_or_else(|| relay_server::git::list_branches(&repo));
+                    branches.iter().map(|b| index_admin::rebuild(&path, &repo, b)).collect::<Result<Vec<_>, _>>()
+                })
+                .map(|rebuilt| serde_json::json!({ "repo": name, "rebuilt": rebuilt })),
+            IndexCommand::Drop { repo: name, branch } => index_admin::open_repo(root, &name)
+                .and_then(|(path, _)| index_admin::drop_index(&path, &branch))
+                .map(|()| serde_json::json!({ "repo": name, "dropped": branch })),
+            IndexCommand::Gc { repo } => all_repos(repo)
+                .into_iter()
+                .map(|name| {
+                    let (path, repo) = index_admin::open_repo(root, &name)?;
+                    let report = index_admin::gc(&path, &repo)?;
+                    Ok(serde_json::json!({ "repo": name, "removed": report }))
+                })
+                .collect::<Result<Vec<_>, index_admin::IndexAdminError>>()
+                .map(serde_json::Value::from),
+        };
+        match res {
+            Ok(out) => {
+                println!("{}", serde_json::to_string_pretty(&out)?);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Index command failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     info!(repo_path = %config.state.repo_path.display(), "Repository path resolved");
 
+    // Catch up every branch in the background so the first QUERY finds a fresh index.
+    config.state.index_worker.start(config.index_workers);
+    config.state.index_worker.notify_all();
+
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -109,6 +252,38 @@ async fn main() -> Result<()> {
         .route("/openapi.yaml", get(handlers::get_openapi_yaml))
         .route("/swagger-ui", get(handlers::get_swagger_ui))
         .route("/api/config", get(handlers::get_api_config))
+        .route(
+            "/api/branches",
+            get(handlers::list_branches).post(handlers::create_branch),
+        )
+        .route(
+            "/api/branches/*name",
+            delete(handlers::delete_branch).patch(handlers::rename_branch),
+        )
+        .route("/api/merge", post(handlers::merge_branches))
+        .route("/api/indexing", get(handlers::get_indexing_status))
+        .route("/api/events", get(handlers::get_events))
+        .route("/api/graphql", get(handlers::get_graphql).post(handlers::post_graphql))
+        .route("/api/revert", post(handlers::post_revert))
+        .route("/api/restore", post(handlers::post_restore))
+        .route("/api/uploads", post(handlers::create_upload))
+        .route(
+            "/api/uploads/:id",
+            get(handlers::get_upload)
+                .head(handlers::head_upload)
+                .patch(handlers::patch_upload)
+                .delete(handlers::delete_upload),
+        )
+        .route("/api/uploads/:id/finalize", post(handlers::finalize_upload))
+        .route("/api/admin/repos", post(handlers::create_repo))
+        .route("/api/admin/repos/:name", delete(handlers::delete_repo))
+        .route("/api/admin/repos/:name/fork", post(handlers::fork_repo))
+        .route(
+            "/api/admin/index/:name",
+            get(handlers::get_index_status).delete(handlers::drop_index),
+        )
+        .route("/api/admin/index/:name/rebuild", post(handlers::rebuild_index))
+        .route("/api/admin/index/:name/gc", post(handlers::gc_index))
         .route("/git-pull", post(handlers::post_git_pull))
         .route("/hooks/github/:repo", post(handlers::post_github_hook))
         .route("/transpile", post(transpiler::post_transpile))
@@ -134,7 +309,7 @@ async fn main() -> Result<()> {
                 .put(handlers::put_file)
                 .delete(handlers::delete_file)
                 .options(handlers::options_capabilities)
-                .fallback(relay_path_fallback),
+                .fallback(handlers::relay_path_fallback),
         )
         .layer(cors)
         .layer(TraceLayer::new_for_http())
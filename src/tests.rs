@@ -6,8 +6,8 @@ mod tests {
     use std::path::Path as FsPath;
     use tempfile::tempdir;
     use axum::{
-        extract::{Path as AxPath, State},
-        http::{HeaderMap, StatusCode},
+        extract::{Path as AxPath, Query, State},
+        http::{header, HeaderMap, StatusCode},
         response::IntoResponse,
         Json,
     };
@@ -16,12 +16,14 @@ mod tests {
 
     fn test_state(repo_path: std::path::PathBuf) -> AppState {
         AppState {
-            repo_path,
+            repo_path: repo_path.clone(),
             static_paths: Vec::new(),
             node_fqdn: Some("test.local".to_string()),
             relay_server_id: None,
             authorized_repos: None,
             features_manifest: None,
+            admin_token: None,
+            index_worker: crate::git::index_worker::IndexWorker::new(repo_path),
         }
     }
 
@@ -34,6 +36,25 @@ mod tests {
         h
     }
 
+    /// `{root}/repo.git` with a `main` commit configuring a no-op `index` hook; returns the commit.
+    fn init_repo_with_index_hook(root: &FsPath) -> (Repository, git2::Oid) {
+        let repo = Repository::init_bare(root.join("repo.git")).unwrap();
+        let sig = Signature::now("relay", "relay@local").unwrap();
+        let config_oid = repo
+            .blob(b"server:\n  hooks:\n    index:\n      path: hooks/server/index.mjs\n")
+            .unwrap();
+        let index_oid = repo.blob(b"process.exit(0);").unwrap();
+        let tree_oid = git2::build::TreeUpdateBuilder::new()
+            .upsert(".relay.yaml", config_oid, git2::FileMode::Blob)
+            .upsert("hooks/server/index.mjs", index_oid, git2::FileMode::Blob)
+            .create_updated(&repo, &repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap())
+            .unwrap();
+        let tree = repo.find_tree(tree_oid).unwrap();
+        let commit = repo.commit(Some("refs/heads/main"), &sig, &sig, "init", &tree, &[]).unwrap();
+        drop(tree);
+        (repo, commit)
+    }
+
     /// Test OPTIONS returns repository list with branches and commit heads
     #[tokio::test]
     async fn test_options_returns_repo_list() {
@@ -412,9 +433,12 @@ server:
         let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
         let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
         
-        let results = json["results"].as_array().unwrap();
-        assert_eq!(results.len(), 1);
-        assert_eq!(results[0]["title"], "Test Item");
+        assert_eq!(json["total"], 1);
+        assert_eq!(json["page"], 0);
+        assert_eq!(json["pageSize"], 25);
+        let items = json["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["title"], "Test Item");
     }
 
     #[tokio::test]
@@ -472,7 +496,7 @@ process.exit(0);
             State(state),
             headers,
             AxPath("Test".to_string()),
-            None,
+            Some(Query(HashMap::from([("legacy".to_string(), "true".to_string())]))),
             None
         ).await;
 
@@ -486,4 +510,662 @@ process.exit(0);
         assert_eq!(results.len(), 1);
         assert_eq!(results[0]["title"], "Test Item");
     }
+
+    #[tokio::test]
+    async fn test_query_all_branches_tags_results() {
+        let repo_dir = tempdir().unwrap();
+        let (repo, c) = init_repo_with_index_hook(repo_dir.path());
+        repo.reference("refs/heads/proposal-1", c, false, "branch").unwrap();
+
+        let state = test_state(repo_dir.path().to_path_buf());
+        let mut headers = host_header("repo");
+        headers.insert(HEADER_BRANCH, ALL_BRANCHES.parse().unwrap());
+        let body = serde_json::json!({
+            "filter": { "title": "Test Item" },
+            "sort": [{ "field": "_branch", "dir": "desc" }],
+            "pageSize": 1
+        });
+        let response = handlers::handle_query(
+            State(state),
+            headers,
+            AxPath("query".to_string()),
+            None,
+            Some(Json(body)),
+        )
+        .await;
+
+        let (parts, body) = response.into_response().into_parts();
+        assert_eq!(parts.status, StatusCode::OK);
+        let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(json["total"], 2);
+        let items = json["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["_branch"], "proposal-1");
+        assert_eq!(items[0]["title"], "Test Item");
+    }
+
+    #[tokio::test]
+    async fn test_query_streams_csv_and_ndjson() {
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+        let state = test_state(repo_dir.path().to_path_buf());
+
+        let mut headers = host_header("repo");
+        headers.insert(header::ACCEPT, "text/csv".parse().unwrap());
+        let params = HashMap::from([("columns".to_string(), "title,_id,missing".to_string())]);
+        let response = handlers::handle_query(
+            State(state.clone()),
+            headers,
+            AxPath("query".to_string()),
+            Some(Query(params)),
+            Some(Json(serde_json::json!({ "filter": { "title": "Test Item" } }))),
+        )
+        .await;
+        let (parts, body) = response.into_response().into_parts();
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers[header::CONTENT_TYPE], "text/csv; charset=utf-8");
+        // Read off the index cursor: the count is not known before the first row.
+        assert!(!parts.headers.contains_key("x-total-count"));
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), "title,_id,missing\r\nTest Item,1,\r\n");
+
+        // Sorting needs every row first, so that export is collected and counted.
+        let response = handlers::handle_query(
+            State(state.clone()),
+            host_header("repo"),
+            AxPath("query".to_string()),
+            None,
+            Some(Json(serde_json::json!({ "format": "csv", "sort": [{ "field": "title" }], "columns": ["title"] }))),
+        )
+        .await;
+        let (parts, body) = response.into_response().into_parts();
+        assert_eq!(parts.headers["x-total-count"], "1");
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), "title\r\nTest Item\r\n");
+
+        let response = handlers::handle_query(
+            State(state.clone()),
+            host_header("repo"),
+            AxPath("query".to_string()),
+            None,
+            Some(Json(serde_json::json!({ "format": "ndjson", "projection": ["title"] }))),
+        )
+        .await;
+        let (parts, body) = response.into_response().into_parts();
+        assert_eq!(parts.headers[header::CONTENT_TYPE], "application/x-ndjson");
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines, vec![serde_json::json!({ "title": "Test Item" })]);
+
+        // Streamed CSV without `columns`: a first pass over the cursor collects the header.
+        let response = handlers::handle_query(
+            State(state.clone()),
+            host_header("repo"),
+            AxPath("query".to_string()),
+            None,
+            Some(Json(serde_json::json!({ "format": "csv", "projection": ["title"] }))),
+        )
+        .await;
+        let (parts, body) = response.into_response().into_parts();
+        assert!(!parts.headers.contains_key("x-total-count"));
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap(), "title\r\nTest Item\r\n");
+    }
+
+    /// The QUERY verb reaches `handle_query` through the router fallback, query string included
+    #[tokio::test]
+    async fn test_query_verb_through_router_keeps_query_params() {
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+        let app = axum::Router::new()
+            .route(
+                "/*path",
+                axum::routing::get(handlers::handle_get_file).fallback(handlers::relay_path_fallback),
+            )
+            .with_state(test_state(repo_dir.path().to_path_buf()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::builder().resolve("repo.test.local", addr).build().unwrap();
+        let query = reqwest::Method::from_bytes(b"QUERY").unwrap();
+        let url = format!("http://repo.test.local:{}/query", addr.port());
+        let response = client
+            .request(query.clone(), format!("{}?legacy=true", url))
+            .json(&serde_json::json!({ "filter": { "title": "Test Item" } }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let json: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(json["results"][0]["title"], "Test Item");
+
+        let response = client.request(query, format!("{}?format=ndjson", url)).send().await.unwrap();
+        assert_eq!(response.headers()[reqwest::header::CONTENT_TYPE], "application/x-ndjson");
+
+        let response = client.post(&url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_query_verb_rejects_malformed_and_oversized_bodies() {
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+        let app = axum::Router::new()
+            .route(
+                "/*path",
+                axum::routing::get(handlers::handle_get_file).fallback(handlers::relay_path_fallback),
+            )
+            .with_state(test_state(repo_dir.path().to_path_buf()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::builder().resolve("repo.test.local", addr).build().unwrap();
+        let query = reqwest::Method::from_bytes(b"QUERY").unwrap();
+        let url = format!("http://repo.test.local:{}/query", addr.port());
+        let response = client.request(query.clone(), &url).body("{\"filter\": ").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let json: serde_json::Value = response.json().await.unwrap();
+        assert!(json["error"].as_str().unwrap().contains("EOF"), "{}", json);
+
+        let oversized = format!("{{\"filter\": {{\"title\": \"{}\"}}}}", "x".repeat(3 * 1024 * 1024));
+        let response = client.request(query, &url).body(oversized).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_lists_and_resolves_references() {
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path().join("films.git")).unwrap();
+        let sig = Signature::now("relay", "relay@local").unwrap();
+        let config = r#"
+server:
+  db:
+    sources:
+      - { glob: "movies/*.yaml", collection: movies }
+      - { glob: "directors/*.yaml", collection: directors }
+    collections:
+      movies:
+        schema:
+          type: object
+          properties:
+            title: { type: string }
+            starring: { type: array, items: { type: string }, x-ref: directors }
+"#;
+        let files = [
+            (".relay.yaml", config),
+            ("movies/heat.yaml", "title: Heat\nyear: 1995\ndirectorId: mann\nstarring: [mann, scott]\n"),
+            ("movies/alien.yaml", "title: Alien\nyear: 1979\ndirectorId: scott\n"),
+            ("directors/mann.yaml", "id: mann\nname: Michael Mann\n"),
+            ("directors/scott.yaml", "id: scott\nname: Ridley Scott\n"),
+        ];
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for (path, content) in files {
+            update.upsert(path, repo.blob(content.as_bytes()).unwrap(), git2::FileMode::Blob);
+        }
+        let empty = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        let tree = repo.find_tree(update.create_updated(&repo, &empty).unwrap()).unwrap();
+        repo.commit(Some("refs/heads/main"), &sig, &sig, "init", &tree, &[]).unwrap();
+        let state = test_state(repo_dir.path().to_path_buf());
+
+        let request = serde_json::json!({
+            "query": "query($f: JSON) { _collections movies(filter: $f, sort: [{ field: \"year\", dir: DESC }]) { total items { title year director { name } starringRef { id } } } directorsById(id: \"scott\") { name _raw } nobody: directorsById(id: \"nobody\") { name } }",
+            "variables": { "f": { "year": { "$gte": 1970 } } }
+        });
+        let response = handlers::post_graphql(
+            State(state.clone()),
+            host_header("films"),
+            Query(HashMap::new()),
+            Json(serde_json::from_value(request).unwrap()),
+        )
+        .await;
+        let (parts, body) = response.into_parts();
+        assert_eq!(parts.status, StatusCode::OK);
+        let json: serde_json::Value =
+            serde_json::from_slice(&axum::body::to_bytes(body, usize::MAX).await.unwrap()).unwrap();
+        assert!(json.get("errors").is_none(), "{}", json);
+        let data = &json["data"];
+        assert_eq!(data["_collections"], serde_json::json!(["directors", "movies"]));
+        assert_eq!(data["movies"]["total"], 2);
+        assert_eq!(
+            data["movies"]["items"],
+            serde_json::json!([
+                { "title": "Heat", "year": 1995, "director": { "name": "Michael Mann" },
+                  "starringRef": [{ "id": "mann" }, { "id": "scott" }] },
+                { "title": "Alien", "year": 1979, "director": { "name": "Ridley Scott" }, "starringRef": [] }
+            ])
+        );
+        assert_eq!(data["directorsById"]["name"], "Ridley Scott");
+        assert_eq!(data["directorsById"]["_raw"]["_path"], "directors/scott.yaml");
+        assert!(data["nobody"].is_null());
+
+        // Nesting past the depth limit is refused before anything is read.
+        let nested = format!("{{ __schema {{ types {{ {}name{} }} }} }}", "ofType { ".repeat(12), " }".repeat(12));
+        let response = handlers::post_graphql(
+            State(state.clone()),
+            host_header("films"),
+            Query(HashMap::new()),
+            Json(async_graphql::Request::new(nested)),
+        )
+        .await;
+        let json: serde_json::Value =
+            serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(json["errors"][0]["message"].as_str().unwrap().contains("too deep"), "{}", json);
+
+        // Without `query`, GET returns the derived schema.
+        let response = handlers::get_graphql(State(state), host_header("films"), Query(HashMap::new())).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let sdl = String::from_utf8(body.to_vec()).unwrap();
+        assert!(sdl.contains("type Movies {"), "{}", sdl);
+        assert!(sdl.contains("director: Directors"), "{}", sdl);
+        assert!(sdl.contains("starring: [String]"), "{}", sdl);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_queries_share_one_indexing_run() {
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+
+        let state = test_state(repo_dir.path().to_path_buf());
+        let queries: Vec<_> = (0..8).map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let response = handlers::handle_query(
+                    State(state),
+                    host_header("repo"),
+                    AxPath("query".to_string()),
+                    None,
+                    None,
+                )
+                .await;
+                let (parts, body) = response.into_response().into_parts();
+                let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
+                (parts.status, serde_json::from_slice::<serde_json::Value>(&body_bytes).unwrap())
+            })
+        }).collect();
+        // The test hook inserts one document per run: a second run would duplicate it.
+        for q in queries {
+            let (status, json) = q.await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(json["total"], 1);
+        }
+    }
+
+    /// Hooks indexing an `asOf` snapshot work in the snapshot directory, not under `branches/`
+    #[tokio::test]
+    async fn test_as_of_hook_run_stays_in_snapshot_dir() {
+        let repo_dir = tempdir().unwrap();
+        let (_, commit) = init_repo_with_index_hook(repo_dir.path());
+        let state = test_state(repo_dir.path().to_path_buf());
+
+        let response = handlers::handle_query(
+            State(state),
+            host_header("repo"),
+            AxPath("query".to_string()),
+            None,
+            Some(Json(serde_json::json!({ "asOf": commit.to_string() }))),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        let data = repo_dir.path().join("repo.git").join(".relay_data");
+        assert!(data.join("snapshots").join(commit.to_string()).join("hook-ran").exists());
+        assert!(!data.join("branches").exists());
+    }
+
+    async fn indexing_status(state: &AppState) -> serde_json::Value {
+        let response = handlers::get_indexing_status(State(state.clone()), host_header("repo")).await;
+        let (_, body) = response.into_response().into_parts();
+        let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
+        serde_json::from_slice(&body_bytes).unwrap()
+    }
+
+    /// Poll /api/indexing until `branch` is fresh; returns its status entry.
+    async fn wait_fresh(state: &AppState, branch: &str) -> serde_json::Value {
+        for _ in 0..200 {
+            let status = indexing_status(state).await;
+            let entry = status["branches"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|b| b["branch"] == branch)
+                .cloned();
+            if let Some(entry) = entry.filter(|b| b["fresh"] == true && b["state"] == "idle") {
+                return entry;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("branch {} was not indexed in the background", branch);
+    }
+
+    /// Writes and post-receive trigger files get indexed without any QUERY
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_background_worker_indexes_writes_and_push_triggers() {
+        let repo_dir = tempdir().unwrap();
+        let repo_path = repo_dir.path().join("repo.git");
+        let repo = Repository::init_bare(&repo_path).unwrap();
+        let state = test_state(repo_dir.path().to_path_buf());
+        state.index_worker.start(1);
+
+        let response = handlers::put_file(
+            State(state.clone()),
+            host_header("repo"),
+            AxPath("docs/meta.yaml".to_string()),
+            None,
+            axum::body::Bytes::from_static(b"title: Hello\n"),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        let main = wait_fresh(&state, "main").await;
+        assert_eq!(main["runs"], 1);
+        assert!(main["lastError"].is_null());
+        let index = crate::git::indexdb::BranchIndex::open_existing(&repo_path, "main").unwrap().unwrap();
+        assert_eq!(index.count("index").unwrap(), 1);
+
+        // A push received by git daemon: the hook handler only drops a trigger file.
+        let sig = Signature::now("relay", "relay@local").unwrap();
+        let blob = repo.blob(b"title: Pushed\n").unwrap();
+        let tree_oid = git2::build::TreeUpdateBuilder::new()
+            .upsert("a/meta.yaml", blob, git2::FileMode::Blob)
+            .upsert("b/meta.yaml", blob, git2::FileMode::Blob)
+            .create_updated(&repo, &repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap())
+            .unwrap();
+        let tree = repo.find_tree(tree_oid).unwrap();
+        // hex(branch) of this name would exceed the 255-byte file name limit.
+        let pushed = format!("feature/{}", "x".repeat(200));
+        let pushed_head = repo.commit(Some(&format!("refs/heads/{}", pushed)), &sig, &sig, "push", &tree, &[]).unwrap();
+        let triggers = repo_path.join(".relay_data").join(crate::git::index_worker::TRIGGER_DIR);
+        std::fs::create_dir_all(&triggers).unwrap();
+        std::fs::write(triggers.join("garbage"), b"").unwrap();
+        let (_listener, mut rx) = crate::git::changes::subscribe("repo");
+        let pushed_head = pushed_head.to_string();
+        crate::git::index_worker::request_indexing(&repo_path, &pushed, None, Some(&pushed_head)).unwrap();
+
+        let feature = wait_fresh(&state, &pushed).await;
+        assert_eq!(feature["runs"], 1);
+        let index = crate::git::indexdb::BranchIndex::open_existing(&repo_path, &pushed).unwrap().unwrap();
+        assert_eq!(index.count("index").unwrap(), 2);
+        assert_eq!(std::fs::read_dir(triggers).unwrap().count(), 0);
+        // The trigger carries the push's exact tips into the change feed.
+        loop {
+            let event = next_event(&mut rx, "repo").await;
+            if event["type"] == "branch" && event["branch"] == pushed.as_str() {
+                assert!(event["old"].is_null());
+                assert_eq!(event["head"], pushed_head.as_str());
+                break;
+            }
+        }
+    }
+
+    /// Branch API moves key the worker like QUERY does: one shared run, visible in /api/indexing
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_branch_api_notify_and_query_share_one_run() {
+        // A relative root like the default `data`, which git2's `repo.path()` makes absolute.
+        let repo_dir = tempfile::tempdir_in("target").unwrap();
+        let root = repo_dir.path().strip_prefix(std::env::current_dir().unwrap()).unwrap();
+        init_repo_with_index_hook(root);
+        let state = test_state(root.to_path_buf());
+        state.index_worker.start(1);
+
+        let response = handlers::create_branch(
+            State(state.clone()),
+            host_header("repo"),
+            Json(handlers::branches::CreateBranchRequest { name: "feature".to_string(), from: None }),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::CREATED);
+        let mut headers = host_header("repo");
+        headers.insert(HEADER_BRANCH, "feature".parse().unwrap());
+        let response = handlers::handle_query(State(state.clone()), headers, AxPath("query".to_string()), None, None).await;
+        let (parts, body) = response.into_response().into_parts();
+        let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
+        assert_eq!(parts.status, StatusCode::OK);
+        // The test hook inserts one document per run: a second run would duplicate it.
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body_bytes).unwrap()["total"], 1);
+
+        let feature = wait_fresh(&state, "feature").await;
+        assert!(feature["lastError"].is_null());
+    }
+
+    /// PUT and DELETE create unsigned server commits, which branches requiring signatures refuse
+    #[tokio::test]
+    async fn test_writes_to_signed_branch_are_forbidden() {
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init_bare(repo_dir.path().join("repo.git")).unwrap();
+        let sig = Signature::now("relay", "relay@local").unwrap();
+        let config = repo
+            .blob(b"git:\n  branchRules:\n    branches:\n      - name: \"release/*\"\n        rule:\n          requireSigned: true\n")
+            .unwrap();
+        let readme = repo.blob(b"hello").unwrap();
+        let tree_oid = git2::build::TreeUpdateBuilder::new()
+            .upsert(".relay.yaml", config, git2::FileMode::Blob)
+            .upsert("README.md", readme, git2::FileMode::Blob)
+            .create_updated(&repo, &repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap())
+            .unwrap();
+        let tree = repo.find_tree(tree_oid).unwrap();
+        let c = repo.commit(Some("refs/heads/main"), &sig, &sig, "init", &tree, &[]).unwrap();
+        repo.reference("refs/heads/release/1.0", c, false, "branch").unwrap();
+        let state = test_state(repo_dir.path().to_path_buf());
+
+        let mut headers = host_header("repo");
+        headers.insert(HEADER_BRANCH, "release/1.0".parse().unwrap());
+        let response = handlers::put_file(
+            State(state.clone()),
+            headers.clone(),
+            AxPath("docs/new.md".to_string()),
+            None,
+            axum::body::Bytes::from_static(b"new"),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        let response =
+            handlers::delete_file(State(state.clone()), headers, AxPath("README.md".to_string()), None).await;
+        assert_eq!(response.into_response().status(), StatusCode::FORBIDDEN);
+        assert_eq!(repo.refname_to_id("refs/heads/release/1.0").unwrap(), c);
+
+        let response = handlers::put_file(
+            State(state),
+            host_header("repo"),
+            AxPath("docs/new.md".to_string()),
+            None,
+            axum::body::Bytes::from_static(b"new"),
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+    }
+
+    /// A PATCH in flight locks its upload; cancelling it (client gone) releases the lock
+    #[tokio::test]
+    async fn test_upload_patch_lock_released_when_request_dropped() {
+        use tokio_stream::StreamExt;
+
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+        let state = test_state(repo_dir.path().to_path_buf());
+        let response = handlers::create_upload(
+            State(state.clone()),
+            host_header("repo"),
+            Json(serde_json::from_value(serde_json::json!({ "path": "big.bin" })).unwrap()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let id = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["id"].as_str().unwrap().to_string();
+        let patch = |body: axum::body::Body| {
+            let mut headers = host_header("repo");
+            headers.insert("Upload-Offset", "0".parse().unwrap());
+            handlers::patch_upload(State(state.clone()), headers, AxPath(id.clone()), body)
+        };
+
+        // A body that sends one chunk and then stalls.
+        let stalled = tokio_stream::iter([Ok::<_, std::io::Error>(axum::body::Bytes::from_static(b"abc"))])
+            .chain(tokio_stream::pending());
+        let in_flight = tokio::spawn(patch(axum::body::Body::from_stream(stalled)));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let response = patch(axum::body::Body::from("abc")).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        // Finalize and DELETE wait for the PATCH too, rather than committing or removing a growing file.
+        let response = handlers::finalize_upload(State(state.clone()), host_header("repo"), AxPath(id.clone())).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        let response = handlers::delete_upload(State(state.clone()), host_header("repo"), AxPath(id.clone())).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+
+        in_flight.abort();
+        assert!(in_flight.await.unwrap_err().is_cancelled());
+        let response = patch(axum::body::Body::from("abc")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["Upload-Offset"], "3");
+    }
+
+    /// Next feed event about `repo`, skipping events of repos of concurrently running tests and
+    /// writes to the quarantine collection.
+    async fn next_event(
+        rx: &mut tokio::sync::broadcast::Receiver<crate::git::changes::ChangeEvent>,
+        repo: &str,
+    ) -> serde_json::Value {
+        let wait = std::time::Duration::from_secs(10);
+        loop {
+            let event = tokio::time::timeout(wait, rx.recv()).await.expect("no change event").unwrap();
+            if event.repo() == repo && event.collection() != Some(crate::git::schema::QUARANTINE_COLLECTION) {
+                return serde_json::to_value(&event).unwrap();
+            }
+        }
+    }
+
+    /// Writes publish a branch event, then the index writes of the catch-up indexing run
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_change_feed_streams_branch_and_document_events() {
+        use tokio_stream::StreamExt;
+
+        let repo_dir = tempdir().unwrap();
+        Repository::init_bare(repo_dir.path().join("feed.git")).unwrap();
+        let state = test_state(repo_dir.path().to_path_buf());
+        state.index_worker.start(1);
+        assert!(!crate::git::changes::has_subscribers("feed"));
+        let (listener, mut rx) = crate::git::changes::subscribe("feed");
+        assert!(crate::git::changes::has_subscribers("feed"));
+
+        let put = |body: &'static [u8]| {
+            handlers::put_file(
+                State(state.clone()),
+                host_header("feed"),
+                AxPath("movies/heat/meta.yaml".to_string()),
+                None,
+                axum::body::Bytes::from_static(body),
+            )
+        };
+        assert_eq!(put(b"title: Heat\n").await.into_response().status(), StatusCode::OK);
+        let branch = next_event(&mut rx, "feed").await;
+        assert_eq!(branch["type"], "branch");
+        assert_eq!(branch["branch"], "main");
+        assert!(branch["old"].is_null());
+        let head = branch["head"].as_str().unwrap().to_string();
+        let docs = next_event(&mut rx, "feed").await;
+        assert_eq!(docs["type"], "documents");
+        assert_eq!(docs["collection"], "index");
+        assert_eq!(docs["head"], head.as_str());
+        let inserted = docs["changes"].as_array().unwrap().last().unwrap().clone();
+        assert_eq!(inserted["op"], "insert");
+        assert_eq!(inserted["doc"]["_path"], "movies/heat/meta.yaml");
+        assert_eq!(inserted["doc"]["title"], "Heat");
+
+        assert_eq!(put(b"title: Heat\nyear: 1995\n").await.into_response().status(), StatusCode::OK);
+        let branch = next_event(&mut rx, "feed").await;
+        assert_eq!(branch["old"], head.as_str());
+        let docs = next_event(&mut rx, "feed").await;
+        let changes = docs["changes"].as_array().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], serde_json::json!({"op": "remove", "query": {"_path": "movies/heat/meta.yaml"}}));
+        assert_eq!(changes[1]["op"], "insert");
+        assert_eq!(changes[1]["doc"]["year"], 1995);
+
+        let response = handlers::delete_file(
+            State(state.clone()),
+            host_header("feed"),
+            AxPath("movies/heat/meta.yaml".to_string()),
+            None,
+        )
+        .await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        assert_eq!(next_event(&mut rx, "feed").await["type"], "branch");
+        let docs = next_event(&mut rx, "feed").await;
+        assert_eq!(
+            docs["changes"],
+            serde_json::json!([{"op": "remove", "query": {"_path": "movies/heat/meta.yaml"}}])
+        );
+        drop(listener);
+        assert!(!crate::git::changes::has_subscribers("feed"));
+
+        // The SSE stream starts with the current heads.
+        let response = handlers::get_events(
+            State(state.clone()),
+            host_header("feed"),
+            Query(handlers::events::EventsQuery::default()),
+        )
+        .await;
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body().into_data_stream();
+        let first = String::from_utf8(body.next().await.unwrap().unwrap().to_vec()).unwrap();
+        assert!(first.starts_with("event: ready\n"), "{}", first);
+        assert!(first.contains("\"branchHeads\":{\"main\":"), "{}", first);
+    }
+
+    /// Only the exact admin bearer token opens the admin API
+    #[tokio::test]
+    async fn test_admin_token_must_match_exactly() {
+        let repo_dir = tempdir().unwrap();
+        Repository::init_bare(repo_dir.path().join("repo.git")).unwrap();
+        let mut state = test_state(repo_dir.path().to_path_buf());
+        state.admin_token = Some("s3cret-token".to_string());
+        let status = |auth: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(auth) = auth {
+                headers.insert(header::AUTHORIZATION, auth.parse().unwrap());
+            }
+            handlers::get_index_status(State(state.clone()), headers, AxPath("repo".to_string()))
+        };
+        assert_eq!(status(Some("Bearer s3cret-token")).await.status(), StatusCode::OK);
+        for auth in [None, Some("Bearer s3cret"), Some("Bearer s3cret-token2"), Some("Bearer "), Some("s3cret-token")] {
+            assert_eq!(status(auth).await.status(), StatusCode::UNAUTHORIZED, "{:?}", auth);
+        }
+    }
+
+    /// A fork that cannot be allowlisted is reported as a failure, naming the fork that exists
+    #[tokio::test]
+    async fn test_fork_authorize_failure_is_an_error() {
+        let repo_dir = tempdir().unwrap();
+        init_repo_with_index_hook(repo_dir.path());
+        // Not a mapping: adding the fork's entry fails.
+        let allowlist = repo_dir.path().join("authorized-repos.yaml");
+        std::fs::write(&allowlist, "- not-a-mapping\n").unwrap();
+        let mut state = test_state(repo_dir.path().to_path_buf());
+        state.admin_token = Some("s3cret-token".to_string());
+        state.authorized_repos = Some(std::sync::Arc::new(crate::authorized_repos::AuthorizedReposFile {
+            relay_server_id: None,
+            repos: HashMap::new(),
+            path: Some(allowlist),
+        }));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer s3cret-token".parse().unwrap());
+
+        let response = handlers::fork_repo(
+            State(state),
+            headers,
+            AxPath("repo".to_string()),
+            Json(serde_json::from_value(serde_json::json!({ "name": "copy", "authorize": true })).unwrap()),
+        )
+        .await;
+        let (parts, body) = response.into_parts();
+        let body_bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap().to_vec();
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(parts.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(json["error"].as_str().unwrap().contains("was created"), "{}", json);
+        assert_eq!(json["repo"]["name"], "copy");
+        assert!(repo_dir.path().join("copy.git").is_dir());
+    }
 }
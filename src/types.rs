@@ -1,4 +1,6 @@
+use globset::GlobBuilder;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::path::PathBuf;
 use std::sync::Arc;
 use axum::{
@@ -11,6 +13,8 @@ use axum::{
 pub const HEADER_REPO: &str = "X-Relay-Repo";
 pub const HEADER_BRANCH: &str = "X-Relay-Branch";
 pub const DEFAULT_BRANCH: &str = "main";
+/// `X-Relay-Branch` value that fans a QUERY out over every branch.
+pub const ALL_BRANCHES: &str = "all";
 pub const DEFAULT_IPFS_CACHE_ROOT: &str = "/tmp/ipfs-cache";
 
 #[derive(Clone)]
@@ -27,6 +31,10 @@ pub struct AppState {
     pub authorized_repos: Option<Arc<crate::authorized_repos::AuthorizedReposFile>>,
     /// Written by relay-install.sh (`state/features.json`); exposed in /api/config.
     pub features_manifest: Option<Arc<serde_json::Value>>,
+    /// Bearer token for `/api/admin/*`; admin endpoints are disabled when unset (`RELAY_ADMIN_TOKEN`).
+    pub admin_token: Option<String>,
+    /// Background indexer notified by writes, pulls and pushes.
+    pub index_worker: crate::git::index_worker::IndexWorker,
 }
 
 #[derive(Deserialize, Debug)]
@@ -53,6 +61,56 @@ pub struct RelayConfig {
 #[derive(Deserialize, Debug, Default, Serialize)]
 pub struct ServerConfig {
     pub hooks: Option<std::collections::HashMap<String, HookPath>>,
+    /// Per-branch index store settings.
+    #[serde(default)]
+    pub db: Option<DbConfig>,
+}
+
+#[derive(Deserialize, Debug, Default, Serialize)]
+pub struct DbConfig {
+    #[serde(default)]
+    pub collections: std::collections::HashMap<String, CollectionConfig>,
+    /// Files indexed natively (no `index` hook); defaults to every `meta.yaml`/`meta.yml` into `index`.
+    #[serde(default)]
+    pub sources: Option<Vec<IndexSource>>,
+    /// JSON Schemas for files matching a glob, checked on commit/push and during indexing.
+    #[serde(default)]
+    pub schemas: Vec<PathSchema>,
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+pub struct PathSchema {
+    /// Repo-relative glob, e.g. `**/meta.yaml`
+    pub glob: String,
+    pub schema: SchemaRef,
+}
+
+/// A JSON Schema given inline, or as the repo path of a JSON/YAML schema file.
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SchemaRef {
+    Path(String),
+    Inline(serde_json::Value),
+}
+
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+pub struct IndexSource {
+    /// Repo-relative glob, e.g. `**/meta.yaml` or `catalog/*.json`
+    pub glob: String,
+    pub collection: String,
+}
+
+#[derive(Deserialize, Debug, Default, Serialize, Clone)]
+pub struct CollectionConfig {
+    /// Dotted field paths with secondary indexes (multikey over arrays).
+    #[serde(default)]
+    pub indexes: Vec<String>,
+    /// Dotted field paths fed to the full-text (BM25) index used by QUERY `search`.
+    #[serde(default)]
+    pub search: Vec<String>,
+    /// JSON Schema every document of the collection must satisfy; invalid ones are quarantined.
+    #[serde(default)]
+    pub schema: Option<SchemaRef>,
 }
 
 #[derive(Deserialize, Debug, Default, Serialize)]
@@ -73,12 +131,54 @@ pub struct BranchRulesConfig {
     pub branches: Option<Vec<BranchRuleNamed>>,
 }
 
+impl BranchRulesConfig {
+    /// Rule for `branch`: the most specific named entry that matches, otherwise `default`.
+    ///
+    /// An exact name beats any pattern; among patterns, the one with more literal characters wins,
+    /// then the one with fewer wildcards, then the entry listed first.
+    pub fn rule_for(&self, branch: &str) -> Option<BranchRule> {
+        self.branches
+            .as_ref()
+            .and_then(|list| {
+                list.iter()
+                    .enumerate()
+                    .filter(|(_, b)| b.matches(branch))
+                    .max_by_key(|(i, b)| (b.name == branch, b.literal_len(), Reverse(b.wildcards()), Reverse(*i)))
+            })
+            .map(|(_, b)| b.rule.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
 #[derive(Deserialize, Debug, Default, Serialize, Clone)]
 pub struct BranchRuleNamed {
+    /// Branch name or glob pattern (`release/*`, `users/**`); `*` does not cross a `/`.
     pub name: String,
     pub rule: BranchRule,
 }
 
+impl BranchRuleNamed {
+    const GLOB_META: &'static [char] = &['*', '?', '[', ']', '{', '}', ','];
+
+    /// Whether this entry applies to `branch`. An invalid pattern only matches its exact name.
+    pub fn matches(&self, branch: &str) -> bool {
+        self.name == branch
+            || GlobBuilder::new(&self.name)
+                .literal_separator(true)
+                .build()
+                .map(|g| g.compile_matcher().is_match(branch))
+                .unwrap_or(false)
+    }
+
+    fn literal_len(&self) -> usize {
+        self.name.chars().filter(|c| !Self::GLOB_META.contains(c)).count()
+    }
+
+    fn wildcards(&self) -> usize {
+        self.name.matches(['*', '?', '[']).count()
+    }
+}
+
 #[derive(Deserialize, Debug, Default, Serialize, Clone)]
 pub struct BranchRule {
     #[serde(rename = "requireSigned")]
@@ -89,6 +189,19 @@ pub struct BranchRule {
     pub allowed_key_fingerprints: Option<Vec<String>>,
     #[serde(rename = "allowUnsigned")]
     pub allow_unsigned: Option<bool>,
+    /// Protected branches cannot be deleted or renamed through the branch API.
+    pub protected: Option<bool>,
+}
+
+impl BranchRule {
+    /// True when new tips of this branch must carry a verified signature.
+    pub fn requires_signature(&self) -> bool {
+        self.require_signed.unwrap_or(false) && !self.allow_unsigned.unwrap_or(false)
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protected.unwrap_or(false)
+    }
 }
 
 #[derive(Deserialize, Debug, Default, Serialize)]